@@ -0,0 +1,121 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::api::error::AppError;
+use crate::api::AppState;
+use crate::clock::Clock;
+use crate::db::DB;
+use crate::models::{AuditEntry, TenantId};
+
+/// Entries returned by `GET /{tenant_id}/audit` without a `limit`, and the most it returns
+const DEFAULT_AUDIT_PAGE_SIZE: u64 = 100;
+const MAX_AUDIT_PAGE_SIZE: u64 = 1000;
+
+/// Caller of an API request. With an admin token every request passed it, so the token
+/// is recorded, otherwise the IP address of the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Actor(pub String);
+
+impl FromRequestParts<AppState> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if state.admin_api_token.is_some() {
+            return Ok(Actor("token:admin".to_string()));
+        }
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        Ok(Actor(ip))
+    }
+}
+
+/// Records writes made through the API in the audit log
+#[derive(Clone)]
+pub struct AuditLogger {
+    db: Arc<DB>,
+}
+
+impl AuditLogger {
+    pub fn new(db: Arc<DB>) -> Self {
+        AuditLogger { db }
+    }
+
+    /// Called after the write succeeded. A failure is logged but doesn't fail the request,
+    /// the write is already done.
+    pub async fn record(
+        &self,
+        tenant_id: &TenantId,
+        actor: &Actor,
+        action: &str,
+        resource_type: &str,
+        resource_id: &str,
+        payload: serde_json::Value,
+    ) {
+        let entry = AuditEntry {
+            id: 0,
+            tenant_id: tenant_id.clone(),
+            actor: actor.0.clone(),
+            action: action.to_string(),
+            resource_type: resource_type.to_string(),
+            resource_id: resource_id.to_string(),
+            payload,
+            timestamp: self.db.clock().now_secs(),
+        };
+        if let Err(e) = self.db.insert_audit_entry(&entry).await {
+            error!(error = ?e, ?entry, "Failed to write audit log entry");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditLogQuery {
+    /// Only entries with a larger id, `next_after` of the previous page
+    pub after: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditLogPage {
+    pub entries: Vec<AuditEntry>,
+    /// `after` of the next page, `None` on the last page
+    pub next_after: Option<u64>,
+}
+
+/// Audit log of the tenant, oldest entries first
+pub async fn get_audit_log_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+    Query(query): Query<AuditLogQuery>,
+) -> Result<Json<AuditLogPage>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_PAGE_SIZE)
+        .clamp(1, MAX_AUDIT_PAGE_SIZE);
+    let entries = state
+        .db
+        .list_audit_entries(&tenant_id, query.after.unwrap_or(0), limit)
+        .await?;
+    let next_after = if entries.len() as u64 == limit {
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    Ok(Json(AuditLogPage {
+        entries,
+        next_after,
+    }))
+}
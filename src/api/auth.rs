@@ -0,0 +1,46 @@
+use axum::{
+    extract::{Request, State},
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::api::error::AppError;
+use crate::api::AppState;
+
+/// Paths that stay reachable without a token, e.g. for load balancer probes.
+/// Devices authenticate to `/provision` with their provisioning token.
+const PUBLIC_PATHS: [&str; 2] = ["/health", "/provision"];
+
+/// Rejects requests without a valid `Authorization: Bearer <admin_api_token>` header.
+/// Only installed when an admin token is configured.
+pub async fn require_admin_token(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(expected) = &state.admin_api_token else {
+        return Ok(next.run(request).await);
+    };
+    if PUBLIC_PATHS.contains(&request.uri().path()) {
+        return Ok(next.run(request).await);
+    }
+
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+            Ok(next.run(request).await)
+        }
+        Some(_) => Err(AppError::Unauthorized("Invalid API token".to_string())),
+        None => Err(AppError::Unauthorized("Missing bearer token".to_string())),
+    }
+}
+
+/// Compares without an early exit so the response time doesn't leak how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
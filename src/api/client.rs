@@ -1 +1,837 @@
+//! Typed HTTP client for the Forest REST API.
+//!
+//! Every method maps to one route and returns the same model types the server uses.
 
+use std::collections::HashMap;
+
+use reqwest::{RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::api::audit::AuditLogPage;
+use crate::api::handlers::{
+    ConnectedClient, HomeResponse, MetricCatalogEntry, MqttMetersResponse,
+    MqttSubscriptionsResponse, ProvisionResponse, ProvisioningTokenResponse, TimeResponse,
+};
+use crate::api::read_only::ReadOnlyMode;
+use crate::certs::CertificateData;
+use crate::dataconfig::{DataConfig, DataConfigEntry};
+use crate::db::backup::BackupInfo;
+use crate::db::export::DeviceExport;
+use crate::models::{
+    AlarmEvent, AlarmRule, DeviceGroup, DeviceInformation, DeviceMetadata, DeviceStatus,
+    FirmwareSummary, LabelSelector, ShadowWebhook, Tenant, TenantQuota, TenantUsage,
+};
+use crate::shadow::{NestedStateDocument, Shadow};
+use crate::timeseries::TimeSeriesModel;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+    // 4xx responses
+    #[error("Client error ({status}): {message}")]
+    Client { status: u16, message: String },
+    // 5xx responses
+    #[error("Server error ({status}): {message}")]
+    Server { status: u16, message: String },
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+#[derive(Clone)]
+pub struct ForestClient {
+    base_url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl ForestClient {
+    pub fn new(base_url: &str, token: Option<String>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    fn shadow_query(name: Option<&str>) -> Vec<(&'static str, String)> {
+        match name {
+            Some(name) => vec![("name", name.to_string())],
+            None => vec![],
+        }
+    }
+
+    async fn execute(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let message = match response.json::<ErrorResponse>().await {
+            Ok(body) => body.message,
+            Err(_) => status.canonical_reason().unwrap_or_default().to_string(),
+        };
+        if status.is_server_error() {
+            Err(ClientError::Server {
+                status: status.as_u16(),
+                message,
+            })
+        } else {
+            Err(ClientError::Client {
+                status: status.as_u16(),
+                message,
+            })
+        }
+    }
+
+    async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, ClientError> {
+        Ok(self.execute(request).await?.json().await?)
+    }
+
+    async fn empty(&self, request: RequestBuilder) -> Result<(), ClientError> {
+        self.execute(request).await?;
+        Ok(())
+    }
+
+    async fn text(&self, request: RequestBuilder) -> Result<String, ClientError> {
+        Ok(self.execute(request).await?.text().await?)
+    }
+
+    // Server
+
+    pub async fn home(&self) -> Result<HomeResponse, ClientError> {
+        self.json(self.http.get(self.url("/"))).await
+    }
+
+    pub async fn health(&self) -> Result<String, ClientError> {
+        self.text(self.http.get(self.url("/health"))).await
+    }
+
+    pub async fn time(&self, device_time: Option<u64>) -> Result<TimeResponse, ClientError> {
+        let mut request = self.http.get(self.url("/time"));
+        if let Some(device_time) = device_time {
+            request = request.query(&[("device_time", device_time)]);
+        }
+        self.json(request).await
+    }
+
+    pub async fn mqtt_subscriptions(&self) -> Result<MqttSubscriptionsResponse, ClientError> {
+        self.json(self.http.get(self.url("/admin/mqtt/subscriptions")))
+            .await
+    }
+
+    pub async fn mqtt_meters(&self) -> Result<MqttMetersResponse, ClientError> {
+        self.json(self.http.get(self.url("/admin/mqtt/meters")))
+            .await
+    }
+
+    /// Writes a snapshot of the server's SQLite database to its backup directory
+    pub async fn backup(&self) -> Result<BackupInfo, ClientError> {
+        self.json(self.http.post(self.url("/admin/backup"))).await
+    }
+
+    pub async fn read_only(&self) -> Result<bool, ClientError> {
+        let mode: ReadOnlyMode = self
+            .json(self.http.get(self.url("/admin/readonly")))
+            .await?;
+        Ok(mode.enabled)
+    }
+
+    /// While enabled the API rejects all writes except this one and backups with 503
+    pub async fn set_read_only(&self, enabled: bool) -> Result<(), ClientError> {
+        let request = self
+            .http
+            .post(self.url("/admin/readonly"))
+            .json(&ReadOnlyMode { enabled });
+        self.empty(request).await
+    }
+
+    pub async fn openapi(&self) -> Result<serde_json::Value, ClientError> {
+        self.json(self.http.get(self.url("/openapi.json"))).await
+    }
+
+    // Shadows
+
+    pub async fn get_shadow(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        shadow_name: Option<&str>,
+    ) -> Result<Shadow, ClientError> {
+        let url = self.url(&format!("/{}/things/{}/shadow", tenant_id, device_id));
+        let request = self.http.get(url).query(&Self::shadow_query(shadow_name));
+        self.json(request).await
+    }
+
+    pub async fn update_shadow(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        shadow_name: Option<&str>,
+        update: &NestedStateDocument,
+    ) -> Result<Shadow, ClientError> {
+        let url = self.url(&format!("/{}/things/{}/shadow", tenant_id, device_id));
+        let request = self
+            .http
+            .post(url)
+            .query(&Self::shadow_query(shadow_name))
+            .json(update);
+        self.json(request).await
+    }
+
+    pub async fn delete_shadow(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        shadow_name: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let url = self.url(&format!("/{}/things/{}/shadow", tenant_id, device_id));
+        let request = self
+            .http
+            .delete(url)
+            .query(&Self::shadow_query(shadow_name));
+        self.empty(request).await
+    }
+
+    // Timeseries
+
+    pub async fn get_timeseries(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        metric: &str,
+        start: u64,
+        end: u64,
+        points: Option<usize>,
+    ) -> Result<TimeSeriesModel, ClientError> {
+        let url = self.url(&format!("/{}/data/{}/{}", tenant_id, device_id, metric));
+        let mut request = self.http.get(url).query(&[("start", start), ("end", end)]);
+        if let Some(points) = points {
+            request = request.query(&[("points", points)]);
+        }
+        self.json(request).await
+    }
+
+    pub async fn get_last_timeseries(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        metric: &str,
+        limit: Option<u64>,
+    ) -> Result<TimeSeriesModel, ClientError> {
+        let url = self.url(&format!(
+            "/{}/data/{}/{}/last",
+            tenant_id, device_id, metric
+        ));
+        let mut request = self.http.get(url);
+        if let Some(limit) = limit {
+            request = request.query(&[("limit", limit)]);
+        }
+        self.json(request).await
+    }
+
+    pub async fn get_timeseries_by_prefix(
+        &self,
+        tenant_id: &str,
+        device_prefix: &str,
+        metric: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<HashMap<String, TimeSeriesModel>, ClientError> {
+        let url = self.url(&format!(
+            "/{}/data/prefix/{}/{}",
+            tenant_id, device_prefix, metric
+        ));
+        self.json(self.http.get(url).query(&[("start", start), ("end", end)]))
+            .await
+    }
+
+    pub async fn get_metric_catalog(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<Vec<MetricCatalogEntry>, ClientError> {
+        let url = self.url(&format!("/{}/data/{}/catalog", tenant_id, device_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn post_telemetry(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        payload: &serde_json::Value,
+    ) -> Result<(), ClientError> {
+        let url = self.url(&format!("/{}/data/{}", tenant_id, device_id));
+        self.empty(self.http.post(url).json(payload)).await
+    }
+
+    // Data configs
+
+    pub async fn store_tenant_data_config(
+        &self,
+        tenant_id: &str,
+        config: &DataConfig,
+    ) -> Result<DataConfig, ClientError> {
+        let url = self.url(&format!("/{}/dataconfig", tenant_id));
+        self.json(self.http.put(url).json(config)).await
+    }
+
+    pub async fn get_tenant_data_config(&self, tenant_id: &str) -> Result<DataConfig, ClientError> {
+        let url = self.url(&format!("/{}/dataconfig", tenant_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn delete_tenant_data_config(&self, tenant_id: &str) -> Result<(), ClientError> {
+        let url = self.url(&format!("/{}/dataconfig", tenant_id));
+        self.empty(self.http.delete(url)).await
+    }
+
+    pub async fn store_device_data_config(
+        &self,
+        tenant_id: &str,
+        device_prefix: &str,
+        config: &DataConfig,
+    ) -> Result<DataConfig, ClientError> {
+        let url = self.url(&format!(
+            "/{}/dataconfig/device/{}",
+            tenant_id, device_prefix
+        ));
+        self.json(self.http.put(url).json(config)).await
+    }
+
+    /// Returns the effective config for a device (tenant config merged with the label configs
+    /// and the best prefix match)
+    pub async fn get_device_data_config(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<DataConfig, ClientError> {
+        let url = self.url(&format!("/{}/dataconfig/device/{}", tenant_id, device_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn delete_device_data_config(
+        &self,
+        tenant_id: &str,
+        device_prefix: &str,
+    ) -> Result<(), ClientError> {
+        let url = self.url(&format!(
+            "/{}/dataconfig/device/{}",
+            tenant_id, device_prefix
+        ));
+        self.empty(self.http.delete(url)).await
+    }
+
+    pub async fn store_label_data_config(
+        &self,
+        tenant_id: &str,
+        label: &LabelSelector,
+        config: &DataConfig,
+    ) -> Result<DataConfig, ClientError> {
+        let url = self.url(&format!("/{}/dataconfig/label/{}", tenant_id, label));
+        self.json(self.http.put(url).json(config)).await
+    }
+
+    pub async fn get_label_data_config(
+        &self,
+        tenant_id: &str,
+        label: &LabelSelector,
+    ) -> Result<DataConfig, ClientError> {
+        let url = self.url(&format!("/{}/dataconfig/label/{}", tenant_id, label));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn delete_label_data_config(
+        &self,
+        tenant_id: &str,
+        label: &LabelSelector,
+    ) -> Result<(), ClientError> {
+        let url = self.url(&format!("/{}/dataconfig/label/{}", tenant_id, label));
+        self.empty(self.http.delete(url)).await
+    }
+
+    pub async fn list_data_configs(
+        &self,
+        tenant_id: &str,
+    ) -> Result<Vec<DataConfigEntry>, ClientError> {
+        let url = self.url(&format!("/{}/dataconfig/all", tenant_id));
+        self.json(self.http.get(url)).await
+    }
+
+    // Devices
+
+    pub async fn list_connected(
+        &self,
+        tenant_id: &str,
+    ) -> Result<Vec<ConnectedClient>, ClientError> {
+        let url = self.url(&format!("/{}/connected", tenant_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn create_device(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        force: bool,
+    ) -> Result<DeviceMetadata, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}", tenant_id, device_id));
+        let request = self
+            .http
+            .post(url)
+            .query(&[("force", force)])
+            .json(&json!({}));
+        self.json(request).await
+    }
+
+    pub async fn list_devices(&self, tenant_id: &str) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!("/{}/devices", tenant_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn get_device_info(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<DeviceInformation, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}", tenant_id, device_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn get_device_metadata(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<DeviceMetadata, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/metadata", tenant_id, device_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn export_device(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        include_metrics: bool,
+    ) -> Result<DeviceExport, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/export", tenant_id, device_id));
+        let request = self
+            .http
+            .get(url)
+            .query(&[("include_metrics", include_metrics)]);
+        self.json(request).await
+    }
+
+    pub async fn delete_device(&self, tenant_id: &str, device_id: &str) -> Result<(), ClientError> {
+        let url = self.url(&format!("/{}/devices/{}", tenant_id, device_id));
+        self.empty(self.http.delete(url)).await
+    }
+
+    pub async fn get_device_tags(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, String>, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/tags", tenant_id, device_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn set_device_tags(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/tags", tenant_id, device_id));
+        self.json(self.http.put(url).json(tags)).await
+    }
+
+    pub async fn list_devices_by_tag(
+        &self,
+        tenant_id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!("/{}/devices", tenant_id));
+        let tag = format!("{}:{}", key, value);
+        self.json(self.http.get(url).query(&[("tag", tag)])).await
+    }
+
+    /// Devices that run another firmware than their target firmware
+    pub async fn list_devices_needing_update(
+        &self,
+        tenant_id: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!("/{}/devices", tenant_id));
+        self.json(self.http.get(url).query(&[("needs_update", true)]))
+            .await
+    }
+
+    pub async fn firmware_summary(&self, tenant_id: &str) -> Result<FirmwareSummary, ClientError> {
+        let url = self.url(&format!("/{}/firmware/summary", tenant_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn get_device_labels(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, String>, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/labels", tenant_id, device_id));
+        self.json(self.http.get(url)).await
+    }
+
+    /// Sets the labels with a value and removes the ones set to `None`, returns all labels
+    pub async fn update_device_labels(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        changes: &HashMap<String, Option<String>>,
+    ) -> Result<HashMap<String, String>, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/labels", tenant_id, device_id));
+        self.json(self.http.patch(url).json(changes)).await
+    }
+
+    pub async fn list_devices_by_label(
+        &self,
+        tenant_id: &str,
+        label: &LabelSelector,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!("/{}/devices", tenant_id));
+        self.json(self.http.get(url).query(&[("label", label.to_string())]))
+            .await
+    }
+
+    pub async fn activate_device(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<DeviceMetadata, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/activate", tenant_id, device_id));
+        self.json(self.http.post(url)).await
+    }
+
+    pub async fn deactivate_device(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        decommission: bool,
+    ) -> Result<DeviceMetadata, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/deactivate", tenant_id, device_id));
+        let request = self.http.post(url).query(&[("decommission", decommission)]);
+        self.json(request).await
+    }
+
+    pub async fn list_devices_by_status(
+        &self,
+        tenant_id: &str,
+        status: DeviceStatus,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!("/{}/devices", tenant_id));
+        self.json(self.http.get(url).query(&[("status", status)]))
+            .await
+    }
+
+    pub async fn list_stale_devices(
+        &self,
+        tenant_id: &str,
+        hours: u64,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!("/{}/devices/stale", tenant_id));
+        self.json(self.http.get(url).query(&[("hours", hours)]))
+            .await
+    }
+
+    // Groups
+
+    pub async fn create_group(
+        &self,
+        tenant_id: &str,
+        group_id: &str,
+        device_ids: &[&str],
+    ) -> Result<DeviceGroup, ClientError> {
+        let url = self.url(&format!("/{}/groups", tenant_id));
+        let body = json!({"group_id": group_id, "device_ids": device_ids});
+        self.json(self.http.post(url).json(&body)).await
+    }
+
+    pub async fn list_group_devices(
+        &self,
+        tenant_id: &str,
+        group_id: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!("/{}/groups/{}/devices", tenant_id, group_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn add_device_to_group(
+        &self,
+        tenant_id: &str,
+        group_id: &str,
+        device_id: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!(
+            "/{}/groups/{}/devices/{}",
+            tenant_id, group_id, device_id
+        ));
+        self.json(self.http.put(url)).await
+    }
+
+    pub async fn remove_device_from_group(
+        &self,
+        tenant_id: &str,
+        group_id: &str,
+        device_id: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!(
+            "/{}/groups/{}/devices/{}",
+            tenant_id, group_id, device_id
+        ));
+        self.json(self.http.delete(url)).await
+    }
+
+    pub async fn update_group_desired(
+        &self,
+        tenant_id: &str,
+        group_id: &str,
+        shadow_name: Option<&str>,
+        desired: &serde_json::Value,
+    ) -> Result<Vec<Shadow>, ClientError> {
+        let url = self.url(&format!(
+            "/{}/groups/{}/shadow/desired",
+            tenant_id, group_id
+        ));
+        let request = self
+            .http
+            .post(url)
+            .query(&Self::shadow_query(shadow_name))
+            .json(desired);
+        self.json(request).await
+    }
+
+    // Alarms
+
+    pub async fn create_alarm_rule(
+        &self,
+        tenant_id: &str,
+        rule: &AlarmRule,
+    ) -> Result<AlarmRule, ClientError> {
+        let url = self.url(&format!("/{}/alarms", tenant_id));
+        self.json(self.http.post(url).json(rule)).await
+    }
+
+    pub async fn list_device_alarms(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        limit: Option<u64>,
+    ) -> Result<Vec<AlarmEvent>, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/alarms", tenant_id, device_id));
+        let mut request = self.http.get(url);
+        if let Some(limit) = limit {
+            request = request.query(&[("limit", limit)]);
+        }
+        self.json(request).await
+    }
+
+    // Webhooks
+
+    pub async fn create_shadow_webhook(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        webhook: &ShadowWebhook,
+    ) -> Result<ShadowWebhook, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/webhooks", tenant_id, device_id));
+        self.json(self.http.post(url).json(webhook)).await
+    }
+
+    pub async fn list_shadow_webhooks(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<Vec<ShadowWebhook>, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/webhooks", tenant_id, device_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn update_shadow_webhook(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        webhook_id: &str,
+        webhook: &ShadowWebhook,
+    ) -> Result<ShadowWebhook, ClientError> {
+        let url = self.url(&format!(
+            "/{}/devices/{}/webhooks/{}",
+            tenant_id, device_id, webhook_id
+        ));
+        self.json(self.http.put(url).json(webhook)).await
+    }
+
+    pub async fn delete_shadow_webhook(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        webhook_id: &str,
+    ) -> Result<(), ClientError> {
+        let url = self.url(&format!(
+            "/{}/devices/{}/webhooks/{}",
+            tenant_id, device_id, webhook_id
+        ));
+        self.empty(self.http.delete(url)).await
+    }
+
+    // Tenants
+
+    pub async fn create_tenant(&self, tenant: &Tenant) -> Result<Tenant, ClientError> {
+        self.json(self.http.post(self.url("/tenants")).json(tenant))
+            .await
+    }
+
+    pub async fn get_tenant(&self, tenant_id: &str) -> Result<Tenant, ClientError> {
+        let url = self.url(&format!("/tenants/{}", tenant_id));
+        self.json(self.http.get(url)).await
+    }
+
+    pub async fn set_tenant_quota(
+        &self,
+        tenant_id: &str,
+        quota: &TenantQuota,
+    ) -> Result<Tenant, ClientError> {
+        let url = self.url(&format!("/tenants/{}/quota", tenant_id));
+        self.json(self.http.put(url).json(quota)).await
+    }
+
+    pub async fn tenant_usage(&self, tenant_id: &str) -> Result<TenantUsage, ClientError> {
+        let url = self.url(&format!("/tenants/{}/usage", tenant_id));
+        self.json(self.http.get(url)).await
+    }
+
+    // Credentials
+
+    pub async fn add_device_password(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<(), ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/passwords", tenant_id, device_id));
+        let body = json!({"username": username, "password_plaintext": password});
+        self.empty(self.http.post(url).json(&body)).await
+    }
+
+    pub async fn list_device_passwords(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let url = self.url(&format!("/{}/devices/{}/passwords", tenant_id, device_id));
+        self.json(self.http.get(url)).await
+    }
+
+    /// Writes made through the API, oldest first. Pass `next_after` of a page to get the next one.
+    pub async fn audit_log(
+        &self,
+        tenant_id: &str,
+        after: Option<u64>,
+        limit: Option<u64>,
+    ) -> Result<AuditLogPage, ClientError> {
+        let mut request = self.http.get(self.url(&format!("/{}/audit", tenant_id)));
+        if let Some(after) = after {
+            request = request.query(&[("after", after)]);
+        }
+        if let Some(limit) = limit {
+            request = request.query(&[("limit", limit)]);
+        }
+        self.json(request).await
+    }
+
+    // Certificates
+
+    pub async fn generate_server_ca(&self) -> Result<(), ClientError> {
+        self.empty(self.http.post(self.url("/cacert/server"))).await
+    }
+
+    pub async fn get_server_ca(&self) -> Result<String, ClientError> {
+        self.text(self.http.get(self.url("/cacert/server"))).await
+    }
+
+    pub async fn generate_tenant_ca(&self, tenant_id: &str) -> Result<(), ClientError> {
+        let url = self.url(&format!("/tenants/{}/cacert/generate", tenant_id));
+        self.empty(self.http.post(url)).await
+    }
+
+    pub async fn upload_tenant_ca(&self, tenant_id: &str, ca_pem: &str) -> Result<(), ClientError> {
+        let url = self.url(&format!("/tenants/{}/cacert", tenant_id));
+        self.empty(self.http.post(url).body(ca_pem.to_string()))
+            .await
+    }
+
+    pub async fn get_tenant_ca(&self, tenant_id: &str) -> Result<String, ClientError> {
+        let url = self.url(&format!("/tenants/{}/cacert", tenant_id));
+        self.text(self.http.get(url)).await
+    }
+
+    pub async fn generate_client_cert(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+    ) -> Result<CertificateData, ClientError> {
+        let url = self.url(&format!(
+            "/tenants/{}/devices/{}/client_cert/generate",
+            tenant_id, device_id
+        ));
+        self.json(self.http.post(url)).await
+    }
+
+    /// DER encoded PKCS#12 bundle of the client cert, its key and the CA chain
+    pub async fn export_client_pkcs12(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        password: &str,
+    ) -> Result<Vec<u8>, ClientError> {
+        let url = self.url(&format!(
+            "/tenants/{}/devices/{}/client_cert.p12",
+            tenant_id, device_id
+        ));
+        let request = self.http.get(url).query(&[("password", password)]);
+        Ok(self.execute(request).await?.bytes().await?.to_vec())
+    }
+
+    /// The raw token is only returned here
+    pub async fn create_provisioning_token(
+        &self,
+        tenant_id: &str,
+        device_id: &str,
+        ttl_seconds: u64,
+    ) -> Result<ProvisioningTokenResponse, ClientError> {
+        let url = self.url(&format!(
+            "/{}/devices/{}/provisioning-token",
+            tenant_id, device_id
+        ));
+        self.json(self.http.post(url).query(&[("ttl_seconds", ttl_seconds)]))
+            .await
+    }
+
+    pub async fn provision(&self, token: &str) -> Result<ProvisionResponse, ClientError> {
+        let url = self.url("/provision");
+        self.json(self.http.post(url).query(&[("token", token)]))
+            .await
+    }
+}
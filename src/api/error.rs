@@ -1,6 +1,7 @@
 use crate::certs::CertificateError;
 use crate::db::DatabaseError;
-use axum::http::StatusCode;
+use crate::shadow::ShadowError;
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde::Serialize;
@@ -20,6 +21,21 @@ pub enum AppError {
     InternalServerError(String),
     #[error("Conflict: {0}")]
     Conflict(String),
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Unprocessable entity: {0}")]
+    UnprocessableEntity(String),
+    // 422 with every problem found in the request body
+    #[error("Validation failed: {}", .0.join(", "))]
+    ValidationFailed(Vec<String>),
+    // 429 with the seconds to wait in the Retry-After header
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String, u64),
+    // 503 with the seconds to wait in the Retry-After header
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String, u64),
 }
 
 impl IntoResponse for AppError {
@@ -28,8 +44,12 @@ impl IntoResponse for AppError {
         #[derive(Serialize)]
         struct ErrorResponse {
             message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            errors: Option<Vec<String>>,
         }
 
+        let mut errors = None;
+        let mut retry_after = None;
         let (status, message) = match self {
             AppError::NotFound(msg) => {
                 // Add msg to not found message
@@ -39,6 +59,47 @@ impl IntoResponse for AppError {
                 // Add msg to conflict message
                 (StatusCode::CONFLICT, format!("Conflict: {}", msg))
             }
+            AppError::PreconditionFailed(msg) => (
+                StatusCode::PRECONDITION_FAILED,
+                format!("Precondition failed: {}", msg),
+            ),
+            AppError::Unauthorized(msg) => {
+                (StatusCode::UNAUTHORIZED, format!("Unauthorized: {}", msg))
+            }
+            AppError::UnprocessableEntity(msg) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Unprocessable entity: {}", msg),
+            ),
+            AppError::ValidationFailed(list) => {
+                let message = format!("Validation failed: {} error(s)", list.len());
+                errors = Some(list);
+                (StatusCode::UNPROCESSABLE_ENTITY, message)
+            }
+            AppError::TooManyRequests(msg, retry_after_secs) => {
+                retry_after = Some(retry_after_secs);
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    format!("Too many requests: {}", msg),
+                )
+            }
+            AppError::ServiceUnavailable(msg, retry_after_secs) => {
+                retry_after = Some(retry_after_secs);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("Service unavailable: {}", msg),
+                )
+            }
+            // No Retry-After, waiting doesn't free any quota
+            AppError::DatabaseError(DatabaseError::QuotaExceeded(msg)) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Quota exceeded: {}", msg),
+            ),
+            AppError::DatabaseError(DatabaseError::ShadowError(
+                e @ ShadowError::TooLarge { .. },
+            )) => (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()),
+            AppError::DatabaseError(DatabaseError::Unsupported(msg)) => {
+                (StatusCode::NOT_IMPLEMENTED, msg)
+            }
             AppError::DatabaseError(e) => {
                 tracing::error!(error=?e, "Database error in API");
                 // Add error to database error message
@@ -64,6 +125,12 @@ impl IntoResponse for AppError {
             }
         };
 
-        (status, Json(ErrorResponse { message })).into_response()
+        let mut response = (status, Json(ErrorResponse { message, errors })).into_response();
+        if let Some(secs) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
     }
 }
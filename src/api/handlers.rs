@@ -1,28 +1,67 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use crate::api::audit::Actor;
 use crate::api::error::AppError;
 use crate::api::services::create_device;
 use crate::api::AppState;
-use crate::certs::CertificateData;
-use crate::dataconfig::{DataConfig, DataConfigEntry};
+use crate::certs::{CertificateData, CertificateError};
+use crate::clock::Clock;
+use crate::dataconfig::{ConfigSource, DataConfig, DataConfigEntry, DataType, MetricConfig};
+use crate::db::backup::BackupInfo;
+use crate::db::export::DeviceExport;
 use crate::db::DatabaseError;
-use crate::models::{DeviceCredential, DeviceInformation, DeviceMetadata, Tenant};
+use crate::models::{
+    AlarmEvent, AlarmRule, DeadLetter, DeviceGroup, DeviceInformation, DeviceMetadata,
+    DeviceStatus, ExtractionError, FirmwareSummary, LabelSelector, ProvisioningToken,
+    ShadowWebhook, Tenant, TenantQuota, TenantUsage,
+};
 use crate::models::{ShadowName, TenantId};
+use crate::mqtt::{RouterMeterSnapshot, SubscriptionMeterSnapshot};
+use crate::processor::config_cache::ConfigInvalidation;
+use crate::processor::rate_limit::ShadowRateLimitStatus;
 use crate::processor::send_delta_to_mqtt;
-use crate::shadow::{NestedStateDocument, Shadow, StateUpdateDocument};
-use crate::timeseries::{TimeSeriesConversions, TimeSeriesModel};
+use crate::processor::webhooks::ShadowWebhookDispatch;
+use crate::shadow::{NestedStateDocument, Shadow, StateDocument, StateUpdateDocument};
+use crate::timeseries::{
+    Aggregation, BoundingBox, CalendarUnit, GapReport, MetricTimeSeries, MetricValue,
+    TimeSeriesConversions, TimeSeriesModel,
+};
 use axum::{
     extract::{Path, Query, State},
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct HomeResponse {
     pub connected_devices: usize,
     pub mqtt_messages_received: u64,
     pub mqtt_messages_sent: u64,
     pub mqtt_messages_dropped: u64,
+    /// Metric rows queued in the ingest buffer
+    pub metrics_buffered: u64,
+    /// Metric rows written from the ingest buffer
+    pub metrics_flushed: u64,
+    /// Retransmitted metric values that were skipped
+    pub metrics_deduplicated: u64,
+    /// Data configs served from the processor cache
+    pub data_config_cache_hits: u64,
+    /// Data configs loaded from the database
+    pub data_config_cache_misses: u64,
+    /// MQTT shadow updates discarded by the per device rate limit
+    pub shadow_rate_limited_total: u64,
+    /// MQTT messages dropped by the per device message rate limit
+    pub mqtt_messages_throttled: u64,
+    /// MQTT messages dropped because their payload exceeds the size limit
+    pub mqtt_messages_oversized: u64,
+    /// Metric points skipped because a stream client fell behind
+    pub metric_stream_dropped: u64,
     pub forest_version: String,
 }
 
@@ -39,6 +78,39 @@ pub async fn home_handler(State(state): State<AppState>) -> Result<Json<HomeResp
     let mqtt_dropped = metrics
         .messages_dropped
         .load(std::sync::atomic::Ordering::Relaxed);
+    let metrics_buffered = state
+        .ingest_metrics
+        .buffered
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let metrics_flushed = state
+        .ingest_metrics
+        .flushed
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let metrics_deduplicated = state
+        .ingest_metrics
+        .deduplicated
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let data_config_cache_hits = state
+        .ingest_metrics
+        .config_cache_hits
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let data_config_cache_misses = state
+        .ingest_metrics
+        .config_cache_misses
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let shadow_rate_limited_total = state.shadow_rate_limiter.limited_total();
+    let mqtt_messages_throttled = state
+        .ingest_metrics
+        .throttled
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let mqtt_messages_oversized = state
+        .ingest_metrics
+        .oversized
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let metric_stream_dropped = state
+        .ingest_metrics
+        .stream_dropped
+        .load(std::sync::atomic::Ordering::Relaxed);
     let forest_version = env!("CARGO_PKG_VERSION").to_string();
 
     let response = HomeResponse {
@@ -46,6 +118,15 @@ pub async fn home_handler(State(state): State<AppState>) -> Result<Json<HomeResp
         mqtt_messages_received: mqtt_received,
         mqtt_messages_sent: mqtt_sent,
         mqtt_messages_dropped: mqtt_dropped,
+        metrics_buffered,
+        metrics_flushed,
+        metrics_deduplicated,
+        data_config_cache_hits,
+        data_config_cache_misses,
+        shadow_rate_limited_total,
+        mqtt_messages_throttled,
+        mqtt_messages_oversized,
+        metric_stream_dropped,
         forest_version,
     };
 
@@ -56,69 +137,323 @@ pub async fn health_handler() -> &'static str {
     "OK"
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MqttSubscriptionsResponse {
+    /// Latest meter of every subscription filter of the router
+    pub filters: Vec<SubscriptionMeterSnapshot>,
+    /// Client ids of the connected devices
+    pub connections: Vec<String>,
+    /// Totals of the latest router meter, `None` before the broker pushed one
+    pub total_connections: Option<usize>,
+    pub total_subscriptions: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MqttMetersResponse {
+    /// Number of router meters kept
+    pub capacity: usize,
+    /// Captured router meters, oldest first
+    pub meters: Vec<RouterMeterSnapshot>,
+}
+
+pub async fn mqtt_subscriptions_handler(
+    State(state): State<AppState>,
+) -> Result<Json<MqttSubscriptionsResponse>, AppError> {
+    let mut connections: Vec<String> = state
+        .connected_clients
+        .iter()
+        .map(|client| client.key().clone())
+        .collect();
+    connections.sort();
+    let latest = state.mqtt_metrics.router_meters().pop();
+    Ok(Json(MqttSubscriptionsResponse {
+        filters: state.mqtt_metrics.subscription_meters(),
+        connections,
+        total_connections: latest.as_ref().map(|m| m.total_connections),
+        total_subscriptions: latest.as_ref().map(|m| m.total_subscriptions),
+    }))
+}
+
+pub async fn mqtt_meters_handler(
+    State(state): State<AppState>,
+) -> Result<Json<MqttMetersResponse>, AppError> {
+    Ok(Json(MqttMetersResponse {
+        capacity: state.mqtt_metrics.meter_capacity(),
+        meters: state.mqtt_metrics.router_meters(),
+    }))
+}
+
+/// Writes a snapshot of the database to the backup directory
+pub async fn create_backup_handler(
+    State(state): State<AppState>,
+) -> Result<Json<BackupInfo>, AppError> {
+    let info = state.db.backup().await?;
+    tracing::info!(
+        path = info.path,
+        size = info.size_bytes,
+        "Database backup written"
+    );
+    Ok(Json(info))
+}
+
+/// Hand maintained OpenAPI 3 document, update it together with the routes
+const OPENAPI_SPEC: &str = include_str!("openapi.json");
+
+pub async fn openapi_handler() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "application/json")], OPENAPI_SPEC)
+}
+
+/// Checks an `If-Match` / `If-None-Match` header value against an entity tag.
+/// The header may contain a comma separated list of tags or `*`.
+fn etag_matches(header_value: &str, etag: &str) -> bool {
+    header_value
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag == etag)
+}
+
 pub async fn get_shadow_handler(
     Path((_tenant_id, device_id)): Path<(String, String)>,
     State(state): State<AppState>,
     Query(params): Query<HashMap<String, String>>,
-) -> Result<Json<Shadow>, AppError> {
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
     let db = state.db.clone();
     let maybe_shadow_name = params.get("name");
     let shadow_name = match maybe_shadow_name {
         Some(name) => ShadowName::from_str(name),
         None => ShadowName::Default,
     };
-    match db
+    let shadow = match db
         ._get_shadow(&device_id, &shadow_name, &TenantId::Default)
         .await
     {
-        Ok(doc) => Ok(Json(doc)),
-        Err(DatabaseError::NotFoundError(_)) => Err(AppError::NotFound(format!(
-            "Shadow ({}) not found for device: {}",
-            shadow_name.as_str(),
-            device_id
-        ))),
-        Err(e) => Err(AppError::DatabaseError(e)),
+        Ok(doc) => doc,
+        Err(DatabaseError::NotFoundError(_)) => {
+            return Err(AppError::NotFound(format!(
+                "Shadow ({}) not found for device: {}",
+                shadow_name.as_str(),
+                device_id
+            )))
+        }
+        Err(e) => return Err(AppError::DatabaseError(e)),
+    };
+
+    let etag = shadow.etag();
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        if etag_matches(if_none_match, &etag) {
+            return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+        }
     }
+    Ok(([(ETAG, etag)], Json(shadow)).into_response())
 }
 
 pub async fn update_shadow_handler(
     Path((_tenant_id, device_id)): Path<(String, String)>,
     State(state): State<AppState>,
     Query(params): Query<HashMap<String, String>>,
+    actor: Actor,
+    headers: HeaderMap,
     Json(nested_update_doc): Json<NestedStateDocument>,
-) -> Result<Json<Shadow>, AppError> {
+) -> Result<Response, AppError> {
     let tenant_id = TenantId::Default;
     let maybe_shadow_name = params.get("name");
     let shadow_name = match maybe_shadow_name {
         Some(name) => ShadowName::from_str(name),
         None => ShadowName::Default,
     };
+
+    // Optimistic concurrency: only apply the update if the client saw the current shadow
+    if let Some(if_match) = headers.get(IF_MATCH).and_then(|v| v.to_str().ok()) {
+        let current_etag = match state
+            .db
+            ._get_shadow(&device_id, &shadow_name, &tenant_id)
+            .await
+        {
+            Ok(current) => Some(current.etag()),
+            Err(DatabaseError::NotFoundError(_)) => None,
+            Err(e) => return Err(AppError::DatabaseError(e)),
+        };
+        let matches = match &current_etag {
+            Some(etag) => etag_matches(if_match, etag),
+            None => false,
+        };
+        if !matches {
+            return Err(AppError::PreconditionFailed(format!(
+                "Shadow ({}) for device {} was modified",
+                shadow_name.as_str(),
+                device_id
+            )));
+        }
+    }
+
+    let payload = serde_json::to_value(&nested_update_doc).unwrap_or_default();
     let update_doc = StateUpdateDocument::from_nested_state(
         nested_update_doc,
         &device_id,
         &shadow_name,
         &tenant_id,
     );
+    let response = apply_shadow_update(&state, &params, update_doc).await?;
+    state
+        .audit
+        .record(
+            &tenant_id,
+            &actor,
+            "update",
+            "shadow",
+            &format!("{}/{}", device_id, shadow_name.as_str()),
+            payload,
+        )
+        .await;
+    Ok(response)
+}
+
+/// Upserts the update and sends the delta to the device if `send_delta` is set
+async fn apply_shadow_update(
+    state: &AppState,
+    params: &HashMap<String, String>,
+    update_doc: StateUpdateDocument,
+) -> Result<Response, AppError> {
+    let webhooks = ShadowWebhookDispatch::prepare(&state.db, &update_doc).await;
     // Upsert shadow
     let shadow = match state.db._upsert_shadow(&update_doc).await {
         Ok(updated) => updated,
         Err(e) => return Err(AppError::DatabaseError(e)),
     };
+    if let Some(webhooks) = webhooks {
+        webhooks.dispatch(&shadow);
+    }
 
-    //  Send delta to device if we have a mqtt sender
     if params.get("send_delta").is_some() {
-        if let Some(mqtt_sender) = &state.mqtt_sender {
-            let _delta_sent = send_delta_to_mqtt(&shadow, mqtt_sender, &state.shadow_topic_prefix);
+        send_shadow_delta(state, &shadow).await;
+    }
+
+    Ok(([(ETAG, shadow.etag())], Json(shadow)).into_response())
+}
+
+/// Sends the delta to the device if we have a mqtt sender
+async fn send_shadow_delta(state: &AppState, shadow: &Shadow) {
+    if let Some(mqtt_sender) = &state.mqtt_sender {
+        let (shadow_topic_prefix, max_delta_bytes) = {
+            let config = state.processor_config.read().unwrap();
+            (config.shadow_topic_prefix.clone(), config.max_delta_bytes)
+        };
+        if let Err(e) =
+            send_delta_to_mqtt(shadow, mqtt_sender, &shadow_topic_prefix, max_delta_bytes).await
+        {
+            tracing::warn!("Failed to send delta for {}: {}", shadow.device_id, e);
+        }
+    }
+}
+
+/// Section of the shadow state a patch applies to
+enum ShadowSection {
+    Desired,
+    Reported,
+}
+
+async fn patch_shadow_section(
+    device_id: String,
+    state: AppState,
+    params: HashMap<String, String>,
+    body: serde_json::Value,
+    section: ShadowSection,
+) -> Result<Response, AppError> {
+    if !body.is_object() {
+        return Err(AppError::UnprocessableEntity(
+            "Shadow patch must be a JSON object".to_string(),
+        ));
+    }
+    let tenant_id = TenantId::Default;
+    let shadow_name = match params.get("name") {
+        Some(name) => ShadowName::from_str(name),
+        None => ShadowName::Default,
+    };
+    let (desired, reported) = match section {
+        ShadowSection::Desired => (body, serde_json::Value::Null),
+        ShadowSection::Reported => (serde_json::Value::Null, body),
+    };
+    let nested = NestedStateDocument {
+        state: StateDocument {
+            reported,
+            desired,
+            delta: serde_json::Value::Null,
+        },
+    };
+    let update_doc =
+        StateUpdateDocument::from_nested_state(nested, &device_id, &shadow_name, &tenant_id);
+    apply_shadow_update(&state, &params, update_doc).await
+}
+
+/// Merges the body into the desired state only, reported state stays untouched
+pub async fn patch_desired_shadow_handler(
+    Path((_tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Response, AppError> {
+    patch_shadow_section(device_id, state, params, body, ShadowSection::Desired).await
+}
+
+/// Removes the desired field at `?pointer=`, or the whole desired state without a pointer
+pub async fn delete_desired_shadow_handler(
+    Path((_tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+    let tenant_id = TenantId::Default;
+    let shadow_name = match params.get("name") {
+        Some(name) => ShadowName::from_str(name),
+        None => ShadowName::Default,
+    };
+    let current = match state
+        .db
+        ._get_shadow(&device_id, &shadow_name, &tenant_id)
+        .await
+    {
+        Ok(current) => current,
+        Err(DatabaseError::NotFoundError(_)) => {
+            return Err(AppError::NotFound(format!(
+                "Shadow ({}) not found for device: {}",
+                shadow_name.as_str(),
+                device_id
+            )))
         }
+        Err(e) => return Err(AppError::DatabaseError(e)),
+    };
+
+    // Null removes a field when the update is merged
+    let mut update_doc = StateUpdateDocument::new(&device_id, &shadow_name, &tenant_id);
+    match params.get("pointer") {
+        Some(pointer) => {
+            if !update_doc.clear_desired_pointer(pointer) {
+                return Err(AppError::UnprocessableEntity(format!(
+                    "Invalid JSON pointer: '{}'",
+                    pointer
+                )));
+            }
+        }
+        None => update_doc.clear_desired(&current),
     }
+    apply_shadow_update(&state, &params, update_doc).await
+}
 
-    Ok(Json(shadow))
+/// Merges the body into the reported state only, mainly for test tooling
+pub async fn patch_reported_shadow_handler(
+    Path((_tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Response, AppError> {
+    patch_shadow_section(device_id, state, params, body, ShadowSection::Reported).await
 }
 
 pub async fn delete_shadow_handler(
     Path((_tenant_id, device_id)): Path<(String, String)>,
     State(state): State<AppState>,
     Query(params): Query<HashMap<String, String>>,
+    actor: Actor,
 ) -> Result<Json<()>, AppError> {
     let tenant_id = TenantId::Default;
     let maybe_shadow_name = params.get("name");
@@ -131,15 +466,205 @@ pub async fn delete_shadow_handler(
         ._delete_shadow(&device_id, &shadow_name, &tenant_id)
         .await
     {
-        Ok(_) => Ok(Json(())),
+        Ok(_) => {
+            state
+                .audit
+                .record(
+                    &tenant_id,
+                    &actor,
+                    "delete",
+                    "shadow",
+                    &format!("{}/{}", device_id, shadow_name.as_str()),
+                    serde_json::Value::Null,
+                )
+                .await;
+            Ok(Json(()))
+        }
         Err(e) => Err(AppError::DatabaseError(e)),
     }
 }
 
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    /// The payload is published as serialized JSON
+    #[default]
+    Json,
+    /// The payload is a base64 string, published as the decoded bytes
+    Base64,
+}
+
+#[derive(Deserialize)]
+pub struct PublishBody {
+    pub topic_suffix: String,
+    pub payload: serde_json::Value,
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+    #[serde(default)]
+    pub qos: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PublishResponse {
+    pub topic: String,
+}
+
+/// A topic part must not contain wildcards or empty levels
+fn validate_topic_part(name: &str, part: &str) -> Result<(), AppError> {
+    if part.is_empty()
+        || part.split('/').any(|level| level.is_empty())
+        || part.contains(['+', '#', '\0'])
+    {
+        return Err(AppError::UnprocessableEntity(format!(
+            "Invalid {}: '{}'",
+            name, part
+        )));
+    }
+    Ok(())
+}
+
+/// Publishes to `{shadow_topic_prefix}{device_id}/{topic_suffix}`, e.g. remote commands
+pub async fn publish_to_device_handler(
+    Path((_tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(body): Json<PublishBody>,
+) -> Result<Json<PublishResponse>, AppError> {
+    let Some(mqtt_sender) = &state.mqtt_sender else {
+        return Err(AppError::InternalServerError(
+            "API was started without MQTT".to_string(),
+        ));
+    };
+    validate_topic_part("device id", &device_id)?;
+    validate_topic_part("topic suffix", &body.topic_suffix)?;
+    // The internal link of the broker publishes with QoS 0 only
+    if body.qos != 0 {
+        return Err(AppError::UnprocessableEntity(format!(
+            "Unsupported QoS {}, only 0 is supported",
+            body.qos
+        )));
+    }
+    let payload = match body.encoding {
+        PayloadEncoding::Json => serde_json::to_vec(&body.payload)
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?,
+        PayloadEncoding::Base64 => {
+            let encoded = body.payload.as_str().ok_or_else(|| {
+                AppError::UnprocessableEntity("Base64 payload must be a string".to_string())
+            })?;
+            BASE64_STANDARD.decode(encoded).map_err(|e| {
+                AppError::UnprocessableEntity(format!("Invalid base64 payload: {}", e))
+            })?
+        }
+    };
+
+    let shadow_topic_prefix = state
+        .processor_config
+        .read()
+        .unwrap()
+        .shadow_topic_prefix
+        .clone();
+    let topic = format!("{}{}/{}", shadow_topic_prefix, device_id, body.topic_suffix);
+    mqtt_sender
+        .publish(topic.clone(), payload)
+        .await
+        .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+    Ok(Json(PublishResponse { topic }))
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeseriesAggregation {
+    /// Per second rate of a cumulative counter, see `FloatTimeSeries::counter_rate`
+    Rate,
+    /// Aggregations of the values per `bucket`
+    Mean,
+    Min,
+    Max,
+    Sum,
+}
+
+impl TimeseriesAggregation {
+    fn bucket_aggregation(self) -> Option<Aggregation> {
+        match self {
+            TimeseriesAggregation::Rate => None,
+            TimeseriesAggregation::Mean => Some(Aggregation::Mean),
+            TimeseriesAggregation::Min => Some(Aggregation::Min),
+            TimeseriesAggregation::Max => Some(Aggregation::Max),
+            TimeseriesAggregation::Sum => Some(Aggregation::Sum),
+        }
+    }
+}
+
+/// Parses the `bucket` query parameter, `1d`, `1w` or `1mo`
+fn parse_calendar_bucket(bucket: &str) -> Result<CalendarUnit, AppError> {
+    match bucket {
+        "1d" => Ok(CalendarUnit::Day),
+        "1w" => Ok(CalendarUnit::Week),
+        "1mo" => Ok(CalendarUnit::Month),
+        _ => Err(AppError::UnprocessableEntity(format!(
+            "Unsupported bucket {}, use 1d, 1w or 1mo",
+            bucket
+        ))),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct TimeseriesQuery {
     pub start: u64,
     pub end: u64,
+    /// Downsample the result to this many points (LTTB), numeric metrics only
+    pub points: Option<usize>,
+    /// Transform the series before downsampling, numeric metrics only
+    pub agg: Option<TimeseriesAggregation>,
+    /// Counter drops larger than this are treated as resets for `agg=rate`
+    pub reset_threshold: Option<f64>,
+    /// JSON object, only values with all of these tags are returned
+    pub tags: Option<String>,
+    /// Aggregate the values per calendar day (`1d`), week (`1w`) or month (`1mo`) with `agg`
+    pub bucket: Option<String>,
+    /// IANA time zone of the `bucket` boundaries, e.g. `Europe/Berlin`, defaults to UTC
+    pub tz: Option<String>,
+    /// `minLat,minLong,maxLat,maxLong`, only locations inside are returned
+    pub bbox: Option<String>,
+}
+
+/// Locations of the metric inside `?bbox=`, layered onto the time range
+async fn timeseries_in_bbox(
+    state: &AppState,
+    tenant_id: &TenantId,
+    device_id: &str,
+    metric: &str,
+    range: &TimeseriesQuery,
+) -> Result<MetricTimeSeries, AppError> {
+    let bbox = range.bbox.as_deref().unwrap_or_default();
+    let Some(bbox) = BoundingBox::parse(bbox) else {
+        return Err(AppError::UnprocessableEntity(format!(
+            "Invalid bounding box {}, use minLat,minLong,maxLat,maxLong",
+            bbox
+        )));
+    };
+    if range.tags.is_some() {
+        return Err(AppError::UnprocessableEntity(
+            "bbox can't be combined with a tag filter".to_string(),
+        ));
+    }
+    // The box filter skips all other values, so check the metric holds locations at all
+    let last = state
+        .db
+        .get_last_metric(tenant_id, device_id, metric, 1)
+        .await?;
+    if last
+        .iter()
+        .any(|(_, value)| !matches!(value, MetricValue::Location(_)))
+    {
+        return Err(AppError::UnprocessableEntity(format!(
+            "Metric {} is not a location and can't be filtered by bbox",
+            metric
+        )));
+    }
+    Ok(state
+        .db
+        .get_metric_in_bbox(tenant_id, device_id, metric, range.start, range.end, &bbox)
+        .await?)
 }
 
 pub async fn get_timeseries_handler(
@@ -149,20 +674,112 @@ pub async fn get_timeseries_handler(
 ) -> Result<Json<TimeSeriesModel>, AppError> {
     let db = &state.db;
     let tenant_id = TenantId::Default;
-    let timeseries = match db
-        .get_metric(&tenant_id, &device_id, &metric, range.start, range.end)
-        .await
-    {
-        Ok(ts) => ts,
-        Err(DatabaseError::NotFoundError(_)) => {
-            return Err(AppError::NotFound(format!(
-                "No timeseries found for {} / {}",
-                device_id, metric
-            )));
+    let tag_filter = match &range.tags {
+        Some(tags) => match serde_json::from_str::<serde_json::Value>(tags) {
+            Ok(filter) if filter.is_object() => Some(filter),
+            _ => {
+                return Err(AppError::UnprocessableEntity(format!(
+                    "Tag filter must be a JSON object: {}",
+                    tags
+                )))
+            }
+        },
+        None => None,
+    };
+    let timeseries = if range.bbox.is_some() {
+        timeseries_in_bbox(&state, &tenant_id, &device_id, &metric, &range).await?
+    } else {
+        match db
+            .get_metric(
+                &tenant_id,
+                &device_id,
+                &metric,
+                range.start,
+                range.end,
+                tag_filter.as_ref(),
+            )
+            .await
+        {
+            Ok(ts) => ts,
+            Err(DatabaseError::NotFoundError(_)) => {
+                return Err(AppError::NotFound(format!(
+                    "No timeseries found for {} / {}",
+                    device_id, metric
+                )));
+            }
+            Err(e) => return Err(AppError::DatabaseError(e)),
         }
-        Err(e) => return Err(AppError::DatabaseError(e)),
     };
-    Ok(Json(timeseries.to_model(&device_id, &metric)))
+    let bucket = match &range.bucket {
+        Some(bucket) => {
+            let unit = parse_calendar_bucket(bucket)?;
+            let tz = match &range.tz {
+                Some(tz) => tz.parse::<Tz>().map_err(|_| {
+                    AppError::UnprocessableEntity(format!("Unknown time zone: {}", tz))
+                })?,
+                None => Tz::UTC,
+            };
+            // Rates are averaged per bucket
+            let aggregation = range
+                .agg
+                .and_then(TimeseriesAggregation::bucket_aggregation)
+                .unwrap_or(Aggregation::Mean);
+            Some((unit, tz, aggregation))
+        }
+        None if range
+            .agg
+            .is_some_and(|agg| agg.bucket_aggregation().is_some()) =>
+        {
+            return Err(AppError::UnprocessableEntity(
+                "Aggregations other than rate require a bucket".to_string(),
+            ));
+        }
+        None => None,
+    };
+    let value_unit = metric_unit(&state, &tenant_id, &device_id, &metric).await?;
+    if range.points.is_none() && range.agg.is_none() && bucket.is_none() {
+        return Ok(Json(
+            timeseries
+                .to_model(&device_id, &metric)
+                .with_unit(value_unit),
+        ));
+    }
+    let Some(mut float_ts) = timeseries.to_float_series() else {
+        // Locations can be averaged per bucket, on the sphere
+        if let (Some(location_ts), Some((unit, tz, Aggregation::Mean))) =
+            (timeseries.to_location_series(), bucket)
+        {
+            if range.points.is_none() && range.agg != Some(TimeseriesAggregation::Rate) {
+                let centroids = location_ts.resample_calendar_centroid(unit, tz);
+                return Ok(Json(
+                    centroids
+                        .to_model(&device_id, &metric)
+                        .with_unit(value_unit),
+                ));
+            }
+        }
+        return Err(AppError::UnprocessableEntity(format!(
+            "Metric {} is not numeric and can't be aggregated or downsampled",
+            metric
+        )));
+    };
+    if let Some(TimeseriesAggregation::Rate) = range.agg {
+        float_ts = float_ts.counter_rate(range.reset_threshold.unwrap_or(0.0));
+    }
+    if let Some((unit, tz, aggregation)) = bucket {
+        float_ts = float_ts.resample_calendar(unit, tz, aggregation);
+    }
+    if let Some(points) = range.points {
+        float_ts = float_ts.downsample_lttb(points);
+    }
+    // A rate is per second of the unit
+    let value_unit = match range.agg {
+        Some(TimeseriesAggregation::Rate) => value_unit.map(|unit| format!("{}/s", unit)),
+        _ => value_unit,
+    };
+    Ok(Json(
+        float_ts.to_model(&device_id, &metric).with_unit(value_unit),
+    ))
 }
 
 #[derive(Deserialize)]
@@ -192,8 +809,140 @@ pub async fn get_last_timeseries_handler(
         }
         Err(e) => return Err(AppError::DatabaseError(e)),
     };
+    let value_unit = metric_unit(&state, &tenant_id, &device_id, &metric).await?;
+
+    Ok(Json(
+        timeseries
+            .to_model(&device_id, &metric)
+            .with_unit(value_unit),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct GapsQuery {
+    pub start: u64,
+    pub end: u64,
+    /// Seconds between two expected points
+    pub interval: u64,
+    /// Seconds a point may be late before it counts as missing, a tenth of `interval` by default
+    pub tolerance: Option<u64>,
+}
+
+/// Stretches of `start..=end` in which the device missed points it should have sent
+/// every `interval` seconds, with the expected and actual number of points
+pub async fn get_timeseries_gaps_handler(
+    Path((tenant_id, device_id, metric)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+    Query(query): Query<GapsQuery>,
+) -> Result<Json<GapReport>, AppError> {
+    if query.interval == 0 {
+        return Err(AppError::UnprocessableEntity(
+            "interval must be greater than 0".to_string(),
+        ));
+    }
+    if query.end < query.start {
+        return Err(AppError::UnprocessableEntity(
+            "end must not be before start".to_string(),
+        ));
+    }
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let timeseries = state
+        .db
+        .get_metric(
+            &tenant_id,
+            &device_id,
+            &metric,
+            query.start,
+            query.end,
+            None,
+        )
+        .await?;
+    let tolerance = query.tolerance.unwrap_or(query.interval / 10);
+    Ok(Json(timeseries.find_gaps_in(
+        query.start,
+        query.end,
+        query.interval,
+        tolerance,
+    )))
+}
+
+/// A metric stored for a device, described by the effective data config of the device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricCatalogEntry {
+    pub name: String,
+    pub unit: Option<String>,
+    pub display_name: Option<String>,
+    /// `None` if no metric of the current config produces the name, e.g. after it was removed
+    pub data_type: Option<DataType>,
+}
+
+/// The stored metrics of the device with their units, display names and data types
+pub async fn get_metric_catalog_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<MetricCatalogEntry>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let names = state.db.list_metric_names(&tenant_id, &device_id).await?;
+    let config = state
+        .db
+        .get_data_config(&tenant_id, Some(&device_id))
+        .await?
+        .unwrap_or_default();
+    let catalog = names
+        .into_iter()
+        .map(|name| {
+            let metric = config.metric_config(&name);
+            MetricCatalogEntry {
+                unit: metric.and_then(|m| m.unit.clone()),
+                display_name: metric.and_then(|m| m.display_name.clone()),
+                data_type: metric.map(|m| m.data_type.clone()),
+                name,
+            }
+        })
+        .collect();
+    Ok(Json(catalog))
+}
+
+/// `MetricConfig::unit` of the metric in the effective data config of the device
+async fn metric_unit(
+    state: &AppState,
+    tenant_id: &TenantId,
+    device_id: &str,
+    metric: &str,
+) -> Result<Option<String>, AppError> {
+    let config = state.db.get_data_config(tenant_id, Some(device_id)).await?;
+    Ok(config
+        .as_ref()
+        .and_then(|config| config.metric_config(metric))
+        .and_then(|m| m.unit.clone()))
+}
+
+#[derive(Deserialize)]
+pub struct TimeRangeQuery {
+    pub start: u64,
+    pub end: u64,
+}
 
-    Ok(Json(timeseries.to_model(&device_id, &metric)))
+/// The metric of every device whose id starts with the prefix, keyed by device id.
+/// Bounded to the first `MAX_PREFIX_QUERY_DEVICES` devices.
+pub async fn get_prefix_timeseries_handler(
+    Path((_tenant_id, prefix, metric)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+    Query(range): Query<TimeRangeQuery>,
+) -> Result<Json<HashMap<String, TimeSeriesModel>>, AppError> {
+    let tenant_id = TenantId::Default;
+    let series = state
+        .db
+        .get_metric_by_device_prefix(&tenant_id, &prefix, &metric, range.start, range.end)
+        .await?;
+    let models = series
+        .into_iter()
+        .map(|(device_id, ts)| {
+            let model = ts.to_model(&device_id, &metric);
+            (device_id, model)
+        })
+        .collect();
+    Ok(Json(models))
 }
 
 pub async fn post_telemetry_handler(
@@ -204,6 +953,14 @@ pub async fn post_telemetry_handler(
     let tenant_id = TenantId::from_str(&tenant_id);
     let db = &state.db;
 
+    if let Err(wait) = state.telemetry_rate_limiter.check(&tenant_id, &device_id) {
+        // Whole seconds, rounded up so a retry after the header never hits the limit again
+        return Err(AppError::TooManyRequests(
+            format!("Telemetry rate limit of device {} exceeded", device_id),
+            wait.as_secs_f64().ceil().max(1.0) as u64,
+        ));
+    }
+
     let maybe_config = db
         .get_data_config(&tenant_id, Some(&device_id))
         .await
@@ -219,9 +976,15 @@ pub async fn post_telemetry_handler(
     };
 
     let mut counter = 0;
-    for (metric_name, metric_value) in metrics {
+    for metric in metrics {
         if let Err(e) = db
-            .put_metric(&tenant_id, &device_id, &metric_name, metric_value)
+            .put_metric(
+                &tenant_id,
+                &device_id,
+                &metric.name,
+                metric.value,
+                metric.tags.as_ref(),
+            )
             .await
         {
             return Err(AppError::DatabaseError(e));
@@ -233,18 +996,46 @@ pub async fn post_telemetry_handler(
     Ok(Json(()))
 }
 
+/// Makes the processor reload the cached data configs of the tenant
+fn invalidate_data_configs(state: &AppState, tenant_id: TenantId) {
+    if let Some(sender) = &state.config_invalidation {
+        // Fails only without a running processor, nothing is cached then
+        let _ = sender.send(ConfigInvalidation { tenant_id });
+    }
+}
+
 pub async fn store_device_config_handler(
     Path((tenant_id, device_prefix)): Path<(String, String)>,
     State(state): State<AppState>,
+    actor: Actor,
     Json(config): Json<DataConfig>,
 ) -> Result<Json<DataConfig>, AppError> {
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(AppError::ValidationFailed(errors));
+    }
     let db = &state.db;
     let tenant_id = TenantId::from_str(&tenant_id);
     match db
         .store_device_data_config(&tenant_id, &device_prefix, &config)
         .await
     {
-        Ok(_) => Ok(Json(config)),
+        Ok(_) => {
+            let payload = serde_json::to_value(&config).unwrap_or_default();
+            state
+                .audit
+                .record(
+                    &tenant_id,
+                    &actor,
+                    "store",
+                    "data_config",
+                    &device_prefix,
+                    payload,
+                )
+                .await;
+            invalidate_data_configs(&state, tenant_id);
+            Ok(Json(config))
+        }
         Err(e) => Err(AppError::DatabaseError(e)),
     }
 }
@@ -254,10 +1045,17 @@ pub async fn store_tenant_config_handler(
     State(state): State<AppState>,
     Json(config): Json<DataConfig>,
 ) -> Result<Json<DataConfig>, AppError> {
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(AppError::ValidationFailed(errors));
+    }
     let db = &state.db;
     let tenant_id = TenantId::from_str(&tenant_id);
     match db.store_tenant_data_config(&tenant_id, &config).await {
-        Ok(_) => Ok(Json(config)),
+        Ok(_) => {
+            invalidate_data_configs(&state, tenant_id);
+            Ok(Json(config))
+        }
         Err(e) => Err(AppError::DatabaseError(e)),
     }
 }
@@ -296,54 +1094,191 @@ pub async fn get_config_handler(
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ExplainedMetric {
+    pub metric: MetricConfig,
+    pub source: ConfigSource,
+}
+
+/// Metrics of the effective device config with the config each one comes from
+pub async fn explain_config_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ExplainedMetric>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    match state
+        .db
+        .get_data_config_explained(&tenant_id, &device_id)
+        .await
+    {
+        Ok(explained) => Ok(Json(
+            explained
+                .into_iter()
+                .map(|(metric, source)| ExplainedMetric { metric, source })
+                .collect(),
+        )),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
 pub async fn delete_config_handler(
-    Path((tenant_id, device_prefix)): Path<(String, Option<String>)>,
+    Path((tenant_id, device_prefix)): Path<(String, String)>,
     State(state): State<AppState>,
 ) -> Result<Json<()>, AppError> {
     let db = &state.db;
     let tenant_id = TenantId::from_str(&tenant_id);
     match db
-        .delete_data_config(&tenant_id, device_prefix.as_deref())
+        .delete_data_config(&tenant_id, Some(&device_prefix))
         .await
     {
-        Ok(_) => Ok(Json(())),
+        Ok(_) => {
+            invalidate_data_configs(&state, tenant_id);
+            Ok(Json(()))
+        }
         Err(e) => Err(AppError::DatabaseError(e)),
     }
 }
 
-pub async fn list_configs_handler(
+pub async fn delete_tenant_config_handler(
     Path(tenant_id): Path<String>,
     State(state): State<AppState>,
-) -> Result<Json<Vec<DataConfigEntry>>, AppError> {
+) -> Result<Json<()>, AppError> {
     let db = &state.db;
     let tenant_id = TenantId::from_str(&tenant_id);
-    match db.list_data_configs(&tenant_id).await {
-        Ok(configs) => Ok(Json(configs)),
+    match db.delete_data_config(&tenant_id, None).await {
+        Ok(_) => {
+            invalidate_data_configs(&state, tenant_id);
+            Ok(Json(()))
+        }
         Err(e) => Err(AppError::DatabaseError(e)),
     }
 }
 
-pub async fn list_connections_handler(
-    Path(_tenant_id): Path<String>,
-    State(state): State<AppState>,
-) -> Result<Json<Vec<String>>, AppError> {
-    let devices = state.connected_clients;
-    let device_iter = devices.iter();
-    let connections = device_iter.map(|x| (*x).to_owned()).collect();
-    Ok(Json(connections))
-}
-
-/// Body for creating or updating device metadata.
-/// Includes an optional `key` for credentials.
-#[derive(Deserialize)]
-pub struct PutDeviceBody {
-    key: Option<String>,
+fn parse_label_selector(selector: &str) -> Result<LabelSelector, AppError> {
+    LabelSelector::parse(selector).ok_or_else(|| {
+        AppError::UnprocessableEntity(format!(
+            "Label selector must be key=value, got '{}'",
+            selector
+        ))
+    })
 }
 
-pub async fn post_device_metadata_handler(
-    Path((tenant_id, device_id)): Path<(String, String)>,
+pub async fn store_label_config_handler(
+    Path((tenant_id, selector)): Path<(String, String)>,
     State(state): State<AppState>,
-    Json(device_info): Json<PutDeviceBody>,
+    Json(config): Json<DataConfig>,
+) -> Result<Json<DataConfig>, AppError> {
+    let selector = parse_label_selector(&selector)?;
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(AppError::ValidationFailed(errors));
+    }
+    let tenant_id = TenantId::from_str(&tenant_id);
+    match state
+        .db
+        .store_label_data_config(&tenant_id, &selector, &config)
+        .await
+    {
+        Ok(_) => {
+            invalidate_data_configs(&state, tenant_id);
+            Ok(Json(config))
+        }
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+pub async fn get_label_config_handler(
+    Path((tenant_id, selector)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<DataConfig>, AppError> {
+    let selector = parse_label_selector(&selector)?;
+    let tenant_id = TenantId::from_str(&tenant_id);
+    match state.db.get_label_data_config(&tenant_id, &selector).await {
+        Ok(Some(config)) => Ok(Json(config)),
+        Ok(None) => Err(AppError::NotFound(format!(
+            "No config found for tenant: {} and label: {}",
+            tenant_id, selector
+        ))),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+pub async fn delete_label_config_handler(
+    Path((tenant_id, selector)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<()>, AppError> {
+    let selector = parse_label_selector(&selector)?;
+    let tenant_id = TenantId::from_str(&tenant_id);
+    match state
+        .db
+        .delete_label_data_config(&tenant_id, &selector)
+        .await
+    {
+        Ok(_) => {
+            invalidate_data_configs(&state, tenant_id);
+            Ok(Json(()))
+        }
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+pub async fn list_configs_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<DataConfigEntry>>, AppError> {
+    let db = &state.db;
+    let tenant_id = TenantId::from_str(&tenant_id);
+    match db.list_data_configs(&tenant_id).await {
+        Ok(configs) => Ok(Json(configs)),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectedClient {
+    pub client_id: String,
+    /// Unix seconds
+    pub connected_at: u64,
+}
+
+/// MQTT clients of the tenant connected to the broker, by client id
+pub async fn list_connections_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ConnectedClient>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let mut connections: Vec<ConnectedClient> = state
+        .connected_clients
+        .iter()
+        .filter(|client| client.tenant == tenant_id)
+        .map(|client| ConnectedClient {
+            client_id: client.key().clone(),
+            connected_at: client.connected_at,
+        })
+        .collect();
+    connections.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+    Ok(Json(connections))
+}
+
+/// Body for creating or updating device metadata.
+/// Includes an optional `key` for credentials.
+#[derive(Deserialize)]
+pub struct PutDeviceBody {
+    key: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateDeviceQuery {
+    #[serde(default)]
+    pub force: bool,
+}
+
+pub async fn post_device_metadata_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(query): Query<CreateDeviceQuery>,
+    actor: Actor,
+    Json(device_info): Json<PutDeviceBody>,
 ) -> Result<Json<DeviceMetadata>, AppError> {
     // Ensure the path parameters match the body
     let tenant_id = TenantId::from_str(&tenant_id);
@@ -357,12 +1292,19 @@ pub async fn post_device_metadata_handler(
         // You might want to store this key in the device metadata
         // or use it for certificate generation
     }
-    let metadata = create_device(&device_id, &tenant_id, db, cert_manager).await?;
-
-    match state.db.put_device_metadata(&metadata).await {
-        Ok(_) => Ok(Json(metadata)),
-        Err(e) => Err(AppError::DatabaseError(e)),
-    }
+    let metadata = create_device(&device_id, &tenant_id, db, cert_manager, query.force).await?;
+    state
+        .audit
+        .record(
+            &tenant_id,
+            &actor,
+            "create",
+            "device",
+            &device_id,
+            serde_json::json!({"force": query.force}),
+        )
+        .await;
+    Ok(Json(metadata))
 }
 
 // Handler to get detailed device information
@@ -386,10 +1328,10 @@ pub async fn get_device_info_handler(
     };
 
     // Check connection status
-    let connected = state.connected_clients.contains(&device_id);
-
-    // Get shadow last update time if requested
-    let mut last_shadow_update = None;
+    let connected = state
+        .connected_clients
+        .get(&device_id)
+        .is_some_and(|client| client.tenant == tenant_id);
 
     // Get shadow name from query params or use default
     let maybe_shadow_name = params.get("name");
@@ -398,20 +1340,11 @@ pub async fn get_device_info_handler(
         None => ShadowName::Default,
     };
 
-    // Try to get the shadow to extract last_updated
-    match state
+    // None if the device has no such shadow
+    let last_shadow_update = state
         .db
-        ._get_shadow(&device_id, &shadow_name, &tenant_id)
-        .await
-    {
-        Ok(shadow) => {
-            last_shadow_update = Some(shadow.get_last_updated());
-        }
-        Err(DatabaseError::NotFoundError(_)) => {
-            // No shadow found, leave last_shadow_update as None
-        }
-        Err(e) => return Err(AppError::DatabaseError(e)),
-    }
+        .get_shadow_last_updated(&device_id, &shadow_name, &tenant_id)
+        .await?;
 
     // Construct the DeviceInformation response
     let mut past_minute_rates = None;
@@ -466,19 +1399,204 @@ pub async fn get_device_metadata_handler(
     }
 }
 
+pub async fn get_device_tags_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<HashMap<String, String>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+
+    match state.db.get_device_metadata(&tenant_id, &device_id).await? {
+        Some(metadata) => Ok(Json(metadata.tags)),
+        None => Err(AppError::NotFound(format!(
+            "Device not found: {}",
+            device_id
+        ))),
+    }
+}
+
+/// Replaces all tags of the device, an empty object removes them
+pub async fn put_device_tags_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(tags): Json<HashMap<String, String>>,
+) -> Result<Json<HashMap<String, String>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+
+    match state
+        .db
+        .set_device_tags(&tenant_id, &device_id, tags.clone())
+        .await
+    {
+        Ok(()) => Ok(Json(tags)),
+        Err(DatabaseError::InvalidKeyError(key)) => Err(AppError::UnprocessableEntity(format!(
+            "Invalid tag key: '{}'",
+            key
+        ))),
+        Err(DatabaseError::NotFoundError(_)) => Err(AppError::NotFound(format!(
+            "Device not found: {}",
+            device_id
+        ))),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+pub async fn get_device_labels_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<HashMap<String, String>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+
+    match state.db.get_device_metadata(&tenant_id, &device_id).await? {
+        Some(metadata) => Ok(Json(metadata.labels)),
+        None => Err(AppError::NotFound(format!(
+            "Device not found: {}",
+            device_id
+        ))),
+    }
+}
+
+/// Merges the labels into the existing ones, a `null` value removes the label
+pub async fn patch_device_labels_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(changes): Json<HashMap<String, Option<String>>>,
+) -> Result<Json<HashMap<String, String>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+
+    match state
+        .db
+        .update_device_labels(&tenant_id, &device_id, changes)
+        .await
+    {
+        Ok(labels) => {
+            // Labels select data configs
+            invalidate_data_configs(&state, tenant_id);
+            Ok(Json(labels))
+        }
+        Err(DatabaseError::InvalidKeyError(key)) => Err(AppError::UnprocessableEntity(format!(
+            "Invalid label key: '{}'",
+            key
+        ))),
+        Err(DatabaseError::NotFoundError(_)) => Err(AppError::NotFound(format!(
+            "Device not found: {}",
+            device_id
+        ))),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeviceExportQuery {
+    pub include_metrics: Option<bool>,
+}
+
+pub async fn export_device_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(query): Query<DeviceExportQuery>,
+) -> Result<Json<DeviceExport>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let include_metrics = query.include_metrics.unwrap_or(false);
+
+    let export = state
+        .db
+        .export_device(&tenant_id, &device_id, include_metrics)
+        .await?;
+    if export.metadata.is_none() && export.shadows.is_empty() {
+        return Err(AppError::NotFound(format!(
+            "Device not found for tenant: {} and device: {}",
+            tenant_id, device_id
+        )));
+    }
+    Ok(Json(export))
+}
+
+#[derive(Deserialize)]
+pub struct DeadLettersQuery {
+    pub device_id: Option<String>,
+    pub limit: Option<u64>,
+}
+
+pub async fn list_dead_letters_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+    Query(query): Query<DeadLettersQuery>,
+) -> Result<Json<Vec<DeadLetter>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let limit = query.limit.unwrap_or(100);
+
+    let dead_letters = state
+        .db
+        .list_dead_letters(&tenant_id, query.device_id.as_deref(), limit)
+        .await?;
+    Ok(Json(dead_letters))
+}
+
+#[derive(Deserialize)]
+pub struct ListDevicesQuery {
+    /// Only devices with this tag, as `key:value`
+    pub tag: Option<String>,
+    /// Only devices with this label, as `key=value`
+    pub label: Option<String>,
+    /// Only devices with this lifecycle status
+    pub status: Option<DeviceStatus>,
+    /// Only devices that do (or don't) run another firmware than their target firmware
+    pub needs_update: Option<bool>,
+}
+
 // Handler to list all devices for a tenant
 pub async fn list_devices_handler(
     Path(tenant_id): Path<String>,
     State(state): State<AppState>,
+    Query(query): Query<ListDevicesQuery>,
 ) -> Result<Json<Vec<String>>, AppError> {
     let tenant_id = TenantId::from_str(&tenant_id);
-
-    match state.db.list_devices(&tenant_id).await {
+    let label = query
+        .label
+        .as_deref()
+        .map(parse_label_selector)
+        .transpose()?;
+
+    let devices = match &query.tag {
+        Some(tag) => {
+            let Some((key, value)) = tag.split_once(':') else {
+                return Err(AppError::UnprocessableEntity(format!(
+                    "Tag filter must be key:value, got '{}'",
+                    tag
+                )));
+            };
+            match state.db.get_devices_by_tag(&tenant_id, key, value).await {
+                Err(DatabaseError::InvalidKeyError(key)) => {
+                    return Err(AppError::UnprocessableEntity(format!(
+                        "Invalid tag key: '{}'",
+                        key
+                    )))
+                }
+                devices => devices,
+            }
+        }
+        None => match &label {
+            Some(label) => state.db.get_devices_by_label(&tenant_id, label).await,
+            None => state.db.list_devices(&tenant_id).await,
+        },
+    };
+    match devices {
         Ok(devices) => {
             // Return a list of device IDs
             // Convert the DeviceMetadata objects to just their device IDs
             let device_ids = devices
                 .into_iter()
+                .filter(|metadata| query.status.is_none_or(|status| metadata.status == status))
+                .filter(|metadata| {
+                    query
+                        .needs_update
+                        .is_none_or(|needs_update| metadata.needs_firmware_update() == needs_update)
+                })
+                .filter(|metadata| {
+                    label
+                        .as_ref()
+                        .is_none_or(|label| metadata.labels.get(&label.key) == Some(&label.value))
+                })
                 .map(|metadata| metadata.device_id)
                 .collect();
             Ok(Json(device_ids))
@@ -487,6 +1605,90 @@ pub async fn list_devices_handler(
     }
 }
 
+pub async fn activate_device_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<DeviceMetadata>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let Some(metadata) = state.db.get_device_metadata(&tenant_id, &device_id).await? else {
+        return Err(AppError::NotFound(format!(
+            "Device not found: {}",
+            device_id
+        )));
+    };
+    if metadata.status == DeviceStatus::Decommissioned {
+        return Err(AppError::Conflict(format!(
+            "Device {} is decommissioned",
+            device_id
+        )));
+    }
+    set_device_status(&state, &tenant_id, &device_id, DeviceStatus::Active).await
+}
+
+#[derive(Deserialize)]
+pub struct DeactivateDeviceQuery {
+    /// Decommission the device for good instead of disabling it
+    #[serde(default)]
+    pub decommission: bool,
+}
+
+pub async fn deactivate_device_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(query): Query<DeactivateDeviceQuery>,
+) -> Result<Json<DeviceMetadata>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let status = if query.decommission {
+        DeviceStatus::Decommissioned
+    } else {
+        DeviceStatus::Inactive
+    };
+    set_device_status(&state, &tenant_id, &device_id, status).await
+}
+
+async fn set_device_status(
+    state: &AppState,
+    tenant_id: &TenantId,
+    device_id: &str,
+    status: DeviceStatus,
+) -> Result<Json<DeviceMetadata>, AppError> {
+    match state
+        .db
+        .set_device_status(tenant_id, device_id, status)
+        .await
+    {
+        Ok(metadata) => Ok(Json(metadata)),
+        Err(DatabaseError::NotFoundError(_)) => Err(AppError::NotFound(format!(
+            "Device not found: {}",
+            device_id
+        ))),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StaleDevicesQuery {
+    /// Defaults to 24
+    pub hours: Option<u64>,
+}
+
+/// Devices whose shadows were not updated within the last `hours`
+pub async fn list_stale_devices_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+    Query(query): Query<StaleDevicesQuery>,
+) -> Result<Json<Vec<String>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let hours = query.hours.unwrap_or(24);
+    let older_than = state
+        .db
+        .clock()
+        .now_secs()
+        .saturating_sub(hours.saturating_mul(3600));
+    let devices = state.db.list_stale_devices(&tenant_id, older_than).await?;
+    Ok(Json(devices))
+}
+
 // Handler to delete device metadata
 pub async fn delete_device_metadata_handler(
     Path((tenant_id, device_id)): Path<(String, String)>,
@@ -528,6 +1730,191 @@ pub async fn get_tenant_handler(
     }
 }
 
+pub async fn put_tenant_quota_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+    Json(quota): Json<TenantQuota>,
+) -> Result<Json<Tenant>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    match state.db.set_tenant_quota(&tenant_id, quota).await {
+        Ok(tenant) => Ok(Json(tenant)),
+        Err(DatabaseError::NotFoundError(_)) => Err(AppError::NotFound(format!(
+            "Tenant not found: {}",
+            tenant_id
+        ))),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+/// Tenants without a stored record can still have data, they are counted as well
+pub async fn get_tenant_usage_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<TenantUsage>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    Ok(Json(state.db.tenant_usage(&tenant_id).await?))
+}
+
+/// Firmware versions reported by the devices of the tenant
+pub async fn get_firmware_summary_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<FirmwareSummary>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let devices = state.db.list_devices(&tenant_id).await?;
+    Ok(Json(FirmwareSummary::from_devices(&devices)))
+}
+
+#[derive(Deserialize)]
+pub struct CreateGroupBody {
+    pub group_id: String,
+    #[serde(default)]
+    pub device_ids: Vec<String>,
+}
+
+pub async fn create_group_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+    Json(body): Json<CreateGroupBody>,
+) -> Result<Json<DeviceGroup>, AppError> {
+    if body.group_id.is_empty() {
+        return Err(AppError::UnprocessableEntity(
+            "group_id must not be empty".to_string(),
+        ));
+    }
+    let mut device_ids = body.device_ids;
+    device_ids.sort();
+    device_ids.dedup();
+    let group = DeviceGroup {
+        group_id: body.group_id,
+        tenant_id: TenantId::from_str(&tenant_id),
+        device_ids,
+        created_at: state.db.clock().now_secs(),
+    };
+    if state.db.create_group(&group).await? {
+        Ok(Json(group))
+    } else {
+        Err(AppError::Conflict(format!(
+            "Group already exists: {}",
+            group.group_id
+        )))
+    }
+}
+
+async fn group_devices(
+    state: &AppState,
+    tenant_id: &str,
+    group_id: &str,
+) -> Result<Vec<String>, AppError> {
+    let tenant_id = TenantId::from_str(tenant_id);
+    match state.db.list_group_devices(&tenant_id, group_id).await {
+        Ok(device_ids) => Ok(device_ids),
+        Err(DatabaseError::NotFoundError(_)) => {
+            Err(AppError::NotFound(format!("Group not found: {}", group_id)))
+        }
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+pub async fn list_group_devices_handler(
+    Path((tenant_id, group_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<String>>, AppError> {
+    Ok(Json(group_devices(&state, &tenant_id, &group_id).await?))
+}
+
+pub async fn add_group_device_handler(
+    Path((tenant_id, group_id, device_id)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<String>>, AppError> {
+    let t_id = TenantId::from_str(&tenant_id);
+    match state
+        .db
+        .add_device_to_group(&t_id, &group_id, &device_id)
+        .await
+    {
+        Ok(_) => Ok(Json(group_devices(&state, &tenant_id, &group_id).await?)),
+        Err(DatabaseError::NotFoundError(_)) => {
+            Err(AppError::NotFound(format!("Group not found: {}", group_id)))
+        }
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+pub async fn remove_group_device_handler(
+    Path((tenant_id, group_id, device_id)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<String>>, AppError> {
+    let t_id = TenantId::from_str(&tenant_id);
+    if !state
+        .db
+        .remove_device_from_group(&t_id, &group_id, &device_id)
+        .await?
+    {
+        return Err(AppError::NotFound(format!(
+            "Device {} is not in group {}",
+            device_id, group_id
+        )));
+    }
+    Ok(Json(group_devices(&state, &tenant_id, &group_id).await?))
+}
+
+/// Merges the body into the desired state of every device in the group. All shadows are
+/// written in one transaction, returns them in device order.
+pub async fn update_group_desired_handler(
+    Path((tenant_id, group_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Json<Vec<Shadow>>, AppError> {
+    if !body.is_object() {
+        return Err(AppError::UnprocessableEntity(
+            "Shadow patch must be a JSON object".to_string(),
+        ));
+    }
+    let device_ids = group_devices(&state, &tenant_id, &group_id).await?;
+    let shadow_name = match params.get("name") {
+        Some(name) => ShadowName::from_str(name),
+        None => ShadowName::Default,
+    };
+    // Shadows live in the default tenant, like for the single device shadow routes
+    let updates: Vec<StateUpdateDocument> = device_ids
+        .iter()
+        .map(|device_id| {
+            let nested = NestedStateDocument {
+                state: StateDocument {
+                    reported: serde_json::Value::Null,
+                    desired: body.clone(),
+                    delta: serde_json::Value::Null,
+                },
+            };
+            StateUpdateDocument::from_nested_state(
+                nested,
+                device_id,
+                &shadow_name,
+                &TenantId::Default,
+            )
+        })
+        .collect();
+    let mut webhooks = Vec::with_capacity(updates.len());
+    for update in &updates {
+        webhooks.push(ShadowWebhookDispatch::prepare(&state.db, update).await);
+    }
+    let shadows = state.db.upsert_shadows(&updates).await?;
+    for (shadow, webhooks) in shadows.iter().zip(webhooks) {
+        if let Some(webhooks) = webhooks {
+            webhooks.dispatch(shadow);
+        }
+    }
+
+    if params.get("send_delta").is_some() {
+        for shadow in &shadows {
+            send_shadow_delta(&state, shadow).await;
+        }
+    }
+    Ok(Json(shadows))
+}
+
 #[derive(Deserialize)]
 pub struct AddPasswordBody {
     pub username: String,
@@ -537,30 +1924,37 @@ pub struct AddPasswordBody {
 pub async fn add_device_password_handler(
     Path((tenant_id_str, device_id)): Path<(String, String)>,
     State(state): State<AppState>,
+    actor: Actor,
     Json(body): Json<AddPasswordBody>,
 ) -> Result<Json<()>, AppError> {
     let tenant_id = TenantId::from_str(&tenant_id_str);
 
-    let password_hash = match bcrypt::hash(&body.password_plaintext, bcrypt::DEFAULT_COST) {
-        Ok(h) => h,
-        Err(e) => {
-            return Err(AppError::InternalServerError(format!(
-                "Hashing error: {}",
-                e
-            )))
+    match state
+        .db
+        .set_device_password(
+            &tenant_id,
+            &device_id,
+            &body.username,
+            &body.password_plaintext,
+            Some(state.bcrypt_cost),
+        )
+        .await
+    {
+        Ok(_) => {
+            // Never the password itself
+            state
+                .audit
+                .record(
+                    &tenant_id,
+                    &actor,
+                    "add_password",
+                    "device",
+                    &device_id,
+                    serde_json::json!({"username": body.username}),
+                )
+                .await;
+            Ok(Json(()))
         }
-    };
-
-    let credential = DeviceCredential {
-        tenant_id,
-        device_id,
-        username: body.username,
-        password_hash,
-        created_at: chrono::Utc::now().timestamp() as u64,
-    };
-
-    match state.db.add_device_password(&credential).await {
-        Ok(_) => Ok(Json(())),
         Err(e) => Err(AppError::DatabaseError(e)),
     }
 }
@@ -576,6 +1970,196 @@ pub async fn get_device_passwords_handler(
     }
 }
 
+pub async fn get_extraction_errors_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ExtractionError>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let errors = state
+        .db
+        .list_extraction_errors(&tenant_id, &device_id)
+        .await?;
+    Ok(Json(errors))
+}
+
+/// Creates or replaces an alarm rule, the tenant is taken from the path
+pub async fn create_alarm_rule_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+    Json(mut rule): Json<AlarmRule>,
+) -> Result<Json<AlarmRule>, AppError> {
+    for (name, value) in [
+        ("alarm_id", &rule.alarm_id),
+        ("device_id", &rule.device_id),
+        ("metric_name", &rule.metric_name),
+    ] {
+        if value.is_empty() {
+            return Err(AppError::UnprocessableEntity(format!(
+                "{} must not be empty",
+                name
+            )));
+        }
+    }
+    if !rule.threshold.is_finite() {
+        return Err(AppError::UnprocessableEntity(
+            "threshold must be a finite number".to_string(),
+        ));
+    }
+    rule.tenant_id = TenantId::from_str(&tenant_id);
+    state.db.store_alarm_rule(&rule).await?;
+    Ok(Json(rule))
+}
+
+#[derive(Deserialize)]
+pub struct AlarmEventsQuery {
+    pub limit: Option<u64>,
+}
+
+/// Alarm events of the device, newest first
+pub async fn list_device_alarms_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(query): Query<AlarmEventsQuery>,
+) -> Result<Json<Vec<AlarmEvent>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let limit = query.limit.unwrap_or(100);
+
+    let events = state
+        .db
+        .list_alarm_events(&tenant_id, &device_id, limit)
+        .await?;
+    Ok(Json(events))
+}
+
+/// Webhook as returned by the API, without its secret
+fn redact_secret(mut webhook: ShadowWebhook) -> ShadowWebhook {
+    webhook.secret = None;
+    webhook
+}
+
+fn validate_shadow_webhook(webhook: &ShadowWebhook) -> Result<(), AppError> {
+    if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+        return Err(AppError::UnprocessableEntity(
+            "url must be an http or https URL".to_string(),
+        ));
+    }
+    if webhook.events.is_empty() {
+        return Err(AppError::UnprocessableEntity(
+            "events must not be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Registers a webhook for the shadows of the device, a missing `webhook_id` is generated
+pub async fn create_shadow_webhook_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(mut webhook): Json<ShadowWebhook>,
+) -> Result<Json<ShadowWebhook>, AppError> {
+    validate_shadow_webhook(&webhook)?;
+    if webhook.webhook_id.is_empty() {
+        webhook.webhook_id = uuid::Uuid::new_v4().simple().to_string();
+    }
+    let tenant_id = TenantId::from_str(&tenant_id);
+    state
+        .db
+        .store_shadow_webhook(&tenant_id, &device_id, &webhook)
+        .await?;
+    Ok(Json(redact_secret(webhook)))
+}
+
+pub async fn list_shadow_webhooks_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ShadowWebhook>>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let webhooks = state.db.get_shadow_webhooks(&tenant_id, &device_id).await?;
+    Ok(Json(webhooks.into_iter().map(redact_secret).collect()))
+}
+
+async fn find_shadow_webhook(
+    state: &AppState,
+    tenant_id: &TenantId,
+    device_id: &str,
+    webhook_id: &str,
+) -> Result<ShadowWebhook, AppError> {
+    state
+        .db
+        .get_shadow_webhooks(tenant_id, device_id)
+        .await?
+        .into_iter()
+        .find(|webhook| webhook.webhook_id == webhook_id)
+        .ok_or_else(|| {
+            AppError::NotFound(format!(
+                "Webhook {} not found for device {}",
+                webhook_id, device_id
+            ))
+        })
+}
+
+pub async fn get_shadow_webhook_handler(
+    Path((tenant_id, device_id, webhook_id)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<ShadowWebhook>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let webhook = find_shadow_webhook(&state, &tenant_id, &device_id, &webhook_id).await?;
+    Ok(Json(redact_secret(webhook)))
+}
+
+/// Replaces an existing webhook, the id is taken from the path
+pub async fn put_shadow_webhook_handler(
+    Path((tenant_id, device_id, webhook_id)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+    Json(mut webhook): Json<ShadowWebhook>,
+) -> Result<Json<ShadowWebhook>, AppError> {
+    validate_shadow_webhook(&webhook)?;
+    let tenant_id = TenantId::from_str(&tenant_id);
+    find_shadow_webhook(&state, &tenant_id, &device_id, &webhook_id).await?;
+    webhook.webhook_id = webhook_id;
+    state
+        .db
+        .store_shadow_webhook(&tenant_id, &device_id, &webhook)
+        .await?;
+    Ok(Json(redact_secret(webhook)))
+}
+
+pub async fn delete_shadow_webhook_handler(
+    Path((tenant_id, device_id, webhook_id)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<()>, AppError> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    if !state
+        .db
+        .delete_shadow_webhook(&tenant_id, &device_id, &webhook_id)
+        .await?
+    {
+        return Err(AppError::NotFound(format!(
+            "Webhook {} not found for device {}",
+            webhook_id, device_id
+        )));
+    }
+    Ok(Json(()))
+}
+
+/// Shadow update rate limit of a device, devices without recent updates have a full bucket
+pub async fn get_shadow_rate_limit_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Json<ShadowRateLimitStatus> {
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let max_per_second = state
+        .processor_config
+        .read()
+        .unwrap()
+        .max_shadow_updates_per_second;
+    Json(
+        state
+            .shadow_rate_limiter
+            .status(&tenant_id, &device_id, max_per_second),
+    )
+}
+
 // Generate server CA
 pub async fn generate_server_ca_handler(
     State(state): State<AppState>,
@@ -673,12 +2257,191 @@ pub async fn generate_client_cert_handler(
     }
 }
 
+#[derive(Deserialize)]
+pub struct Pkcs12Query {
+    /// Protects the bundle, clients ask for it on import
+    pub password: String,
+}
+
+// Export client cert as PKCS#12
+pub async fn export_client_pkcs12_handler(
+    Path((tenant_id_str, device_id)): Path<(String, String)>,
+    Query(query): Query<Pkcs12Query>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    let tenant_manager = state
+        .cert_manager
+        .for_tenant(tenant_id_str.clone())
+        .map_err(|e| AppError::InternalServerError(format!("Cert Manager: {}", e)))?;
+    match tenant_manager.export_client_pkcs12(&device_id, &query.password) {
+        Ok(der) => Ok((
+            [
+                (CONTENT_TYPE, "application/x-pkcs12".to_string()),
+                (
+                    CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.p12\"", device_id),
+                ),
+            ],
+            der,
+        )
+            .into_response()),
+        Err(CertificateError::FileNotFound(_)) => Err(AppError::NotFound(format!(
+            "No client certificate for device {}",
+            device_id
+        ))),
+        Err(e) => Err(AppError::InternalServerError(format!(
+            "Failed to export Client Cert: {}",
+            e
+        ))),
+    }
+}
+
+/// Provisioning tokens are valid for at most 30 days
+const MAX_PROVISIONING_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+fn default_provisioning_ttl_seconds() -> u64 {
+    3600
+}
+
+#[derive(Deserialize)]
+pub struct ProvisioningTokenQuery {
+    #[serde(default = "default_provisioning_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProvisioningTokenResponse {
+    /// Only returned here, the server keeps the hash
+    pub token: String,
+    pub device_id: String,
+    /// Unix time in seconds
+    pub expires_at: u64,
+}
+
+pub async fn create_provisioning_token_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    Query(query): Query<ProvisioningTokenQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<ProvisioningTokenResponse>, AppError> {
+    if query.ttl_seconds == 0 || query.ttl_seconds > MAX_PROVISIONING_TTL_SECONDS {
+        return Err(AppError::UnprocessableEntity(format!(
+            "ttl_seconds must be between 1 and {}",
+            MAX_PROVISIONING_TTL_SECONDS
+        )));
+    }
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let Some(expires_at) = state.db.clock().now_secs().checked_add(query.ttl_seconds) else {
+        return Err(AppError::UnprocessableEntity(
+            "ttl_seconds is out of range".to_string(),
+        ));
+    };
+    let (token, provisioning_token) =
+        ProvisioningToken::generate(&tenant_id, &device_id, expires_at).map_err(|e| {
+            AppError::InternalServerError(format!("Failed to generate token: {}", e))
+        })?;
+    state
+        .db
+        .store_provisioning_token(&provisioning_token)
+        .await?;
+    Ok(Json(ProvisioningTokenResponse {
+        token,
+        device_id,
+        expires_at,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ProvisionQuery {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProvisionResponse {
+    pub tenant_id: TenantId,
+    pub device_id: String,
+    pub certificate: CertificateData,
+}
+
+/// Exchanges a provisioning token for a client certificate of its device.
+/// Reachable without the admin token, the provisioning token is the credential.
+/// The device is created like through `POST /{tenant_id}/devices/{device_id}`, so the
+/// device quota applies, and inactive or decommissioned devices get no certificate.
+pub async fn provision_handler(
+    Query(query): Query<ProvisionQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<ProvisionResponse>, AppError> {
+    let token_hash = ProvisioningToken::hash(&query.token);
+    let now = state.db.clock().now_secs();
+    let Some(token) = state.db.redeem_provisioning_token(&token_hash, now).await? else {
+        return Err(AppError::Unauthorized(
+            "Invalid, expired or used provisioning token".to_string(),
+        ));
+    };
+    match provision_device(&state, &token).await {
+        Ok(metadata) => {
+            tracing::info!(%token.tenant_id, token.device_id, "Device provisioned with a token");
+            let certificate = CertificateData {
+                cert: metadata.certificate.unwrap_or_default(),
+                key: metadata.key.unwrap_or_default(),
+            };
+            Ok(Json(ProvisionResponse {
+                tenant_id: token.tenant_id,
+                device_id: token.device_id,
+                certificate,
+            }))
+        }
+        Err(e) => {
+            // The device may retry once the cause is fixed, e.g. the quota raised
+            if let Err(release_err) = state.db.release_provisioning_token(&token_hash).await {
+                tracing::error!(
+                    %token.tenant_id,
+                    token.device_id,
+                    "Failed to release provisioning token: {}",
+                    release_err
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Issues a fresh client certificate for the device of a redeemed provisioning token
+async fn provision_device(
+    state: &AppState,
+    token: &ProvisioningToken,
+) -> Result<DeviceMetadata, AppError> {
+    if let Some(existing) = state
+        .db
+        .get_device_metadata(&token.tenant_id, &token.device_id)
+        .await?
+    {
+        if !existing.status.can_connect() {
+            return Err(AppError::Conflict(format!(
+                "Device {} is inactive or decommissioned",
+                token.device_id
+            )));
+        }
+    }
+    let tenant_manager = state
+        .cert_manager
+        .for_tenant(token.tenant_id.to_string())
+        .map_err(|e| AppError::InternalServerError(format!("Cert Manager: {}", e)))?;
+    create_device(
+        &token.device_id,
+        &token.tenant_id,
+        state.db.clone(),
+        Arc::new(tenant_manager),
+        true,
+    )
+    .await
+}
+
 #[derive(Deserialize)]
 pub struct TimeRequestQuery {
     pub device_time: Option<u64>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TimeResponse {
     pub server_time: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -1,18 +1,32 @@
+pub mod audit;
+pub mod auth;
 pub mod client;
 pub mod error;
 pub mod handlers;
+pub mod rate_limit;
+pub mod read_only;
 pub mod routes;
 pub mod services;
+pub mod stream;
 
 use tokio_util::sync::CancellationToken;
 
+use crate::api::audit::AuditLogger;
+use crate::api::rate_limit::{TelemetryRateLimiter, TELEMETRY_BUCKET_PRUNE_INTERVAL};
 use crate::api::routes::get_routes;
 use crate::certs::CertificateManager;
 use crate::config::ForestConfig;
 use crate::db::DB;
 use crate::mqtt::{MqttSender, MqttServerMetrics};
+use crate::processor::config_cache::ConfigInvalidationSender;
+use crate::processor::ingest::IngestMetrics;
+use crate::processor::rate_limit::ShadowRateLimiter;
+use crate::processor::stream::MetricStreamSender;
+use crate::processor::ProcessorConfig;
 use crate::server::ConnectionSet;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -20,9 +34,23 @@ pub struct AppState {
     pub mqtt_sender: Option<MqttSender>,
     pub mqtt_metrics: Arc<MqttServerMetrics>,
     pub connected_clients: Arc<ConnectionSet>,
-    pub shadow_topic_prefix: String,
+    pub processor_config: Arc<RwLock<ProcessorConfig>>,
+    pub ingest_metrics: Arc<IngestMetrics>,
+    /// Notifies the processor about changed data configs, `None` without a processor
+    pub config_invalidation: Option<ConfigInvalidationSender>,
+    /// Metric points stored by the processor, `None` without a processor
+    pub metric_stream: Option<MetricStreamSender>,
+    pub shadow_rate_limiter: Arc<ShadowRateLimiter>,
+    pub telemetry_rate_limiter: Arc<TelemetryRateLimiter>,
     pub cert_manager: Arc<CertificateManager>,
     pub broker_controller: Option<rumqttd::BrokerController>,
+    /// Bearer token required for all API calls, `None` leaves the API open
+    pub admin_api_token: Option<String>,
+    /// bcrypt cost of device passwords set via the API
+    pub bcrypt_cost: u32,
+    /// Rejects all writes while set, toggled via `/admin/readonly`
+    pub read_only: Arc<AtomicBool>,
+    pub audit: AuditLogger,
 }
 
 pub async fn start_api_server(
@@ -32,32 +60,73 @@ pub async fn start_api_server(
     mqtt_metrics: Arc<MqttServerMetrics>,
     connected_clients: Arc<ConnectionSet>,
     config: &ForestConfig,
+    processor_config: Arc<RwLock<ProcessorConfig>>,
+    ingest_metrics: Arc<IngestMetrics>,
+    config_invalidation: Option<ConfigInvalidationSender>,
+    metric_stream: Option<MetricStreamSender>,
+    shadow_rate_limiter: Arc<ShadowRateLimiter>,
     broker_controller: Option<rumqttd::BrokerController>,
 ) -> (CancellationToken, tokio::task::JoinHandle<()>) {
     let cert_manager =
         Arc::new(CertificateManager::new(&config.cert_dir, config.tenant_id.clone()).unwrap());
+    let telemetry_rate_limiter = Arc::new(TelemetryRateLimiter::new(
+        config.http_telemetry_rate_per_second,
+        config.http_telemetry_burst,
+        db.clock().clone(),
+    ));
     let state = AppState {
         db: db.clone(),
         mqtt_sender,
         mqtt_metrics,
         connected_clients,
-        shadow_topic_prefix: config.processor.shadow_topic_prefix.to_owned(),
+        processor_config,
+        ingest_metrics,
+        config_invalidation,
+        metric_stream,
+        shadow_rate_limiter,
+        telemetry_rate_limiter: telemetry_rate_limiter.clone(),
         cert_manager,
         broker_controller,
+        admin_api_token: config.admin_api_token.clone(),
+        bcrypt_cost: config.bcrypt_cost,
+        read_only: Arc::new(AtomicBool::new(config.api_read_only)),
+        audit: AuditLogger::new(db.clone()),
     };
-    let app = get_routes(state);
+    let app = get_routes(state, config.api_compression, &config.cors_allowed_origins);
     let listener = tokio::net::TcpListener::bind(bind_addr).await.unwrap();
     let cancel_token = CancellationToken::new();
     let server_cancel_token = cancel_token.clone();
 
+    if telemetry_rate_limiter.is_enabled() {
+        let prune_cancel_token = cancel_token.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TELEMETRY_BUCKET_PRUNE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = prune_cancel_token.cancelled() => break,
+                    _ = interval.tick() => {
+                        telemetry_rate_limiter.prune();
+                    }
+                }
+            }
+        });
+    }
+
     let server_handle = tokio::spawn(async move {
-        axum::serve(listener, app)
-            .with_graceful_shutdown(async move {
-                _ = server_cancel_token.cancelled().await;
-            })
-            .await
-            .unwrap();
+        // The peer address identifies the caller in the audit log
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            _ = server_cancel_token.cancelled().await;
+        })
+        .await
+        .unwrap();
     });
 
     (cancel_token, server_handle)
 }
+
+#[cfg(test)]
+mod tests;
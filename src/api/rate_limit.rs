@@ -0,0 +1,104 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tracing::warn;
+
+use crate::clock::Clock;
+use crate::models::TenantId;
+
+/// How often buckets that refilled completely are dropped
+pub const TELEMETRY_BUCKET_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    /// Unix time in milliseconds
+    last_refill: u64,
+    /// Whether the last request was rejected, to log only the first one of a series
+    limited: bool,
+}
+
+impl TokenBucket {
+    /// Tokens available at `now`, at most `burst`
+    fn available(&self, rate: f64, burst: f64, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.last_refill) as f64 / 1000.0;
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
+
+/// Token bucket per device limiting the telemetry posted to the HTTP API
+pub struct TelemetryRateLimiter {
+    /// Tokens added per second, 0 disables the limit
+    rate: f64,
+    /// Requests a device can send at once after being idle
+    burst: f64,
+    buckets: DashMap<(TenantId, String), TokenBucket>,
+    clock: Arc<dyn Clock>,
+}
+
+impl TelemetryRateLimiter {
+    pub fn new(per_second: u32, burst: u32, clock: Arc<dyn Clock>) -> Self {
+        TelemetryRateLimiter {
+            rate: per_second as f64,
+            // A bucket holding less than one token would never let a request through
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new(),
+            clock,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// Takes a token for a request of the device, returns the time until the next token
+    /// is available if the request has to be rejected
+    pub fn check(&self, tenant_id: &TenantId, device_id: &str) -> Result<(), Duration> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let now = self.clock.now_millis();
+        let mut bucket = self
+            .buckets
+            .entry((tenant_id.clone(), device_id.to_string()))
+            .or_insert_with(|| TokenBucket {
+                tokens: self.burst,
+                last_refill: now,
+                limited: false,
+            });
+        bucket.tokens = bucket.available(self.rate, self.burst, now);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+            return Ok(());
+        }
+
+        if !bucket.limited {
+            bucket.limited = true;
+            warn!(
+                %tenant_id,
+                device_id,
+                per_second = self.rate,
+                "Device exceeds the HTTP telemetry rate limit"
+            );
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+
+    /// Drops the buckets that refilled completely, they behave like new ones.
+    /// Returns the number of buckets dropped.
+    pub fn prune(&self) -> usize {
+        let now = self.clock.now_millis();
+        let before = self.buckets.len();
+        self.buckets
+            .retain(|_, bucket| bucket.available(self.rate, self.burst, now) < self.burst);
+        before - self.buckets.len()
+    }
+
+    /// Devices with a bucket
+    pub fn tracked_devices(&self) -> usize {
+        self.buckets.len()
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::atomic::Ordering;
+
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::api::error::AppError;
+use crate::api::AppState;
+
+/// Seconds rejected clients are asked to wait before retrying
+pub const READ_ONLY_RETRY_AFTER_SECS: u64 = 60;
+
+/// Paths that accept writes in read-only mode, so it can be turned off again and the
+/// database can be backed up during maintenance
+const WRITABLE_PATHS: [&str; 2] = ["/admin/readonly", "/admin/backup"];
+
+/// Rejects every request that isn't a read with 503 while the API is read-only.
+/// Installed on all routes, so new write routes are covered without further checks.
+pub async fn reject_writes_when_read_only(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let is_read = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if is_read
+        || !state.read_only.load(Ordering::Relaxed)
+        || WRITABLE_PATHS.contains(&request.uri().path())
+    {
+        return Ok(next.run(request).await);
+    }
+    Err(AppError::ServiceUnavailable(
+        "API is read-only for maintenance".to_string(),
+        READ_ONLY_RETRY_AFTER_SECS,
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadOnlyMode {
+    pub enabled: bool,
+}
+
+pub async fn get_read_only_handler(State(state): State<AppState>) -> Json<ReadOnlyMode> {
+    Json(ReadOnlyMode {
+        enabled: state.read_only.load(Ordering::Relaxed),
+    })
+}
+
+pub async fn set_read_only_handler(
+    State(state): State<AppState>,
+    Json(mode): Json<ReadOnlyMode>,
+) -> Json<ReadOnlyMode> {
+    let previous = state.read_only.swap(mode.enabled, Ordering::Relaxed);
+    if previous != mode.enabled {
+        tracing::warn!(enabled = mode.enabled, "API read-only mode changed");
+    }
+    Json(mode)
+}
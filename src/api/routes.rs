@@ -1,38 +1,137 @@
+use crate::api::audit::get_audit_log_handler;
+use crate::api::auth::require_admin_token;
 use crate::api::handlers::*;
+use crate::api::read_only::{
+    get_read_only_handler, reject_writes_when_read_only, set_read_only_handler,
+};
+use crate::api::stream::metric_stream_handler;
 use crate::api::AppState;
 use axum::{
-    routing::{get, post, put},
+    http::{header::ETAG, HeaderValue, Method},
+    middleware,
+    routing::{get, patch, post, put},
     Router,
 };
+use tower_http::compression::predicate::{DefaultPredicate, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Responses smaller than this are not worth the compression overhead
+const COMPRESSION_MIN_SIZE: u16 = 1024;
 
-pub fn get_routes(state: AppState) -> Router {
+/// Routes that can return large JSON payloads (shadows and timeseries)
+fn bulk_data_routes() -> Router<AppState> {
     Router::new()
-        .route("/", get(home_handler))
-        .route("/health", get(health_handler))
-        .route("/time", get(time_handler))
         .route(
             "/{tenant_id}/things/{device_id}/shadow",
             get(get_shadow_handler)
                 .post(update_shadow_handler)
                 .delete(delete_shadow_handler),
         )
+        .route(
+            "/{tenant_id}/things/{device_id}/shadow/desired",
+            patch(patch_desired_shadow_handler).delete(delete_desired_shadow_handler),
+        )
+        .route(
+            "/{tenant_id}/things/{device_id}/shadow/reported",
+            patch(patch_reported_shadow_handler),
+        )
+        .route(
+            "/{tenant_id}/groups/{group_id}/shadow/desired",
+            post(update_group_desired_handler),
+        )
         .route(
             "/{tenant_id}/data/{device_id}/{metric}",
             get(get_timeseries_handler),
         )
+        .route(
+            "/{tenant_id}/data/{device_id}/{metric}/last",
+            get(get_last_timeseries_handler),
+        )
+        .route(
+            "/{tenant_id}/data/{device_id}/{metric}/gaps",
+            get(get_timeseries_gaps_handler),
+        )
+        .route(
+            "/{tenant_id}/data/{device_id}/catalog",
+            get(get_metric_catalog_handler),
+        )
+        .route(
+            "/{tenant_id}/data/prefix/{prefix}/{metric}",
+            get(get_prefix_timeseries_handler),
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/export",
+            get(export_device_handler),
+        )
+}
+
+/// Builds the CORS layer, `None` if no origins are allowed
+fn cors_layer(allowed_origins: &[String]) -> Option<CorsLayer> {
+    if allowed_origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if allowed_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        // Invalid origins are reported by ForestConfig::validate
+        AllowOrigin::list(
+            allowed_origins
+                .iter()
+                .filter_map(|o| HeaderValue::from_str(o).ok()),
+        )
+    };
+    let layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::PATCH,
+        ])
+        .allow_headers(Any)
+        // Dashboards need the ETag for conditional shadow updates
+        .expose_headers([ETAG]);
+    Some(layer)
+}
+
+pub fn get_routes(state: AppState, compression: bool, cors_allowed_origins: &[String]) -> Router {
+    let mut bulk_data = bulk_data_routes();
+    if compression {
+        // Negotiated via Accept-Encoding, clients without gzip support get plain responses
+        let predicate = DefaultPredicate::new().and(SizeAbove::new(COMPRESSION_MIN_SIZE));
+        bulk_data = bulk_data.layer(CompressionLayer::new().compress_when(predicate));
+    }
+
+    let auth_enabled = state.admin_api_token.is_some();
+    let mut router = Router::new()
+        .route("/", get(home_handler))
+        .route("/health", get(health_handler))
+        .route("/time", get(time_handler))
+        .route("/openapi.json", get(openapi_handler))
+        .route("/admin/mqtt/subscriptions", get(mqtt_subscriptions_handler))
+        .route("/admin/mqtt/meters", get(mqtt_meters_handler))
+        .route("/admin/backup", post(create_backup_handler))
+        .route(
+            "/admin/readonly",
+            get(get_read_only_handler).post(set_read_only_handler),
+        )
+        .merge(bulk_data)
         .route(
             "/{tenant_id}/data/{device_id}",
             post(post_telemetry_handler),
         )
+        // Outside of the bulk data routes, the upgrade response must not be compressed
         .route(
-            "/{tenant_id}/data/{device_id}/{metric}/last",
-            get(get_last_timeseries_handler),
+            "/{tenant_id}/data/{device_id}/{metric}/stream",
+            get(metric_stream_handler),
         )
         .route(
             "/{tenant_id}/dataconfig",
             put(store_tenant_config_handler)
                 .get(get_tenant_config_handler)
-                .delete(delete_config_handler),
+                .delete(delete_tenant_config_handler),
         )
         .route(
             "/{tenant_id}/dataconfig/device/{device_prefix}",
@@ -40,9 +139,33 @@ pub fn get_routes(state: AppState) -> Router {
                 .get(get_config_handler)
                 .delete(delete_config_handler),
         )
+        .route(
+            "/{tenant_id}/dataconfig/device/{device_prefix}/explain",
+            get(explain_config_handler),
+        )
+        .route(
+            "/{tenant_id}/dataconfig/label/{selector}",
+            put(store_label_config_handler)
+                .get(get_label_config_handler)
+                .delete(delete_label_config_handler),
+        )
         .route("/{tenant_id}/dataconfig/all", get(list_configs_handler))
+        .route(
+            "/{tenant_id}/things/{device_id}/publish",
+            post(publish_to_device_handler),
+        )
         .route("/{tenant_id}/connected", get(list_connections_handler))
         .route("/{tenant_id}/devices", get(list_devices_handler))
+        .route(
+            "/{tenant_id}/devices/stale",
+            get(list_stale_devices_handler),
+        )
+        .route("/{tenant_id}/dead-letters", get(list_dead_letters_handler))
+        .route("/{tenant_id}/audit", get(get_audit_log_handler))
+        .route(
+            "/{tenant_id}/firmware/summary",
+            get(get_firmware_summary_handler),
+        )
         .route(
             "/{tenant_id}/devices/{device_id}",
             get(get_device_info_handler)
@@ -53,12 +176,67 @@ pub fn get_routes(state: AppState) -> Router {
             "/{tenant_id}/devices/{device_id}/metadata",
             get(get_device_metadata_handler),
         )
+        .route(
+            "/{tenant_id}/devices/{device_id}/activate",
+            post(activate_device_handler),
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/deactivate",
+            post(deactivate_device_handler),
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/tags",
+            get(get_device_tags_handler).put(put_device_tags_handler),
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/labels",
+            get(get_device_labels_handler).patch(patch_device_labels_handler),
+        )
+        .route("/{tenant_id}/groups", post(create_group_handler))
+        .route(
+            "/{tenant_id}/groups/{group_id}/devices",
+            get(list_group_devices_handler),
+        )
+        .route(
+            "/{tenant_id}/groups/{group_id}/devices/{device_id}",
+            put(add_group_device_handler).delete(remove_group_device_handler),
+        )
         .route("/tenants", post(create_tenant_handler))
         .route("/tenants/{tenant_id}", get(get_tenant_handler))
+        .route("/tenants/{tenant_id}/quota", put(put_tenant_quota_handler))
+        .route("/tenants/{tenant_id}/usage", get(get_tenant_usage_handler))
         .route(
             "/{tenant_id}/devices/{device_id}/passwords",
             get(get_device_passwords_handler).post(add_device_password_handler),
         )
+        .route(
+            "/{tenant_id}/devices/{device_id}/provisioning-token",
+            post(create_provisioning_token_handler),
+        )
+        .route("/provision", post(provision_handler))
+        .route(
+            "/{tenant_id}/devices/{device_id}/extraction-errors",
+            get(get_extraction_errors_handler),
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/shadow-rate-limit",
+            get(get_shadow_rate_limit_handler),
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/webhooks",
+            get(list_shadow_webhooks_handler).post(create_shadow_webhook_handler),
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/webhooks/{webhook_id}",
+            get(get_shadow_webhook_handler)
+                .put(put_shadow_webhook_handler)
+                .delete(delete_shadow_webhook_handler),
+        )
+        .route("/{tenant_id}/alarms", post(create_alarm_rule_handler))
+        .route(
+            "/{tenant_id}/devices/{device_id}/alarms",
+            get(list_device_alarms_handler),
+        )
         .route(
             "/cacert/server",
             get(get_server_ca_handler).post(generate_server_ca_handler),
@@ -75,5 +253,23 @@ pub fn get_routes(state: AppState) -> Router {
             "/tenants/{tenant_id}/devices/{device_id}/client_cert/generate",
             post(generate_client_cert_handler),
         )
-        .with_state(state)
+        .route(
+            "/tenants/{tenant_id}/devices/{device_id}/client_cert.p12",
+            get(export_client_pkcs12_handler),
+        )
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            reject_writes_when_read_only,
+        ));
+
+    // Outside of the read-only check, unauthenticated writes get a 401 either way
+    if auth_enabled {
+        router = router.layer(middleware::from_fn_with_state(state, require_admin_token));
+    }
+    // CORS is the outermost layer, browsers send preflight requests without credentials
+    match cors_layer(cors_allowed_origins) {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
 }
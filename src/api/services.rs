@@ -5,24 +5,29 @@ use crate::certs::CertificateManager;
 use crate::db::DB;
 use crate::models::{DeviceMetadata, TenantId};
 
+/// Creates a device with a fresh client certificate.
+/// Provisioning is idempotent: an existing device is returned unchanged unless `force`
+/// is set, in which case only the certificate and key are replaced and everything else
+/// (creation time, tags, labels, status, firmware and OTA state) is kept.
 pub async fn create_device(
     device_id: &str,
     tenant_id: &TenantId,
     db: Arc<DB>,
     cert_manager: Arc<CertificateManager>,
+    force: bool,
 ) -> Result<DeviceMetadata, AppError> {
     // Check if device already exists
     let existing_device = db.get_device_metadata(&tenant_id, &device_id).await?;
-    if existing_device.is_some() {
-        return Err(AppError::Conflict(format!(
-            "Device {} already exists",
-            device_id
-        )));
+    match &existing_device {
+        Some(existing_device) if !force => return Ok(existing_device.clone()),
+        Some(_) => {}
+        None => db.check_device_quota(tenant_id).await?,
     }
     // Generate Device Cert and Key
     let cert_data = cert_manager.create_client_cert(device_id)?;
-    let device_metadata =
-        DeviceMetadata::new(&device_id, &tenant_id).with_credentials(cert_data.cert, cert_data.key);
+    let device_metadata = existing_device
+        .unwrap_or_else(|| DeviceMetadata::new(&device_id, &tenant_id))
+        .with_credentials(cert_data.cert, cert_data.key);
     // Save device metadata to DB
     db.put_device_metadata(&device_metadata).await?;
     Ok(device_metadata)
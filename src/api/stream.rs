@@ -0,0 +1,74 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::response::Response;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+use crate::api::error::AppError;
+use crate::api::AppState;
+use crate::models::TenantId;
+use crate::processor::ingest::IngestMetrics;
+use crate::processor::stream::MetricEvent;
+
+/// Pushes every point of the metric the processor stores as a `[timestamp, value]` text
+/// message, until the client disconnects
+pub async fn metric_stream_handler(
+    ws: WebSocketUpgrade,
+    Path((tenant_id, device_id, metric)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    let Some(sender) = &state.metric_stream else {
+        return Err(AppError::NotFound(
+            "Metric streams require a running processor".to_string(),
+        ));
+    };
+    // Subscribed before the upgrade, so no point stored meanwhile is missed
+    let receiver = sender.subscribe();
+    let tenant_id = TenantId::from_str(&tenant_id);
+    let metrics = state.ingest_metrics.clone();
+    Ok(ws.on_upgrade(move |socket| {
+        stream_metric(socket, receiver, tenant_id, device_id, metric, metrics)
+    }))
+}
+
+async fn stream_metric(
+    mut socket: WebSocket,
+    mut receiver: broadcast::Receiver<MetricEvent>,
+    tenant_id: TenantId,
+    device_id: String,
+    metric: String,
+    metrics: Arc<IngestMetrics>,
+) {
+    debug!(%tenant_id, device_id, metric, "Metric stream opened");
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) if event.matches(&tenant_id, &device_id, &metric) => {
+                    let message = serde_json::json!([event.timestamp, event.value]).to_string();
+                    if socket.send(Message::Text(message.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                // A slow client skips points instead of holding back the processor
+                Err(RecvError::Lagged(skipped)) => {
+                    metrics.stream_dropped.fetch_add(skipped, Ordering::Relaxed);
+                    debug!(%tenant_id, device_id, skipped, "Metric stream client fell behind");
+                }
+                Err(RecvError::Closed) => {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+            // Pings are answered by axum, other messages of the client are ignored
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    debug!(%tenant_id, device_id, metric, "Metric stream closed");
+}
@@ -0,0 +1,63 @@
+use super::rate_limit::TelemetryRateLimiter;
+use crate::clock::MockClock;
+use crate::models::TenantId;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+fn test_telemetry_rate_limit_burst() {
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let limiter = TelemetryRateLimiter::new(2, 3, clock.clone());
+    let tenant_id = TenantId::Default;
+
+    // The burst passes, the next request waits for half a second at 2 per second
+    for _ in 0..3 {
+        assert!(limiter.check(&tenant_id, "dev_1").is_ok());
+    }
+    assert_eq!(
+        limiter.check(&tenant_id, "dev_1"),
+        Err(Duration::from_millis(500))
+    );
+    // Other devices and tenants have their own bucket
+    assert!(limiter.check(&tenant_id, "dev_2").is_ok());
+    assert!(limiter.check(&TenantId::from_str("other"), "dev_1").is_ok());
+
+    clock.advance(Duration::from_millis(500));
+    assert!(limiter.check(&tenant_id, "dev_1").is_ok());
+    assert!(limiter.check(&tenant_id, "dev_1").is_err());
+
+    // Tokens refill up to the burst only
+    clock.advance(Duration::from_secs(60));
+    for _ in 0..3 {
+        assert!(limiter.check(&tenant_id, "dev_1").is_ok());
+    }
+    assert!(limiter.check(&tenant_id, "dev_1").is_err());
+}
+
+#[test]
+fn test_telemetry_rate_limit_disabled() {
+    let limiter = TelemetryRateLimiter::new(0, 1, Arc::new(MockClock::new(0)));
+    assert!(!limiter.is_enabled());
+    for _ in 0..100 {
+        assert!(limiter.check(&TenantId::Default, "dev_1").is_ok());
+    }
+    assert_eq!(limiter.tracked_devices(), 0);
+}
+
+#[test]
+fn test_telemetry_rate_limit_prune() {
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let limiter = TelemetryRateLimiter::new(1, 2, clock.clone());
+    let tenant_id = TenantId::Default;
+    limiter.check(&tenant_id, "idle").unwrap();
+    clock.advance(Duration::from_secs(1));
+    limiter.check(&tenant_id, "busy").unwrap();
+    assert_eq!(limiter.tracked_devices(), 2);
+
+    // Only the bucket that refilled completely is dropped
+    assert_eq!(limiter.prune(), 1);
+    assert_eq!(limiter.tracked_devices(), 1);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(limiter.prune(), 1);
+    assert_eq!(limiter.tracked_devices(), 0);
+}
@@ -3,8 +3,10 @@ use openssl::bn::{BigNum, MsbOption};
 use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
 use openssl::pkey::{PKey, Private};
 use openssl::rsa::Rsa;
+use openssl::stack::Stack;
 use openssl::x509::extension::{
     AuthorityKeyIdentifier, BasicConstraints, KeyUsage, SubjectAlternativeName,
     SubjectKeyIdentifier,
@@ -421,13 +423,21 @@ impl CertificateManager {
         // Ensure CA exists
         self.ensure_ca_exists()?;
 
+        // Generate client private key
+        let client_key = Self::generate_private_key()?;
+        self.create_client_cert_with_key(client_name, &client_key)
+    }
+
+    /// Create a client certificate for an existing key
+    fn create_client_cert_with_key(
+        &self,
+        client_name: &str,
+        client_key: &PKey<Private>,
+    ) -> CertResult<CertificateData> {
         // Load CA key and certificate
         let ca_key = self.load_private_key_absolute(&self.get_ca_key_path())?;
         let ca_cert = self.load_certificate_absolute(&self.get_ca_file_path())?;
 
-        // Generate client private key
-        let client_key = Self::generate_private_key()?;
-
         // Create client certificate request
         let mut req_builder = X509ReqBuilder::new()?;
         let mut x509_name = X509NameBuilder::new()?;
@@ -436,8 +446,8 @@ impl CertificateManager {
         let x509_name = x509_name.build();
 
         req_builder.set_subject_name(&x509_name)?;
-        req_builder.set_pubkey(&client_key)?;
-        req_builder.sign(&client_key, MessageDigest::sha256())?;
+        req_builder.set_pubkey(client_key)?;
+        req_builder.sign(client_key, MessageDigest::sha256())?;
         let req = req_builder.build();
 
         // Create client certificate
@@ -472,7 +482,7 @@ impl CertificateManager {
         cert_builder.set_not_before(&not_before)?;
         cert_builder.set_not_after(&not_after)?;
 
-        cert_builder.set_pubkey(&client_key)?;
+        cert_builder.set_pubkey(client_key)?;
 
         // Set client certificate extensions
         let basic_constraints = BasicConstraints::new().build()?;
@@ -499,12 +509,33 @@ impl CertificateManager {
         let client_cert_filename = format!("{}-cert.pem", client_name);
         let client_key_filename = format!("{}-key.pem", client_name);
 
-        let key = self.save_private_key(&client_key, &client_key_filename)?;
+        let key = self.save_private_key(client_key, &client_key_filename)?;
         let cert = self.save_certificate(&client_cert, &client_cert_filename)?;
 
         Ok(CertificateData { cert, key })
     }
 
+    /// Bundle the client certificate, its key and the CA chain as PKCS#12 protected by
+    /// `password`, for clients that can't load PEM files. Returns the DER bytes.
+    pub fn export_client_pkcs12(&self, client_name: &str, password: &str) -> CertResult<Vec<u8>> {
+        let client_key = self.load_private_key(&format!("{}-key.pem", client_name))?;
+        let client_cert = self.load_certificate(&format!("{}-cert.pem", client_name))?;
+
+        // An uploaded CA file may hold a whole chain
+        let mut ca_chain = Stack::new()?;
+        for ca_cert in X509::stack_from_pem(self.get_ca_cert_pem()?.as_bytes())? {
+            ca_chain.push(ca_cert)?;
+        }
+
+        let pkcs12 = Pkcs12::builder()
+            .name(client_name)
+            .pkey(&client_key)
+            .cert(&client_cert)
+            .ca(ca_chain)
+            .build2(password)?;
+        Ok(pkcs12.to_der()?)
+    }
+
     /// Check if server certificate exists and contains all required hostnames
     pub fn is_server_cert_valid(&self, server_name: &str, host_names: &[&str]) -> CertResult<bool> {
         // Check if certificate files exist
@@ -694,6 +725,64 @@ impl CertificateManager {
         Ok(())
     }
 
+    /// Re-issue the server certificate with a fresh key, keeping the common name and
+    /// DNS names of the current certificate. Returns the path of the new certificate.
+    pub fn rotate_server_cert(&self) -> CertResult<PathBuf> {
+        let cert_path = self.get_file_path(SERVER_CERT_FILENAME);
+        if !cert_path.exists() {
+            return Err(CertificateError::FileNotFound(
+                cert_path.display().to_string(),
+            ));
+        }
+        let cert = self.load_certificate(SERVER_CERT_FILENAME)?;
+
+        let server_name = match cert.subject_name().entries_by_nid(Nid::COMMONNAME).next() {
+            Some(entry) => entry
+                .data()
+                .as_utf8()
+                .map_err(|_| {
+                    CertificateError::InvalidCertificate(
+                        "Common name is not valid UTF-8".to_string(),
+                    )
+                })?
+                .to_string(),
+            None => {
+                return Err(CertificateError::MissingData(
+                    "Certificate is missing Common Name".to_string(),
+                ))
+            }
+        };
+        let host_names: Vec<String> = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.dnsname().map(|dns| dns.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let host_names: Vec<&str> = host_names.iter().map(|h| h.as_str()).collect();
+
+        let server_key = Self::generate_private_key()?;
+        self.create_server_cert_with_key(&server_name, &host_names, &server_key)?;
+        Ok(cert_path)
+    }
+
+    /// Re-issue a client certificate for the existing client key.
+    /// Returns the path of the new certificate.
+    pub fn rotate_client_cert(&self, client_name: &str) -> CertResult<PathBuf> {
+        let key_filename = format!("{}-key.pem", client_name);
+        let key_path = self.get_file_path(&key_filename);
+        if !key_path.exists() {
+            return Err(CertificateError::FileNotFound(
+                key_path.display().to_string(),
+            ));
+        }
+        let client_key = self.load_private_key(&key_filename)?;
+        self.create_client_cert_with_key(client_name, &client_key)?;
+        Ok(self.get_file_path(&format!("{}-cert.pem", client_name)))
+    }
+
     /// Create a server certificate signed by the CA with multiple host names
     pub fn create_server_cert(&self, server_name: &str) -> CertResult<()> {
         // Generate server private key
@@ -161,3 +161,118 @@ fn test_is_server_cert_valid() {
         .is_server_cert_valid("wrong.com", &["example.com"])
         .unwrap());
 }
+
+fn serial_number(path: &Path) -> String {
+    let cert = X509::from_pem(&fs::read(path).unwrap()).unwrap();
+    cert.serial_number()
+        .to_bn()
+        .unwrap()
+        .to_hex_str()
+        .unwrap()
+        .to_string()
+}
+
+fn dir_entries(dir: &Path) -> Vec<String> {
+    let mut entries: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    entries.sort();
+    entries
+}
+
+#[test]
+fn test_rotate_ca() {
+    let temp_dir = tempdir().unwrap();
+    let cert_manager = CertificateManager::new(&temp_dir, None).unwrap();
+    cert_manager.create_ca(None).unwrap();
+    let old_serial = serial_number(&cert_manager.get_ca_file_path());
+
+    cert_manager.create_ca(None).unwrap();
+
+    assert_ne!(serial_number(&cert_manager.get_ca_file_path()), old_serial);
+    let backup = cert_manager.get_ca_file_path().with_extension("pem.bak");
+    assert_eq!(serial_number(&backup), old_serial);
+}
+
+#[test]
+fn test_rotate_server_cert_keeps_hostnames() {
+    let temp_dir = tempdir().unwrap();
+    let cert_manager = CertificateManager::new(&temp_dir, None).unwrap();
+    cert_manager
+        .setup("example.com", &["example.com", "mqtt.example.com"])
+        .unwrap();
+    let cert_path = temp_dir.path().join(SERVER_CERT_FILENAME);
+    let key_path = temp_dir.path().join(SERVER_KEY_FILENAME);
+    let old_serial = serial_number(&cert_path);
+    let old_key = fs::read_to_string(&key_path).unwrap();
+    let entries = dir_entries(temp_dir.path());
+
+    let rotated = cert_manager.rotate_server_cert().unwrap();
+
+    assert_eq!(rotated, cert_path);
+    assert_ne!(serial_number(&cert_path), old_serial);
+    assert_ne!(fs::read_to_string(&key_path).unwrap(), old_key);
+    assert!(cert_manager
+        .is_server_cert_valid("example.com", &["example.com", "mqtt.example.com"])
+        .unwrap());
+    assert_eq!(dir_entries(temp_dir.path()), entries);
+}
+
+#[test]
+fn test_rotate_client_cert_keeps_key() {
+    let temp_dir = tempdir().unwrap();
+    let cert_manager = CertificateManager::new(&temp_dir, None).unwrap();
+    cert_manager.create_client_cert("client1").unwrap();
+    let cert_path = temp_dir.path().join("client1-cert.pem");
+    let key_path = temp_dir.path().join("client1-key.pem");
+    let old_serial = serial_number(&cert_path);
+    let old_key = fs::read_to_string(&key_path).unwrap();
+    let entries = dir_entries(temp_dir.path());
+
+    let rotated = cert_manager.rotate_client_cert("client1").unwrap();
+
+    assert_eq!(rotated, cert_path);
+    assert_ne!(serial_number(&cert_path), old_serial);
+    assert_eq!(fs::read_to_string(&key_path).unwrap(), old_key);
+    assert_eq!(dir_entries(temp_dir.path()), entries);
+
+    assert!(matches!(
+        cert_manager.rotate_client_cert("unknown"),
+        Err(CertificateError::FileNotFound(_))
+    ));
+}
+
+#[test]
+fn test_export_client_pkcs12() {
+    let temp_dir = tempdir().unwrap();
+    let cert_manager = CertificateManager::new(&temp_dir, Some("tenant1".to_string())).unwrap();
+    let data = cert_manager.create_client_cert("client1").unwrap();
+
+    let der = cert_manager
+        .export_client_pkcs12("client1", "p12-secret")
+        .unwrap();
+    let parsed = Pkcs12::from_der(&der)
+        .unwrap()
+        .parse2("p12-secret")
+        .unwrap();
+    let cert = parsed.cert.unwrap();
+    assert_eq!(cert.to_pem().unwrap(), data.cert.as_bytes());
+    assert!(parsed
+        .pkey
+        .unwrap()
+        .public_eq(&PKey::private_key_from_pem(data.key.as_bytes()).unwrap()));
+    let ca_chain = parsed.ca.unwrap();
+    assert_eq!(ca_chain.len(), 1);
+    assert_eq!(
+        ca_chain.get(0).unwrap().to_pem().unwrap(),
+        cert_manager.get_ca_cert_pem().unwrap().as_bytes()
+    );
+
+    // The password is required to read it
+    assert!(Pkcs12::from_der(&der).unwrap().parse2("wrong").is_err());
+    assert!(matches!(
+        cert_manager.export_client_pkcs12("unknown", "p12-secret"),
+        Err(CertificateError::FileNotFound(_))
+    ));
+}
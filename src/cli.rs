@@ -1,6 +1,15 @@
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use thiserror::Error;
+
+use crate::db::export::ExportRecord;
+use crate::db::{DatabaseError, DB};
+use crate::models::{AuthConfig, DeviceCredential, DeviceMetadata, ShadowName, Tenant, TenantId};
+use crate::shadow::{NestedStateDocument, Shadow, ShadowSerializationError, StateUpdateDocument};
+use crate::timeseries::{TimeSeriesConversions, TimeSeriesModel};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -42,4 +51,559 @@ pub enum Commands {
         #[arg(long)]
         device_id: String,
     },
+    /// Issue a new client certificate and key and print both as PEM
+    #[command(name = "issue-client-cert")]
+    IssueClientCert {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+    },
+    /// Write a commented template config file
+    #[command(name = "init-config")]
+    InitConfig {
+        /// Output file (.toml), prints to stdout if omitted
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// List registered devices
+    #[command(name = "device-list")]
+    DeviceList {
+        /// Tenant ID, uses the default tenant if omitted
+        #[arg(long)]
+        tenant: Option<String>,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+        /// Only list devices with an ID sorting after this one
+        #[arg(long)]
+        after: Option<String>,
+        /// Maximum number of devices to list
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Print a device shadow
+    #[command(name = "shadow-get")]
+    ShadowGet {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+        /// Shadow name, uses the default shadow if omitted
+        #[arg(long)]
+        shadow_name: Option<String>,
+        /// Tenant ID, uses the default tenant if omitted
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Update a device shadow from a JSON file (`{"state": {"desired": {...}}}`)
+    #[command(name = "shadow-set")]
+    ShadowSet {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+        /// Shadow name, uses the default shadow if omitted
+        #[arg(long)]
+        shadow_name: Option<String>,
+        /// JSON file containing the state update
+        #[arg(long)]
+        json_file: PathBuf,
+        /// Tenant ID, uses the default tenant if omitted
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Print the values of a device metric
+    #[command(name = "timeseries-query")]
+    TimeseriesQuery {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+        /// Metric name
+        #[arg(long)]
+        metric: String,
+        /// Start of the range, unix timestamp or ISO-8601 (e.g. 2024-03-15T14:00:00Z)
+        #[arg(long, required_unless_present = "last")]
+        start: Option<String>,
+        /// End of the range, unix timestamp or ISO-8601, defaults to now
+        #[arg(long)]
+        end: Option<String>,
+        /// Print the last N values instead of a range
+        #[arg(long, conflicts_with_all = ["start", "end"])]
+        last: Option<u64>,
+        /// Reduce the result to N points (LTTB), numeric metrics only
+        #[arg(long)]
+        downsample: Option<usize>,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+        /// Tenant ID, uses the default tenant if omitted
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Start the broker and print all messages matching a topic pattern
+    #[command(name = "mqtt-watch")]
+    MqttWatch {
+        /// Topic filter, supports the `+` and `#` wildcards
+        #[arg(long, default_value = "#")]
+        pattern: String,
+        /// Stop after this many seconds without a message
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Pretty print JSON payloads
+        #[arg(long)]
+        json_pretty: bool,
+    },
+    /// Create or replace a tenant
+    #[command(name = "tenant-create")]
+    TenantCreate {
+        /// Tenant ID, alphanumeric characters and hyphens
+        #[arg(long)]
+        tenant_id: String,
+        /// Allow devices to authenticate with username and password
+        #[arg(long)]
+        allow_passwords: bool,
+        /// Allow devices to authenticate with client certificates
+        #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+        allow_certs: bool,
+    },
+    /// List all tenants
+    #[command(name = "tenant-list")]
+    TenantList,
+    /// Export tenants, devices, shadows, credentials and data configs as JSON lines
+    #[command(name = "db-export")]
+    DbExport {
+        /// Output file
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Import records written by db-export, existing records are replaced
+    #[command(name = "db-import")]
+    DbImport {
+        /// Input file
+        #[arg(long)]
+        input: PathBuf,
+    },
+    /// Add or replace a username and password a device can authenticate with
+    #[command(name = "device-add-password")]
+    DeviceAddPassword {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+        /// MQTT username
+        #[arg(long)]
+        username: String,
+        /// Plaintext password, only its bcrypt hash is stored
+        #[arg(long)]
+        password: String,
+        /// Tenant ID, uses the default tenant if omitted
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Remove a username of a device
+    #[command(name = "device-delete-password")]
+    DeviceDeletePassword {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+        /// MQTT username
+        #[arg(long)]
+        username: String,
+        /// Tenant ID, uses the default tenant if omitted
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// List the usernames of a device
+    #[command(name = "device-list-passwords")]
+    DeviceListPasswords {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+        /// Tenant ID, uses the default tenant if omitted
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Re-issue a certificate, e.g. before it expires
+    #[command(name = "cert-rotate")]
+    CertRotate {
+        #[command(subcommand)]
+        component: CertComponent,
+    },
 }
+
+#[derive(Subcommand)]
+pub enum CertComponent {
+    /// Create a new CA, the old one is kept as backup.
+    /// Server and client certificates have to be re-issued afterwards.
+    Ca,
+    /// Re-issue the server certificate with a new key, keeping its hostnames
+    Server,
+    /// Re-issue a client certificate for the existing client key
+    Client {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid shadow document: {0}")]
+    ShadowError(#[from] ShadowSerializationError),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] DatabaseError),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// Renders rows as an ASCII table with a header
+pub fn format_table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let separator = format!(
+        "+{}+\n",
+        widths
+            .iter()
+            .map(|w| "-".repeat(w + 2))
+            .collect::<Vec<_>>()
+            .join("+")
+    );
+    let format_row = |cells: &[&str]| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!(" {:<w$} ", cell, w = w))
+            .collect();
+        format!("|{}|\n", cells.join("|"))
+    };
+
+    let mut table = separator.clone();
+    table.push_str(&format_row(header));
+    table.push_str(&separator);
+    for row in rows {
+        let cells: Vec<&str> = row.iter().map(String::as_str).collect();
+        table.push_str(&format_row(&cells));
+    }
+    table.push_str(&separator);
+    table
+}
+
+/// Renders rows as CSV with a header, quoting cells where needed
+pub fn format_csv(header: &[&str], rows: &[Vec<String>]) -> String {
+    fn escape(cell: &str) -> String {
+        if cell.contains([',', '"', '\n']) {
+            format!("\"{}\"", cell.replace('"', "\"\""))
+        } else {
+            cell.to_string()
+        }
+    }
+    let mut csv = header.join(",") + "\n";
+    for row in rows {
+        let cells: Vec<String> = row.iter().map(|c| escape(c)).collect();
+        csv.push_str(&cells.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Sorts devices by ID and returns the page following `after`.
+/// The last device ID of a page can be passed as `after` to fetch the next one.
+pub fn paginate_devices(
+    mut devices: Vec<DeviceMetadata>,
+    after: Option<&str>,
+    limit: Option<usize>,
+) -> Vec<DeviceMetadata> {
+    devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    devices
+        .into_iter()
+        .filter(|d| after.is_none_or(|after| d.device_id.as_str() > after))
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+const DEVICE_HEADER: [&str; 3] = ["device_id", "created_at", "has_cert"];
+
+fn device_rows(devices: &[DeviceMetadata]) -> Vec<Vec<String>> {
+    devices
+        .iter()
+        .map(|d| {
+            vec![
+                d.device_id.clone(),
+                format_timestamp(d.created_at),
+                d.certificate.is_some().to_string(),
+            ]
+        })
+        .collect()
+}
+
+pub fn format_device_table(devices: &[DeviceMetadata]) -> String {
+    format_table(&DEVICE_HEADER, &device_rows(devices))
+}
+
+pub fn format_device_csv(devices: &[DeviceMetadata]) -> String {
+    format_csv(&DEVICE_HEADER, &device_rows(devices))
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    chrono::DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+pub async fn tenant_create(
+    db: &DB,
+    tenant_id: &str,
+    allow_passwords: bool,
+    allow_certificates: bool,
+) -> Result<Tenant, CliError> {
+    // Same rules as for the tenant certificate directories
+    if tenant_id.is_empty() || !tenant_id.chars().all(|c| c.is_alphanumeric() || c == '-') {
+        return Err(CliError::InvalidArgument(format!(
+            "Tenant ID must only contain alphanumeric characters and hyphens: '{}'",
+            tenant_id
+        )));
+    }
+    let tenant = Tenant::new(&TenantId::from_str(tenant_id)).with_auth_config(AuthConfig {
+        allow_passwords,
+        allow_certificates,
+    });
+    db.put_tenant(&tenant).await?;
+    Ok(tenant)
+}
+
+pub async fn device_add_password(
+    db: &DB,
+    device_id: &str,
+    username: &str,
+    password: &str,
+    tenant: Option<&str>,
+    bcrypt_cost: Option<u32>,
+) -> Result<DeviceCredential, CliError> {
+    if username.is_empty() || password.is_empty() {
+        return Err(CliError::InvalidArgument(
+            "Username and password must not be empty".to_string(),
+        ));
+    }
+    let tenant_id = TenantId::from_option(tenant);
+    Ok(db
+        .set_device_password(&tenant_id, device_id, username, password, bcrypt_cost)
+        .await?)
+}
+
+/// Returns false if the device has no such username
+pub async fn device_delete_password(
+    db: &DB,
+    device_id: &str,
+    username: &str,
+    tenant: Option<&str>,
+) -> Result<bool, CliError> {
+    let tenant_id = TenantId::from_option(tenant);
+    Ok(db
+        .remove_device_password(&tenant_id, device_id, username)
+        .await?)
+}
+
+pub async fn device_list_passwords(
+    db: &DB,
+    device_id: &str,
+    tenant: Option<&str>,
+) -> Result<Vec<String>, CliError> {
+    let tenant_id = TenantId::from_option(tenant);
+    Ok(db.list_device_passwords(&tenant_id, device_id).await?)
+}
+
+/// Writes all records as JSON lines, returns the number of records
+pub async fn db_export(db: &DB, output: &Path) -> Result<usize, CliError> {
+    let records = db.export_records().await?;
+    let mut writer = BufWriter::new(File::create(output)?);
+    for record in &records {
+        // Records only contain JSON compatible types
+        serde_json::to_writer(&mut writer, record).unwrap();
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(records.len())
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub failed: usize,
+}
+
+/// Imports a db-export file. Broken lines and failing records are logged and skipped.
+pub async fn db_import(db: &DB, input: &Path) -> Result<ImportSummary, CliError> {
+    let reader = BufReader::new(File::open(input)?);
+    let mut summary = ImportSummary::default();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = match serde_json::from_str::<ExportRecord>(&line) {
+            Ok(record) => db.import_record(&record).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(()) => summary.imported += 1,
+            Err(e) => {
+                tracing::warn!(line = index + 1, "Failed to import record: {}", e);
+                summary.failed += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+pub fn format_tenant_table(tenants: &[Tenant]) -> String {
+    let rows: Vec<Vec<String>> = tenants
+        .iter()
+        .map(|t| {
+            vec![
+                t.tenant_id.to_string(),
+                t.auth_config.allow_passwords.to_string(),
+                t.auth_config.allow_certificates.to_string(),
+                format_timestamp(t.created_at),
+            ]
+        })
+        .collect();
+    format_table(
+        &[
+            "tenant_id",
+            "allow_passwords",
+            "allow_certificates",
+            "created_at",
+        ],
+        &rows,
+    )
+}
+
+pub async fn shadow_get(
+    db: &DB,
+    device_id: &str,
+    shadow_name: Option<&str>,
+    tenant: Option<&str>,
+) -> Result<Shadow, CliError> {
+    let shadow_name = ShadowName::from_option(shadow_name);
+    let tenant_id = TenantId::from_option(tenant);
+    Ok(db._get_shadow(device_id, &shadow_name, &tenant_id).await?)
+}
+
+pub async fn shadow_set(
+    db: &DB,
+    device_id: &str,
+    shadow_name: Option<&str>,
+    json_file: &Path,
+    tenant: Option<&str>,
+) -> Result<Shadow, CliError> {
+    let json = std::fs::read_to_string(json_file)?;
+    let nested = NestedStateDocument::from_json(&json)?;
+    let update = StateUpdateDocument::from_nested_state(
+        nested,
+        device_id,
+        &ShadowName::from_option(shadow_name),
+        &TenantId::from_option(tenant),
+    );
+    Ok(db._upsert_shadow(&update).await?)
+}
+
+/// Parses a unix timestamp (seconds) or an ISO-8601 date/time.
+/// Date/times without an offset are interpreted as UTC.
+pub fn parse_timestamp(value: &str) -> Result<u64, CliError> {
+    let value = value.trim();
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Ok(timestamp);
+    }
+    let datetime = chrono::DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.to_utc())
+        .or_else(|_| {
+            chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").map(|dt| dt.and_utc())
+        })
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        })
+        .map_err(|_| CliError::InvalidArgument(format!("Invalid timestamp: '{}'", value)))?;
+    u64::try_from(datetime.timestamp())
+        .map_err(|_| CliError::InvalidArgument(format!("Timestamp before 1970: '{}'", value)))
+}
+
+pub enum QueryRange {
+    /// Start and end timestamp, both inclusive
+    Between(u64, u64),
+    Last(u64),
+}
+
+pub async fn timeseries_query(
+    db: &DB,
+    device_id: &str,
+    metric: &str,
+    range: QueryRange,
+    downsample: Option<usize>,
+    tenant: Option<&str>,
+) -> Result<TimeSeriesModel, CliError> {
+    let tenant_id = TenantId::from_option(tenant);
+    let ts = match range {
+        QueryRange::Between(start, end) => {
+            db.get_metric(&tenant_id, device_id, metric, start, end, None)
+                .await?
+        }
+        QueryRange::Last(limit) => {
+            db.get_last_metric(&tenant_id, device_id, metric, limit)
+                .await?
+        }
+    };
+
+    match downsample {
+        Some(points) => {
+            let float_ts = ts.to_float_series().ok_or_else(|| {
+                CliError::InvalidArgument(format!("Metric {} is not numeric", metric))
+            })?;
+            Ok(float_ts.downsample_lttb(points).to_model(device_id, metric))
+        }
+        None => Ok(ts.to_model(device_id, metric)),
+    }
+}
+
+pub fn format_timeseries_table(model: &TimeSeriesModel) -> String {
+    let rows: Vec<Vec<String>> = model
+        .data
+        .iter()
+        .map(|(timestamp, value)| vec![format_timestamp(*timestamp), value.to_string()])
+        .collect();
+    format_table(&["timestamp", "value"], &rows)
+}
+
+/// Formats a received MQTT message as `<timestamp> <topic>: <payload>`.
+/// Payloads that aren't valid UTF-8 are printed lossy.
+pub fn format_mqtt_message(
+    received_at: chrono::DateTime<chrono::Utc>,
+    topic: &str,
+    payload: &[u8],
+    json_pretty: bool,
+) -> String {
+    let payload = match serde_json::from_slice::<serde_json::Value>(payload) {
+        Ok(json) if json_pretty => serde_json::to_string_pretty(&json).unwrap(),
+        _ => String::from_utf8_lossy(payload).into_owned(),
+    };
+    format!(
+        "{} {}: {}",
+        received_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        topic,
+        payload
+    )
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,439 @@
+use super::*;
+use crate::db::DB;
+use crate::models::{DeviceStatus, TenantId};
+use crate::timeseries::{LatLong, MetricValue};
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+async fn setup_db() -> DB {
+    let db_id = uuid::Uuid::new_v4().simple();
+    DB::open_default(&format!(
+        "sqlite:file:memdb_{}?mode=memory&cache=shared",
+        db_id
+    ))
+    .await
+    .unwrap()
+}
+
+async fn setup_devices() -> Vec<DeviceMetadata> {
+    let db = setup_db().await;
+
+    for (device_id, certificate) in [("dev-c", None), ("dev-a", Some("cert")), ("dev-b", None)] {
+        let metadata = DeviceMetadata {
+            device_id: device_id.to_string(),
+            tenant_id: TenantId::Default,
+            certificate: certificate.map(str::to_string),
+            key: None,
+            created_at: 0,
+            tags: HashMap::new(),
+            labels: HashMap::new(),
+            status: DeviceStatus::Active,
+            firmware_version: None,
+            target_firmware_version: None,
+            ota_status: None,
+        };
+        db.put_device_metadata(&metadata).await.unwrap();
+    }
+    db.list_devices(&TenantId::Default).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_paginate_devices() {
+    let devices = setup_devices().await;
+    assert_eq!(devices.len(), 3);
+
+    let ids = |devices: Vec<DeviceMetadata>| -> Vec<String> {
+        devices.into_iter().map(|d| d.device_id).collect()
+    };
+    assert_eq!(
+        ids(paginate_devices(devices.clone(), None, None)),
+        vec!["dev-a", "dev-b", "dev-c"]
+    );
+    assert_eq!(
+        ids(paginate_devices(devices.clone(), None, Some(2))),
+        vec!["dev-a", "dev-b"]
+    );
+    assert_eq!(
+        ids(paginate_devices(devices.clone(), Some("dev-b"), Some(2))),
+        vec!["dev-c"]
+    );
+    assert!(paginate_devices(devices, Some("dev-c"), None).is_empty());
+}
+
+#[tokio::test]
+async fn test_format_device_table() {
+    let devices = paginate_devices(setup_devices().await, None, None);
+    let table = format_device_table(&devices);
+    let expected = "\
++-----------+---------------------+----------+
+| device_id | created_at          | has_cert |
++-----------+---------------------+----------+
+| dev-a     | 1970-01-01 00:00:00 | true     |
+| dev-b     | 1970-01-01 00:00:00 | false    |
+| dev-c     | 1970-01-01 00:00:00 | false    |
++-----------+---------------------+----------+
+";
+    assert_eq!(table, expected);
+}
+
+#[tokio::test]
+async fn test_shadow_set_and_get() {
+    let db = setup_db().await;
+    let temp_dir = TempDir::new().unwrap();
+    let json_file = temp_dir.path().join("update.json");
+    std::fs::write(&json_file, r#"{"state": {"desired": {"led": "on"}}}"#).unwrap();
+
+    let missing = shadow_get(&db, "dev1", None, None).await;
+    assert!(matches!(
+        missing,
+        Err(CliError::DatabaseError(DatabaseError::NotFoundError(_)))
+    ));
+
+    let shadow = shadow_set(&db, "dev1", Some("config"), &json_file, Some("tenant1"))
+        .await
+        .unwrap();
+    assert_eq!(shadow.get_version(), 1);
+
+    let shadow = shadow_get(&db, "dev1", Some("config"), Some("tenant1"))
+        .await
+        .unwrap();
+    assert_eq!(shadow.get_desired_value()["led"], "on");
+    assert_eq!(shadow.tenant_id, TenantId::from_str("tenant1"));
+    // The default shadow was not touched
+    assert!(shadow_get(&db, "dev1", None, Some("tenant1"))
+        .await
+        .is_err());
+
+    std::fs::write(&json_file, "not json").unwrap();
+    let invalid = shadow_set(&db, "dev1", None, &json_file, None).await;
+    assert!(matches!(invalid, Err(CliError::ShadowError(_))));
+}
+
+#[test]
+fn test_parse_timestamp() {
+    assert_eq!(parse_timestamp("1710511200").unwrap(), 1710511200);
+    assert_eq!(parse_timestamp("2024-03-15T14:00:00Z").unwrap(), 1710511200);
+    assert_eq!(
+        parse_timestamp("2024-03-15T16:00:00+02:00").unwrap(),
+        1710511200
+    );
+    assert_eq!(parse_timestamp("2024-03-15T14:00:00").unwrap(), 1710511200);
+    assert_eq!(parse_timestamp("2024-03-15").unwrap(), 1710460800);
+    assert!(matches!(
+        parse_timestamp("yesterday"),
+        Err(CliError::InvalidArgument(_))
+    ));
+    assert!(parse_timestamp("1969-12-31").is_err());
+}
+
+async fn setup_timeseries() -> DB {
+    let db = setup_db().await;
+    for i in 0..10u64 {
+        db.insert_metric_row(
+            &TenantId::Default,
+            "dev1",
+            "temp",
+            1710511200 + i * 60,
+            MetricValue::Float(20.0 + i as f64),
+        )
+        .await
+        .unwrap();
+    }
+    db.insert_metric_row(
+        &TenantId::Default,
+        "dev1",
+        "position",
+        1710511200,
+        MetricValue::Location(LatLong::new(48.2, 16.4)),
+    )
+    .await
+    .unwrap();
+    db
+}
+
+#[tokio::test]
+async fn test_timeseries_query() {
+    let db = setup_timeseries().await;
+
+    let range = QueryRange::Between(1710511200 + 60, 1710511200 + 180);
+    let model = timeseries_query(&db, "dev1", "temp", range, None, None)
+        .await
+        .unwrap();
+    assert_eq!(model.data.len(), 3);
+    assert_eq!(model.data[0], (1710511260, serde_json::json!(21.0)));
+
+    let model = timeseries_query(&db, "dev1", "temp", QueryRange::Last(2), None, None)
+        .await
+        .unwrap();
+    let timestamps: Vec<u64> = model.data.iter().map(|(t, _)| *t).collect();
+    assert_eq!(timestamps, vec![1710511200 + 480, 1710511200 + 540]);
+
+    let range = QueryRange::Between(0, u32::MAX as u64);
+    let model = timeseries_query(&db, "dev1", "temp", range, Some(4), None)
+        .await
+        .unwrap();
+    assert_eq!(model.data.len(), 4);
+    assert_eq!(model.data[0].0, 1710511200);
+    assert_eq!(model.data[3].0, 1710511200 + 540);
+
+    // Locations can't be downsampled
+    let range = QueryRange::Between(0, u32::MAX as u64);
+    let result = timeseries_query(&db, "dev1", "position", range, Some(4), None).await;
+    assert!(matches!(result, Err(CliError::InvalidArgument(_))));
+}
+
+#[tokio::test]
+async fn test_timeseries_output_formats() {
+    let db = setup_timeseries().await;
+    let range = QueryRange::Between(1710511200, 1710511260);
+    let model = timeseries_query(&db, "dev1", "temp", range, None, None)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        model.to_csv(),
+        "timestamp,value\n1710511200,20.0\n1710511260,21.0\n"
+    );
+    let table = format_timeseries_table(&model);
+    assert!(table.contains("| 2024-03-15 14:01:00 | 21.0  |"));
+
+    let range = QueryRange::Between(1710511200, 1710511200);
+    let model = timeseries_query(&db, "dev1", "position", range, None, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        model.to_csv(),
+        "timestamp,value\n1710511200,\"{\"\"lat\"\":48.2,\"\"long\"\":16.4}\"\n"
+    );
+}
+
+#[test]
+fn test_format_csv_escapes_cells() {
+    let rows = vec![vec!["a,b".to_string(), "say \"hi\"".to_string()]];
+    assert_eq!(
+        format_csv(&["x", "y"], &rows),
+        "x,y\n\"a,b\",\"say \"\"hi\"\"\"\n"
+    );
+}
+
+#[test]
+fn test_format_mqtt_message() {
+    let received_at = chrono::DateTime::from_timestamp(1710511200, 0).unwrap();
+    let payload = br#"{"temp":21.5}"#;
+
+    assert_eq!(
+        format_mqtt_message(received_at, "tenants/default/things/dev1", payload, false),
+        r#"2024-03-15T14:00:00.000Z tenants/default/things/dev1: {"temp":21.5}"#
+    );
+    assert_eq!(
+        format_mqtt_message(received_at, "t", payload, true),
+        "2024-03-15T14:00:00.000Z t: {\n  \"temp\": 21.5\n}"
+    );
+    // Non JSON payloads are printed as is
+    assert_eq!(
+        format_mqtt_message(received_at, "t", b"plain", true),
+        "2024-03-15T14:00:00.000Z t: plain"
+    );
+}
+
+#[tokio::test]
+async fn test_tenant_create_and_list() {
+    let db = setup_db().await;
+
+    tenant_create(&db, "acme", true, false).await.unwrap();
+    tenant_create(&db, "beta-corp", false, true).await.unwrap();
+    assert!(matches!(
+        tenant_create(&db, "bad/tenant", false, true).await,
+        Err(CliError::InvalidArgument(_))
+    ));
+
+    let tenants = db.list_tenants().await.unwrap();
+    assert_eq!(tenants.len(), 2);
+    assert_eq!(tenants[0].tenant_id, TenantId::from_str("acme"));
+    assert!(tenants[0].auth_config.allow_passwords);
+    assert!(!tenants[0].auth_config.allow_certificates);
+    assert!(!tenants[1].auth_config.allow_passwords);
+
+    let table = format_tenant_table(&tenants);
+    assert!(table.contains("| tenant_id | allow_passwords | allow_certificates |"));
+    assert!(table.contains("| acme      | true            | false              |"));
+    assert!(table.contains("| beta-corp | false           | true               |"));
+}
+
+async fn populate_export_db(db: &DB) {
+    let acme = TenantId::from_str("acme");
+    tenant_create(db, "acme", true, true).await.unwrap();
+    for tenant_id in [TenantId::Default, acme.clone()] {
+        db.put_device_metadata(&DeviceMetadata {
+            device_id: "dev1".to_string(),
+            tenant_id: tenant_id.clone(),
+            certificate: Some("cert".to_string()),
+            key: Some("key".to_string()),
+            created_at: 1710511200,
+            tags: HashMap::new(),
+            labels: HashMap::new(),
+            status: DeviceStatus::Active,
+            firmware_version: None,
+            target_firmware_version: None,
+            ota_status: None,
+        })
+        .await
+        .unwrap();
+    }
+    let update = StateUpdateDocument::from_nested_state(
+        NestedStateDocument::from_json(r#"{"state": {"reported": {"led": "on"}}}"#).unwrap(),
+        "dev1",
+        &ShadowName::Default,
+        &acme,
+    );
+    db._upsert_shadow(&update).await.unwrap();
+    db._upsert_shadow(&update).await.unwrap();
+    db.add_device_password(&crate::models::DeviceCredential {
+        tenant_id: acme.clone(),
+        device_id: "dev1".to_string(),
+        username: "user".to_string(),
+        password_hash: "$2b$04$hash".to_string(),
+        created_at: 1710511200,
+    })
+    .await
+    .unwrap();
+    let config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&acme, &config).await.unwrap();
+    db.store_device_data_config(&TenantId::Default, "dev", &config)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_db_export_import_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("export.jsonl");
+    let source = setup_db().await;
+    populate_export_db(&source).await;
+
+    let exported = db_export(&source, &path).await.unwrap();
+    // 1 tenant, 2 devices, 1 shadow, 1 credential, 2 data configs
+    assert_eq!(exported, 7);
+
+    let target = setup_db().await;
+    let summary = db_import(&target, &path).await.unwrap();
+    assert_eq!(
+        summary,
+        ImportSummary {
+            imported: 7,
+            failed: 0
+        }
+    );
+
+    let as_json = |records: Vec<ExportRecord>| -> Vec<serde_json::Value> {
+        records
+            .iter()
+            .map(|r| serde_json::to_value(r).unwrap())
+            .collect()
+    };
+    assert_eq!(
+        as_json(target.export_records().await.unwrap()),
+        as_json(source.export_records().await.unwrap())
+    );
+    let shadow = target
+        ._get_shadow("dev1", &ShadowName::Default, &TenantId::from_str("acme"))
+        .await
+        .unwrap();
+    assert_eq!(shadow.get_version(), 2);
+}
+
+#[tokio::test]
+async fn test_db_import_skips_broken_records() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("broken.jsonl");
+    std::fs::write(
+        &path,
+        "{\"type\": \"tenant\", \"data\": {\"tenant_id\": \"ok\", \"auth_config\": {\"allow_passwords\": false, \"allow_certificates\": true}, \"created_at\": 0}}\n\
+         not json\n\
+         \n\
+         {\"type\": \"unknown\", \"data\": {}}\n",
+    )
+    .unwrap();
+
+    let db = setup_db().await;
+    let summary = db_import(&db, &path).await.unwrap();
+    assert_eq!(
+        summary,
+        ImportSummary {
+            imported: 1,
+            failed: 2
+        }
+    );
+    assert_eq!(db.list_tenants().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_device_password_commands() {
+    let db = setup_db().await;
+
+    let credential = device_add_password(&db, "pw-dev", "user-b", "secret", Some("acme"), Some(4))
+        .await
+        .unwrap();
+    assert_eq!(credential.tenant_id, TenantId::from_str("acme"));
+    assert_ne!(credential.password_hash, "secret");
+    device_add_password(&db, "pw-dev", "user-a", "other", Some("acme"), Some(4))
+        .await
+        .unwrap();
+    assert!(db
+        .verify_device_password(&TenantId::from_str("acme"), "pw-dev", "user-b", "secret")
+        .await
+        .unwrap());
+    assert!(matches!(
+        device_add_password(&db, "pw-dev", "", "secret", Some("acme"), None).await,
+        Err(CliError::InvalidArgument(_))
+    ));
+
+    assert_eq!(
+        device_list_passwords(&db, "pw-dev", Some("acme"))
+            .await
+            .unwrap(),
+        vec!["user-a".to_string(), "user-b".to_string()]
+    );
+    // Credentials are scoped to the tenant
+    assert!(device_list_passwords(&db, "pw-dev", None)
+        .await
+        .unwrap()
+        .is_empty());
+
+    assert!(
+        device_delete_password(&db, "pw-dev", "user-b", Some("acme"))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !device_delete_password(&db, "pw-dev", "user-b", Some("acme"))
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        device_list_passwords(&db, "pw-dev", Some("acme"))
+            .await
+            .unwrap(),
+        vec!["user-a".to_string()]
+    );
+    assert!(!db
+        .verify_device_password(&TenantId::from_str("acme"), "pw-dev", "user-b", "secret")
+        .await
+        .unwrap());
+}
+
+#[test]
+fn test_parse_issue_client_cert() {
+    let cli =
+        Cli::try_parse_from(["forest", "issue-client-cert", "--device-id", "sensor-1"]).unwrap();
+    match cli.command {
+        Commands::IssueClientCert { device_id } => assert_eq!(device_id, "sensor-1"),
+        _ => panic!("Expected issue-client-cert"),
+    }
+
+    // The device ID is required
+    assert!(Cli::try_parse_from(["forest", "issue-client-cert"]).is_err());
+}
@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Source of the current time, replaced by a `MockClock` in tests
+pub trait Clock: Send + Sync {
+    /// Unix time in milliseconds
+    fn now_millis(&self) -> u64;
+
+    /// Unix time in seconds
+    fn now_secs(&self) -> u64 {
+        self.now_millis() / 1000
+    }
+}
+
+/// The system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        chrono::Utc::now().timestamp_millis() as u64
+    }
+}
+
+/// A clock that only moves when it is set or advanced
+#[derive(Debug, Default)]
+pub struct MockClock {
+    millis: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(millis: u64) -> Self {
+        MockClock {
+            millis: AtomicU64::new(millis),
+        }
+    }
+
+    pub fn set_millis(&self, millis: u64) {
+        self.millis.store(millis, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.millis
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.millis.load(Ordering::Relaxed)
+    }
+}
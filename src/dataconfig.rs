@@ -3,31 +3,379 @@ use crate::timeseries::{LatLong, MetricValue};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Array elements a wildcard pointer is expanded to at most, further ones are ignored
+pub const MAX_WILDCARD_ELEMENTS: usize = 256;
+
+/// Characters of a `String` metric that are stored, longer values are truncated
+pub const MAX_TEXT_CHARS: usize = 255;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub enum DataType {
+    #[default]
     Float,
     Int,
     LocationObject,
     LocationTuple,
+    /// `true`/`false` stored as `Int` 1/0
+    Boolean,
+    /// Stored as `Text`, truncated to `MAX_TEXT_CHARS` characters
+    String,
+    /// Latitude at `json_pointer`, longitude at `long_json_pointer`
+    LocationSplit,
+    /// GeoJSON `{"type": "Point", "coordinates": [long, lat]}` or a bare `[long, lat]`
+    GeoJsonPoint,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct MetricConfig {
     pub json_pointer: String,
     pub name: String,
     pub data_type: DataType,
+    /// Pointer to an object in the payload whose entries are stored as tags
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags_pointer: Option<String>,
+    /// Static tags stored with every value, e.g. `{"sensor": "north"}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Value>,
+    /// Pointer to the measurement time in the payload, unix seconds or an RFC 3339 string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_json_pointer: Option<String>,
+    /// Name of the values found by a `*` segment in `json_pointer`, e.g. `channel_{index}`.
+    /// Defaults to `<name>_{index}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_template: Option<String>,
+    /// Numbers are stored as `value * scale + offset`, e.g. `0.001` for millivolts in volts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<f64>,
+    /// Decimals `Float` values are rounded to after scaling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub round_decimals: Option<u32>,
+    /// Pointer to the longitude of a `LocationSplit` metric
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_json_pointer: Option<String>,
+    /// Unit of the stored values, e.g. `°C`, for dashboards
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// Human readable name of the metric, e.g. `Outside temperature`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A location, `None` if the coordinates are out of range
+fn location(lat: f64, long: f64) -> Option<MetricValue> {
+    if lat.abs() > 90.0 || long.abs() > 180.0 {
+        return None;
+    }
+    Some(MetricValue::Location(LatLong::new(lat, long)))
+}
+
+impl MetricConfig {
+    /// `other` replacing this metric in a merged config. Its `unit` and `display_name` are
+    /// kept if `other` doesn't set them.
+    pub fn overridden_by(&self, other: &MetricConfig) -> MetricConfig {
+        MetricConfig {
+            unit: other.unit.clone().or_else(|| self.unit.clone()),
+            display_name: other
+                .display_name
+                .clone()
+                .or_else(|| self.display_name.clone()),
+            ..other.clone()
+        }
+    }
+
+    /// True if values stored as `metric_name` come from this metric, directly or as one
+    /// element of a `*` segment
+    pub fn produces(&self, metric_name: &str) -> bool {
+        if self.split_wildcard().is_none() {
+            return self.name == metric_name;
+        }
+        let template = self
+            .name_template
+            .clone()
+            .unwrap_or_else(|| format!("{}_{{index}}", self.name));
+        let Some((prefix, suffix)) = template.split_once("{index}") else {
+            return false;
+        };
+        metric_name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+    }
+
+    /// Splits a pointer with a `*` segment into the pointer to the array and the pointer
+    /// within each element, e.g. `/channels/*/v` into `/channels` and `/v`
+    fn split_wildcard(&self) -> Option<(&str, &str)> {
+        let mut offset = 0;
+        for segment in self.json_pointer.split('/').skip(1) {
+            if segment == "*" {
+                return Some((
+                    &self.json_pointer[..offset],
+                    &self.json_pointer[offset + 2..],
+                ));
+            }
+            offset += segment.len() + 1;
+        }
+        None
+    }
+
+    /// Values at `json_pointer` with their metric names, one per array element for wildcards
+    fn find_values<'a>(&self, json_value: &'a Value) -> Vec<(String, &'a Value)> {
+        let Some((array_pointer, element_pointer)) = self.split_wildcard() else {
+            return json_value
+                .pointer(&self.json_pointer)
+                .map(|value| vec![(self.name.clone(), value)])
+                .unwrap_or_default();
+        };
+        // Nested wildcards are rejected by validate
+        if element_pointer.split('/').any(|segment| segment == "*") {
+            return Vec::new();
+        }
+        let Some(elements) = json_value.pointer(array_pointer).and_then(Value::as_array) else {
+            return Vec::new();
+        };
+        let template = self
+            .name_template
+            .clone()
+            .unwrap_or_else(|| format!("{}_{{index}}", self.name));
+        elements
+            .iter()
+            .take(MAX_WILDCARD_ELEMENTS)
+            .enumerate()
+            .filter_map(|(index, element)| {
+                let value = element.pointer(element_pointer)?;
+                Some((template.replace("{index}", &index.to_string()), value))
+            })
+            .collect()
+    }
+
+    /// The number in the payload with `scale` and `offset` applied
+    fn scaled_number(&self, value: &Value) -> Option<f64> {
+        Some(value.as_f64()? * self.scale.unwrap_or(1.0) + self.offset.unwrap_or(0.0))
+    }
+
+    /// Converts the value found in the payload to the configured data type
+    fn parse_value(&self, value: &Value, json_value: &Value) -> Option<MetricValue> {
+        match self.data_type {
+            DataType::Float => {
+                let float = self.scaled_number(value)?;
+                let float = match self.round_decimals {
+                    Some(decimals) => {
+                        let factor = 10f64.powi(decimals as i32);
+                        (float * factor).round() / factor
+                    }
+                    None => float,
+                };
+                Some(MetricValue::Float(float))
+            }
+            DataType::Int if self.scale.is_some() || self.offset.is_some() => self
+                .scaled_number(value)
+                .map(|f| MetricValue::Int(f.round() as i64)),
+            DataType::Int => {
+                // handle both i64 and f64 as int
+                let int = value.as_i64().or(value.as_f64().map(|f| f as i64));
+                int.map(MetricValue::Int)
+            }
+            DataType::LocationObject => location(value["lat"].as_f64()?, value["long"].as_f64()?),
+            DataType::LocationTuple => location(value[0].as_f64()?, value[1].as_f64()?),
+            DataType::LocationSplit => {
+                let long = json_value.pointer(self.long_json_pointer.as_ref()?)?;
+                location(value.as_f64()?, long.as_f64()?)
+            }
+            DataType::GeoJsonPoint => {
+                let coordinates = match value {
+                    Value::Object(point) if point.get("type")? == "Point" => {
+                        point.get("coordinates")?
+                    }
+                    _ => value,
+                };
+                location(coordinates[1].as_f64()?, coordinates[0].as_f64()?)
+            }
+            DataType::Boolean => value.as_bool().map(|b| MetricValue::Int(b as i64)),
+            DataType::String => value
+                .as_str()
+                .map(|text| MetricValue::Text(text.chars().take(MAX_TEXT_CHARS).collect())),
+        }
+    }
+
+    /// Static tags merged with the tags found at `tags_pointer`, the payload wins on conflicts
+    fn extract_tags(&self, json_value: &Value) -> Option<Value> {
+        let mut tags = match &self.tags {
+            Some(Value::Object(tags)) => tags.clone(),
+            _ => serde_json::Map::new(),
+        };
+        if let Some(Value::Object(dynamic)) = self
+            .tags_pointer
+            .as_ref()
+            .and_then(|pointer| json_value.pointer(pointer))
+        {
+            tags.extend(dynamic.clone());
+        }
+        if tags.is_empty() {
+            None
+        } else {
+            Some(Value::Object(tags))
+        }
+    }
+
+    /// Measurement time found at `timestamp_json_pointer`, `None` if missing or not a time
+    fn extract_timestamp(&self, json_value: &Value) -> Option<u64> {
+        let value = json_value.pointer(self.timestamp_json_pointer.as_ref()?)?;
+        match value {
+            Value::Number(number) => number
+                .as_u64()
+                .or_else(|| number.as_f64().filter(|f| *f >= 0.0).map(|f| f as u64)),
+            Value::String(text) => chrono::DateTime::parse_from_rfc3339(text)
+                .ok()
+                .and_then(|time| u64::try_from(time.timestamp()).ok()),
+            _ => None,
+        }
+    }
+}
+
+/// Type the JSON schema declares for the value at a JSON pointer, following `properties`
+/// for object keys and `items` for array indices and wildcards. `None` if the schema doesn't say.
+fn schema_type_at(schema: &Value, pointer: &str) -> Option<String> {
+    let mut current = schema;
+    for token in pointer.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        current = match current.get("properties").and_then(|p| p.get(&token)) {
+            Some(property) => property,
+            None if token == "*" || token.parse::<usize>().is_ok() => current.get("items")?,
+            None => return None,
+        };
+    }
+    current.get("type")?.as_str().map(str::to_string)
+}
+
+/// Normalizes a payload before the metrics are extracted, all pointers are JSON pointers.
+/// Steps that don't match the payload leave it unchanged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum TransformStep {
+    /// Moves the value at `from` to `to`
+    RenameField { from: String, to: String },
+    /// Multiplies the number at `pointer`, e.g. `0.1` for values sent in tenths
+    ScaleValue { pointer: String, factor: f64 },
+    /// Sets `pointer` to a fixed value
+    SetField { pointer: String, value: Value },
+}
+
+impl TransformStep {
+    pub fn apply(&self, json_value: &mut Value) {
+        match self {
+            TransformStep::RenameField { from, to } => {
+                if let Some(value) = take_pointer(json_value, from) {
+                    // Put it back if the target can't be created
+                    if let Err(value) = set_pointer(json_value, to, value) {
+                        let _ = set_pointer(json_value, from, value);
+                    }
+                }
+            }
+            TransformStep::ScaleValue { pointer, factor } => {
+                if let Some(target) = json_value.pointer_mut(pointer) {
+                    let scaled = target
+                        .as_f64()
+                        .and_then(|number| serde_json::Number::from_f64(number * factor));
+                    if let Some(scaled) = scaled {
+                        *target = Value::Number(scaled);
+                    }
+                }
+            }
+            TransformStep::SetField { pointer, value } => {
+                let _ = set_pointer(json_value, pointer, value.clone());
+            }
+        }
+    }
+}
+
+fn unescape_pointer_key(key: &str) -> String {
+    key.replace("~1", "/").replace("~0", "~")
+}
+
+/// Removes and returns the value at `pointer` from its parent object
+fn take_pointer(json_value: &mut Value, pointer: &str) -> Option<Value> {
+    let (parent, key) = pointer.rsplit_once('/')?;
+    json_value
+        .pointer_mut(parent)?
+        .as_object_mut()?
+        .remove(&unescape_pointer_key(key))
+}
+
+/// Sets the value at `pointer`, creating missing objects on the way.
+/// The value is handed back if something other than an object is in the way.
+fn set_pointer(json_value: &mut Value, pointer: &str, value: Value) -> Result<(), Value> {
+    let Some(path) = pointer.strip_prefix('/') else {
+        return Err(value);
+    };
+    let keys: Vec<String> = path.split('/').map(unescape_pointer_key).collect();
+    let (last, parents) = keys.split_last().unwrap();
+    let mut current = json_value;
+    for key in parents {
+        let Value::Object(map) = current else {
+            return Err(value);
+        };
+        current = map
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(serde_json::Map::new()));
+    }
+    match current {
+        Value::Object(map) => {
+            map.insert(last.clone(), value);
+            Ok(())
+        }
+        _ => Err(value),
+    }
+}
+
+/// A metric value found in a payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtractedMetric {
+    pub name: String,
+    pub value: MetricValue,
+    pub tags: Option<Value>,
+    /// Measurement time from the payload, see `MetricConfig::timestamp_json_pointer`
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct DataConfig {
     pub metrics: Vec<MetricConfig>,
+    /// Applied to the payload in order before the metrics are extracted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transformations: Vec<TransformStep>,
+    /// JSON schema the payload has to match, nothing is extracted from other payloads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_schema: Option<Value>,
+    /// Skips storing a value equal to the last stored value of the metric
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub enable_deduplication: bool,
+}
+
+/// The config a metric of the effective device config comes from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ConfigSource {
+    Tenant,
+    /// The config of a label selector, `key=value`
+    Label(String),
+    DevicePrefix(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DataConfigEntry {
     pub tenant_id: TenantId,
     pub device_prefix: Option<String>,
+    /// Set for configs targeting a label instead of a device prefix, `key=value`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_selector: Option<String>,
     pub metrics: Vec<MetricConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transformations: Vec<TransformStep>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_schema: Option<Value>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub enable_deduplication: bool,
 }
 
 impl DataConfig {
@@ -36,58 +384,179 @@ impl DataConfig {
         let mut merged = self.metrics.clone();
         for om in &other.metrics {
             if let Some(existing) = merged.iter_mut().find(|m| m.name == om.name) {
-                *existing = om.clone();
+                *existing = existing.overridden_by(om);
             } else {
                 merged.push(om.clone());
             }
         }
-        DataConfig { metrics: merged }
+        // Transformations are not merged, the device ones replace the tenant ones
+        let transformations = if other.transformations.is_empty() {
+            self.transformations.clone()
+        } else {
+            other.transformations.clone()
+        };
+        DataConfig {
+            metrics: merged,
+            transformations,
+            payload_schema: other
+                .payload_schema
+                .clone()
+                .or_else(|| self.payload_schema.clone()),
+            // Enabled by either config, a device config can't turn it off again
+            enable_deduplication: self.enable_deduplication || other.enable_deduplication,
+        }
     }
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    /// The metric whose values are stored as `metric_name`
+    pub fn metric_config(&self, metric_name: &str) -> Option<&MetricConfig> {
+        self.metrics.iter().find(|m| m.produces(metric_name))
     }
 
-    pub fn from_json(json: &str) -> DataConfig {
-        serde_json::from_str(json).unwrap()
+    pub fn to_json_result(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
     }
 
-    pub fn extract_metrics_from_json(&self, json_value: Value) -> Vec<(String, MetricValue)> {
-        let mut metrics = Vec::new();
+    pub fn try_from_json(json: &str) -> Result<DataConfig, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// Checks the config before it is stored, returns every problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut names = std::collections::HashSet::new();
         for metric in &self.metrics {
-            if let Some(value) = json_value.pointer(&metric.json_pointer) {
-                // handle data types
-                let value: Option<MetricValue> = match metric.data_type {
-                    DataType::Float => value.as_f64().map(MetricValue::Float),
-                    DataType::Int => {
-                        // handle both i64 and f64 as int
-                        let int = value.as_i64().or(value.as_f64().map(|f| f as i64));
-                        int.map(MetricValue::Int)
-                    }
-                    DataType::LocationObject => {
-                        let lat = value["lat"].as_f64();
-                        let long = value["long"].as_f64();
-                        if let (Some(lat), Some(long)) = (lat, long) {
-                            Some(MetricValue::Location(LatLong::new(lat, long)))
-                        } else {
-                            None
-                        }
+            if !metric.json_pointer.starts_with('/') {
+                errors.push(format!(
+                    "Metric '{}': json_pointer '{}' must start with '/'",
+                    metric.name, metric.json_pointer
+                ));
+            }
+            let wildcards = metric
+                .json_pointer
+                .split('/')
+                .filter(|segment| *segment == "*")
+                .count();
+            if wildcards > 1 {
+                errors.push(format!(
+                    "Metric '{}': json_pointer '{}' has nested wildcards, only one '*' is supported",
+                    metric.name, metric.json_pointer
+                ));
+            }
+            if let Some(template) = &metric.name_template {
+                if wildcards == 0 {
+                    errors.push(format!(
+                        "Metric '{}': name_template needs a '*' segment in json_pointer",
+                        metric.name
+                    ));
+                } else if !template.contains("{index}") {
+                    errors.push(format!(
+                        "Metric '{}': name_template '{}' must contain '{{index}}'",
+                        metric.name, template
+                    ));
+                }
+            }
+            match (&metric.data_type, &metric.long_json_pointer) {
+                (DataType::LocationSplit, None) => errors.push(format!(
+                    "Metric '{}': LocationSplit needs a long_json_pointer",
+                    metric.name
+                )),
+                (DataType::LocationSplit, Some(pointer)) => {
+                    if !pointer.starts_with('/') {
+                        errors.push(format!(
+                            "Metric '{}': long_json_pointer '{}' must start with '/'",
+                            metric.name, pointer
+                        ));
                     }
-                    DataType::LocationTuple => {
-                        let lat = value[0].as_f64();
-                        let long = value[1].as_f64();
-                        if let (Some(lat), Some(long)) = (lat, long) {
-                            Some(MetricValue::Location(LatLong::new(lat, long)))
-                        } else {
-                            None
-                        }
+                    if wildcards > 0 || pointer.split('/').any(|segment| segment == "*") {
+                        errors.push(format!(
+                            "Metric '{}': LocationSplit pointers can't contain wildcards",
+                            metric.name
+                        ));
                     }
-                };
+                }
+                (_, Some(_)) => errors.push(format!(
+                    "Metric '{}': long_json_pointer is only used by LocationSplit",
+                    metric.name
+                )),
+                (_, None) => {}
+            }
+            if !names.insert(metric.name.as_str()) {
+                errors.push(format!("Metric '{}' is configured twice", metric.name));
+            }
+            if metric.data_type == DataType::LocationTuple {
+                let declared = self
+                    .payload_schema
+                    .as_ref()
+                    .and_then(|schema| schema_type_at(schema, &metric.json_pointer));
+                if let Some(declared) = declared.filter(|t| t != "array") {
+                    errors.push(format!(
+                        "Metric '{}': LocationTuple needs an array at '{}', schema declares '{}'",
+                        metric.name, metric.json_pointer, declared
+                    ));
+                }
+            }
+        }
+        if let Err(e) = self.validate_schema() {
+            errors.push(e);
+        }
+        errors
+    }
+
+    /// Fails if `payload_schema` is not a valid JSON schema
+    pub fn validate_schema(&self) -> Result<(), String> {
+        match &self.payload_schema {
+            Some(schema) => jsonschema::validator_for(schema)
+                .map(|_| ())
+                .map_err(|e| format!("Invalid payload schema: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the payload against `payload_schema`, returns the first violation
+    fn validate_payload(&self, json_value: &Value) -> Result<(), String> {
+        let Some(schema) = &self.payload_schema else {
+            return Ok(());
+        };
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| format!("Invalid payload schema: {}", e))?;
+        match validator.iter_errors(json_value).next() {
+            Some(error) => Err(error.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Extracts every configured metric found in the payload.
+    /// Payloads not matching `payload_schema` yield no metrics.
+    pub fn extract_metrics_from_json(&self, mut json_value: Value) -> Vec<ExtractedMetric> {
+        if let Err(error) = self.validate_payload(&json_value) {
+            tracing::warn!(%error, "Payload rejected by schema");
+            return Vec::new();
+        }
+        for step in &self.transformations {
+            step.apply(&mut json_value);
+        }
+        let mut metrics = Vec::new();
+        for metric in &self.metrics {
+            for (name, value) in metric.find_values(&json_value) {
+                let value = metric.parse_value(value, &json_value);
+                // Can't be stored or serialized sensibly, see NonFinitePolicy
+                if let Some(value) = value.as_ref().filter(|v| !v.is_finite()) {
+                    tracing::warn!(metric = %name, ?value, "Skipped non-finite metric");
+                    continue;
+                }
                 if let Some(value) = value {
-                    metrics.push((metric.name.clone(), value));
+                    metrics.push(ExtractedMetric {
+                        name,
+                        value,
+                        tags: metric.extract_tags(&json_value),
+                        timestamp: metric.extract_timestamp(&json_value),
+                    });
                 }
             }
         }
         metrics
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,549 @@
+use super::*;
+use serde_json::json;
+
+fn metric(name: &str, json_pointer: &str) -> MetricConfig {
+    MetricConfig {
+        json_pointer: json_pointer.to_string(),
+        name: name.to_string(),
+        data_type: DataType::Float,
+        ..Default::default()
+    }
+}
+
+fn transformed(step: TransformStep, mut payload: Value) -> Value {
+    step.apply(&mut payload);
+    payload
+}
+
+#[test]
+fn test_transform_step_serialization() {
+    let step: TransformStep =
+        serde_json::from_value(json!({"type": "ScaleValue", "pointer": "/t", "factor": 0.1}))
+            .unwrap();
+    assert_eq!(
+        step,
+        TransformStep::ScaleValue {
+            pointer: "/t".to_string(),
+            factor: 0.1
+        }
+    );
+    let json = serde_json::to_value(TransformStep::RenameField {
+        from: "/t".to_string(),
+        to: "/temp".to_string(),
+    })
+    .unwrap();
+    assert_eq!(
+        json,
+        json!({"type": "RenameField", "from": "/t", "to": "/temp"})
+    );
+
+    // Configs stored before transformations existed still load
+    let config = DataConfig::try_from_json(r#"{"metrics": []}"#).unwrap();
+    assert!(config.transformations.is_empty());
+    assert!(!config.to_json_result().unwrap().contains("transformations"));
+}
+
+#[test]
+fn test_rename_field() {
+    let rename = |from: &str, to: &str| TransformStep::RenameField {
+        from: from.to_string(),
+        to: to.to_string(),
+    };
+    assert_eq!(
+        transformed(rename("/t", "/env/temp"), json!({"t": 21, "h": 40})),
+        json!({"env": {"temp": 21}, "h": 40})
+    );
+    // Missing source, nothing to do
+    assert_eq!(
+        transformed(rename("/x", "/temp"), json!({"t": 21})),
+        json!({"t": 21})
+    );
+    // The target runs through a number, the field stays where it was
+    assert_eq!(
+        transformed(rename("/t", "/h/temp"), json!({"t": 21, "h": 40})),
+        json!({"t": 21, "h": 40})
+    );
+}
+
+#[test]
+fn test_scale_value() {
+    let scale = |pointer: &str| TransformStep::ScaleValue {
+        pointer: pointer.to_string(),
+        factor: 0.1,
+    };
+    let payload = transformed(scale("/values/0"), json!({"values": [215, 400]}));
+    assert!((payload["values"][0].as_f64().unwrap() - 21.5).abs() < 1e-9);
+    assert_eq!(payload["values"][1], 400);
+    // Not a number, left as is
+    assert_eq!(
+        transformed(scale("/t"), json!({"t": "215"})),
+        json!({"t": "215"})
+    );
+}
+
+#[test]
+fn test_set_field() {
+    let set = TransformStep::SetField {
+        pointer: "/meta/unit".to_string(),
+        value: json!("C"),
+    };
+    assert_eq!(
+        transformed(set.clone(), json!({"t": 21})),
+        json!({"t": 21, "meta": {"unit": "C"}})
+    );
+    assert_eq!(
+        transformed(set, json!({"meta": {"unit": "F"}})),
+        json!({"meta": {"unit": "C"}})
+    );
+}
+
+#[test]
+fn test_chained_transformations() {
+    let config = DataConfig {
+        metrics: vec![metric("temperature", "/temp"), metric("offset", "/offset")],
+        transformations: vec![
+            TransformStep::RenameField {
+                from: "/T".to_string(),
+                to: "/temp".to_string(),
+            },
+            // Runs on the renamed field
+            TransformStep::ScaleValue {
+                pointer: "/temp".to_string(),
+                factor: 0.5,
+            },
+            TransformStep::SetField {
+                pointer: "/offset".to_string(),
+                value: json!(1.5),
+            },
+        ],
+        payload_schema: None,
+        enable_deduplication: false,
+    };
+    let metrics = config.extract_metrics_from_json(json!({"T": 43}));
+    let values: Vec<(&str, &MetricValue)> = metrics
+        .iter()
+        .map(|m| (m.name.as_str(), &m.value))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            ("temperature", &MetricValue::Float(21.5)),
+            ("offset", &MetricValue::Float(1.5)),
+        ]
+    );
+}
+
+#[test]
+fn test_payload_schema() {
+    let config = DataConfig {
+        metrics: vec![metric("temperature", "/temperature")],
+        transformations: Vec::new(),
+        payload_schema: Some(json!({
+            "type": "object",
+            "properties": {"temperature": {"type": "number"}},
+            "required": ["temperature"]
+        })),
+        enable_deduplication: false,
+    };
+    assert!(config.validate_schema().is_ok());
+
+    let metrics = config.extract_metrics_from_json(json!({"temperature": 21.5}));
+    assert_eq!(metrics.len(), 1);
+    assert!(config
+        .extract_metrics_from_json(json!({"temperature": "21.5"}))
+        .is_empty());
+    assert!(config
+        .extract_metrics_from_json(json!({"humidity": 40}))
+        .is_empty());
+
+    let invalid = DataConfig {
+        payload_schema: Some(json!({"type": "no-such-type"})),
+        ..config
+    };
+    assert!(invalid.validate_schema().is_err());
+}
+
+#[test]
+fn test_validate() {
+    let valid = DataConfig {
+        metrics: vec![metric("temperature", "/temperature")],
+        ..Default::default()
+    };
+    assert!(valid.validate().is_empty());
+
+    let location = MetricConfig {
+        data_type: DataType::LocationTuple,
+        ..metric("position", "/gps/pos")
+    };
+    let config = DataConfig {
+        metrics: vec![
+            metric("temperature", "temperature"),
+            metric("temperature", "/temp"),
+            location,
+        ],
+        transformations: Vec::new(),
+        payload_schema: Some(json!({
+            "type": "object",
+            "properties": {"gps": {"type": "object", "properties": {"pos": {"type": "string"}}}}
+        })),
+        enable_deduplication: false,
+    };
+    let errors = config.validate();
+    assert_eq!(errors.len(), 3, "{:?}", errors);
+    assert!(errors[0].contains("must start with '/'"));
+    assert!(errors[1].contains("configured twice"));
+    assert!(errors[2].contains("LocationTuple"));
+
+    // Without a declared type the pointer can't be checked
+    let config = DataConfig {
+        payload_schema: Some(json!({"type": "object"})),
+        ..config
+    };
+    assert_eq!(config.validate().len(), 2);
+}
+
+#[test]
+fn test_wildcard_expansion() {
+    let channels = MetricConfig {
+        name_template: Some("channel_{index}".to_string()),
+        ..metric("channel", "/channels/*/v")
+    };
+    let config = DataConfig {
+        metrics: vec![channels, metric("load", "/loads/*")],
+        ..Default::default()
+    };
+    assert!(config.validate().is_empty());
+
+    let metrics = config.extract_metrics_from_json(json!({
+        "channels": [{"v": 1.2}, {"v": "off"}, {"x": 2}, {"v": 3.4}],
+        "loads": [0.5, 0.7]
+    }));
+    let values: Vec<(&str, &MetricValue)> = metrics
+        .iter()
+        .map(|m| (m.name.as_str(), &m.value))
+        .collect();
+    // Non-numeric and missing elements are skipped, indices stay those of the array
+    assert_eq!(
+        values,
+        vec![
+            ("channel_0", &MetricValue::Float(1.2)),
+            ("channel_3", &MetricValue::Float(3.4)),
+            ("load_0", &MetricValue::Float(0.5)),
+            ("load_1", &MetricValue::Float(0.7)),
+        ]
+    );
+
+    // Not an array, nothing to expand
+    assert!(config
+        .extract_metrics_from_json(json!({"channels": {"v": 1.2}}))
+        .is_empty());
+
+    let long: Vec<f64> = (0..MAX_WILDCARD_ELEMENTS + 10).map(|i| i as f64).collect();
+    let metrics = config.extract_metrics_from_json(json!({ "loads": long }));
+    assert_eq!(metrics.len(), MAX_WILDCARD_ELEMENTS);
+}
+
+#[test]
+fn test_validate_wildcards() {
+    let nested = MetricConfig {
+        name_template: Some("cell_{index}".to_string()),
+        ..metric("cell", "/rows/*/cells/*/v")
+    };
+    let no_index = MetricConfig {
+        name_template: Some("channel".to_string()),
+        ..metric("channel", "/channels/*/v")
+    };
+    let no_wildcard = MetricConfig {
+        name_template: Some("temp_{index}".to_string()),
+        ..metric("temp", "/temp")
+    };
+    let config = DataConfig {
+        metrics: vec![nested, no_index, no_wildcard],
+        ..Default::default()
+    };
+    let errors = config.validate();
+    assert_eq!(errors.len(), 3, "{:?}", errors);
+    assert!(errors[0].contains("nested wildcards"));
+    assert!(errors[1].contains("must contain '{index}'"));
+    assert!(errors[2].contains("needs a '*' segment"));
+
+    // Nested wildcards yield nothing if such a config was stored anyway
+    assert!(config
+        .extract_metrics_from_json(json!({"rows": [{"cells": [{"v": 1.0}]}]}))
+        .is_empty());
+}
+
+#[test]
+fn test_metrics_sharing_a_pointer() {
+    let raw = MetricConfig {
+        data_type: DataType::Int,
+        ..metric("adc_raw", "/temperature")
+    };
+    let tenant = DataConfig {
+        metrics: vec![metric("temperature", "/temperature")],
+        ..Default::default()
+    };
+    let device = DataConfig {
+        metrics: vec![raw],
+        ..Default::default()
+    };
+    let config = tenant.merge_with(&device);
+    assert_eq!(config.metrics.len(), 2);
+    assert!(config.validate().is_empty());
+
+    let metrics = config.extract_metrics_from_json(json!({"temperature": 21.7}));
+    let values: Vec<(&str, &MetricValue)> = metrics
+        .iter()
+        .map(|m| (m.name.as_str(), &m.value))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            ("temperature", &MetricValue::Float(21.7)),
+            ("adc_raw", &MetricValue::Int(21)),
+        ]
+    );
+}
+
+#[test]
+fn test_merge_units() {
+    let tenant = DataConfig {
+        metrics: vec![
+            MetricConfig {
+                unit: Some("°C".to_string()),
+                display_name: Some("Temperature".to_string()),
+                ..metric("temp", "/temp")
+            },
+            MetricConfig {
+                unit: Some("%".to_string()),
+                ..metric("humidity", "/humidity")
+            },
+        ],
+        ..Default::default()
+    };
+    let device = DataConfig {
+        metrics: vec![
+            MetricConfig {
+                unit: Some("°F".to_string()),
+                ..metric("temp", "/temp_f")
+            },
+            metric("humidity", "/rh"),
+        ],
+        ..Default::default()
+    };
+    let merged = tenant.merge_with(&device);
+
+    // The device unit wins, a display name it doesn't set is kept
+    let temp = merged.metric_config("temp").unwrap();
+    assert_eq!(temp.json_pointer, "/temp_f");
+    assert_eq!(temp.unit.as_deref(), Some("°F"));
+    assert_eq!(temp.display_name.as_deref(), Some("Temperature"));
+    let humidity = merged.metric_config("humidity").unwrap();
+    assert_eq!(humidity.json_pointer, "/rh");
+    assert_eq!(humidity.unit.as_deref(), Some("%"));
+
+    // Configs stored before units existed load without them
+    let old: MetricConfig = serde_json::from_value(
+        json!({"json_pointer": "/temp", "name": "temp", "data_type": "Float"}),
+    )
+    .unwrap();
+    assert_eq!(old.unit, None);
+    assert!(!serde_json::to_string(&old).unwrap().contains("unit"));
+}
+
+#[test]
+fn test_metric_config_by_stored_name() {
+    let config = DataConfig {
+        metrics: vec![
+            metric("temp", "/temp"),
+            metric("ch", "/channels/*"),
+            MetricConfig {
+                name_template: Some("load_{index}_pct".to_string()),
+                ..metric("load", "/cores/*/load")
+            },
+        ],
+        ..Default::default()
+    };
+    let name = |stored: &str| config.metric_config(stored).map(|m| m.name.as_str());
+    assert_eq!(name("temp"), Some("temp"));
+    assert_eq!(name("ch_0"), Some("ch"));
+    assert_eq!(name("ch_12"), Some("ch"));
+    assert_eq!(name("load_3_pct"), Some("load"));
+    assert_eq!(name("ch"), None);
+    assert_eq!(name("ch_x"), None);
+    assert_eq!(name("load_3"), None);
+    assert_eq!(name("humidity"), None);
+}
+
+#[test]
+fn test_scale_and_offset() {
+    let millivolts = MetricConfig {
+        scale: Some(0.001),
+        round_decimals: Some(2),
+        ..metric("voltage", "/mv")
+    };
+    let counts = MetricConfig {
+        data_type: DataType::Int,
+        scale: Some(0.5),
+        offset: Some(-10.0),
+        ..metric("level", "/adc")
+    };
+    let raw = MetricConfig {
+        data_type: DataType::Int,
+        ..metric("raw", "/adc")
+    };
+    let config = DataConfig {
+        metrics: vec![millivolts, counts, raw],
+        ..Default::default()
+    };
+
+    let metrics = config.extract_metrics_from_json(json!({"mv": 3287, "adc": 1023}));
+    let values: Vec<(&str, &MetricValue)> = metrics
+        .iter()
+        .map(|m| (m.name.as_str(), &m.value))
+        .collect();
+    // 1023 * 0.5 - 10 = 501.5 is rounded, unscaled ints are unchanged
+    assert_eq!(
+        values,
+        vec![
+            ("voltage", &MetricValue::Float(3.29)),
+            ("level", &MetricValue::Int(502)),
+            ("raw", &MetricValue::Int(1023)),
+        ]
+    );
+
+    // Configs stored before scaling existed still load and serialize without the fields
+    let config = DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/t", "name": "t", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    assert_eq!(config.metrics[0].scale, None);
+    assert!(!config.to_json_result().unwrap().contains("scale"));
+}
+
+#[test]
+fn test_boolean_and_string_metrics() {
+    let boolean = |name: &str, pointer: &str| MetricConfig {
+        data_type: DataType::Boolean,
+        ..metric(name, pointer)
+    };
+    let state = MetricConfig {
+        data_type: DataType::String,
+        ..metric("state", "/state")
+    };
+    let config = DataConfig {
+        metrics: vec![boolean("door", "/door"), boolean("relay", "/relay"), state],
+        ..Default::default()
+    };
+
+    let long_state = "x".repeat(MAX_TEXT_CHARS + 10);
+    let metrics = config.extract_metrics_from_json(json!({
+        "door": true,
+        "relay": false,
+        "state": long_state
+    }));
+    assert_eq!(metrics.len(), 3);
+    assert_eq!(metrics[0].value, MetricValue::Int(1));
+    assert_eq!(metrics[1].value, MetricValue::Int(0));
+    assert_eq!(
+        metrics[2].value,
+        MetricValue::Text("x".repeat(MAX_TEXT_CHARS))
+    );
+
+    // Values of another JSON type are skipped
+    let metrics = config.extract_metrics_from_json(json!({"door": 1, "state": 2}));
+    assert!(metrics.is_empty());
+}
+
+#[test]
+fn test_location_shapes() {
+    let location = |name: &str, pointer: &str, data_type: DataType| MetricConfig {
+        data_type,
+        ..metric(name, pointer)
+    };
+    let split = MetricConfig {
+        long_json_pointer: Some("/gps/longitude".to_string()),
+        ..location("split", "/gps/latitude", DataType::LocationSplit)
+    };
+    let config = DataConfig {
+        metrics: vec![
+            location("object", "/object", DataType::LocationObject),
+            location("tuple", "/tuple", DataType::LocationTuple),
+            split,
+            location("geojson", "/geojson", DataType::GeoJsonPoint),
+            location("bare", "/bare", DataType::GeoJsonPoint),
+        ],
+        ..Default::default()
+    };
+
+    let metrics = config.extract_metrics_from_json(json!({
+        "object": {"lat": 48.2, "long": 16.4},
+        "tuple": [48.2, 16.4],
+        "gps": {"latitude": 48.2, "longitude": 16.4},
+        "geojson": {"type": "Point", "coordinates": [16.4, 48.2]},
+        "bare": [16.4, 48.2]
+    }));
+    let names: Vec<&str> = metrics.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, vec!["object", "tuple", "split", "geojson", "bare"]);
+    for metric in &metrics {
+        assert_eq!(
+            metric.value,
+            MetricValue::Location(LatLong::new(48.2, 16.4)),
+            "{}",
+            metric.name
+        );
+    }
+
+    // Out of range coordinates are rejected, e.g. GeoJSON read in lat-long order
+    let metrics = config.extract_metrics_from_json(json!({
+        "object": {"lat": 91.0, "long": 16.4},
+        "tuple": [48.2, -180.5],
+        "gps": {"latitude": -90.5, "longitude": 16.4},
+        "geojson": {"type": "Point", "coordinates": [16.4, 148.2]},
+        "bare": [190.0, 48.2]
+    }));
+    assert!(metrics.is_empty());
+
+    // The boundaries are valid, other GeoJSON geometries are not
+    let metrics = config.extract_metrics_from_json(json!({
+        "tuple": [-90.0, 180.0],
+        "geojson": {"type": "LineString", "coordinates": [16.4, 48.2]}
+    }));
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(
+        metrics[0].value,
+        MetricValue::Location(LatLong::new(-90.0, 180.0))
+    );
+}
+
+#[test]
+fn test_validate_location_split() {
+    let split = |name: &str, long_json_pointer: Option<&str>| MetricConfig {
+        data_type: DataType::LocationSplit,
+        long_json_pointer: long_json_pointer.map(str::to_string),
+        ..metric(name, "/lat")
+    };
+    let config = DataConfig {
+        metrics: vec![
+            split("valid", Some("/long")),
+            split("missing", None),
+            split("relative", Some("long")),
+            MetricConfig {
+                json_pointer: "/points/*/lat".to_string(),
+                ..split("wildcard", Some("/points/*/long"))
+            },
+            MetricConfig {
+                long_json_pointer: Some("/long".to_string()),
+                ..metric("float", "/t")
+            },
+        ],
+        ..Default::default()
+    };
+
+    let errors = config.validate();
+    assert_eq!(errors.len(), 4, "{:?}", errors);
+    assert!(errors[0].contains("'missing'"));
+    assert!(errors[1].contains("'long' must start with '/'"));
+    assert!(errors[2].contains("'wildcard'") && errors[2].contains("wildcards"));
+    assert!(errors[3].contains("only used by LocationSplit"));
+}
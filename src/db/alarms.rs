@@ -0,0 +1,143 @@
+use crate::db::{DatabaseError, DB};
+use crate::models::{AlarmCondition, AlarmEvent, AlarmRule, TenantId};
+
+fn condition_to_str(condition: AlarmCondition) -> &'static str {
+    match condition {
+        AlarmCondition::Above => "above",
+        AlarmCondition::Below => "below",
+        AlarmCondition::Equal => "equal",
+    }
+}
+
+fn condition_from_str(condition: &str) -> Result<AlarmCondition, DatabaseError> {
+    match condition {
+        "above" => Ok(AlarmCondition::Above),
+        "below" => Ok(AlarmCondition::Below),
+        "equal" => Ok(AlarmCondition::Equal),
+        _ => Err(DatabaseError::DatabaseValueError(format!(
+            "Unknown alarm condition: {}",
+            condition
+        ))),
+    }
+}
+
+impl DB {
+    /// Stores the rule, replacing a rule of the tenant with the same `alarm_id`
+    pub async fn store_alarm_rule(&self, rule: &AlarmRule) -> Result<(), DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let t_id = rule.tenant_id.to_string();
+            let mut tx = pool.begin().await?;
+
+            sqlx::query("DELETE FROM alarm_rules WHERE tenant_id = $1 AND alarm_id = $2")
+                .bind(&t_id)
+                .bind(&rule.alarm_id)
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query(
+                "INSERT INTO alarm_rules (tenant_id, alarm_id, device_id, metric_name, condition, threshold) VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(&t_id)
+            .bind(&rule.alarm_id)
+            .bind(&rule.device_id)
+            .bind(&rule.metric_name)
+            .bind(condition_to_str(rule.condition))
+            .bind(rule.threshold)
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await?;
+            Ok(())
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Rules of the device, sorted by `alarm_id`
+    pub async fn get_alarm_rules(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+    ) -> Result<Vec<AlarmRule>, DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let rows: Vec<(String, String, String, f64)> = sqlx::query_as(
+                "SELECT alarm_id, metric_name, condition, threshold FROM alarm_rules
+                 WHERE tenant_id = $1 AND device_id = $2 ORDER BY alarm_id",
+            )
+            .bind(tenant_id.to_string())
+            .bind(device_id)
+            .fetch_all(&**pool)
+            .await?;
+
+            rows.into_iter()
+                .map(|(alarm_id, metric_name, condition, threshold)| {
+                    Ok(AlarmRule {
+                        tenant_id: tenant_id.clone(),
+                        device_id: device_id.to_string(),
+                        metric_name,
+                        condition: condition_from_str(&condition)?,
+                        threshold,
+                        alarm_id,
+                    })
+                })
+                .collect()
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    pub async fn insert_alarm_event(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        event: &AlarmEvent,
+    ) -> Result<(), DatabaseError> {
+        if let Some(pool) = &self.pool {
+            sqlx::query(
+                "INSERT INTO alarm_events (tenant_id, device_id, alarm_id, triggered_at, value) VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(tenant_id.to_string())
+            .bind(device_id)
+            .bind(&event.alarm_id)
+            .bind(event.triggered_at as i64)
+            .bind(event.value)
+            .execute(&**pool)
+            .await?;
+            Ok(())
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Newest alarm events of the device first
+    pub async fn list_alarm_events(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        limit: u64,
+    ) -> Result<Vec<AlarmEvent>, DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let rows: Vec<(String, i64, f64)> = sqlx::query_as(
+                "SELECT alarm_id, triggered_at, value FROM alarm_events
+                 WHERE tenant_id = $1 AND device_id = $2
+                 ORDER BY triggered_at DESC LIMIT $3",
+            )
+            .bind(tenant_id.to_string())
+            .bind(device_id)
+            .bind(limit as i64)
+            .fetch_all(&**pool)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|(alarm_id, triggered_at, value)| AlarmEvent {
+                    alarm_id,
+                    triggered_at: triggered_at as u64,
+                    value,
+                })
+                .collect())
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::db::{DatabaseError, DB};
+use crate::models::{AuditEntry, TenantId};
+
+impl DB {
+    /// Stores the entry, its `id` is assigned by the database
+    pub async fn insert_audit_entry(&self, entry: &AuditEntry) -> Result<(), DatabaseError> {
+        if let Some(pool) = &self.pool {
+            sqlx::query(
+                "INSERT INTO audit_log (tenant_id, actor, action, resource_type, resource_id, payload_json, timestamp) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            )
+            .bind(entry.tenant_id.to_string())
+            .bind(&entry.actor)
+            .bind(&entry.action)
+            .bind(&entry.resource_type)
+            .bind(&entry.resource_id)
+            .bind(entry.payload.to_string())
+            .bind(entry.timestamp as i64)
+            .execute(&**pool)
+            .await?;
+            Ok(())
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Up to `limit` entries of the tenant with an id above `after`, oldest first
+    pub async fn list_audit_entries(
+        &self,
+        tenant_id: &TenantId,
+        after: u64,
+        limit: u64,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let rows: Vec<(i64, String, String, String, String, String, i64)> = sqlx::query_as(
+                "SELECT id, actor, action, resource_type, resource_id, payload_json, timestamp
+                 FROM audit_log WHERE tenant_id = $1 AND id > $2 ORDER BY id LIMIT $3",
+            )
+            .bind(tenant_id.to_string())
+            .bind(after as i64)
+            .bind(limit as i64)
+            .fetch_all(&**pool)
+            .await?;
+
+            rows.into_iter()
+                .map(
+                    |(id, actor, action, resource_type, resource_id, payload, timestamp)| {
+                        let payload = serde_json::from_str(&payload).map_err(|e| {
+                            DatabaseError::DatabaseValueError(format!(
+                                "Failed to deserialize audit payload: {}",
+                                e
+                            ))
+                        })?;
+                        Ok(AuditEntry {
+                            id: id as u64,
+                            tenant_id: tenant_id.clone(),
+                            actor,
+                            action,
+                            resource_type,
+                            resource_id,
+                            payload,
+                            timestamp: timestamp as u64,
+                        })
+                    },
+                )
+                .collect()
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::db::{DatabaseError, DB};
+
+/// Snapshots in the backup directory are named `forest-backup-<UTC time>.db`
+const BACKUP_FILE_PREFIX: &str = "forest-backup-";
+
+/// A snapshot of the main database
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub path: String,
+    pub size_bytes: u64,
+    pub created_at: u64,
+}
+
+impl DB {
+    /// Writes a consistent copy of the main SQLite database to `path` with `VACUUM INTO`,
+    /// the database stays usable meanwhile. A separate timeseries database isn't included.
+    pub async fn backup_to(&self, path: &Path) -> Result<BackupInfo, DatabaseError> {
+        if self.path.starts_with("postgres") {
+            return Err(DatabaseError::Unsupported(
+                "Backups are only supported for SQLite, use pg_dump for Postgres".to_string(),
+            ));
+        }
+        let Some(pool) = &self.pool else {
+            return Err(DatabaseError::DatabaseConnectionError);
+        };
+        if tokio::fs::try_exists(path).await.unwrap_or(false) {
+            return Err(DatabaseError::DatabaseValueError(format!(
+                "Backup file {} already exists",
+                path.display()
+            )));
+        }
+        // Shadows held by the cache are written first, so the copy has them
+        self.flush_shadows().await?;
+        let created_at = self.clock.now_secs();
+        sqlx::query("VACUUM INTO $1")
+            .bind(path.to_string_lossy().into_owned())
+            .execute(&**pool)
+            .await?;
+        let size_bytes = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| {
+                DatabaseError::DatabaseValueError(format!(
+                    "Failed to read backup file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?
+            .len();
+        Ok(BackupInfo {
+            path: path.display().to_string(),
+            size_bytes,
+            created_at,
+        })
+    }
+
+    /// Writes a snapshot to the backup directory and removes the oldest snapshots
+    /// beyond `backup_keep`
+    pub async fn backup(&self) -> Result<BackupInfo, DatabaseError> {
+        tokio::fs::create_dir_all(&self.backup_dir)
+            .await
+            .map_err(|e| self.backup_dir_error(e))?;
+        let now = chrono::DateTime::from_timestamp_millis(self.clock.now_millis() as i64)
+            .unwrap_or_default();
+        let name = format!(
+            "{}{}.db",
+            BACKUP_FILE_PREFIX,
+            now.format("%Y%m%dT%H%M%S%.3fZ")
+        );
+        let info = self.backup_to(&self.backup_dir.join(name)).await?;
+
+        if self.backup_keep > 0 {
+            let mut snapshots = self.backup_files().await?;
+            let excess = snapshots.len().saturating_sub(self.backup_keep);
+            for snapshot in snapshots.drain(..excess) {
+                match tokio::fs::remove_file(&snapshot).await {
+                    Ok(()) => info!(path = %snapshot.display(), "Removed old backup"),
+                    Err(e) => {
+                        warn!(path = %snapshot.display(), error = ?e, "Failed to remove old backup")
+                    }
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    /// Snapshots in the backup directory, oldest first
+    pub async fn backup_files(&self) -> Result<Vec<PathBuf>, DatabaseError> {
+        let mut entries = tokio::fs::read_dir(&self.backup_dir)
+            .await
+            .map_err(|e| self.backup_dir_error(e))?;
+        let mut files = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| self.backup_dir_error(e))?
+        {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(BACKUP_FILE_PREFIX) && name.ends_with(".db") {
+                files.push(entry.path());
+            }
+        }
+        // The time in the name sorts chronologically
+        files.sort();
+        Ok(files)
+    }
+
+    fn backup_dir_error(&self, error: std::io::Error) -> DatabaseError {
+        DatabaseError::DatabaseValueError(format!(
+            "Backup directory {}: {}",
+            self.backup_dir.display(),
+            error
+        ))
+    }
+}
@@ -0,0 +1,264 @@
+use crate::dataconfig::{DataConfig, DataConfigEntry};
+use crate::db::{DatabaseError, DB};
+use crate::models::{DeviceCredential, DeviceMetadata, LabelSelector, Tenant, TenantId};
+use crate::shadow::Shadow;
+use crate::timeseries::{TimeSeriesConversions, TimeSeriesModel};
+use serde::{Deserialize, Serialize};
+
+/// Number of most recent values exported per metric
+pub const DEVICE_EXPORT_METRIC_LIMIT: u64 = 1000;
+
+/// A single record of a database export, written as one JSON line.
+/// Timeseries data is not part of the export.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum ExportRecord {
+    Tenant(Tenant),
+    Device(DeviceMetadata),
+    Shadow(Shadow),
+    Credential(DeviceCredential),
+    DataConfig(DataConfigEntry),
+}
+
+/// Everything stored for a single device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceExport {
+    pub tenant_id: TenantId,
+    pub device_id: String,
+    pub metadata: Option<DeviceMetadata>,
+    pub shadows: Vec<Shadow>,
+    /// Effective data config, the tenant config merged with the best matching device config
+    pub data_config: Option<DataConfig>,
+    /// The most recent values of every metric, only if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Vec<TimeSeriesModel>>,
+}
+
+fn deserialize<T: serde::de::DeserializeOwned>(data: &str, what: &str) -> Result<T, DatabaseError> {
+    serde_json::from_str(data).map_err(|e| {
+        DatabaseError::DatabaseValueError(format!("Failed to deserialize {}: {}", what, e))
+    })
+}
+
+impl DB {
+    /// Reads all tenants, devices, shadows, credentials and data configs of all tenants
+    pub async fn export_records(&self) -> Result<Vec<ExportRecord>, DatabaseError> {
+        if let Some(pool) = &self.pool {
+            // Shadows are read from the table, pending cached updates must be in it
+            self.flush_shadows().await?;
+            let mut records = Vec::new();
+
+            for tenant in self.list_tenants().await? {
+                records.push(ExportRecord::Tenant(tenant));
+            }
+
+            let rows: Vec<(String,)> = sqlx::query_as(
+                "SELECT metadata FROM device_metadata ORDER BY tenant_id, device_id",
+            )
+            .fetch_all(&**pool)
+            .await?;
+            for (data,) in rows {
+                records.push(ExportRecord::Device(deserialize(&data, "device metadata")?));
+            }
+
+            let rows: Vec<(String,)> = sqlx::query_as(
+                "SELECT data FROM shadows ORDER BY tenant_id, device_id, shadow_name",
+            )
+            .fetch_all(&**pool)
+            .await?;
+            for (data,) in rows {
+                records.push(ExportRecord::Shadow(Shadow::from_json(&data)?));
+            }
+
+            let rows: Vec<(String, String, String, String, i64)> = sqlx::query_as(
+                "SELECT tenant_id, device_id, username, password_hash, created_at FROM device_credentials
+                 ORDER BY tenant_id, device_id, username",
+            )
+            .fetch_all(&**pool)
+            .await?;
+            for (tenant_id, device_id, username, password_hash, created_at) in rows {
+                records.push(ExportRecord::Credential(DeviceCredential {
+                    tenant_id: TenantId::from_str(&tenant_id),
+                    device_id,
+                    username,
+                    password_hash,
+                    created_at: created_at as u64,
+                }));
+            }
+
+            let rows: Vec<(String, String, String)> = sqlx::query_as(
+                "SELECT tenant_id, device_prefix, config FROM data_configs ORDER BY tenant_id, device_prefix",
+            )
+            .fetch_all(&**pool)
+            .await?;
+            for (tenant_id, prefix, config) in rows {
+                let config: DataConfig = deserialize(&config, "data config")?;
+                records.push(ExportRecord::DataConfig(DataConfigEntry {
+                    tenant_id: TenantId::from_str(&tenant_id),
+                    device_prefix: if prefix.is_empty() {
+                        None
+                    } else {
+                        Some(prefix)
+                    },
+                    label_selector: None,
+                    metrics: config.metrics,
+                    transformations: config.transformations,
+                    payload_schema: config.payload_schema,
+                    enable_deduplication: config.enable_deduplication,
+                }));
+            }
+
+            let rows: Vec<(String, String, String, String)> = sqlx::query_as(
+                "SELECT tenant_id, label_key, label_value, config FROM label_data_configs
+                 ORDER BY tenant_id, label_key, label_value",
+            )
+            .fetch_all(&**pool)
+            .await?;
+            for (tenant_id, key, value, config) in rows {
+                let config: DataConfig = deserialize(&config, "data config")?;
+                records.push(ExportRecord::DataConfig(DataConfigEntry {
+                    tenant_id: TenantId::from_str(&tenant_id),
+                    device_prefix: None,
+                    label_selector: Some(LabelSelector { key, value }.to_string()),
+                    metrics: config.metrics,
+                    transformations: config.transformations,
+                    payload_schema: config.payload_schema,
+                    enable_deduplication: config.enable_deduplication,
+                }));
+            }
+
+            Ok(records)
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Stores an exported record as is, replacing an existing one with the same key
+    pub async fn import_record(&self, record: &ExportRecord) -> Result<(), DatabaseError> {
+        match record {
+            ExportRecord::Tenant(tenant) => self.put_tenant(tenant).await,
+            ExportRecord::Device(metadata) => self.put_device_metadata(metadata).await,
+            ExportRecord::Shadow(shadow) => self.put_shadow(shadow).await,
+            // Stores the hash, passwords are never exported in plaintext
+            ExportRecord::Credential(credential) => self.add_device_password(credential).await,
+            ExportRecord::DataConfig(entry) => {
+                let config = DataConfig {
+                    metrics: entry.metrics.clone(),
+                    transformations: entry.transformations.clone(),
+                    payload_schema: entry.payload_schema.clone(),
+                    enable_deduplication: entry.enable_deduplication,
+                };
+                if let Some(selector) = &entry.label_selector {
+                    let selector = LabelSelector::parse(selector)
+                        .ok_or_else(|| DatabaseError::InvalidKeyError(selector.to_string()))?;
+                    return self
+                        .store_label_data_config(&entry.tenant_id, &selector, &config)
+                        .await;
+                }
+                match &entry.device_prefix {
+                    Some(prefix) => {
+                        self.store_device_data_config(&entry.tenant_id, prefix, &config)
+                            .await
+                    }
+                    None => {
+                        self.store_tenant_data_config(&entry.tenant_id, &config)
+                            .await
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collects metadata, shadows, data config and optionally the recent metrics of a device
+    pub async fn export_device(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        include_metrics: bool,
+    ) -> Result<DeviceExport, DatabaseError> {
+        if let (Some(pool), Some(_)) = (&self.pool, &self.ts_pool) {
+            let t_id = tenant_id.to_string();
+            let metadata = self.get_device_metadata(tenant_id, device_id).await?;
+            self.flush_shadows().await?;
+
+            let rows: Vec<(String,)> = sqlx::query_as(
+                "SELECT data FROM shadows WHERE tenant_id = $1 AND device_id = $2 ORDER BY shadow_name",
+            )
+            .bind(&t_id)
+            .bind(device_id)
+            .fetch_all(&**pool)
+            .await?;
+            let shadows = rows
+                .into_iter()
+                .map(|(data,)| Shadow::from_json(&data))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let data_config = self.get_data_config(tenant_id, Some(device_id)).await?;
+
+            let metrics = if include_metrics {
+                let mut metrics = Vec::new();
+                for name in self.list_metric_names(tenant_id, device_id).await? {
+                    let ts = self
+                        .get_last_metric(tenant_id, device_id, &name, DEVICE_EXPORT_METRIC_LIMIT)
+                        .await?;
+                    metrics.push(ts.to_model(device_id, &name));
+                }
+                Some(metrics)
+            } else {
+                None
+            };
+
+            Ok(DeviceExport {
+                tenant_id: tenant_id.clone(),
+                device_id: device_id.to_string(),
+                metadata,
+                shadows,
+                data_config,
+                metrics,
+            })
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Stores a shadow without applying it as an update, keeping its version and metadata
+    pub async fn put_shadow(&self, shadow: &Shadow) -> Result<(), DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let tenant_id = shadow.tenant_id.to_string();
+            let shadow_name = shadow.shadow_name.as_str().to_string();
+            let data = shadow.to_json()?;
+
+            self.write_shadow_uncached(&shadow.device_id, &shadow.shadow_name, &shadow.tenant_id, || async {
+                let mut tx = pool.begin().await?;
+                sqlx::query(
+                    "DELETE FROM shadows WHERE tenant_id = $1 AND device_id = $2 AND shadow_name = $3",
+                )
+                .bind(&tenant_id)
+                .bind(&shadow.device_id)
+                .bind(&shadow_name)
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query(
+                    "INSERT INTO shadows (tenant_id, device_id, shadow_name, data, last_updated) VALUES ($1, $2, $3, $4, $5)",
+                )
+                .bind(&tenant_id)
+                .bind(&shadow.device_id)
+                .bind(&shadow_name)
+                .bind(&data)
+                .bind(shadow.get_last_updated() as i64)
+                .execute(&mut *tx)
+                .await?;
+
+                tx.commit().await?;
+                Ok(())
+            })
+            .await?;
+            // The shadow may or may not be new, the tenant is counted again on its next check
+            self.shadow_counts.remove(&shadow.tenant_id);
+            Ok(())
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+}
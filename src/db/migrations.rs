@@ -0,0 +1,183 @@
+//! Versioned schema changes, applied by `DB::open` after the tables are created.
+//!
+//! The `CREATE TABLE IF NOT EXISTS` statements in `open` always describe the current
+//! schema, so a fresh database already has every column. Migrations bring tables created
+//! by older versions up to date. Each one runs once, in order, and its version is
+//! recorded in `schema_migrations`. Steps must be idempotent, a fresh database already
+//! has the columns they add.
+
+use super::DatabaseError;
+use crate::clock::{Clock, SystemClock};
+use sqlx::{AnyConnection, Connection};
+use std::collections::HashSet;
+use tracing::info;
+
+/// Database a migration applies to, timeseries data can live in a separate database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MigrationTarget {
+    Main,
+    Timeseries,
+}
+
+pub(crate) enum MigrationStep {
+    /// Adds the column unless the table already has it
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        sql_type: &'static str,
+    },
+    /// A statement that is safe to run on a fresh database, per backend
+    Sql {
+        sqlite: &'static str,
+        postgres: &'static str,
+    },
+}
+
+pub(crate) struct Migration {
+    /// Unique across both targets, migrations are applied in this order
+    pub version: i64,
+    pub target: MigrationTarget,
+    pub description: &'static str,
+    pub steps: &'static [MigrationStep],
+}
+
+/// Append only, applied versions are never run again
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        target: MigrationTarget::Timeseries,
+        description: "tags of metric values",
+        steps: &[MigrationStep::AddColumn {
+            table: "timeseries_data",
+            column: "value_tags",
+            sql_type: "TEXT",
+        }],
+    },
+    Migration {
+        version: 2,
+        target: MigrationTarget::Timeseries,
+        description: "text metric values",
+        steps: &[MigrationStep::AddColumn {
+            table: "timeseries_data",
+            column: "value_text",
+            sql_type: "TEXT",
+        }],
+    },
+    Migration {
+        version: 3,
+        target: MigrationTarget::Main,
+        description: "empty tag map in device metadata",
+        steps: &[MigrationStep::Sql {
+            sqlite: "UPDATE device_metadata SET metadata = json_set(metadata, '$.tags', json('{}'))
+                WHERE json_extract(metadata, '$.tags') IS NULL",
+            postgres: "UPDATE device_metadata SET metadata = jsonb_set(metadata::jsonb, '{tags}', '{}'::jsonb)::text
+                WHERE (metadata::jsonb) -> 'tags' IS NULL",
+        }],
+    },
+    Migration {
+        version: 4,
+        target: MigrationTarget::Main,
+        description: "last update of shadows, backfilled from the shadow document",
+        steps: &[
+            MigrationStep::AddColumn {
+                table: "shadows",
+                column: "last_updated",
+                sql_type: "BIGINT",
+            },
+            MigrationStep::Sql {
+                sqlite: "UPDATE shadows SET last_updated = json_extract(data, '$.last_updated')
+                    WHERE last_updated IS NULL",
+                postgres: "UPDATE shadows SET last_updated = ((data::jsonb) ->> 'last_updated')::bigint
+                    WHERE last_updated IS NULL",
+            },
+            MigrationStep::Sql {
+                sqlite: "CREATE INDEX IF NOT EXISTS ix_shadows_last_updated ON shadows (tenant_id, last_updated)",
+                postgres: "CREATE INDEX IF NOT EXISTS ix_shadows_last_updated ON shadows (tenant_id, last_updated)",
+            },
+        ],
+    },
+];
+
+impl MigrationStep {
+    async fn apply(
+        &self,
+        conn: &mut AnyConnection,
+        is_postgres: bool,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            MigrationStep::AddColumn {
+                table,
+                column,
+                sql_type,
+            } => {
+                // A failing probe would abort the whole transaction on Postgres
+                let exists_query = if is_postgres {
+                    "SELECT COUNT(*) FROM information_schema.columns
+                     WHERE table_schema = current_schema() AND table_name = $1 AND column_name = $2"
+                } else {
+                    "SELECT COUNT(*) FROM pragma_table_info($1) WHERE name = $2"
+                };
+                let (count,): (i64,) = sqlx::query_as(exists_query)
+                    .bind(*table)
+                    .bind(*column)
+                    .fetch_one(&mut *conn)
+                    .await?;
+                if count == 0 {
+                    let alter = format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, sql_type);
+                    sqlx::query(&alter).execute(&mut *conn).await?;
+                }
+            }
+            MigrationStep::Sql { sqlite, postgres } => {
+                let query = if is_postgres { postgres } else { sqlite };
+                sqlx::query(query).execute(&mut *conn).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies the pending migrations of `target`, each in its own transaction.
+/// Returns the versions that were applied.
+pub(crate) async fn run_migrations(
+    conn: &mut AnyConnection,
+    target: MigrationTarget,
+    is_postgres: bool,
+) -> Result<Vec<i64>, DatabaseError> {
+    // BIGINT like the other timestamps, so both columns decode as i64 on every backend
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            applied_at BIGINT NOT NULL
+        )",
+    )
+    .execute(&mut *conn)
+    .await?;
+    let rows: Vec<(i64,)> = sqlx::query_as("SELECT version FROM schema_migrations")
+        .fetch_all(&mut *conn)
+        .await?;
+    let applied: HashSet<i64> = rows.into_iter().map(|(version,)| version).collect();
+
+    let mut newly_applied = Vec::new();
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|m| m.target == target && !applied.contains(&m.version))
+    {
+        let mut tx = conn.begin().await?;
+        for step in migration.steps {
+            step.apply(&mut *tx, is_postgres).await?;
+        }
+        sqlx::query("INSERT INTO schema_migrations (version, applied_at) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(SystemClock.now_secs() as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        info!(
+            version = migration.version,
+            description = migration.description,
+            "Applied schema migration"
+        );
+        newly_applied.push(migration.version);
+    }
+    Ok(newly_applied)
+}
@@ -0,0 +1,87 @@
+use crate::db::{DatabaseError, DB};
+use crate::models::{ProvisioningToken, TenantId};
+
+impl DB {
+    pub async fn store_provisioning_token(
+        &self,
+        token: &ProvisioningToken,
+    ) -> Result<(), DatabaseError> {
+        if let Some(pool) = &self.pool {
+            sqlx::query(
+                "INSERT INTO provisioning_tokens (token_hash, tenant_id, device_id, expires_at, used) VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(&token.token_hash)
+            .bind(token.tenant_id.to_string())
+            .bind(&token.device_id)
+            .bind(token.expires_at as i64)
+            .bind(token.used as i64)
+            .execute(&**pool)
+            .await?;
+            Ok(())
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    pub async fn get_provisioning_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ProvisioningToken>, DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let row: Option<(String, String, String, i64, i64)> = sqlx::query_as(
+                "SELECT token_hash, tenant_id, device_id, expires_at, used FROM provisioning_tokens WHERE token_hash = $1",
+            )
+            .bind(token_hash)
+            .fetch_optional(&**pool)
+            .await?;
+            Ok(row.map(
+                |(token_hash, tenant_id, device_id, expires_at, used)| ProvisioningToken {
+                    token_hash,
+                    device_id,
+                    tenant_id: TenantId::from_str(&tenant_id),
+                    expires_at: expires_at as u64,
+                    used: used != 0,
+                },
+            ))
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Marks the token as used, `None` if it is unknown, already used or expired at `now`.
+    /// Concurrent calls with the same token redeem it only once.
+    pub async fn redeem_provisioning_token(
+        &self,
+        token_hash: &str,
+        now: u64,
+    ) -> Result<Option<ProvisioningToken>, DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let result = sqlx::query(
+                "UPDATE provisioning_tokens SET used = 1 WHERE token_hash = $1 AND used = 0 AND expires_at > $2",
+            )
+            .bind(token_hash)
+            .bind(now as i64)
+            .execute(&**pool)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Ok(None);
+            }
+            self.get_provisioning_token(token_hash).await
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Makes a redeemed token usable again, for when provisioning failed after redeeming it
+    pub async fn release_provisioning_token(&self, token_hash: &str) -> Result<(), DatabaseError> {
+        if let Some(pool) = &self.pool {
+            sqlx::query("UPDATE provisioning_tokens SET used = 0 WHERE token_hash = $1")
+                .bind(token_hash)
+                .execute(&**pool)
+                .await?;
+            Ok(())
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+}
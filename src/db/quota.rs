@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::db::{DatabaseError, DB};
+use crate::models::{Tenant, TenantId, TenantQuota, TenantUsage};
+use crate::shadow::StateUpdateDocument;
+
+impl DB {
+    /// Quota of the tenant, tenants without a stored record are unlimited.
+    /// Cached after the first lookup, `put_tenant` keeps the cache up to date.
+    pub async fn tenant_quota(&self, tenant_id: &TenantId) -> Result<TenantQuota, DatabaseError> {
+        if let Some(quota) = self.tenant_quotas.get(tenant_id) {
+            return Ok(quota.clone());
+        }
+        let quota = self
+            .get_tenant(tenant_id)
+            .await?
+            .map(|tenant| tenant.quota)
+            .unwrap_or_default();
+        self.tenant_quotas.insert(tenant_id.clone(), quota.clone());
+        Ok(quota)
+    }
+
+    /// Replaces the quota of the tenant, returns the updated tenant
+    pub async fn set_tenant_quota(
+        &self,
+        tenant_id: &TenantId,
+        quota: TenantQuota,
+    ) -> Result<Tenant, DatabaseError> {
+        let Some(mut tenant) = self.get_tenant(tenant_id).await? else {
+            return Err(DatabaseError::NotFoundError(format!(
+                "Tenant {} not found",
+                tenant_id
+            )));
+        };
+        tenant.quota = quota;
+        self.put_tenant(&tenant).await?;
+        Ok(tenant)
+    }
+
+    pub async fn count_devices(&self, tenant_id: &TenantId) -> Result<u64, DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let (count,): (i64,) =
+                sqlx::query_as("SELECT COUNT(*) FROM device_metadata WHERE tenant_id = $1")
+                    .bind(tenant_id.to_string())
+                    .fetch_one(&**pool)
+                    .await?;
+            Ok(count as u64)
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Fails with `QuotaExceeded` if the tenant already has `max_devices` devices
+    pub async fn check_device_quota(&self, tenant_id: &TenantId) -> Result<(), DatabaseError> {
+        let Some(max_devices) = self.tenant_quota(tenant_id).await?.max_devices else {
+            return Ok(());
+        };
+        if self.count_devices(tenant_id).await? >= max_devices {
+            return Err(DatabaseError::QuotaExceeded(format!(
+                "Tenant {} has reached its limit of {} devices",
+                tenant_id, max_devices
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fails with `QuotaExceeded` if `rows` more timeseries rows would exceed the quota of
+    /// the tenant. The rows are counted in the database once, later writes are added by
+    /// `record_timeseries_rows`.
+    pub(crate) async fn check_timeseries_quota(
+        &self,
+        tenant_id: &TenantId,
+        rows: u64,
+    ) -> Result<(), DatabaseError> {
+        let Some(max_rows) = self.tenant_quota(tenant_id).await?.max_timeseries_rows else {
+            return Ok(());
+        };
+        let stored = match self.timeseries_rows.get(tenant_id).map(|count| *count) {
+            Some(count) => count,
+            None => {
+                let Some(ts_pool) = &self.ts_pool else {
+                    return Err(DatabaseError::DatabaseConnectionError);
+                };
+                let (count,): (i64,) =
+                    sqlx::query_as("SELECT COUNT(*) FROM timeseries_data WHERE tenant_id = $1")
+                        .bind(tenant_id.to_string())
+                        .fetch_one(&**ts_pool)
+                        .await?;
+                // A concurrent first check may have counted already, keep its count
+                *self
+                    .timeseries_rows
+                    .entry(tenant_id.clone())
+                    .or_insert(count as u64)
+            }
+        };
+        if stored + rows > max_rows {
+            return Err(DatabaseError::QuotaExceeded(format!(
+                "Tenant {} has reached its limit of {} timeseries rows",
+                tenant_id, max_rows
+            )));
+        }
+        Ok(())
+    }
+
+    /// Adds written rows to the count of a tenant with a row quota
+    pub(crate) fn record_timeseries_rows(&self, tenant_id: &TenantId, rows: u64) {
+        if let Some(mut count) = self.timeseries_rows.get_mut(tenant_id) {
+            *count += rows;
+        }
+    }
+
+    /// Fails with `QuotaExceeded` if creating the shadows of `updates` that don't exist yet
+    /// would exceed the shadow quota of their tenant. Returns the number of shadows the
+    /// updates create per tenant with a quota, to be passed to `record_new_shadows` once
+    /// they are written.
+    pub(crate) async fn check_shadow_quota(
+        &self,
+        updates: &[StateUpdateDocument],
+    ) -> Result<HashMap<TenantId, u64>, DatabaseError> {
+        let mut new_shadows: HashMap<&TenantId, (u64, HashSet<(&str, &str)>)> = HashMap::new();
+        for update in updates {
+            let Some(max_shadows) = self.tenant_quota(&update.tenant_id).await?.max_shadows else {
+                continue;
+            };
+            let exists = self
+                .cached_shadow(&update.device_id, &update.shadow_name, &update.tenant_id)
+                .is_some()
+                || self
+                    .load_shadow(&update.device_id, &update.shadow_name, &update.tenant_id)
+                    .await?
+                    .is_some();
+            if !exists {
+                new_shadows
+                    .entry(&update.tenant_id)
+                    .or_insert_with(|| (max_shadows, HashSet::new()))
+                    .1
+                    .insert((update.device_id.as_str(), update.shadow_name.as_str()));
+            }
+        }
+        let mut created = HashMap::new();
+        for (tenant_id, (max_shadows, shadows)) in new_shadows {
+            if self.stored_shadows(tenant_id).await? + shadows.len() as u64 > max_shadows {
+                return Err(DatabaseError::QuotaExceeded(format!(
+                    "Tenant {} has reached its limit of {} shadows",
+                    tenant_id, max_shadows
+                )));
+            }
+            created.insert(tenant_id.clone(), shadows.len() as u64);
+        }
+        Ok(created)
+    }
+
+    /// Shadows of the tenant, counted in the database once. Later changes are applied by
+    /// `record_new_shadows` and `record_deleted_shadow`.
+    async fn stored_shadows(&self, tenant_id: &TenantId) -> Result<u64, DatabaseError> {
+        if let Some(count) = self.shadow_counts.get(tenant_id).map(|count| *count) {
+            return Ok(count);
+        }
+        let Some(pool) = &self.pool else {
+            return Err(DatabaseError::DatabaseConnectionError);
+        };
+        // Shadows created in the cache are only counted once they are written
+        self.flush_shadows().await?;
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM shadows WHERE tenant_id = $1")
+            .bind(tenant_id.to_string())
+            .fetch_one(&**pool)
+            .await?;
+        // A concurrent first check may have counted already, keep its count
+        Ok(*self
+            .shadow_counts
+            .entry(tenant_id.clone())
+            .or_insert(count as u64))
+    }
+
+    /// Adds the shadows returned by `check_shadow_quota` to the counts of their tenants
+    pub(crate) fn record_new_shadows(&self, new_shadows: HashMap<TenantId, u64>) {
+        for (tenant_id, created) in new_shadows {
+            if let Some(mut count) = self.shadow_counts.get_mut(&tenant_id) {
+                *count += created;
+            }
+        }
+    }
+
+    /// Removes a deleted shadow from the count of its tenant
+    pub(crate) fn record_deleted_shadow(&self, tenant_id: &TenantId) {
+        if let Some(mut count) = self.shadow_counts.get_mut(tenant_id) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Counts the devices, metric rows and shadows of the tenant
+    pub async fn tenant_usage(&self, tenant_id: &TenantId) -> Result<TenantUsage, DatabaseError> {
+        let (Some(pool), Some(ts_pool)) = (&self.pool, &self.ts_pool) else {
+            return Err(DatabaseError::DatabaseConnectionError);
+        };
+        // Shadows created in the cache are only counted once they are written
+        self.flush_shadows().await?;
+        let t_id = tenant_id.to_string();
+        let device_count = self.count_devices(tenant_id).await?;
+        let (shadow_count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM shadows WHERE tenant_id = $1")
+                .bind(&t_id)
+                .fetch_one(&**pool)
+                .await?;
+        let (metric_row_count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM timeseries_data WHERE tenant_id = $1")
+                .bind(&t_id)
+                .fetch_one(&**ts_pool)
+                .await?;
+
+        let approximate_bytes = if self.path.starts_with("postgres") {
+            let mut bytes = 0.0;
+            for (table, rows) in [
+                ("device_metadata", device_count),
+                ("shadows", shadow_count as u64),
+            ] {
+                let (size, total): (i64, i64) = sqlx::query_as(&format!(
+                    "SELECT pg_total_relation_size('{0}'), (SELECT COUNT(*) FROM {0})",
+                    table
+                ))
+                .fetch_one(&**pool)
+                .await?;
+                if total > 0 {
+                    bytes += size as f64 * rows as f64 / total as f64;
+                }
+            }
+            Some(bytes.round() as u64)
+        } else {
+            None
+        };
+
+        Ok(TenantUsage {
+            device_count,
+            metric_row_count: metric_row_count as u64,
+            shadow_count: shadow_count as u64,
+            approximate_bytes,
+        })
+    }
+}
@@ -0,0 +1,30 @@
+use crate::db::{DatabaseError, DB};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+impl DB {
+    /// Deletes the metric values of all tenants older than `timestamp`, returns the number
+    /// of deleted rows
+    pub async fn delete_metrics_before(&self, timestamp: u64) -> Result<u64, DatabaseError> {
+        let Some(ts_pool) = &self.ts_pool else {
+            return Err(DatabaseError::DatabaseConnectionError);
+        };
+        let result = sqlx::query("DELETE FROM timeseries_data WHERE timestamp < $1")
+            .bind(timestamp as i64)
+            .execute(&**ts_pool)
+            .await?;
+        // Row quotas count again on their next check
+        self.timeseries_rows.clear();
+        Ok(result.rows_affected())
+    }
+
+    /// Deletes the metric values older than `retention_days` days, returns the number of
+    /// deleted rows
+    pub async fn prune_metrics(&self, retention_days: u32) -> Result<u64, DatabaseError> {
+        let cutoff = self
+            .clock
+            .now_secs()
+            .saturating_sub(retention_days as u64 * SECONDS_PER_DAY);
+        self.delete_metrics_before(cutoff).await
+    }
+}
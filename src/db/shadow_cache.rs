@@ -0,0 +1,308 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use sqlx::AnyPool;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::db::{DatabaseError, DB};
+use crate::models::{ShadowName, TenantId};
+use crate::shadow::{Shadow, StateUpdateDocument};
+
+/// Shadows kept in memory at most, persisted ones are dropped when it is reached
+pub const SHADOW_CACHE_CAPACITY: usize = 10_000;
+
+/// Tenant, device id and shadow name
+type ShadowKey = (String, String, String);
+
+fn shadow_key(tenant_id: &TenantId, device_id: &str, shadow_name: &ShadowName) -> ShadowKey {
+    (
+        tenant_id.to_string(),
+        device_id.to_string(),
+        shadow_name.as_str().to_string(),
+    )
+}
+
+struct CachedShadow {
+    shadow: Shadow,
+    /// Changed since it was last written to the database
+    dirty: bool,
+}
+
+/// Hot shadows in memory, updates are applied here and written to the database in batches
+pub(crate) struct ShadowCache {
+    entries: DashMap<ShadowKey, CachedShadow>,
+    /// Held while writing shadows, so a flush can't overwrite a later delete or import
+    write_lock: tokio::sync::Mutex<()>,
+    cancel_token: CancellationToken,
+}
+
+impl ShadowCache {
+    fn new() -> Self {
+        ShadowCache {
+            entries: DashMap::new(),
+            write_lock: tokio::sync::Mutex::new(()),
+            cancel_token: CancellationToken::new(),
+        }
+    }
+
+    /// Starts the cache with a task writing the changed shadows every `interval`
+    pub(crate) fn start(pool: Arc<AnyPool>, interval: Duration) -> Arc<Self> {
+        let cache = Arc::new(ShadowCache::new());
+        tokio::spawn({
+            let cache = cache.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = cache.cancel_token.cancelled() => break,
+                        _ = tokio::time::sleep(interval) => {}
+                    }
+                    if let Err(e) = cache.flush(&pool).await {
+                        warn!(error = ?e, "Failed to write cached shadows, retrying");
+                    }
+                }
+            }
+        });
+        cache
+    }
+
+    pub(crate) fn stop(&self) {
+        self.cancel_token.cancel();
+    }
+
+    fn get(&self, key: &ShadowKey) -> Option<Shadow> {
+        self.entries.get(key).map(|cached| cached.shadow.clone())
+    }
+
+    /// Applies the update to the cached shadow, `None` if it isn't cached
+    fn update(
+        &self,
+        key: &ShadowKey,
+        update: &StateUpdateDocument,
+        max_bytes: usize,
+    ) -> Option<Result<Shadow, DatabaseError>> {
+        let mut cached = self.entries.get_mut(key)?;
+        // Update a copy, a failed update must not change the cached shadow
+        let mut shadow = cached.shadow.clone();
+        if let Err(e) = shadow
+            .update(update)
+            .and_then(|()| shadow.check_size(max_bytes))
+        {
+            return Some(Err(e.into()));
+        }
+        cached.shadow = shadow.clone();
+        cached.dirty = true;
+        Some(Ok(shadow))
+    }
+
+    /// Caches a shadow read from the database, unless another update cached it meanwhile
+    fn insert_loaded(&self, key: ShadowKey, shadow: Shadow) {
+        if self.entries.len() >= SHADOW_CACHE_CAPACITY {
+            self.entries.retain(|_, cached| cached.dirty);
+        }
+        self.entries.entry(key).or_insert(CachedShadow {
+            shadow,
+            dirty: false,
+        });
+    }
+
+    /// Writes all changed shadows in one transaction, returns how many were written
+    pub(crate) async fn flush(&self, pool: &AnyPool) -> Result<usize, DatabaseError> {
+        let _guard = self.write_lock.lock().await;
+        let mut changed = Vec::new();
+        for mut cached in self.entries.iter_mut() {
+            if cached.dirty {
+                cached.dirty = false;
+                changed.push(cached.shadow.clone());
+            }
+        }
+        if changed.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = write_shadows(pool, &changed).await {
+            // Written again with the next flush, unless a newer update is pending anyway
+            for shadow in &changed {
+                let key = shadow_key(&shadow.tenant_id, &shadow.device_id, &shadow.shadow_name);
+                if let Some(mut cached) = self.entries.get_mut(&key) {
+                    cached.dirty = true;
+                }
+            }
+            return Err(e);
+        }
+        Ok(changed.len())
+    }
+}
+
+/// Replaces the stored shadows in one transaction
+async fn write_shadows(pool: &AnyPool, shadows: &[Shadow]) -> Result<(), DatabaseError> {
+    let mut tx = pool.begin().await?;
+    for shadow in shadows {
+        let tenant_id = shadow.tenant_id.to_string();
+        let shadow_name = shadow.shadow_name.as_str().to_string();
+        let data = shadow.to_json()?;
+
+        sqlx::query(
+            "DELETE FROM shadows WHERE tenant_id = $1 AND device_id = $2 AND shadow_name = $3",
+        )
+        .bind(&tenant_id)
+        .bind(&shadow.device_id)
+        .bind(&shadow_name)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO shadows (tenant_id, device_id, shadow_name, data, last_updated) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&tenant_id)
+        .bind(&shadow.device_id)
+        .bind(&shadow_name)
+        .bind(&data)
+        .bind(shadow.get_last_updated() as i64)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+impl DB {
+    /// Applies the update to the cached shadow, loading it from the database on the first
+    /// update. The result is written by the next flush.
+    pub(crate) async fn upsert_cached_shadow(
+        &self,
+        cache: &ShadowCache,
+        update: &StateUpdateDocument,
+    ) -> Result<Shadow, DatabaseError> {
+        let key = shadow_key(&update.tenant_id, &update.device_id, &update.shadow_name);
+        loop {
+            if let Some(result) = cache.update(&key, update, self.max_shadow_bytes) {
+                return result;
+            }
+            // A delete or import must not happen between reading and caching the shadow
+            let _guard = cache.write_lock.lock().await;
+            if cache.entries.contains_key(&key) {
+                continue;
+            }
+            let shadow = match self
+                .load_shadow(&update.device_id, &update.shadow_name, &update.tenant_id)
+                .await?
+            {
+                Some(shadow) => shadow,
+                None => Shadow::new(&update.device_id, &update.shadow_name, &update.tenant_id),
+            };
+            cache.insert_loaded(key.clone(), shadow);
+        }
+    }
+
+    /// `upsert_shadows` with the cache, the updated shadows are written in one transaction.
+    /// They are taken out of the cache meanwhile, so concurrent updates wait for the write
+    /// lock and load them again. Pending changes are put back if nothing was written.
+    pub(crate) async fn upsert_cached_shadows(
+        &self,
+        cache: &ShadowCache,
+        updates: &[StateUpdateDocument],
+    ) -> Result<Vec<Shadow>, DatabaseError> {
+        let Some(pool) = &self.pool else {
+            return Err(DatabaseError::DatabaseConnectionError);
+        };
+        let _guard = cache.write_lock.lock().await;
+        let mut taken = Vec::new();
+        let result = self
+            .write_taken_shadows(cache, pool, updates, &mut taken)
+            .await;
+        if result.is_err() {
+            for (key, cached) in taken {
+                cache.entries.insert(key, cached);
+            }
+        }
+        result
+    }
+
+    async fn write_taken_shadows(
+        &self,
+        cache: &ShadowCache,
+        pool: &AnyPool,
+        updates: &[StateUpdateDocument],
+        taken: &mut Vec<(ShadowKey, CachedShadow)>,
+    ) -> Result<Vec<Shadow>, DatabaseError> {
+        // Several updates of the same shadow are applied in order
+        let mut shadows: HashMap<ShadowKey, Shadow> = HashMap::new();
+        let mut results = Vec::with_capacity(updates.len());
+        for update in updates {
+            let key = shadow_key(&update.tenant_id, &update.device_id, &update.shadow_name);
+            let shadow = match shadows.entry(key) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let shadow = match cache.entries.remove(entry.key()) {
+                        Some((key, cached)) => {
+                            let shadow = cached.shadow.clone();
+                            taken.push((key, cached));
+                            shadow
+                        }
+                        None => self
+                            .load_shadow(&update.device_id, &update.shadow_name, &update.tenant_id)
+                            .await?
+                            .unwrap_or_else(|| {
+                                Shadow::new(
+                                    &update.device_id,
+                                    &update.shadow_name,
+                                    &update.tenant_id,
+                                )
+                            }),
+                    };
+                    entry.insert(shadow)
+                }
+            };
+            shadow.update(update)?;
+            shadow.check_size(self.max_shadow_bytes)?;
+            results.push(shadow.clone());
+        }
+        let shadows: Vec<Shadow> = shadows.into_values().collect();
+        write_shadows(pool, &shadows).await?;
+        Ok(results)
+    }
+
+    pub(crate) fn cached_shadow(
+        &self,
+        device_id: &str,
+        shadow_name: &ShadowName,
+        tenant_id: &TenantId,
+    ) -> Option<Shadow> {
+        let cache = self.shadow_cache.as_ref()?;
+        cache.get(&shadow_key(tenant_id, device_id, shadow_name))
+    }
+
+    /// Runs a direct write to the shadows table, e.g. a delete or import. The cached shadow
+    /// is dropped with its pending changes, the direct write replaces them.
+    pub(crate) async fn write_shadow_uncached<F, Fut>(
+        &self,
+        device_id: &str,
+        shadow_name: &ShadowName,
+        tenant_id: &TenantId,
+        write: F,
+    ) -> Result<(), DatabaseError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<(), DatabaseError>>,
+    {
+        let Some(cache) = &self.shadow_cache else {
+            return write().await;
+        };
+        let _guard = cache.write_lock.lock().await;
+        cache
+            .entries
+            .remove(&shadow_key(tenant_id, device_id, shadow_name));
+        write().await
+    }
+
+    /// Writes the shadow updates still held in memory, a no-op without the shadow cache
+    pub async fn flush_shadows(&self) -> Result<usize, DatabaseError> {
+        match (&self.shadow_cache, &self.pool) {
+            (Some(cache), Some(pool)) => cache.flush(pool).await,
+            _ => Ok(0),
+        }
+    }
+}
@@ -1,9 +1,16 @@
 use super::*;
-use crate::dataconfig::{DataConfig, DataType, MetricConfig};
-use crate::models::{AuthConfig, DeviceCredential, Tenant, TenantId};
+use crate::clock::MockClock;
+use crate::dataconfig::{ConfigSource, DataConfig, DataType, ExtractedMetric, MetricConfig};
+use crate::models::{
+    AlarmCondition, AlarmEvent, AlarmRule, AuditEntry, AuthConfig, DeadLetter, DeviceCredential,
+    DeviceStatus, LabelSelector, ProvisioningToken, ShadowEvent, ShadowWebhook, Tenant, TenantId,
+    TenantQuota, TenantUsage,
+};
 use crate::shadow::StateDocument;
 use crate::timeseries::FloatTimeSeries;
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
 use tempfile::TempDir;
 use uuid::Uuid;
 
@@ -49,6 +56,7 @@ async fn test_put_get_multiple_buckets() {
             "temperature",
             1710511200,
             1710511200 + 3599,
+            None,
         )
         .await
         .unwrap();
@@ -65,16 +73,35 @@ async fn test_no_db_connection_ts() {
         path: String::from("path"),
         pool: None,
         ts_pool: None,
+        non_finite_policy: NonFinitePolicy::default(),
+        max_shadow_bytes: 0,
+        backup_dir: PathBuf::from("backups"),
+        backup_keep: 0,
+        non_finite_rejected: AtomicU64::new(0),
+        corrupt_data_configs: AtomicU64::new(0),
+        shadow_cache: None,
+        clock: Arc::new(SystemClock),
+        tenant_quotas: DashMap::new(),
+        timeseries_rows: DashMap::new(),
+        shadow_counts: DashMap::new(),
+        shadow_webhooks: DashMap::new(),
     };
 
     assert!(matches!(
-        db.put_metric(&TenantId::Default, "dev", "temp", MetricValue::Float(1.0))
-            .await,
+        db.put_metric(
+            &TenantId::Default,
+            "dev",
+            "temp",
+            MetricValue::Float(1.0),
+            None
+        )
+        .await,
         Err(DatabaseError::DatabaseConnectionError)
     ));
 
     assert!(matches!(
-        db.get_metric(&TenantId::Default, "dev", "temp", 0, 1).await,
+        db.get_metric(&TenantId::Default, "dev", "temp", 0, 1, None)
+            .await,
         Err(DatabaseError::DatabaseConnectionError)
     ));
 }
@@ -83,7 +110,7 @@ async fn test_no_db_connection_ts() {
 async fn test_empty_range() {
     let (db, _temp) = setup_db().await;
     let result = db
-        .get_metric(&TenantId::Default, "nonexistent", "temp", 0, 1)
+        .get_metric(&TenantId::Default, "nonexistent", "temp", 0, 1, None)
         .await
         .unwrap();
     assert_eq!(result.len(), 0);
@@ -163,6 +190,18 @@ async fn test_get_timeseries_last() {
         path: "path".to_string(),
         pool: None,
         ts_pool: None,
+        non_finite_policy: NonFinitePolicy::default(),
+        max_shadow_bytes: 0,
+        backup_dir: PathBuf::from("backups"),
+        backup_keep: 0,
+        non_finite_rejected: AtomicU64::new(0),
+        corrupt_data_configs: AtomicU64::new(0),
+        shadow_cache: None,
+        clock: Arc::new(SystemClock),
+        tenant_quotas: DashMap::new(),
+        timeseries_rows: DashMap::new(),
+        shadow_counts: DashMap::new(),
+        shadow_webhooks: DashMap::new(),
     };
     assert!(matches!(
         db_no_conn
@@ -172,6 +211,122 @@ async fn test_get_timeseries_last() {
     ));
 }
 
+#[tokio::test]
+async fn test_put_metric_uses_clock() {
+    let (db, _temp) = setup_db().await;
+    let clock = Arc::new(MockClock::new(1_710_511_200_500));
+    let db = db.with_clock(clock.clone());
+
+    db.put_metric(
+        &TenantId::Default,
+        "dev",
+        "temp",
+        MetricValue::Float(1.0),
+        None,
+    )
+    .await
+    .unwrap();
+    clock.advance(std::time::Duration::from_secs(60));
+    db.put_metric(
+        &TenantId::Default,
+        "dev",
+        "temp",
+        MetricValue::Float(2.0),
+        None,
+    )
+    .await
+    .unwrap();
+
+    let result = db
+        .get_metric(
+            &TenantId::Default,
+            "dev",
+            "temp",
+            1_710_511_200,
+            1_710_511_260,
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        *result.get_value_for_timestamp(1_710_511_200).unwrap(),
+        MetricValue::Float(1.0)
+    );
+    assert_eq!(
+        *result.get_value_for_timestamp(1_710_511_260).unwrap(),
+        MetricValue::Float(2.0)
+    );
+}
+
+#[tokio::test]
+async fn test_text_metric_values() {
+    let (db, _temp) = setup_db().await;
+
+    for (timestamp, state) in [(1710511200, "open"), (1710511260, "closed")] {
+        db.insert_metric_row(
+            &TenantId::Default,
+            "door_1",
+            "state",
+            timestamp,
+            MetricValue::Text(state.to_string()),
+        )
+        .await
+        .unwrap();
+    }
+
+    let result = db
+        .get_metric(&TenantId::Default, "door_1", "state", 0, 2000000000, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        *result.get_value_for_timestamp(1710511200).unwrap(),
+        MetricValue::Text("open".to_string())
+    );
+    let last = db
+        .get_last_metric(&TenantId::Default, "door_1", "state", 1)
+        .await
+        .unwrap();
+    assert_eq!(
+        *last.get_value_for_timestamp(1710511260).unwrap(),
+        MetricValue::Text("closed".to_string())
+    );
+    // Not numeric, nothing to aggregate
+    assert_eq!(result.mean(), None);
+}
+
+#[tokio::test]
+async fn test_data_by_prefix() {
+    let (db, _temp) = setup_db().await;
+
+    for key in ["jobs/1", "jobs/2", "jobs%x", "Jobs/3", "cache/1"] {
+        db.set_data(key, key.as_bytes()).await.unwrap();
+    }
+
+    assert_eq!(
+        db.list_keys_by_prefix("jobs/").await.unwrap(),
+        vec!["jobs/1", "jobs/2"]
+    );
+    // Case sensitive and without wildcards
+    assert_eq!(
+        db.list_keys_by_prefix("jobs%").await.unwrap(),
+        vec!["jobs%x"]
+    );
+
+    assert_eq!(db.delete_data_by_prefix("jobs/").await.unwrap(), 2);
+    assert!(db.list_keys_by_prefix("jobs/").await.unwrap().is_empty());
+    assert_eq!(
+        db.get_data("Jobs/3").await.unwrap(),
+        Some(b"Jobs/3".to_vec())
+    );
+    assert_eq!(
+        db.get_data("cache/1").await.unwrap(),
+        Some(b"cache/1".to_vec())
+    );
+    assert_eq!(db.delete_data_by_prefix("jobs/").await.unwrap(), 0);
+
+    assert_eq!(db.list_keys_by_prefix("").await.unwrap().len(), 3);
+}
+
 #[tokio::test]
 async fn test_upsert_shadow() {
     let (db, _temp) = setup_db().await;
@@ -267,6 +422,160 @@ async fn test_upsert_shadow() {
     assert_eq!(*store_shadow.get_delta_value(), Value::Null);
 }
 
+async fn setup_cached_db() -> DB {
+    let mut config = DatabaseConfig::default();
+    let db_id = Uuid::new_v4().simple();
+    config.path = format!("sqlite:file:memdb_{}?mode=memory&cache=shared", db_id);
+    // Long enough that only the explicit flushes of the test write shadows
+    config.shadow_flush_interval_ms = 3_600_000;
+    DB::open(&config).await.unwrap()
+}
+
+fn reported_update(device_id: &str, reported: Value) -> StateUpdateDocument {
+    StateUpdateDocument {
+        device_id: device_id.to_string(),
+        shadow_name: ShadowName::Default,
+        tenant_id: TenantId::Default,
+        state: StateDocument {
+            reported,
+            desired: Value::Null,
+            delta: Value::Null,
+        },
+    }
+}
+
+#[tokio::test]
+async fn test_cached_shadow_matches_persisted() {
+    let db = setup_cached_db().await;
+
+    db._upsert_shadow(&reported_update("dev1", json!({"temperature": 20.0})))
+        .await
+        .unwrap();
+    db._upsert_shadow(&reported_update("dev1", json!({"humidity": 40})))
+        .await
+        .unwrap();
+
+    let cached = db
+        ._get_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap();
+    assert_eq!(cached.get_reported_value()["temperature"], 20.0);
+    assert_eq!(cached.get_reported_value()["humidity"], 40);
+    assert_eq!(cached.get_version(), 2);
+    // Not written before the flush
+    assert!(db
+        .load_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap()
+        .is_none());
+
+    assert_eq!(db.flush_shadows().await.unwrap(), 1);
+    assert_eq!(db.flush_shadows().await.unwrap(), 0);
+    let stored = db
+        .load_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.to_json().unwrap(), cached.to_json().unwrap());
+
+    // Further updates continue from the cached shadow
+    db._upsert_shadow(&reported_update("dev1", json!({"temperature": 21.0})))
+        .await
+        .unwrap();
+    db.flush().await.unwrap();
+    let stored = db
+        .load_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.get_reported_value()["temperature"], 21.0);
+    assert_eq!(stored.get_reported_value()["humidity"], 40);
+    assert_eq!(stored.get_version(), 3);
+}
+
+#[tokio::test]
+async fn test_cached_shadow_invalidated_by_direct_writes() {
+    let db = setup_cached_db().await;
+
+    db._upsert_shadow(&reported_update("dev1", json!({"temperature": 20.0})))
+        .await
+        .unwrap();
+    db._delete_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap();
+    // The pending update is dropped with the cached shadow
+    assert!(matches!(
+        db._get_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+            .await,
+        Err(DatabaseError::NotFoundError(_))
+    ));
+    assert_eq!(db.flush_shadows().await.unwrap(), 0);
+
+    // An imported shadow replaces the cached one and is the base of the next update
+    db._upsert_shadow(&reported_update("dev1", json!({"temperature": 20.0})))
+        .await
+        .unwrap();
+    let mut imported = Shadow::new("dev1", &ShadowName::Default, &TenantId::Default);
+    imported
+        .update(&reported_update("dev1", json!({"humidity": 50})))
+        .unwrap();
+    db.put_shadow(&imported).await.unwrap();
+
+    let shadow = db
+        ._upsert_shadow(&reported_update("dev1", json!({"pressure": 1000})))
+        .await
+        .unwrap();
+    assert_eq!(shadow.get_reported_value()["humidity"], 50);
+    assert_eq!(shadow.get_reported_value()["pressure"], 1000);
+    assert!(shadow.get_reported_value().get("temperature").is_none());
+
+    db.flush_shadows().await.unwrap();
+    let stored = db
+        .load_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.to_json().unwrap(), shadow.to_json().unwrap());
+}
+
+#[tokio::test]
+async fn test_shadow_size_limit() {
+    for flush_interval_ms in [0, 3_600_000] {
+        let mut config = DatabaseConfig::default();
+        let db_id = Uuid::new_v4().simple();
+        config.path = format!("sqlite:file:memdb_{}?mode=memory&cache=shared", db_id);
+        config.shadow_flush_interval_ms = flush_interval_ms;
+        config.max_shadow_bytes = 1024;
+        let db = DB::open(&config).await.unwrap();
+
+        db._upsert_shadow(&reported_update("dev1", json!({"temperature": 20.0})))
+            .await
+            .unwrap();
+        let large = reported_update("dev1", json!({"firmware_log": "x".repeat(2000)}));
+        assert!(matches!(
+            db._upsert_shadow(&large).await,
+            Err(DatabaseError::ShadowError(ShadowError::TooLarge {
+                max: 1024,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            db.upsert_shadows(&[large]).await,
+            Err(DatabaseError::ShadowError(ShadowError::TooLarge { .. }))
+        ));
+
+        // The rejected updates leave the shadow unchanged
+        db.flush_shadows().await.unwrap();
+        let stored = db
+            .load_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.get_version(), 1);
+        assert!(stored.get_reported_value().get("firmware_log").is_none());
+    }
+}
+
 #[tokio::test]
 async fn test_store_and_get_tenant_data_config() {
     let (db, _temp) = setup_db().await;
@@ -277,13 +586,18 @@ async fn test_store_and_get_tenant_data_config() {
                 json_pointer: "/temperature".to_string(),
                 name: "temperature".to_string(),
                 data_type: DataType::Float,
+                ..Default::default()
             },
             MetricConfig {
                 json_pointer: "/temperature".to_string(),
                 name: "humidity".to_string(),
                 data_type: DataType::Int,
+                ..Default::default()
             },
         ],
+        transformations: Vec::new(),
+        payload_schema: Some(json!({"required": ["temperature"]})),
+        enable_deduplication: false,
     };
 
     db.store_tenant_data_config(&TenantId::Default, &config)
@@ -295,6 +609,10 @@ async fn test_store_and_get_tenant_data_config() {
         .unwrap()
         .unwrap();
     assert_eq!(actual.metrics.len(), 2);
+    assert_eq!(
+        actual.payload_schema,
+        Some(json!({"required": ["temperature"]}))
+    );
 }
 
 #[tokio::test]
@@ -306,7 +624,11 @@ async fn test_store_and_get_device_data_config() {
             json_pointer: "/temperature".to_string(),
             name: "temperature".to_string(),
             data_type: DataType::Float,
+            ..Default::default()
         }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
     };
     db.store_tenant_data_config(&TenantId::new("tenant2"), &tenant_config)
         .await
@@ -327,6 +649,9 @@ async fn test_store_and_get_device_data_config() {
             name: "temperature".to_string(),
             data_type: DataType::Int, // override
         }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
     };
     db.store_device_data_config(&TenantId::new("tenant2"), "deviceA", &device_config)
         .await
@@ -347,7 +672,11 @@ async fn test_store_and_get_device_data_config() {
             json_pointer: "/temp3".to_string(),
             name: "temp2".to_string(),
             data_type: DataType::Float,
+            ..Default::default()
         }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
     };
     db.store_device_data_config(&TenantId::new("tenant2"), "deviceA1", &device_config)
         .await
@@ -365,6 +694,130 @@ async fn test_store_and_get_device_data_config() {
     assert_eq!(merged.metrics[1].data_type, DataType::Float);
 }
 
+#[tokio::test]
+async fn test_data_config_explained() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant1");
+    let metric = |name: &str, data_type: DataType| MetricConfig {
+        json_pointer: format!("/{}", name),
+        name: name.to_string(),
+        data_type,
+        ..Default::default()
+    };
+    let config = |metrics| DataConfig {
+        metrics,
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
+    };
+
+    db.store_tenant_data_config(
+        &tenant_id,
+        &config(vec![
+            metric("temperature", DataType::Float),
+            metric("humidity", DataType::Float),
+        ]),
+    )
+    .await
+    .unwrap();
+    db.store_device_data_config(
+        &tenant_id,
+        "sensor_",
+        &config(vec![
+            metric("temperature", DataType::Int),
+            metric("battery", DataType::Float),
+        ]),
+    )
+    .await
+    .unwrap();
+
+    let explained = db
+        .get_data_config_explained(&tenant_id, "sensor_1")
+        .await
+        .unwrap();
+    let sources: Vec<(&str, &DataType, &ConfigSource)> = explained
+        .iter()
+        .map(|(m, source)| (m.name.as_str(), &m.data_type, source))
+        .collect();
+    let device_prefix = ConfigSource::DevicePrefix("sensor_".to_string());
+    assert_eq!(
+        sources,
+        vec![
+            ("temperature", &DataType::Int, &device_prefix),
+            ("humidity", &DataType::Float, &ConfigSource::Tenant),
+            ("battery", &DataType::Float, &device_prefix),
+        ]
+    );
+
+    // No prefix matches, only the tenant config applies
+    let explained = db
+        .get_data_config_explained(&tenant_id, "gateway_1")
+        .await
+        .unwrap();
+    assert_eq!(explained.len(), 2);
+    assert!(explained
+        .iter()
+        .all(|(_, source)| *source == ConfigSource::Tenant));
+}
+
+#[tokio::test]
+async fn test_corrupt_data_configs() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant1");
+    let config = DataConfig {
+        metrics: vec![MetricConfig {
+            json_pointer: "/temperature".to_string(),
+            name: "temperature".to_string(),
+            data_type: DataType::Float,
+            ..Default::default()
+        }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
+    };
+    db.store_tenant_data_config(&tenant_id, &config)
+        .await
+        .unwrap();
+    db.store_device_data_config(&tenant_id, "sensor_", &config)
+        .await
+        .unwrap();
+
+    let pool = db.pool.as_ref().unwrap();
+    let insert_raw = |prefix: &'static str| {
+        sqlx::query(
+            "INSERT INTO data_configs (tenant_id, device_prefix, config) VALUES ($1, $2, $3)",
+        )
+        .bind("tenant1")
+        .bind(prefix)
+        .bind("{not json")
+        .execute(&**pool)
+    };
+    insert_raw("sensor_north_").await.unwrap();
+
+    // The corrupt longer prefix is skipped, the next best one still applies
+    let result = db
+        .get_data_config(&tenant_id, Some("sensor_north_1"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(result.metrics.len(), 1);
+    assert_eq!(db.corrupt_data_configs(), 1);
+    assert_eq!(db.list_data_configs(&tenant_id).await.unwrap().len(), 2);
+    assert_eq!(db.corrupt_data_configs(), 2);
+
+    // A corrupt tenant config is an error instead of a panic
+    db.delete_data_config(&tenant_id, None).await.unwrap();
+    insert_raw("").await.unwrap();
+    assert!(matches!(
+        db.get_data_config(&tenant_id, Some("sensor_1")).await,
+        Err(DatabaseError::DatabaseValueError(_))
+    ));
+    assert!(matches!(
+        db.list_data_configs(&tenant_id).await,
+        Err(DatabaseError::DatabaseValueError(_))
+    ));
+}
+
 #[tokio::test]
 async fn test_delete_data_config() {
     let (db, _temp) = setup_db().await;
@@ -375,14 +828,22 @@ async fn test_delete_data_config() {
             json_pointer: "/temperature".to_string(),
             name: "temperature".to_string(),
             data_type: DataType::Float,
+            ..Default::default()
         }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
     };
     let device_config = DataConfig {
         metrics: vec![MetricConfig {
             json_pointer: "/humidity".to_string(),
             name: "humidity".to_string(),
             data_type: DataType::Int,
+            ..Default::default()
         }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
     };
 
     // Store configs
@@ -430,21 +891,33 @@ async fn test_list_data_configs() {
             json_pointer: "/temperature".to_string(),
             name: "temperature".to_string(),
             data_type: DataType::Float,
+            ..Default::default()
         }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
     };
     let device1_config = DataConfig {
         metrics: vec![MetricConfig {
             json_pointer: "/humidity".to_string(),
             name: "humidity".to_string(),
             data_type: DataType::Int,
+            ..Default::default()
         }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
     };
     let device2_config = DataConfig {
         metrics: vec![MetricConfig {
             json_pointer: "/pressure".to_string(),
             name: "pressure".to_string(),
             data_type: DataType::Float,
+            ..Default::default()
         }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
     };
 
     // Store configs
@@ -523,76 +996,346 @@ async fn test_tenant_put_get() {
 }
 
 #[tokio::test]
-async fn test_device_passwords() {
+async fn test_tenant_quota() {
     let (db, _temp) = setup_db().await;
-    let tenant_id = TenantId::new("tenant_auth");
-    let device_id = "device_123";
+    let tenant_id = TenantId::new("limited");
 
-    let password = "my_secure_password";
-    let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST).unwrap();
+    // Tenants without a stored record are unlimited
+    assert_eq!(
+        db.tenant_quota(&tenant_id).await.unwrap(),
+        TenantQuota::default()
+    );
+    assert!(matches!(
+        db.set_tenant_quota(&tenant_id, TenantQuota::default())
+            .await,
+        Err(DatabaseError::NotFoundError(_))
+    ));
 
-    let credential = DeviceCredential {
-        tenant_id: tenant_id.clone(),
-        device_id: device_id.to_string(),
-        username: "user1".to_string(),
-        password_hash: hash,
-        created_at: chrono::Utc::now().timestamp() as u64,
+    db.put_tenant(&Tenant::new(&tenant_id)).await.unwrap();
+    let quota = TenantQuota {
+        max_devices: Some(2),
+        max_timeseries_rows: None,
+        max_shadows: None,
+        max_messages_per_second: None,
+        message_burst: None,
     };
-
-    // Add password
-    db.add_device_password(&credential).await.unwrap();
-
-    // Verify correct password
-    let is_valid = db
-        .verify_device_password(&tenant_id, device_id, "user1", password)
+    let tenant = db
+        .set_tenant_quota(&tenant_id, quota.clone())
         .await
         .unwrap();
-    assert!(is_valid);
+    assert_eq!(tenant.quota, quota);
+    assert_eq!(db.tenant_quota(&tenant_id).await.unwrap(), quota);
+    assert_eq!(
+        db.get_tenant(&tenant_id).await.unwrap().unwrap().quota,
+        quota
+    );
 
-    // Verify wrong password
-    let is_invalid = db
-        .verify_device_password(&tenant_id, device_id, "user1", "wrong_password")
-        .await
-        .unwrap();
-    assert!(!is_invalid);
+    for device_id in ["dev1", "dev2"] {
+        db.check_device_quota(&tenant_id).await.unwrap();
+        db.put_device_metadata(&DeviceMetadata::new(device_id, &tenant_id))
+            .await
+            .unwrap();
+    }
+    assert_eq!(db.count_devices(&tenant_id).await.unwrap(), 2);
+    assert!(matches!(
+        db.check_device_quota(&tenant_id).await,
+        Err(DatabaseError::QuotaExceeded(_))
+    ));
+    // Other tenants are not affected
+    db.check_device_quota(&TenantId::Default).await.unwrap();
+}
 
-    // Verify wrong username
-    let is_invalid_user = db
-        .verify_device_password(&tenant_id, device_id, "wrong_user", password)
+#[tokio::test]
+async fn test_timeseries_quota() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("limited");
+    let value = MetricValue::Float(1.0);
+    db.insert_metric_row(&tenant_id, "dev", "temp", 1000, value.clone())
         .await
         .unwrap();
-    assert!(!is_invalid_user);
-
-    // List passwords
-    let usernames = db
-        .list_device_passwords(&tenant_id, device_id)
+    let quota = TenantQuota {
+        max_timeseries_rows: Some(4),
+        ..Default::default()
+    };
+    db.put_tenant(&Tenant::new(&tenant_id).with_quota(quota))
         .await
         .unwrap();
-    assert_eq!(usernames.len(), 1);
-    assert_eq!(usernames[0], "user1");
 
-    // Override existing
-    let hash2 = bcrypt::hash("newword", bcrypt::DEFAULT_COST).unwrap();
-    let credential2 = DeviceCredential {
+    // Rows stored before the quota count as well
+    for timestamp in [1001, 1002] {
+        db.insert_metric_row(&tenant_id, "dev", "temp", timestamp, value.clone())
+            .await
+            .unwrap();
+    }
+    let row = |tenant_id: &TenantId, timestamp: u64| MetricRow {
         tenant_id: tenant_id.clone(),
-        device_id: device_id.to_string(),
-        username: "user1".to_string(),
-        password_hash: hash2,
-        created_at: chrono::Utc::now().timestamp() as u64,
+        device_id: "dev".to_string(),
+        metric_name: "temp".to_string(),
+        timestamp,
+        value: MetricValue::Float(2.0),
+        tags: None,
     };
-    db.add_device_password(&credential2).await.unwrap();
-
-    // Verify overridden password
-    let is_valid2 = db
-        .verify_device_password(&tenant_id, device_id, "user1", "newword")
+    // The batch of the tenant doesn't fit, rows of other tenants are still written
+    let rows = vec![
+        row(&tenant_id, 1003),
+        row(&tenant_id, 1004),
+        row(&TenantId::Default, 1003),
+    ];
+    assert_eq!(db.insert_metric_rows(&rows).await.unwrap(), 1);
+    assert_eq!(db.insert_metric_rows(&rows[..1]).await.unwrap(), 1);
+    assert!(matches!(
+        db.insert_metric_row(&tenant_id, "dev", "temp", 1005, value.clone())
+            .await,
+        Err(DatabaseError::QuotaExceeded(_))
+    ));
+    let ts = db
+        .get_metric(&tenant_id, "dev", "temp", 0, 2000, None)
         .await
         .unwrap();
-    assert!(is_valid2);
-    let is_invalid_old = db
-        .verify_device_password(&tenant_id, device_id, "user1", password)
+    assert_eq!(ts.len(), 4);
+
+    // Raising the quota applies to the next write
+    let quota = TenantQuota {
+        max_timeseries_rows: Some(5),
+        ..Default::default()
+    };
+    db.set_tenant_quota(&tenant_id, quota).await.unwrap();
+    db.insert_metric_row(&tenant_id, "dev", "temp", 1005, value)
         .await
         .unwrap();
-    assert!(!is_invalid_old);
+}
+
+#[tokio::test]
+async fn test_shadow_quota() {
+    let (db, _temp) = setup_db().await;
+    let cached_db = setup_cached_db().await;
+    let quota = TenantQuota {
+        max_shadows: Some(2),
+        ..Default::default()
+    };
+
+    for db in [&db, &cached_db] {
+        db.put_tenant(&Tenant::new(&TenantId::Default).with_quota(quota.clone()))
+            .await
+            .unwrap();
+        for device_id in ["dev1", "dev2"] {
+            db._upsert_shadow(&reported_update(device_id, json!({"mode": "eco"})))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            db._upsert_shadow(&reported_update("dev3", json!({"mode": "eco"})))
+                .await,
+            Err(DatabaseError::QuotaExceeded(_))
+        ));
+        // Existing shadows can still be updated, a batch creating one more is rejected
+        db._upsert_shadow(&reported_update("dev1", json!({"mode": "boost"})))
+            .await
+            .unwrap();
+        let updates = vec![
+            desired_update("dev2", json!({"mode": "boost"})),
+            desired_update("dev3", json!({"mode": "boost"})),
+        ];
+        assert!(matches!(
+            db.upsert_shadows(&updates).await,
+            Err(DatabaseError::QuotaExceeded(_))
+        ));
+        assert!(db
+            .load_shadow("dev3", &ShadowName::Default, &TenantId::Default)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Deleting a shadow frees its slot
+        db._delete_shadow("dev2", &ShadowName::Default, &TenantId::Default)
+            .await
+            .unwrap();
+        db._upsert_shadow(&reported_update("dev3", json!({"mode": "eco"})))
+            .await
+            .unwrap();
+        assert!(matches!(
+            db._upsert_shadow(&reported_update("dev4", json!({"mode": "eco"})))
+                .await,
+            Err(DatabaseError::QuotaExceeded(_))
+        ));
+    }
+    // The shadows are counted once, later checks don't write the cached shadows
+    assert!(cached_db
+        .load_shadow("dev3", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_tenant_usage() {
+    let (db, _temp) = setup_db().await;
+    let cached_db = setup_cached_db().await;
+    let other_tenant = TenantId::new("other");
+
+    for db in [&db, &cached_db] {
+        assert_eq!(
+            db.tenant_usage(&TenantId::Default).await.unwrap(),
+            TenantUsage::default()
+        );
+        for device_id in ["dev1", "dev2"] {
+            db.put_device_metadata(&DeviceMetadata::new(device_id, &TenantId::Default))
+                .await
+                .unwrap();
+            db._upsert_shadow(&reported_update(device_id, json!({"mode": "eco"})))
+                .await
+                .unwrap();
+        }
+        for timestamp in [1000, 1001, 1002] {
+            db.insert_metric_row(
+                &TenantId::Default,
+                "dev1",
+                "temp",
+                timestamp,
+                MetricValue::Float(1.0),
+            )
+            .await
+            .unwrap();
+        }
+        // Data of other tenants isn't counted
+        db.put_device_metadata(&DeviceMetadata::new("dev1", &other_tenant))
+            .await
+            .unwrap();
+        db.insert_metric_row(&other_tenant, "dev1", "temp", 1000, MetricValue::Float(1.0))
+            .await
+            .unwrap();
+
+        let usage = db.tenant_usage(&TenantId::Default).await.unwrap();
+        assert_eq!(usage.device_count, 2);
+        assert_eq!(usage.shadow_count, 2);
+        assert_eq!(usage.metric_row_count, 3);
+        // Only estimated on Postgres
+        assert_eq!(usage.approximate_bytes, None);
+        let usage = db.tenant_usage(&other_tenant).await.unwrap();
+        assert_eq!(usage.device_count, 1);
+        assert_eq!(usage.shadow_count, 0);
+        assert_eq!(usage.metric_row_count, 1);
+    }
+}
+
+#[tokio::test]
+async fn test_provisioning_token() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("acme");
+    let (token, stored) = ProvisioningToken::generate(&tenant_id, "sensor_1", 2000).unwrap();
+    assert_ne!(token, stored.token_hash);
+    assert_eq!(stored.token_hash, ProvisioningToken::hash(&token));
+    db.store_provisioning_token(&stored).await.unwrap();
+
+    // Expired tokens are refused
+    assert!(db
+        .redeem_provisioning_token(&stored.token_hash, 2000)
+        .await
+        .unwrap()
+        .is_none());
+    let redeemed = db
+        .redeem_provisioning_token(&stored.token_hash, 1000)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(redeemed.device_id, "sensor_1");
+    assert_eq!(redeemed.tenant_id, tenant_id);
+    assert!(redeemed.used);
+
+    // A token can only be used once
+    assert!(db
+        .redeem_provisioning_token(&stored.token_hash, 1000)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db
+        .redeem_provisioning_token(&ProvisioningToken::hash("unknown"), 1000)
+        .await
+        .unwrap()
+        .is_none());
+
+    // A released token can be redeemed again
+    db.release_provisioning_token(&stored.token_hash)
+        .await
+        .unwrap();
+    assert!(db
+        .redeem_provisioning_token(&stored.token_hash, 1000)
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn test_device_passwords() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant_auth");
+    let device_id = "device_123";
+
+    let password = "my_secure_password";
+    let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST).unwrap();
+
+    let credential = DeviceCredential {
+        tenant_id: tenant_id.clone(),
+        device_id: device_id.to_string(),
+        username: "user1".to_string(),
+        password_hash: hash,
+        created_at: chrono::Utc::now().timestamp() as u64,
+    };
+
+    // Add password
+    db.add_device_password(&credential).await.unwrap();
+
+    // Verify correct password
+    let is_valid = db
+        .verify_device_password(&tenant_id, device_id, "user1", password)
+        .await
+        .unwrap();
+    assert!(is_valid);
+
+    // Verify wrong password
+    let is_invalid = db
+        .verify_device_password(&tenant_id, device_id, "user1", "wrong_password")
+        .await
+        .unwrap();
+    assert!(!is_invalid);
+
+    // Verify wrong username
+    let is_invalid_user = db
+        .verify_device_password(&tenant_id, device_id, "wrong_user", password)
+        .await
+        .unwrap();
+    assert!(!is_invalid_user);
+
+    // List passwords
+    let usernames = db
+        .list_device_passwords(&tenant_id, device_id)
+        .await
+        .unwrap();
+    assert_eq!(usernames.len(), 1);
+    assert_eq!(usernames[0], "user1");
+
+    // Override existing
+    let hash2 = bcrypt::hash("newword", bcrypt::DEFAULT_COST).unwrap();
+    let credential2 = DeviceCredential {
+        tenant_id: tenant_id.clone(),
+        device_id: device_id.to_string(),
+        username: "user1".to_string(),
+        password_hash: hash2,
+        created_at: chrono::Utc::now().timestamp() as u64,
+    };
+    db.add_device_password(&credential2).await.unwrap();
+
+    // Verify overridden password
+    let is_valid2 = db
+        .verify_device_password(&tenant_id, device_id, "user1", "newword")
+        .await
+        .unwrap();
+    assert!(is_valid2);
+    let is_invalid_old = db
+        .verify_device_password(&tenant_id, device_id, "user1", password)
+        .await
+        .unwrap();
+    assert!(!is_invalid_old);
 
     // Ensure list still returns one item (overridden)
     let usernames = db
@@ -601,3 +1344,1425 @@ async fn test_device_passwords() {
         .unwrap();
     assert_eq!(usernames.len(), 1);
 }
+
+#[tokio::test]
+async fn test_set_device_password() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant_auth");
+
+    let credential = db
+        .set_device_password(&tenant_id, "device_123", "user1", "plaintext", Some(4))
+        .await
+        .unwrap();
+    assert_ne!(credential.password_hash, "plaintext");
+    // The cost is part of the hash: $2b$04$...
+    assert!(credential.password_hash.starts_with("$2b$04$"));
+    assert!(db
+        .verify_device_password(&tenant_id, "device_123", "user1", "plaintext")
+        .await
+        .unwrap());
+    assert!(!db
+        .verify_device_password(&tenant_id, "device_123", "user1", "wrong")
+        .await
+        .unwrap());
+
+    // Invalid costs are rejected and nothing is stored
+    let result = db
+        .set_device_password(&tenant_id, "device_123", "user2", "plaintext", Some(2))
+        .await;
+    assert!(matches!(result, Err(DatabaseError::DatabaseValueError(_))));
+    assert_eq!(
+        db.list_device_passwords(&tenant_id, "device_123")
+            .await
+            .unwrap(),
+        vec!["user1".to_string()]
+    );
+}
+
+#[tokio::test]
+async fn test_dead_letters() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant_dlq");
+
+    for (i, device_id) in ["device_a", "device_b", "device_a"].into_iter().enumerate() {
+        db.store_dead_letter(&DeadLetter {
+            tenant_id: tenant_id.clone(),
+            device_id: device_id.to_string(),
+            topic: format!("things/{}/shadow/update", device_id),
+            payload: format!("{{broken {}", i),
+            error: "Invalid Json: expected value".to_string(),
+            failed_at: 1000 + i as u64,
+        })
+        .await
+        .unwrap();
+    }
+
+    let all = db.list_dead_letters(&tenant_id, None, 10).await.unwrap();
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[0].failed_at, 1002);
+    assert_eq!(all[0].payload, "{broken 2");
+    assert_eq!(all[2].failed_at, 1000);
+
+    let device_a = db
+        .list_dead_letters(&tenant_id, Some("device_a"), 1)
+        .await
+        .unwrap();
+    assert_eq!(device_a.len(), 1);
+    assert_eq!(device_a[0].device_id, "device_a");
+    assert_eq!(device_a[0].failed_at, 1002);
+
+    // Other tenants see nothing
+    assert!(db
+        .list_dead_letters(&TenantId::Default, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_alarm_rules_and_events() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant_alarms");
+    let rule = |alarm_id: &str, condition: AlarmCondition, threshold: f64| AlarmRule {
+        tenant_id: tenant_id.clone(),
+        device_id: "boiler".to_string(),
+        metric_name: "temp".to_string(),
+        condition,
+        threshold,
+        alarm_id: alarm_id.to_string(),
+    };
+
+    db.store_alarm_rule(&rule("too_hot", AlarmCondition::Above, 90.0))
+        .await
+        .unwrap();
+    db.store_alarm_rule(&rule("frozen", AlarmCondition::Below, 0.0))
+        .await
+        .unwrap();
+    // Same id replaces the rule
+    db.store_alarm_rule(&rule("too_hot", AlarmCondition::Above, 80.0))
+        .await
+        .unwrap();
+    let rules = db.get_alarm_rules(&tenant_id, "boiler").await.unwrap();
+    assert_eq!(
+        rules,
+        vec![
+            rule("frozen", AlarmCondition::Below, 0.0),
+            rule("too_hot", AlarmCondition::Above, 80.0),
+        ]
+    );
+    assert!(db
+        .get_alarm_rules(&TenantId::Default, "boiler")
+        .await
+        .unwrap()
+        .is_empty());
+
+    for (alarm_id, triggered_at, value) in [("too_hot", 1000, 85.0), ("frozen", 1001, -2.0)] {
+        let event = AlarmEvent {
+            alarm_id: alarm_id.to_string(),
+            triggered_at,
+            value,
+        };
+        db.insert_alarm_event(&tenant_id, "boiler", &event)
+            .await
+            .unwrap();
+    }
+    let events = db
+        .list_alarm_events(&tenant_id, "boiler", 10)
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].alarm_id, "frozen");
+    assert_eq!(events[0].value, -2.0);
+    assert_eq!(events[1].triggered_at, 1000);
+    let latest = db.list_alarm_events(&tenant_id, "boiler", 1).await.unwrap();
+    assert_eq!(latest, events[..1]);
+    assert!(db
+        .list_alarm_events(&tenant_id, "other", 10)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_shadow_webhooks() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant_webhooks");
+    let webhook = |webhook_id: &str, url: &str, events: Vec<ShadowEvent>| ShadowWebhook {
+        webhook_id: webhook_id.to_string(),
+        url: url.to_string(),
+        secret: Some("s3cret".to_string()),
+        events,
+    };
+
+    assert!(db
+        .get_shadow_webhooks(&tenant_id, "lamp")
+        .await
+        .unwrap()
+        .is_empty());
+    db.store_shadow_webhook(
+        &tenant_id,
+        "lamp",
+        &webhook("b", "http://b", vec![ShadowEvent::Updated]),
+    )
+    .await
+    .unwrap();
+    db.store_shadow_webhook(
+        &tenant_id,
+        "lamp",
+        &webhook("a", "http://a", vec![ShadowEvent::DeltaCleared]),
+    )
+    .await
+    .unwrap();
+    // Same id replaces the webhook, the cached webhooks are reloaded
+    let replaced = webhook(
+        "b",
+        "http://b2",
+        vec![ShadowEvent::Updated, ShadowEvent::DeltaCleared],
+    );
+    db.store_shadow_webhook(&tenant_id, "lamp", &replaced)
+        .await
+        .unwrap();
+    let webhooks = db.get_shadow_webhooks(&tenant_id, "lamp").await.unwrap();
+    assert_eq!(
+        webhooks,
+        vec![
+            webhook("a", "http://a", vec![ShadowEvent::DeltaCleared]),
+            replaced
+        ]
+    );
+    assert!(db
+        .get_shadow_webhooks(&TenantId::Default, "lamp")
+        .await
+        .unwrap()
+        .is_empty());
+
+    assert!(db
+        .delete_shadow_webhook(&tenant_id, "lamp", "a")
+        .await
+        .unwrap());
+    assert!(!db
+        .delete_shadow_webhook(&tenant_id, "lamp", "a")
+        .await
+        .unwrap());
+    let webhooks = db.get_shadow_webhooks(&tenant_id, "lamp").await.unwrap();
+    assert_eq!(webhooks.len(), 1);
+    assert_eq!(webhooks[0].webhook_id, "b");
+}
+
+#[tokio::test]
+async fn test_insert_metric_rows() {
+    let (db, _temp) = setup_db().await;
+    let row = |timestamp: u64, value: f64| MetricRow {
+        tenant_id: TenantId::Default,
+        device_id: "batch_device".to_string(),
+        metric_name: "temperature".to_string(),
+        timestamp,
+        value: MetricValue::Float(value),
+        tags: None,
+    };
+
+    // The non-finite value is skipped, the rest of the batch is written
+    let rows = vec![row(1000, 1.0), row(1001, f64::NAN), row(1002, 3.0)];
+    let written = db.insert_metric_rows(&rows).await.unwrap();
+    assert_eq!(written, 2);
+    assert_eq!(db.non_finite_rejected(), 1);
+
+    let ts = db
+        .get_metric(
+            &TenantId::Default,
+            "batch_device",
+            "temperature",
+            0,
+            2000,
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ts.len(), 2);
+    assert_eq!(db.insert_metric_rows(&[]).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_extraction_errors() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant_extract");
+
+    let first = db
+        .record_extraction_error(&tenant_id, "dev", "No metric", 100)
+        .await
+        .unwrap();
+    assert!(first);
+    let first = db
+        .record_extraction_error(&tenant_id, "dev", "No metric", 105)
+        .await
+        .unwrap();
+    assert!(!first);
+    let first = db
+        .record_extraction_error(&tenant_id, "dev", "Other error", 103)
+        .await
+        .unwrap();
+    assert!(!first);
+
+    let errors = db.list_extraction_errors(&tenant_id, "dev").await.unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].error, "No metric");
+    assert_eq!(errors[0].count, 2);
+    assert_eq!(errors[0].last_seen, 105);
+    assert_eq!(errors[1].count, 1);
+    assert!(db
+        .list_extraction_errors(&tenant_id, "other")
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_metric_tags() {
+    let (db, _temp) = setup_db().await;
+
+    let north = json!({"sensor": "north", "floor": 1});
+    let south = json!({"sensor": "south", "floor": 1});
+    for (i, (tags, value)) in [(&north, 20.0), (&south, 25.0)].into_iter().enumerate() {
+        db.insert_tagged_metric_row(
+            &TenantId::Default,
+            "tag_dev",
+            "temp",
+            1710511200 + i as u64,
+            MetricValue::Float(value),
+            Some(tags),
+        )
+        .await
+        .unwrap();
+    }
+    db.insert_metric_row(
+        &TenantId::Default,
+        "tag_dev",
+        "temp",
+        1710511210,
+        MetricValue::Float(30.0),
+    )
+    .await
+    .unwrap();
+
+    let get = |filter: Value| {
+        let db = &db;
+        async move {
+            db.get_metric(
+                &TenantId::Default,
+                "tag_dev",
+                "temp",
+                0,
+                u32::MAX as u64,
+                Some(&filter),
+            )
+            .await
+            .unwrap()
+        }
+    };
+
+    let result = get(json!({"sensor": "north"})).await;
+    assert_eq!(result.len(), 1);
+    assert_eq!(
+        *result.get_value_for_timestamp(1710511200).unwrap(),
+        MetricValue::Float(20.0)
+    );
+    let result = get(json!({"sensor": "south"})).await;
+    assert_eq!(result.len(), 1);
+    assert_eq!(
+        *result.get_value_for_timestamp(1710511201).unwrap(),
+        MetricValue::Float(25.0)
+    );
+    assert_eq!(get(json!({"floor": 1})).await.len(), 2);
+    assert!(get(json!({"sensor": "east"})).await.is_empty());
+    // An empty filter matches everything, as does no filter at all
+    assert_eq!(get(json!({})).await.len(), 3);
+    let all = db
+        .get_metric(
+            &TenantId::Default,
+            "tag_dev",
+            "temp",
+            0,
+            u32::MAX as u64,
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(all.len(), 3);
+}
+
+#[test]
+fn test_extract_metric_tags() {
+    let config = DataConfig {
+        metrics: vec![
+            MetricConfig {
+                json_pointer: "/temp".to_string(),
+                name: "temp".to_string(),
+                tags_pointer: Some("/labels".to_string()),
+                tags: Some(json!({"sensor": "default", "site": "vienna"})),
+                ..Default::default()
+            },
+            MetricConfig {
+                json_pointer: "/humidity".to_string(),
+                name: "humidity".to_string(),
+                ..Default::default()
+            },
+        ],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
+    };
+
+    let metrics = config.extract_metrics_from_json(json!({
+        "temp": 21.5,
+        "humidity": 40.0,
+        "labels": {"sensor": "north"}
+    }));
+
+    assert_eq!(
+        metrics,
+        vec![
+            ExtractedMetric {
+                name: "temp".to_string(),
+                value: MetricValue::Float(21.5),
+                tags: Some(json!({"sensor": "north", "site": "vienna"})),
+                timestamp: None,
+            },
+            ExtractedMetric {
+                name: "humidity".to_string(),
+                value: MetricValue::Float(40.0),
+                tags: None,
+                timestamp: None,
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_insert_rejects_non_finite_floats() {
+    let (db, _temp) = setup_db().await;
+
+    for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        let result = db
+            .insert_metric_row(
+                &TenantId::Default,
+                "nf_dev",
+                "temp",
+                1710511200,
+                MetricValue::Float(value),
+            )
+            .await;
+        assert!(matches!(result, Err(DatabaseError::DatabaseValueError(_))));
+    }
+    assert_eq!(db.non_finite_rejected(), 3);
+    let stored = db
+        .get_last_metric(&TenantId::Default, "nf_dev", "temp", 10)
+        .await
+        .unwrap();
+    assert!(stored.is_empty());
+}
+
+#[tokio::test]
+async fn test_insert_clamps_infinite_floats() {
+    let mut config = DatabaseConfig::default();
+    config.path = format!(
+        "sqlite:file:memdb_{}?mode=memory&cache=shared",
+        Uuid::new_v4().simple()
+    );
+    config.non_finite_policy = NonFinitePolicy::Clamp;
+    let db = DB::open(&config).await.unwrap();
+
+    for (i, value) in [f64::INFINITY, f64::NEG_INFINITY].into_iter().enumerate() {
+        db.insert_metric_row(
+            &TenantId::Default,
+            "nf_dev",
+            "temp",
+            1710511200 + i as u64,
+            MetricValue::Float(value),
+        )
+        .await
+        .unwrap();
+    }
+    // NaN has no sensible clamped value
+    assert!(db
+        .insert_metric_row(
+            &TenantId::Default,
+            "nf_dev",
+            "temp",
+            1710511210,
+            MetricValue::Float(f64::NAN),
+        )
+        .await
+        .is_err());
+    assert_eq!(db.non_finite_rejected(), 1);
+
+    let stored = db
+        .get_last_metric(&TenantId::Default, "nf_dev", "temp", 10)
+        .await
+        .unwrap();
+    assert_eq!(stored.len(), 2);
+    assert_eq!(
+        *stored.get_value_for_timestamp(1710511200).unwrap(),
+        MetricValue::Float(f64::MAX)
+    );
+    assert_eq!(
+        *stored.get_value_for_timestamp(1710511201).unwrap(),
+        MetricValue::Float(f64::MIN)
+    );
+}
+
+#[tokio::test]
+async fn test_export_device() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::Default;
+
+    db.put_device_metadata(&DeviceMetadata {
+        device_id: "export_dev".to_string(),
+        tenant_id: tenant_id.clone(),
+        certificate: Some("cert".to_string()),
+        key: None,
+        created_at: 1710511200,
+        tags: HashMap::new(),
+        labels: HashMap::new(),
+        status: DeviceStatus::Active,
+        firmware_version: None,
+        target_firmware_version: None,
+        ota_status: None,
+    })
+    .await
+    .unwrap();
+    for shadow_name in [ShadowName::Default, ShadowName::new("config")] {
+        db._upsert_shadow(&StateUpdateDocument {
+            device_id: "export_dev".to_string(),
+            shadow_name,
+            tenant_id: tenant_id.clone(),
+            state: StateDocument {
+                reported: json!({"temperature": 22.5}),
+                desired: Value::Null,
+                delta: Value::Null,
+            },
+        })
+        .await
+        .unwrap();
+    }
+    db.store_tenant_data_config(
+        &tenant_id,
+        &DataConfig {
+            metrics: vec![MetricConfig {
+                json_pointer: "/temperature".to_string(),
+                name: "temperature".to_string(),
+                ..Default::default()
+            }],
+            transformations: Vec::new(),
+            payload_schema: None,
+            enable_deduplication: false,
+        },
+    )
+    .await
+    .unwrap();
+    for i in 0..3 {
+        db.insert_metric_row(
+            &tenant_id,
+            "export_dev",
+            "temperature",
+            1710511200 + i,
+            MetricValue::Float(20.0 + i as f64),
+        )
+        .await
+        .unwrap();
+    }
+
+    let export = db
+        .export_device(&tenant_id, "export_dev", true)
+        .await
+        .unwrap();
+    assert_eq!(export.device_id, "export_dev");
+    assert_eq!(
+        export.metadata.unwrap().certificate.as_deref(),
+        Some("cert")
+    );
+    assert_eq!(export.shadows.len(), 2);
+    assert_eq!(export.data_config.unwrap().metrics[0].name, "temperature");
+    let metrics = export.metrics.unwrap();
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0].metric, "temperature");
+    assert_eq!(metrics[0].data.len(), 3);
+
+    let export = db
+        .export_device(&tenant_id, "export_dev", false)
+        .await
+        .unwrap();
+    assert!(export.metrics.is_none());
+
+    let missing = db
+        .export_device(&tenant_id, "missing", false)
+        .await
+        .unwrap();
+    assert!(missing.metadata.is_none());
+    assert!(missing.shadows.is_empty());
+}
+
+#[tokio::test]
+async fn test_device_groups() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::Default;
+    let group = DeviceGroup {
+        group_id: "floor_1".to_string(),
+        tenant_id: tenant_id.clone(),
+        device_ids: vec![
+            "dev_b".to_string(),
+            "dev_a".to_string(),
+            "dev_b".to_string(),
+        ],
+        created_at: 1700000000,
+    };
+
+    assert!(db.create_group(&group).await.unwrap());
+    assert!(!db.create_group(&group).await.unwrap());
+    let stored = db.get_group(&tenant_id, "floor_1").await.unwrap().unwrap();
+    assert_eq!(stored.device_ids, vec!["dev_a", "dev_b"]);
+    assert_eq!(stored.created_at, 1700000000);
+
+    assert!(db
+        .add_device_to_group(&tenant_id, "floor_1", "dev_c")
+        .await
+        .unwrap());
+    assert!(!db
+        .add_device_to_group(&tenant_id, "floor_1", "dev_c")
+        .await
+        .unwrap());
+    assert!(db
+        .remove_device_from_group(&tenant_id, "floor_1", "dev_a")
+        .await
+        .unwrap());
+    assert!(!db
+        .remove_device_from_group(&tenant_id, "floor_1", "dev_a")
+        .await
+        .unwrap());
+    assert_eq!(
+        db.list_group_devices(&tenant_id, "floor_1").await.unwrap(),
+        vec!["dev_b", "dev_c"]
+    );
+
+    // Groups are scoped to their tenant
+    let other = TenantId::new("other");
+    assert!(db.get_group(&other, "floor_1").await.unwrap().is_none());
+    assert!(matches!(
+        db.list_group_devices(&other, "floor_1").await,
+        Err(DatabaseError::NotFoundError(_))
+    ));
+    assert!(matches!(
+        db.add_device_to_group(&other, "floor_1", "dev_a").await,
+        Err(DatabaseError::NotFoundError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_device_tags() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::Default;
+    for device_id in ["dev_b", "dev_a", "dev_c"] {
+        db.put_device_metadata(&DeviceMetadata::new(device_id, &tenant_id))
+            .await
+            .unwrap();
+    }
+    let tags = |location: &str| {
+        HashMap::from([
+            ("location".to_string(), location.to_string()),
+            ("fw.channel".to_string(), "beta".to_string()),
+        ])
+    };
+    db.set_device_tags(&tenant_id, "dev_b", tags("warehouse-3"))
+        .await
+        .unwrap();
+    db.set_device_tags(&tenant_id, "dev_a", tags("warehouse-3"))
+        .await
+        .unwrap();
+    db.set_device_tags(&tenant_id, "dev_c", tags("office"))
+        .await
+        .unwrap();
+
+    let devices = db
+        .get_devices_by_tag(&tenant_id, "location", "warehouse-3")
+        .await
+        .unwrap();
+    let device_ids: Vec<&str> = devices.iter().map(|d| d.device_id.as_str()).collect();
+    assert_eq!(device_ids, vec!["dev_a", "dev_b"]);
+    assert_eq!(devices[0].tags, tags("warehouse-3"));
+    let devices = db
+        .get_devices_by_tag(&tenant_id, "fw.channel", "beta")
+        .await
+        .unwrap();
+    assert_eq!(devices.len(), 3);
+    assert!(db
+        .get_devices_by_tag(&TenantId::from_str("other"), "location", "office")
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Setting tags replaces the previous ones
+    db.set_device_tags(&tenant_id, "dev_c", HashMap::new())
+        .await
+        .unwrap();
+    assert!(db
+        .get_devices_by_tag(&tenant_id, "location", "office")
+        .await
+        .unwrap()
+        .is_empty());
+
+    assert!(matches!(
+        db.set_device_tags(&tenant_id, "missing", tags("office"))
+            .await,
+        Err(DatabaseError::NotFoundError(_))
+    ));
+    let invalid = HashMap::from([("a\"b".to_string(), "x".to_string())]);
+    assert!(matches!(
+        db.set_device_tags(&tenant_id, "dev_a", invalid).await,
+        Err(DatabaseError::InvalidKeyError(_))
+    ));
+    assert!(matches!(
+        db.get_devices_by_tag(&tenant_id, "", "x").await,
+        Err(DatabaseError::InvalidKeyError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_device_labels() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::Default;
+    for device_id in ["dev_b", "dev_a", "dev_c"] {
+        db.put_device_metadata(&DeviceMetadata::new(device_id, &tenant_id))
+            .await
+            .unwrap();
+    }
+    let berlin = LabelSelector::parse("site=berlin").unwrap();
+    for device_id in ["dev_b", "dev_a"] {
+        let changes = HashMap::from([
+            ("site".to_string(), Some("berlin".to_string())),
+            ("floor".to_string(), Some("2".to_string())),
+        ]);
+        db.update_device_labels(&tenant_id, device_id, changes)
+            .await
+            .unwrap();
+    }
+    let changes = HashMap::from([("site".to_string(), Some("paris".to_string()))]);
+    db.update_device_labels(&tenant_id, "dev_c", changes)
+        .await
+        .unwrap();
+
+    let devices = db.get_devices_by_label(&tenant_id, &berlin).await.unwrap();
+    let device_ids: Vec<&str> = devices.iter().map(|d| d.device_id.as_str()).collect();
+    assert_eq!(device_ids, vec!["dev_a", "dev_b"]);
+    assert_eq!(
+        devices[0].labels.get("floor").map(String::as_str),
+        Some("2")
+    );
+    assert!(db
+        .get_devices_by_label(&TenantId::from_str("other"), &berlin)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Labels not in the update are kept, null values remove labels
+    let changes = HashMap::from([
+        ("site".to_string(), Some("paris".to_string())),
+        ("floor".to_string(), None),
+    ]);
+    let labels = db
+        .update_device_labels(&tenant_id, "dev_b", changes)
+        .await
+        .unwrap();
+    assert_eq!(
+        labels,
+        HashMap::from([("site".to_string(), "paris".to_string())])
+    );
+    let paris = LabelSelector::parse("site=paris").unwrap();
+    let devices = db.get_devices_by_label(&tenant_id, &paris).await.unwrap();
+    assert_eq!(devices.len(), 2);
+    let floor = LabelSelector::parse("floor=2").unwrap();
+    let devices = db.get_devices_by_label(&tenant_id, &floor).await.unwrap();
+    let device_ids: Vec<&str> = devices.iter().map(|d| d.device_id.as_str()).collect();
+    assert_eq!(device_ids, vec!["dev_a"]);
+
+    // Replacing the metadata replaces the labels, deleting the device removes them
+    db.put_device_metadata(&DeviceMetadata::new("dev_c", &tenant_id))
+        .await
+        .unwrap();
+    db.delete_device_metadata(&tenant_id, "dev_b")
+        .await
+        .unwrap();
+    assert!(db
+        .get_devices_by_label(&tenant_id, &paris)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let changes = HashMap::from([("site".to_string(), Some("berlin".to_string()))]);
+    assert!(matches!(
+        db.update_device_labels(&tenant_id, "missing", changes)
+            .await,
+        Err(DatabaseError::NotFoundError(_))
+    ));
+    let invalid = HashMap::from([("a=b".to_string(), Some("x".to_string()))]);
+    assert!(matches!(
+        db.update_device_labels(&tenant_id, "dev_a", invalid).await,
+        Err(DatabaseError::InvalidKeyError(_))
+    ));
+    assert!(LabelSelector::parse("site:berlin").is_none());
+    assert!(LabelSelector::parse("=berlin").is_none());
+}
+
+#[tokio::test]
+async fn test_label_data_configs() {
+    let (db, _temp) = setup_db().await;
+    let tenant_id = TenantId::new("tenant1");
+    let metric = |name: &str, data_type: DataType| MetricConfig {
+        json_pointer: format!("/{}", name),
+        name: name.to_string(),
+        data_type,
+        ..Default::default()
+    };
+    let config = |metrics| DataConfig {
+        metrics,
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
+    };
+    let berlin = LabelSelector::parse("site=berlin").unwrap();
+
+    db.store_tenant_data_config(
+        &tenant_id,
+        &config(vec![
+            metric("temperature", DataType::Float),
+            metric("humidity", DataType::Float),
+        ]),
+    )
+    .await
+    .unwrap();
+    db.store_label_data_config(
+        &tenant_id,
+        &berlin,
+        &config(vec![
+            metric("humidity", DataType::Int),
+            metric("pressure", DataType::Float),
+        ]),
+    )
+    .await
+    .unwrap();
+    db.store_device_data_config(
+        &tenant_id,
+        "sensor_",
+        &config(vec![metric("pressure", DataType::Int)]),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        db.get_label_data_config(&tenant_id, &berlin)
+            .await
+            .unwrap()
+            .unwrap()
+            .metrics
+            .len(),
+        2
+    );
+
+    let mut device = DeviceMetadata::new("sensor_1", &tenant_id);
+    device
+        .labels
+        .insert("site".to_string(), "berlin".to_string());
+    db.put_device_metadata(&device).await.unwrap();
+    db.put_device_metadata(&DeviceMetadata::new("gateway_1", &tenant_id))
+        .await
+        .unwrap();
+
+    // The label config overrides the tenant config, the prefix config overrides both
+    let explained = db
+        .get_data_config_explained(&tenant_id, "sensor_1")
+        .await
+        .unwrap();
+    let sources: Vec<(&str, &DataType, &ConfigSource)> = explained
+        .iter()
+        .map(|(m, source)| (m.name.as_str(), &m.data_type, source))
+        .collect();
+    let label = ConfigSource::Label("site=berlin".to_string());
+    let device_prefix = ConfigSource::DevicePrefix("sensor_".to_string());
+    assert_eq!(
+        sources,
+        vec![
+            ("temperature", &DataType::Float, &ConfigSource::Tenant),
+            ("humidity", &DataType::Int, &label),
+            ("pressure", &DataType::Int, &device_prefix),
+        ]
+    );
+    let effective = db
+        .get_data_config(&tenant_id, Some("sensor_1"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(effective.metrics.len(), 3);
+
+    // Devices without the label only get the tenant config
+    let effective = db
+        .get_data_config(&tenant_id, Some("gateway_1"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(effective.metrics.len(), 2);
+
+    let configs = db.list_data_configs(&tenant_id).await.unwrap();
+    assert_eq!(configs.len(), 3);
+    let label_entry = configs
+        .iter()
+        .find(|entry| entry.label_selector.is_some())
+        .unwrap();
+    assert_eq!(label_entry.label_selector.as_deref(), Some("site=berlin"));
+    assert_eq!(label_entry.device_prefix, None);
+
+    db.delete_label_data_config(&tenant_id, &berlin)
+        .await
+        .unwrap();
+    assert!(db
+        .get_label_data_config(&tenant_id, &berlin)
+        .await
+        .unwrap()
+        .is_none());
+    let explained = db
+        .get_data_config_explained(&tenant_id, "sensor_1")
+        .await
+        .unwrap();
+    assert!(explained.iter().all(|(_, source)| *source != label));
+}
+
+/// Database with the tables of a version before schema migrations, the returned pool
+/// keeps the in memory database alive
+async fn setup_legacy_db() -> (DatabaseConfig, AnyPool) {
+    sqlx::any::install_default_drivers();
+    let mut config = DatabaseConfig::default();
+    config.path = format!(
+        "sqlite:file:memdb_{}?mode=memory&cache=shared",
+        Uuid::new_v4().simple()
+    );
+    let pool = AnyPool::connect(&config.path).await.unwrap();
+    for query in [
+        "CREATE TABLE timeseries_data (timestamp BIGINT NOT NULL, tenant_id TEXT NOT NULL,
+            device_id TEXT NOT NULL, metric_name TEXT NOT NULL, value_float DOUBLE PRECISION,
+            value_int BIGINT, value_lat DOUBLE PRECISION, value_long DOUBLE PRECISION)",
+        "CREATE TABLE shadows (tenant_id TEXT NOT NULL, device_id TEXT NOT NULL,
+            shadow_name TEXT NOT NULL, data TEXT NOT NULL,
+            PRIMARY KEY (tenant_id, device_id, shadow_name))",
+        "CREATE TABLE device_metadata (tenant_id TEXT NOT NULL, device_id TEXT NOT NULL,
+            metadata TEXT NOT NULL, PRIMARY KEY (tenant_id, device_id))",
+    ] {
+        sqlx::query(query).execute(&pool).await.unwrap();
+    }
+    (config, pool)
+}
+
+#[tokio::test]
+async fn test_device_tags_migration() {
+    let (config, pool) = setup_legacy_db().await;
+    let legacy =
+        r#"{"device_id":"old","tenant_id":"default","certificate":null,"key":null,"created_at":0}"#;
+    sqlx::query("INSERT INTO device_metadata (tenant_id, device_id, metadata) VALUES ($1, $2, $3)")
+        .bind("default")
+        .bind("old")
+        .bind(legacy)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // Opening the database adds the empty tag map
+    let reopened = DB::open(&config).await.unwrap();
+    let (metadata,): (String,) =
+        sqlx::query_as("SELECT metadata FROM device_metadata WHERE device_id = 'old'")
+            .fetch_one(&**reopened.pool.as_ref().unwrap())
+            .await
+            .unwrap();
+    let metadata: Value = serde_json::from_str(&metadata).unwrap();
+    assert_eq!(metadata["tags"], json!({}));
+    let device = reopened
+        .get_device_metadata(&TenantId::Default, "old")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(device.tags.is_empty());
+}
+
+fn desired_update(device_id: &str, desired: Value) -> StateUpdateDocument {
+    StateUpdateDocument {
+        device_id: device_id.to_string(),
+        shadow_name: ShadowName::Default,
+        tenant_id: TenantId::Default,
+        state: StateDocument {
+            reported: Value::Null,
+            desired,
+            delta: Value::Null,
+        },
+    }
+}
+
+#[tokio::test]
+async fn test_upsert_shadows() {
+    let (db, _temp) = setup_db().await;
+    let cached_db = setup_cached_db().await;
+
+    for db in [&db, &cached_db] {
+        db._upsert_shadow(&reported_update("dev1", json!({"mode": "eco"})))
+            .await
+            .unwrap();
+        let updates = vec![
+            desired_update("dev1", json!({"mode": "boost"})),
+            desired_update("dev2", json!({"mode": "boost"})),
+            desired_update("dev2", json!({"interval": 60})),
+        ];
+        let shadows = db.upsert_shadows(&updates).await.unwrap();
+        assert_eq!(shadows.len(), 3);
+        assert_eq!(shadows[0].get_reported_value()["mode"], "eco");
+        assert_eq!(shadows[0].get_delta_value()["mode"], "boost");
+        assert_eq!(
+            *shadows[2].get_desired_value(),
+            json!({"mode": "boost", "interval": 60})
+        );
+
+        // Written to the database, pending cached changes included
+        db.flush().await.unwrap();
+        let stored = db
+            .load_shadow("dev1", &ShadowName::Default, &TenantId::Default)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.get_reported_value()["mode"], "eco");
+        assert_eq!(stored.get_desired_value()["mode"], "boost");
+        let stored = db
+            ._get_shadow("dev2", &ShadowName::Default, &TenantId::Default)
+            .await
+            .unwrap();
+        assert_eq!(stored.get_version(), 2);
+    }
+}
+
+#[tokio::test]
+async fn test_get_metric_in_bbox() {
+    let (db, _temp) = setup_db().await;
+    let points = [
+        (1000, 48.2, 16.4),  // inside
+        (2000, 48.0, 16.0),  // on the min corner
+        (3000, 48.5, 16.25), // on the max latitude
+        (4000, 48.6, 16.4),  // north of the box
+        (5000, 48.2, 15.9),  // west of the box
+        (6000, 48.3, 16.3),  // inside, after the time range
+    ];
+    for (timestamp, lat, long) in points {
+        db.insert_metric_row(
+            &TenantId::Default,
+            "tracker",
+            "gps",
+            timestamp,
+            MetricValue::Location(LatLong::new(lat, long)),
+        )
+        .await
+        .unwrap();
+    }
+
+    let bbox = BoundingBox::parse("48.0,16.0,48.5,16.5").unwrap();
+    let ts = db
+        .get_metric_in_bbox(&TenantId::Default, "tracker", "gps", 0, 5000, &bbox)
+        .await
+        .unwrap();
+    let found: Vec<(u64, &MetricValue)> = ts.iter().collect();
+    assert_eq!(
+        found,
+        vec![
+            (1000, &MetricValue::Location(LatLong::new(48.2, 16.4))),
+            (2000, &MetricValue::Location(LatLong::new(48.0, 16.0))),
+            (3000, &MetricValue::Location(LatLong::new(48.5, 16.25))),
+        ]
+    );
+
+    // Other metrics have no coordinates and never match
+    db.insert_metric_row(
+        &TenantId::Default,
+        "tracker",
+        "speed",
+        1000,
+        MetricValue::Float(48.2),
+    )
+    .await
+    .unwrap();
+    let ts = db
+        .get_metric_in_bbox(&TenantId::Default, "tracker", "speed", 0, 5000, &bbox)
+        .await
+        .unwrap();
+    assert_eq!(ts.len(), 0);
+}
+
+#[tokio::test]
+async fn test_get_metric_by_device_prefix() {
+    let (db, _temp) = setup_db().await;
+    for (device_id, value) in [
+        ("dev-1", 1.0),
+        ("dev-2", 2.0),
+        ("other", 3.0),
+        ("DEV-3", 4.0),
+    ] {
+        for timestamp in [1000, 2000] {
+            db.insert_metric_row(
+                &TenantId::Default,
+                device_id,
+                "temp",
+                timestamp,
+                MetricValue::Float(value),
+            )
+            .await
+            .unwrap();
+        }
+    }
+    db.insert_metric_row(
+        &TenantId::Default,
+        "dev-1",
+        "humidity",
+        1000,
+        MetricValue::Float(50.0),
+    )
+    .await
+    .unwrap();
+
+    let series = db
+        .get_metric_by_device_prefix(&TenantId::Default, "dev-", "temp", 0, 1500)
+        .await
+        .unwrap();
+    let mut device_ids: Vec<&String> = series.keys().collect();
+    device_ids.sort();
+    assert_eq!(device_ids, vec!["dev-1", "dev-2"]);
+    let found: Vec<(u64, &MetricValue)> = series["dev-2"].iter().collect();
+    assert_eq!(found, vec![(1000, &MetricValue::Float(2.0))]);
+
+    // `_` is not a wildcard
+    assert!(db
+        .get_metric_by_device_prefix(&TenantId::Default, "dev_", "temp", 0, 3000)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_get_metric_by_device_prefix_bound() {
+    let (db, _temp) = setup_db().await;
+    for i in 0..MAX_PREFIX_QUERY_DEVICES + 5 {
+        db.insert_metric_row(
+            &TenantId::Default,
+            &format!("sensor-{:04}", i),
+            "temp",
+            1000,
+            MetricValue::Float(i as f64),
+        )
+        .await
+        .unwrap();
+    }
+    let series = db
+        .get_metric_by_device_prefix(&TenantId::Default, "sensor-", "temp", 0, 2000)
+        .await
+        .unwrap();
+    assert_eq!(series.len(), MAX_PREFIX_QUERY_DEVICES);
+    assert!(series.contains_key("sensor-0000"));
+    assert!(!series.contains_key(&format!("sensor-{:04}", MAX_PREFIX_QUERY_DEVICES)));
+}
+
+#[tokio::test]
+async fn test_shadow_last_updated_column() {
+    let (db, _temp) = setup_db().await;
+    let cached_db = setup_cached_db().await;
+
+    for db in [&db, &cached_db] {
+        let before = chrono::Utc::now().timestamp() as u64;
+        db._upsert_shadow(&reported_update("dev1", json!({"mode": "eco"})))
+            .await
+            .unwrap();
+        let last_updated = db
+            .get_shadow_last_updated("dev1", &ShadowName::Default, &TenantId::Default)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(last_updated >= before);
+        assert_eq!(
+            db.get_shadow_last_updated("dev2", &ShadowName::Default, &TenantId::Default)
+                .await
+                .unwrap(),
+            None
+        );
+
+        // Pending cached updates count as well
+        assert_eq!(
+            db.list_stale_devices(&TenantId::Default, last_updated + 1)
+                .await
+                .unwrap(),
+            vec!["dev1"]
+        );
+        assert!(db
+            .list_stale_devices(&TenantId::Default, last_updated)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_shadow_last_updated_backfill() {
+    let (config, pool) = setup_legacy_db().await;
+    for (device_id, last_updated) in [("old", 1700000000), ("new", 1800000000)] {
+        let shadow = Shadow::new(device_id, &ShadowName::Default, &TenantId::Default);
+        let mut data: Value = serde_json::from_str(&shadow.to_json().unwrap()).unwrap();
+        data["last_updated"] = json!(last_updated);
+        // Rows written before the column existed
+        sqlx::query(
+            "INSERT INTO shadows (tenant_id, device_id, shadow_name, data) VALUES ($1, $2, $3, $4)",
+        )
+        .bind("default")
+        .bind(device_id)
+        .bind("default")
+        .bind(data.to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+
+    let reopened = DB::open(&config).await.unwrap();
+    assert_eq!(
+        reopened
+            .get_shadow_last_updated("old", &ShadowName::Default, &TenantId::Default)
+            .await
+            .unwrap(),
+        Some(1700000000)
+    );
+    assert_eq!(
+        reopened
+            .list_stale_devices(&TenantId::Default, 1750000000)
+            .await
+            .unwrap(),
+        vec!["old"]
+    );
+}
+
+#[tokio::test]
+async fn test_schema_migrations() {
+    let (config, pool) = setup_legacy_db().await;
+
+    let db = DB::open(&config).await.unwrap();
+    let applied: Vec<(i64, i64)> =
+        sqlx::query_as("SELECT version, applied_at FROM schema_migrations ORDER BY version")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    let versions: Vec<i64> = applied.iter().map(|(version, _)| *version).collect();
+    let expected: Vec<i64> = migrations::MIGRATIONS.iter().map(|m| m.version).collect();
+    assert_eq!(versions, expected);
+    // The added columns are usable
+    db.insert_metric_row(
+        &TenantId::Default,
+        "dev1",
+        "status",
+        1000,
+        MetricValue::Text("ok".to_string()),
+    )
+    .await
+    .unwrap();
+    db._upsert_shadow(&reported_update("dev1", json!({"mode": "eco"})))
+        .await
+        .unwrap();
+    assert!(db
+        .get_shadow_last_updated("dev1", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap()
+        .is_some());
+
+    // Applied migrations are not run again
+    DB::open(&config).await.unwrap();
+    let reapplied: Vec<(i64, i64)> =
+        sqlx::query_as("SELECT version, applied_at FROM schema_migrations ORDER BY version")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(reapplied, applied);
+    let mut conn = pool.acquire().await.unwrap();
+    for target in [MigrationTarget::Main, MigrationTarget::Timeseries] {
+        assert!(run_migrations(&mut *conn, target, false)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_schema_migrations_fresh_db() {
+    let (db, _temp) = setup_db().await;
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM schema_migrations")
+        .fetch_one(&**db.pool.as_ref().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(count as usize, migrations::MIGRATIONS.len());
+}
+
+#[tokio::test]
+async fn test_backup() {
+    let temp = TempDir::new().unwrap();
+    let mut config = DatabaseConfig::default();
+    config.path = format!("sqlite://{}/forest.db?mode=rwc", temp.path().display());
+    config.backup_dir = temp.path().join("backups").display().to_string();
+    config.backup_keep = 2;
+    let clock = Arc::new(MockClock::new(1_710_511_200_000));
+    let db = DB::open(&config).await.unwrap().with_clock(clock.clone());
+
+    for device_id in ["dev1", "dev2"] {
+        db.put_device_metadata(&DeviceMetadata::new(device_id, &TenantId::Default))
+            .await
+            .unwrap();
+    }
+    for timestamp in [1000, 2000, 3000] {
+        db.insert_metric_row(
+            &TenantId::Default,
+            "dev1",
+            "temperature",
+            timestamp,
+            MetricValue::Float(20.5),
+        )
+        .await
+        .unwrap();
+    }
+    db._upsert_shadow(&reported_update("dev1", json!({"mode": "eco"})))
+        .await
+        .unwrap();
+
+    // The copy opens as a database of its own with the same rows
+    let copy_path = temp.path().join("copy.db");
+    let info = db.backup_to(&copy_path).await.unwrap();
+    assert_eq!(info.path, copy_path.display().to_string());
+    assert!(info.size_bytes > 0);
+    assert_eq!(info.created_at, 1_710_511_200);
+    let copy = DB::open_default(&format!("sqlite://{}", copy_path.display()))
+        .await
+        .unwrap();
+    assert_eq!(
+        copy.tenant_usage(&TenantId::Default).await.unwrap(),
+        db.tenant_usage(&TenantId::Default).await.unwrap()
+    );
+    assert_eq!(copy.count_devices(&TenantId::Default).await.unwrap(), 2);
+
+    // An existing file is never overwritten
+    assert!(matches!(
+        db.backup_to(&copy_path).await,
+        Err(DatabaseError::DatabaseValueError(_))
+    ));
+
+    // Only the newest `backup_keep` snapshots are kept
+    let mut snapshots = Vec::new();
+    for _ in 0..3 {
+        snapshots.push(db.backup().await.unwrap().path);
+        clock.advance(Duration::from_secs(3600));
+    }
+    let kept: Vec<String> = db
+        .backup_files()
+        .await
+        .unwrap()
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    assert_eq!(kept, snapshots[1..]);
+    assert!(kept[0].ends_with("forest-backup-20240315T150000.000Z.db"));
+}
+
+#[tokio::test]
+async fn test_backup_postgres_unsupported() {
+    let db = DB {
+        path: "postgres://localhost/forest".to_string(),
+        pool: None,
+        ts_pool: None,
+        non_finite_policy: NonFinitePolicy::default(),
+        max_shadow_bytes: 0,
+        backup_dir: PathBuf::from("backups"),
+        backup_keep: 0,
+        non_finite_rejected: AtomicU64::new(0),
+        corrupt_data_configs: AtomicU64::new(0),
+        shadow_cache: None,
+        clock: Arc::new(SystemClock),
+        tenant_quotas: DashMap::new(),
+        timeseries_rows: DashMap::new(),
+        shadow_counts: DashMap::new(),
+        shadow_webhooks: DashMap::new(),
+    };
+    let err = db.backup_to(Path::new("backup.db")).await.unwrap_err();
+    assert!(matches!(err, DatabaseError::Unsupported(_)));
+    assert!(err.to_string().contains("pg_dump"));
+}
+
+#[tokio::test]
+async fn test_prune_metrics() {
+    let (db, _temp) = setup_db().await;
+    let now = 1_710_511_200;
+    let db = db.with_clock(Arc::new(MockClock::new(now * 1000)));
+    let day = 24 * 60 * 60;
+    let other = TenantId::from_str("other");
+    for tenant_id in [&TenantId::Default, &other] {
+        for timestamp in [now - 40 * day, now - 31 * day, now - 29 * day, now] {
+            db.insert_metric_row(
+                tenant_id,
+                "dev1",
+                "temperature",
+                timestamp,
+                MetricValue::Float(20.5),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    assert_eq!(db.prune_metrics(30).await.unwrap(), 4);
+    for tenant_id in [&TenantId::Default, &other] {
+        let ts = db
+            .get_metric(tenant_id, "dev1", "temperature", 0, now, None)
+            .await
+            .unwrap();
+        let timestamps: Vec<u64> = ts.iter().map(|(timestamp, _)| timestamp).collect();
+        assert_eq!(timestamps, vec![now - 29 * day, now]);
+    }
+    // Nothing left to prune
+    assert_eq!(db.prune_metrics(30).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_audit_log_paging() {
+    let (db, _temp) = setup_db().await;
+    let tenant = TenantId::from_str("audited");
+    for (i, action) in ["create", "update", "delete"].iter().enumerate() {
+        db.insert_audit_entry(&AuditEntry {
+            id: 0,
+            tenant_id: tenant.clone(),
+            actor: "127.0.0.1".to_string(),
+            action: action.to_string(),
+            resource_type: "shadow".to_string(),
+            resource_id: "dev1/default".to_string(),
+            payload: json!({"step": i}),
+            timestamp: 1000 + i as u64,
+        })
+        .await
+        .unwrap();
+    }
+
+    let first = db.list_audit_entries(&tenant, 0, 2).await.unwrap();
+    let actions: Vec<&str> = first.iter().map(|entry| entry.action.as_str()).collect();
+    assert_eq!(actions, ["create", "update"]);
+    assert_eq!(first[1].payload, json!({"step": 1}));
+
+    let rest = db
+        .list_audit_entries(&tenant, first[1].id, 2)
+        .await
+        .unwrap();
+    assert_eq!(rest.len(), 1);
+    assert_eq!(rest[0].action, "delete");
+    assert_eq!(rest[0].timestamp, 1002);
+
+    // Other tenants don't see the entries
+    assert!(db
+        .list_audit_entries(&TenantId::Default, 0, 10)
+        .await
+        .unwrap()
+        .is_empty());
+}
@@ -0,0 +1,112 @@
+use crate::db::{DatabaseError, DB};
+use crate::models::{ShadowEvent, ShadowWebhook, TenantId};
+
+impl DB {
+    /// Stores the webhook, replacing a webhook of the device with the same `webhook_id`
+    pub async fn store_shadow_webhook(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        webhook: &ShadowWebhook,
+    ) -> Result<(), DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let t_id = tenant_id.to_string();
+            let events = serde_json::to_string(&webhook.events)
+                .map_err(|e| DatabaseError::DatabaseValueError(e.to_string()))?;
+            let mut tx = pool.begin().await?;
+
+            sqlx::query(
+                "DELETE FROM shadow_webhooks WHERE tenant_id = $1 AND device_id = $2 AND webhook_id = $3",
+            )
+            .bind(&t_id)
+            .bind(device_id)
+            .bind(&webhook.webhook_id)
+            .execute(&mut *tx)
+            .await?;
+
+            sqlx::query(
+                "INSERT INTO shadow_webhooks (tenant_id, device_id, webhook_id, url, secret, events) VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(&t_id)
+            .bind(device_id)
+            .bind(&webhook.webhook_id)
+            .bind(&webhook.url)
+            .bind(&webhook.secret)
+            .bind(&events)
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await?;
+            self.shadow_webhooks
+                .remove(&(tenant_id.clone(), device_id.to_string()));
+            Ok(())
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Webhooks of the device, sorted by `webhook_id`.
+    /// Cached after the first lookup, storing or deleting a webhook reloads them.
+    pub async fn get_shadow_webhooks(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+    ) -> Result<Vec<ShadowWebhook>, DatabaseError> {
+        let key = (tenant_id.clone(), device_id.to_string());
+        if let Some(webhooks) = self.shadow_webhooks.get(&key) {
+            return Ok(webhooks.clone());
+        }
+        if let Some(pool) = &self.pool {
+            let rows: Vec<(String, String, Option<String>, String)> = sqlx::query_as(
+                "SELECT webhook_id, url, secret, events FROM shadow_webhooks
+                 WHERE tenant_id = $1 AND device_id = $2 ORDER BY webhook_id",
+            )
+            .bind(tenant_id.to_string())
+            .bind(device_id)
+            .fetch_all(&**pool)
+            .await?;
+
+            let webhooks = rows
+                .into_iter()
+                .map(|(webhook_id, url, secret, events)| {
+                    let events: Vec<ShadowEvent> = serde_json::from_str(&events)
+                        .map_err(|e| DatabaseError::DatabaseValueError(e.to_string()))?;
+                    Ok(ShadowWebhook {
+                        webhook_id,
+                        url,
+                        secret,
+                        events,
+                    })
+                })
+                .collect::<Result<Vec<_>, DatabaseError>>()?;
+            self.shadow_webhooks.insert(key, webhooks.clone());
+            Ok(webhooks)
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Returns false if the device has no webhook with this id
+    pub async fn delete_shadow_webhook(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        webhook_id: &str,
+    ) -> Result<bool, DatabaseError> {
+        if let Some(pool) = &self.pool {
+            let result = sqlx::query(
+                "DELETE FROM shadow_webhooks WHERE tenant_id = $1 AND device_id = $2 AND webhook_id = $3",
+            )
+            .bind(tenant_id.to_string())
+            .bind(device_id)
+            .bind(webhook_id)
+            .execute(&**pool)
+            .await?;
+            self.shadow_webhooks
+                .remove(&(tenant_id.clone(), device_id.to_string()));
+            Ok(result.rows_affected() > 0)
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+}
@@ -1,16 +1,24 @@
 extern crate forest;
 
-use std::path::PathBuf;
+use std::path::Path;
 use std::sync::Arc;
 
 use clap::Parser;
 use forest::api::services::create_device as create_device_api;
 use forest::certs::CertificateManager;
-use forest::cli::{Cli, Commands};
+use forest::cli::{
+    db_export, db_import, device_add_password, device_delete_password, device_list_passwords,
+    format_device_csv, format_device_table, format_mqtt_message, format_tenant_table,
+    format_timeseries_table, paginate_devices, parse_timestamp, shadow_get, shadow_set,
+    tenant_create, timeseries_query, CertComponent, Cli, CliError, Commands, OutputFormat,
+    QueryRange,
+};
 use forest::config::ForestConfig;
 use forest::db::DB;
 use forest::models::TenantId;
+use forest::mqtt::start_broker;
 use forest::server::start_server;
+use forest::shadow::Shadow;
 use tokio::runtime::Runtime;
 use tracing::Level;
 
@@ -81,7 +89,7 @@ fn main() {
             if let Some(bind_mqtt_v5) = bind_mqtt_v5 {
                 config.mqtt.bind_v5 = bind_mqtt_v5.clone();
             }
-            run_server(rt, config);
+            run_server(rt, config, config_file);
         }
         Commands::Version => {
             println!("Forest Version: {}", env!("CARGO_PKG_VERSION"));
@@ -90,13 +98,259 @@ fn main() {
         Commands::CreateDevice { device_id } => {
             create_device(rt, &device_id, config);
         }
+        Commands::IssueClientCert { device_id } => {
+            issue_client_cert(&config, device_id);
+        }
+        Commands::InitConfig { output } => {
+            init_config(output.as_deref());
+        }
+        Commands::DeviceList {
+            tenant,
+            output,
+            after,
+            limit,
+        } => {
+            list_devices(
+                rt,
+                config,
+                tenant.as_deref(),
+                *output,
+                after.as_deref(),
+                *limit,
+            );
+        }
+        Commands::ShadowGet {
+            device_id,
+            shadow_name,
+            tenant,
+        } => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                print_shadow(
+                    shadow_get(&db, device_id, shadow_name.as_deref(), tenant.as_deref()).await,
+                );
+            });
+        }
+        Commands::ShadowSet {
+            device_id,
+            shadow_name,
+            json_file,
+            tenant,
+        } => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                let result = shadow_set(
+                    &db,
+                    device_id,
+                    shadow_name.as_deref(),
+                    json_file,
+                    tenant.as_deref(),
+                )
+                .await;
+                print_shadow(result);
+            });
+        }
+        Commands::TimeseriesQuery {
+            device_id,
+            metric,
+            start,
+            end,
+            last,
+            downsample,
+            format,
+            tenant,
+        } => {
+            let range = match last {
+                Some(last) => QueryRange::Last(*last),
+                None => {
+                    // clap requires --start unless --last is given
+                    let start = parse_timestamp(start.as_deref().unwrap_or_default());
+                    let end = match end {
+                        Some(end) => parse_timestamp(end),
+                        None => Ok(chrono::Utc::now().timestamp() as u64),
+                    };
+                    match (start, end) {
+                        (Ok(start), Ok(end)) => QueryRange::Between(start, end),
+                        (Err(e), _) | (_, Err(e)) => {
+                            tracing::error!("{}", e);
+                            return;
+                        }
+                    }
+                }
+            };
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                let result = timeseries_query(
+                    &db,
+                    device_id,
+                    metric,
+                    range,
+                    *downsample,
+                    tenant.as_deref(),
+                )
+                .await;
+                match result {
+                    Ok(model) => match format {
+                        OutputFormat::Table => print!("{}", format_timeseries_table(&model)),
+                        OutputFormat::Csv => print!("{}", model.to_csv()),
+                        OutputFormat::Json => {
+                            println!("{}", serde_json::to_string_pretty(&model).unwrap())
+                        }
+                    },
+                    Err(e) => tracing::error!("Timeseries query failed: {}", e),
+                }
+            });
+        }
+        Commands::MqttWatch {
+            pattern,
+            timeout,
+            json_pretty,
+        } => {
+            mqtt_watch(rt, config, pattern, *timeout, *json_pretty);
+        }
+        Commands::TenantCreate {
+            tenant_id,
+            allow_passwords,
+            allow_certs,
+        } => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                match tenant_create(&db, tenant_id, *allow_passwords, *allow_certs).await {
+                    Ok(tenant) => println!(
+                        "Tenant {} created (allow_passwords: {}, allow_certificates: {})",
+                        tenant.tenant_id,
+                        tenant.auth_config.allow_passwords,
+                        tenant.auth_config.allow_certificates
+                    ),
+                    Err(e) => tracing::error!("Failed to create tenant: {}", e),
+                }
+            });
+        }
+        Commands::TenantList => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                match db.list_tenants().await {
+                    Ok(tenants) => print!("{}", format_tenant_table(&tenants)),
+                    Err(e) => tracing::error!("Failed to list tenants: {:?}", e),
+                }
+            });
+        }
+        Commands::DbExport { output } => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                match db_export(&db, output).await {
+                    Ok(count) => println!("Exported {} records to {}", count, output.display()),
+                    Err(e) => tracing::error!("Export failed: {}", e),
+                }
+            });
+        }
+        Commands::DbImport { input } => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                match db_import(&db, input).await {
+                    Ok(summary) => println!(
+                        "Imported {} records, {} failed",
+                        summary.imported, summary.failed
+                    ),
+                    Err(e) => tracing::error!("Import failed: {}", e),
+                }
+            });
+        }
+        Commands::DeviceAddPassword {
+            device_id,
+            username,
+            password,
+            tenant,
+        } => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                match device_add_password(
+                    &db,
+                    device_id,
+                    username,
+                    password,
+                    tenant.as_deref(),
+                    Some(config.bcrypt_cost),
+                )
+                .await
+                {
+                    Ok(credential) => println!(
+                        "Password for {} of device {} stored (tenant {})",
+                        credential.username, credential.device_id, credential.tenant_id
+                    ),
+                    Err(e) => tracing::error!("Failed to add password: {}", e),
+                }
+            });
+        }
+        Commands::DeviceDeletePassword {
+            device_id,
+            username,
+            tenant,
+        } => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                match device_delete_password(&db, device_id, username, tenant.as_deref()).await {
+                    Ok(true) => {
+                        println!("Password for {} of device {} deleted", username, device_id)
+                    }
+                    Ok(false) => {
+                        tracing::error!("Device {} has no password for {}", device_id, username)
+                    }
+                    Err(e) => tracing::error!("Failed to delete password: {}", e),
+                }
+            });
+        }
+        Commands::DeviceListPasswords { device_id, tenant } => {
+            rt.block_on(async {
+                let Some(db) = open_db(&config).await else {
+                    return;
+                };
+                match device_list_passwords(&db, device_id, tenant.as_deref()).await {
+                    Ok(usernames) => {
+                        for username in usernames {
+                            println!("{}", username);
+                        }
+                    }
+                    Err(e) => tracing::error!("Failed to list passwords: {}", e),
+                }
+            });
+        }
+        Commands::CertRotate { component } => {
+            rotate_cert(&config, component);
+        }
     }
 }
 
-fn run_server(rt: Runtime, config: ForestConfig) {
+fn run_server(rt: Runtime, config: ForestConfig, config_file: Option<&Path>) {
+    if let Err(errors) = config.validate() {
+        for error in &errors {
+            tracing::error!("Invalid config: {}", error);
+        }
+        tracing::error!("Aborting startup, {} config error(s) found", errors.len());
+        // Non-zero so service managers and scripts notice the failed start
+        std::process::exit(2);
+    }
     setup_server_certs(&config);
     rt.block_on(async {
-        let (cancel_token, server_handle) = start_server(&config).await;
+        let (cancel_token, server_handle) = start_server(&config, config_file).await;
         tokio::select! {
             _ = cancel_token.cancelled() => {
                 tracing::warn!("Server exited internally");
@@ -139,6 +393,43 @@ fn setup_server_certs(config: &ForestConfig) {
     }
 }
 
+fn rotate_cert(config: &ForestConfig, component: &CertComponent) {
+    let cert_manager = get_certificate_manager(config);
+    let result = match component {
+        CertComponent::Ca => cert_manager
+            .create_ca(None)
+            .map(|_| cert_manager.get_ca_file_path()),
+        CertComponent::Server => cert_manager.rotate_server_cert(),
+        CertComponent::Client { device_id } => cert_manager.rotate_client_cert(device_id),
+    };
+    match result {
+        Ok(path) => println!("New certificate written to {}", path.display()),
+        Err(e) => tracing::error!("Failed to rotate certificate: {}", e),
+    }
+}
+
+fn issue_client_cert(config: &ForestConfig, device_id: &str) {
+    let cert_manager = get_certificate_manager(config);
+    match cert_manager.create_client_cert(device_id) {
+        Ok(cert_data) => {
+            print!("{}", cert_data.cert);
+            print!("{}", cert_data.key);
+        }
+        Err(e) => tracing::error!("Failed to issue client certificate: {}", e),
+    }
+}
+
+fn init_config(output: Option<&Path>) {
+    let template = ForestConfig::template();
+    match output {
+        Some(path) => match std::fs::write(path, template) {
+            Ok(_) => println!("Config template written to {}", path.display()),
+            Err(e) => tracing::error!("Failed to write config template: {}", e),
+        },
+        None => print!("{}", template),
+    }
+}
+
 fn create_device(rt: Runtime, device_id: &str, config: ForestConfig) {
     println!("Creating device: {}", device_id);
 
@@ -157,7 +448,7 @@ fn create_device(rt: Runtime, device_id: &str, config: ForestConfig) {
         let tenant_id = config.tenant_id.as_deref();
         let tenant = TenantId::from_option(tenant_id);
 
-        match create_device_api(device_id, &tenant, db, cert_manager).await {
+        match create_device_api(device_id, &tenant, db, cert_manager, false).await {
             Ok(device) => {
                 tracing::info!("Device successfully created");
                 println!("\nDevice ID: \n{}", device.device_id);
@@ -174,3 +465,116 @@ fn create_device(rt: Runtime, device_id: &str, config: ForestConfig) {
         }
     });
 }
+
+fn list_devices(
+    rt: Runtime,
+    config: ForestConfig,
+    tenant: Option<&str>,
+    output: OutputFormat,
+    after: Option<&str>,
+    limit: Option<usize>,
+) {
+    rt.block_on(async {
+        let Some(db) = open_db(&config).await else {
+            return;
+        };
+
+        let tenant_id = TenantId::from_option(tenant);
+        let devices = match db.list_devices(&tenant_id).await {
+            Ok(devices) => paginate_devices(devices, after, limit),
+            Err(e) => {
+                tracing::error!("Failed to list devices: {}", e);
+                return;
+            }
+        };
+
+        match output {
+            OutputFormat::Table => print!("{}", format_device_table(&devices)),
+            OutputFormat::Csv => print!("{}", format_device_csv(&devices)),
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(&devices).unwrap())
+            }
+        }
+    });
+}
+
+async fn open_db(config: &ForestConfig) -> Option<DB> {
+    match DB::open_default(&config.database.path).await {
+        Ok(db) => Some(db),
+        Err(e) => {
+            tracing::error!("Failed to open DB: {:?}", e);
+            None
+        }
+    }
+}
+
+fn print_shadow(result: Result<Shadow, CliError>) {
+    match result {
+        Ok(shadow) => println!("{}", serde_json::to_string_pretty(&shadow).unwrap()),
+        Err(e) => tracing::error!("Shadow command failed: {}", e),
+    }
+}
+
+fn mqtt_watch(
+    rt: Runtime,
+    config: ForestConfig,
+    pattern: &str,
+    timeout: Option<u64>,
+    json_pretty: bool,
+) {
+    // The broker needs the server certificates when SSL is enabled
+    setup_server_certs(&config);
+    rt.block_on(async {
+        // Transient database, nothing is persisted while watching
+        let db = match DB::open_default("sqlite:file:memdb_mqtt_watch?mode=memory&cache=shared")
+            .await
+        {
+            Ok(db) => Arc::new(db),
+            Err(e) => {
+                tracing::error!("Failed to open DB: {:?}", e);
+                return;
+            }
+        };
+        let mut mqtt = start_broker(Some(config.mqtt.clone()), db).await;
+        let receiver = mqtt.message_receiver();
+        if let Err(e) = mqtt.mqtt.subscribe(pattern.to_string()).await {
+            tracing::error!("Failed to subscribe to {}: {}", pattern, e);
+            mqtt.shutdown();
+            return;
+        }
+        tracing::info!("Watching {} (Ctrl-C to stop)", pattern);
+
+        let idle_timeout = timeout.map(std::time::Duration::from_secs);
+        loop {
+            let next_message = async {
+                match idle_timeout {
+                    Some(idle_timeout) => {
+                        tokio::time::timeout(idle_timeout, receiver.recv_async()).await
+                    }
+                    None => Ok(receiver.recv_async().await),
+                }
+            };
+            tokio::select! {
+                result = next_message => match result {
+                    Ok(Ok(msg)) => println!(
+                        "{}",
+                        format_mqtt_message(chrono::Utc::now(), &msg.topic, &msg.payload, json_pretty)
+                    ),
+                    Ok(Err(_)) => {
+                        tracing::warn!("Broker channel closed");
+                        break;
+                    }
+                    Err(_) => {
+                        tracing::info!("No message received for {} seconds", timeout.unwrap_or_default());
+                        break;
+                    }
+                },
+                _ = tokio::signal::ctrl_c() => {
+                    tracing::info!("Received Ctrl-C, shutting down gracefully...");
+                    break;
+                }
+            }
+        }
+        mqtt.shutdown();
+    });
+}
@@ -8,6 +8,7 @@ pub mod shadow;
 
 pub mod api;
 pub mod certs;
-mod dataconfig;
+pub mod clock;
+pub mod dataconfig;
 pub mod models;
 pub mod timeseries;
@@ -1,8 +1,11 @@
+use crate::clock::{Clock, SystemClock};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub enum DefaultString {
+    #[default]
     Default,
     Custom(String),
 }
@@ -88,11 +91,38 @@ impl Default for AuthConfig {
     }
 }
 
+/// Limits of a tenant, `None` is unlimited
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TenantQuota {
+    pub max_devices: Option<u64>,
+    pub max_timeseries_rows: Option<u64>,
+    pub max_shadows: Option<u64>,
+    /// Overrides `processor.max_messages_per_second` for the devices of the tenant
+    pub max_messages_per_second: Option<u32>,
+    /// Overrides `processor.message_burst`
+    pub message_burst: Option<u32>,
+}
+
+/// Data stored for a tenant, counted on request
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TenantUsage {
+    pub device_count: u64,
+    pub metric_row_count: u64,
+    pub shadow_count: u64,
+    /// Share of the device and shadow tables in bytes, by the rows of the tenant.
+    /// Only on Postgres, timeseries data can live in a separate database and isn't included.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approximate_bytes: Option<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tenant {
     pub tenant_id: TenantId,
     pub auth_config: AuthConfig,
     pub created_at: u64,
+    /// Tenants stored before quotas existed are unlimited
+    #[serde(default)]
+    pub quota: TenantQuota,
 }
 
 impl Tenant {
@@ -100,7 +130,8 @@ impl Tenant {
         Self {
             tenant_id: tenant_id.clone(),
             auth_config: AuthConfig::default(),
-            created_at: chrono::Utc::now().timestamp() as u64,
+            created_at: SystemClock.now_secs(),
+            quota: TenantQuota::default(),
         }
     }
 
@@ -108,6 +139,11 @@ impl Tenant {
         self.auth_config = auth_config;
         self
     }
+
+    pub fn with_quota(mut self, quota: TenantQuota) -> Self {
+        self.quota = quota;
+        self
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -119,6 +155,64 @@ pub struct DeviceCredential {
     pub created_at: u64,
 }
 
+/// A message the processor gave up on after repeated failures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub tenant_id: TenantId,
+    pub device_id: String,
+    pub topic: String,
+    /// Original payload, invalid UTF-8 is replaced
+    pub payload: String,
+    pub error: String,
+    pub failed_at: u64,
+}
+
+/// A write made through the API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Assigned when the entry is stored, increases with every entry
+    #[serde(default)]
+    pub id: u64,
+    pub tenant_id: TenantId,
+    /// API token or IP address of the caller
+    pub actor: String,
+    pub action: String,
+    pub resource_type: String,
+    pub resource_id: String,
+    pub payload: serde_json::Value,
+    pub timestamp: u64,
+}
+
+/// Telemetry of a device that matched none of its configured metrics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtractionError {
+    pub tenant_id: TenantId,
+    pub device_id: String,
+    pub error: String,
+    pub count: u64,
+    pub last_seen: u64,
+}
+
+/// Lifecycle of a device, inactive and decommissioned devices can't connect
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus {
+    /// Provisioned but not deployed yet
+    #[default]
+    Registered,
+    Active,
+    /// Temporarily disabled, can be activated again
+    Inactive,
+    /// Retired for good, kept for audit purposes
+    Decommissioned,
+}
+
+impl DeviceStatus {
+    pub fn can_connect(&self) -> bool {
+        matches!(self, DeviceStatus::Registered | DeviceStatus::Active)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceMetadata {
     pub device_id: String,
@@ -126,6 +220,116 @@ pub struct DeviceMetadata {
     pub certificate: Option<String>,
     pub key: Option<String>,
     pub created_at: u64,
+    /// Free form key-value labels, e.g. `location: warehouse-3`
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    /// Key-value labels for grouping, e.g. `site: berlin`. Indexed in a side table, so
+    /// devices can be selected by label and data configs can target a label.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Devices stored before the lifecycle was tracked are `registered`
+    #[serde(default)]
+    pub status: DeviceStatus,
+    /// Firmware the device reported it runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<String>,
+    /// Firmware the device reported it is updating to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_firmware_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ota_status: Option<OtaStatus>,
+}
+
+/// Progress of a firmware update, reported by the device as `"downloading"` or
+/// `{"failed": "<reason>"}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OtaStatus {
+    Idle,
+    Downloading,
+    Applying,
+    Failed(String),
+}
+
+impl OtaStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OtaStatus::Idle => "idle",
+            OtaStatus::Downloading => "downloading",
+            OtaStatus::Applying => "applying",
+            OtaStatus::Failed(_) => "failed",
+        }
+    }
+}
+
+/// Firmware fields of a reported shadow state
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FirmwareReport {
+    pub firmware_version: Option<String>,
+    pub target_firmware_version: Option<String>,
+    pub ota_status: Option<OtaStatus>,
+}
+
+impl FirmwareReport {
+    /// Reads `firmware_version`, `target_firmware_version` and `ota_status` of the reported
+    /// state, `None` if it has none of them. Values of the wrong type are ignored.
+    pub fn from_reported(reported: &serde_json::Value) -> Option<Self> {
+        let version = |key: &str| reported.get(key)?.as_str().map(str::to_string);
+        let report = FirmwareReport {
+            firmware_version: version("firmware_version"),
+            target_firmware_version: version("target_firmware_version"),
+            ota_status: reported
+                .get("ota_status")
+                .and_then(|status| serde_json::from_value(status.clone()).ok()),
+        };
+        (report != FirmwareReport::default()).then_some(report)
+    }
+}
+
+/// Devices of a tenant per firmware version
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareSummary {
+    pub device_count: u64,
+    /// Devices per reported firmware version
+    pub versions: BTreeMap<String, u64>,
+    /// Devices that never reported a firmware version
+    pub unknown_version: u64,
+    /// Devices whose firmware differs from their target firmware
+    pub needs_update: u64,
+    /// Devices per OTA status, devices without one are left out
+    pub ota_status: BTreeMap<String, u64>,
+}
+
+impl FirmwareSummary {
+    pub fn from_devices(devices: &[DeviceMetadata]) -> Self {
+        let mut summary = FirmwareSummary::default();
+        for device in devices {
+            summary.device_count += 1;
+            match &device.firmware_version {
+                Some(version) => *summary.versions.entry(version.clone()).or_default() += 1,
+                None => summary.unknown_version += 1,
+            }
+            if device.needs_firmware_update() {
+                summary.needs_update += 1;
+            }
+            if let Some(status) = &device.ota_status {
+                *summary
+                    .ota_status
+                    .entry(status.name().to_string())
+                    .or_default() += 1;
+            }
+        }
+        summary
+    }
+}
+
+/// Devices of a tenant that receive the same desired shadow updates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceGroup {
+    pub group_id: String,
+    pub tenant_id: TenantId,
+    pub device_ids: Vec<String>,
+    pub created_at: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -152,7 +356,13 @@ impl DeviceMetadata {
             tenant_id: tenant_id.to_owned(),
             certificate: None,
             key: None,
-            created_at: chrono::Utc::now().timestamp() as u64,
+            created_at: SystemClock.now_secs(),
+            tags: HashMap::new(),
+            labels: HashMap::new(),
+            status: DeviceStatus::Registered,
+            firmware_version: None,
+            target_firmware_version: None,
+            ota_status: None,
         }
     }
 
@@ -161,4 +371,169 @@ impl DeviceMetadata {
         self.key = Some(key);
         self
     }
+
+    /// Whether the device has a target firmware it doesn't run yet
+    pub fn needs_firmware_update(&self) -> bool {
+        self.target_firmware_version
+            .as_ref()
+            .is_some_and(|target| self.firmware_version.as_ref() != Some(target))
+    }
+
+    /// Takes the fields of the report that are set, returns false if nothing changed
+    pub fn apply_firmware_report(&mut self, report: &FirmwareReport) -> bool {
+        let mut changed = false;
+        if let Some(version) = &report.firmware_version {
+            changed |= self.firmware_version.as_ref() != Some(version);
+            self.firmware_version = Some(version.clone());
+        }
+        if let Some(target) = &report.target_firmware_version {
+            changed |= self.target_firmware_version.as_ref() != Some(target);
+            self.target_firmware_version = Some(target.clone());
+        }
+        if let Some(status) = &report.ota_status {
+            changed |= self.ota_status.as_ref() != Some(status);
+            self.ota_status = Some(status.clone());
+        }
+        changed
+    }
+}
+
+/// Keys of tags and labels, only `[A-Za-z0-9_.-]` is allowed. Tag keys are used in JSON
+/// paths of the tag search, label keys in `key=value` selectors.
+pub fn is_valid_metadata_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Selects the devices with label `key` set to `value`, written as `key=value`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LabelSelector {
+    pub key: String,
+    pub value: String,
+}
+
+impl LabelSelector {
+    pub fn parse(selector: &str) -> Option<Self> {
+        let (key, value) = selector.split_once('=')?;
+        is_valid_metadata_key(key).then(|| LabelSelector {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl Display for LabelSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// When an alarm rule fires, compared to its threshold
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmCondition {
+    Above,
+    Below,
+    Equal,
+}
+
+/// Raises an `AlarmEvent` for every value of the metric that meets the condition
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlarmRule {
+    /// Taken from the path when the rule is created over the API
+    #[serde(default)]
+    pub tenant_id: TenantId,
+    pub device_id: String,
+    pub metric_name: String,
+    pub condition: AlarmCondition,
+    pub threshold: f64,
+    /// Unique per tenant, storing a rule with the same id replaces it
+    pub alarm_id: String,
+}
+
+impl AlarmRule {
+    pub fn is_triggered(&self, value: f64) -> bool {
+        match self.condition {
+            AlarmCondition::Above => value > self.threshold,
+            AlarmCondition::Below => value < self.threshold,
+            AlarmCondition::Equal => value == self.threshold,
+        }
+    }
+}
+
+/// Shadow change a webhook is notified about
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowEvent {
+    /// Every successful update of a shadow of the device
+    Updated,
+    /// An update left a shadow that had a delta without one
+    DeltaCleared,
+}
+
+/// Receives the shadow as JSON when one of `events` happens to a shadow of the device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShadowWebhook {
+    /// Unique per device, generated when the webhook is created over the API
+    #[serde(default)]
+    pub webhook_id: String,
+    pub url: String,
+    /// Signs the body with HMAC-SHA256, never returned by the API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub events: Vec<ShadowEvent>,
+}
+
+/// A value that triggered an alarm rule
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlarmEvent {
+    pub alarm_id: String,
+    /// Timestamp of the value
+    pub triggered_at: u64,
+    pub value: f64,
+}
+
+/// Single use token a device exchanges for a client certificate, only the hash is stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProvisioningToken {
+    /// Hex encoded SHA-256 of the raw token
+    pub token_hash: String,
+    pub device_id: String,
+    pub tenant_id: TenantId,
+    /// Unix time in seconds
+    pub expires_at: u64,
+    pub used: bool,
+}
+
+impl ProvisioningToken {
+    /// Creates a token for the device, returns the raw token with it. The raw token is
+    /// 32 random bytes, hex encoded.
+    pub fn generate(
+        tenant_id: &TenantId,
+        device_id: &str,
+        expires_at: u64,
+    ) -> Result<(String, Self), openssl::error::ErrorStack> {
+        let mut bytes = [0u8; 32];
+        openssl::rand::rand_bytes(&mut bytes)?;
+        let token = to_hex(&bytes);
+        let provisioning_token = ProvisioningToken {
+            token_hash: Self::hash(&token),
+            device_id: device_id.to_string(),
+            tenant_id: tenant_id.clone(),
+            expires_at,
+            used: false,
+        };
+        Ok((token, provisioning_token))
+    }
+
+    /// Hash stored for a raw token
+    pub fn hash(token: &str) -> String {
+        to_hex(&openssl::sha::sha256(token.as_bytes()))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
@@ -1,8 +1,35 @@
 use crate::models::{Tenant, TenantId};
 use crate::mqtt::server::GLOBAL_DB;
+use dashmap::DashMap;
 use rumqttd::ClientInfo;
+use std::sync::OnceLock;
 use tracing::{error, info, warn};
 
+/// Tenants of the clients the auth handler accepted, until the connection monitor takes
+/// them once the client is connected. The broker only reports the client id.
+fn authenticated_tenants() -> &'static DashMap<String, TenantId> {
+    static TENANTS: OnceLock<DashMap<String, TenantId>> = OnceLock::new();
+    TENANTS.get_or_init(DashMap::new)
+}
+
+/// Tenant the client authenticated for, `None` if the auth handler didn't accept it
+pub fn take_authenticated_tenant(client_id: &str) -> Option<TenantId> {
+    authenticated_tenants()
+        .remove(client_id)
+        .map(|(_, tenant)| tenant)
+}
+
+fn accept(client_id: String, tenant_id: &TenantId) -> Option<ClientInfo> {
+    authenticated_tenants().insert(client_id.clone(), tenant_id.clone());
+    Some(ClientInfo {
+        client_id,
+        tenant: Some(tenant_id.to_string()),
+        lower_rate: None,
+        higher_rate: None,
+        message_rates: vec![],
+    })
+}
+
 pub(crate) async fn auth(
     client_id: String,
     username: String,
@@ -46,6 +73,18 @@ pub(crate) async fn auth(
 
     let auth_config = tenant.auth_config;
 
+    // Devices without metadata, e.g. password only devices, have no lifecycle
+    let device = db
+        .get_device_metadata(&tenant_id, &client_id)
+        .await
+        .map_err(|e| format!("DB Error: {}", e))?;
+    if let Some(device) = device {
+        if !device.status.can_connect() {
+            warn!(status = ?device.status, "Device is not allowed to connect");
+            return Ok(None);
+        }
+    }
+
     // Check certificates
     if !common_name.is_empty() {
         if !auth_config.allow_certificates {
@@ -57,13 +96,7 @@ pub(crate) async fn auth(
             return Ok(None);
         }
         // Valid cert auth
-        return Ok(Some(ClientInfo {
-            client_id,
-            tenant: Some(tenant_id.to_string()),
-            lower_rate: None,
-            higher_rate: None,
-            message_rates: vec![],
-        }));
+        return Ok(accept(client_id, &tenant_id));
     }
 
     // Check passwords
@@ -77,13 +110,7 @@ pub(crate) async fn auth(
             .await
             .map_err(|e| format!("DB Error: {}", e))?;
         if is_valid {
-            return Ok(Some(ClientInfo {
-                client_id,
-                tenant: Some(tenant_id.to_string()),
-                lower_rate: None,
-                higher_rate: None,
-                message_rates: vec![],
-            }));
+            return Ok(accept(client_id, &tenant_id));
         } else {
             warn!("Invalid username or password");
             return Ok(None);
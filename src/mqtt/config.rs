@@ -71,6 +71,30 @@ pub struct MqttConfig {
     pub bind_v3: String,
     pub bind_v5: String,
     pub bind_ws: Option<String>,
+    /// Prepended to topics published by the broker itself, e.g. `{public_prefix}heartbeat`
+    #[serde(default = "default_public_prefix")]
+    pub public_prefix: String,
+    /// Router meters kept for `GET /admin/mqtt/meters`, the broker pushes one every 10 seconds
+    #[serde(default = "default_meter_snapshots")]
+    pub meter_snapshots: usize,
+    /// Warns when more messages are dropped within a minute, 0 disables the alert
+    #[serde(default = "default_drop_alert_threshold")]
+    pub drop_alert_threshold: u64,
+    /// Also publishes drop alerts to `{public_prefix}alerts/messages_dropped`
+    #[serde(default)]
+    pub publish_drop_alerts: bool,
+}
+
+fn default_public_prefix() -> String {
+    "public/".to_string()
+}
+
+fn default_meter_snapshots() -> usize {
+    60
+}
+
+fn default_drop_alert_threshold() -> u64 {
+    100
 }
 
 impl Default for MqttConfig {
@@ -85,6 +109,10 @@ impl Default for MqttConfig {
             bind_v3: "127.0.0.1:1883".to_string(),
             bind_v5: "127.0.0.1:1884".to_string(),
             bind_ws: None,
+            public_prefix: default_public_prefix(),
+            meter_snapshots: default_meter_snapshots(),
+            drop_alert_threshold: default_drop_alert_threshold(),
+            publish_drop_alerts: false,
         }
     }
 }
@@ -1,6 +1,6 @@
 use futures_util::stream::StreamExt;
 use rumqttd::local::{LinkRx, LinkTx};
-use rumqttd::Meter::Router;
+use rumqttd::Meter::{Router, Subscription};
 use rumqttd::{alerts::AlertsLink, meters::MetersLink, Alert, Meter, Notification};
 use std::sync::Arc;
 use tokio::select;
@@ -8,8 +8,11 @@ use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
+use crate::clock::Clock;
 use crate::mqtt::messages::{MqttCommand, MqttError, MqttMessage, MqttSender};
-use crate::mqtt::server::MqttServerMetrics;
+use crate::mqtt::server::{
+    DropAlert, MqttServerMetrics, RouterMeterSnapshot, SubscriptionMeterSnapshot, DROP_WINDOW_SECS,
+};
 
 pub(crate) struct ServerLinks {
     pub(crate) tx_link: Option<LinkTx>,
@@ -19,16 +22,36 @@ pub(crate) struct ServerLinks {
     pub(crate) publish_receiver: flume::Receiver<MqttCommand>,
     pub(crate) publish_sender: MqttSender,
     pub(crate) enable_heartbeat: bool,
+    pub(crate) public_prefix: String,
+    pub(crate) drop_alert_threshold: u64,
+    pub(crate) publish_drop_alerts: bool,
+    pub(crate) clock: Arc<dyn Clock>,
     pub(crate) message_sender: flume::Sender<MqttMessage>,
 }
 
-fn handle_meter(meters: Vec<Meter>) {
+fn handle_meter(meters: Vec<Meter>, metrics: &Arc<MqttServerMetrics>) {
     for meter in meters {
         match meter {
             Router(_s, r) => {
                 debug!("Router Meter {}: {:?}", r.sequence, r);
+                metrics.record_router_meter(RouterMeterSnapshot {
+                    timestamp: r.timestamp as u64,
+                    sequence: r.sequence,
+                    total_connections: r.total_connections,
+                    total_subscriptions: r.total_subscriptions,
+                    total_publishes: r.total_publishes,
+                    failed_publishes: r.failed_publishes,
+                });
+            }
+            Subscription(filter, s) => {
+                metrics.record_subscription_meter(SubscriptionMeterSnapshot {
+                    filter,
+                    timestamp: s.timestamp as u64,
+                    sequence: s.sequence,
+                    count: s.count,
+                    total_size: s.total_size,
+                });
             }
-            _ => {}
         }
     }
 }
@@ -73,6 +96,7 @@ async fn mqtt_message_handler(
     mut rx_link: LinkRx,
     message_forward: flume::Sender<MqttMessage>,
     metrics: &Arc<MqttServerMetrics>,
+    clock: Arc<dyn Clock>,
 ) {
     while let Ok(next_notification) = rx_link.next().await {
         if let Some(notification) = next_notification {
@@ -85,9 +109,7 @@ async fn mqtt_message_handler(
                             payload: payload.clone(),
                         });
                         if let Err(_) = res {
-                            metrics
-                                .messages_dropped
-                                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            metrics.record_dropped(clock.now_secs());
                             warn!("Message Dropped");
                             // TODO - figure out how to buffer messages
                         } else {
@@ -111,19 +133,24 @@ async fn alert_handler(alerts: AlertsLink) {
     info!("alert_handler stopped");
 }
 
-async fn meter_handler(metrics: MetersLink) {
-    while let Ok(metric) = metrics.next().await {
-        handle_meter(metric);
+async fn meter_handler(meters: MetersLink, metrics: &Arc<MqttServerMetrics>) {
+    while let Ok(meter) = meters.next().await {
+        handle_meter(meter, metrics);
     }
     info!("meter_handler stopped");
 }
 
-async fn heartbeat_task(publish_channel: MqttSender) {
+async fn heartbeat_task(
+    publish_channel: MqttSender,
+    public_prefix: String,
+    clock: Arc<dyn Clock>,
+) {
+    let topic = format!("{}heartbeat", public_prefix);
     loop {
         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-        let now = chrono::Utc::now().timestamp() as u64;
+        let now = clock.now_secs();
         let payload = format!("{{\"ts\":{}}}", now).into_bytes();
-        if let Err(e) = publish_channel.publish("public/heartbeat".to_string(), payload).await {
+        if let Err(e) = publish_channel.publish(topic.clone(), payload).await {
             error!(error=?e, "Error sending heartbeat");
             break;
         } else {
@@ -133,6 +160,46 @@ async fn heartbeat_task(publish_channel: MqttSender) {
     info!("heartbeat_task stopped");
 }
 
+/// Checks the dropped messages of the last minute every 10 seconds
+async fn drop_alert_task(
+    metrics: Arc<MqttServerMetrics>,
+    clock: Arc<dyn Clock>,
+    threshold: u64,
+    alert_channel: Option<(MqttSender, String)>,
+) {
+    let mut alert = DropAlert::new(threshold);
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        let now = clock.now_secs();
+        let Some(dropped) = alert.check(&metrics, now) else {
+            continue;
+        };
+        warn!(
+            dropped,
+            threshold,
+            "{} messages dropped in the last {} seconds, the processor can't keep up",
+            dropped,
+            DROP_WINDOW_SECS
+        );
+        if let Some((publish_channel, topic)) = &alert_channel {
+            let payload = serde_json::json!({
+                "ts": now,
+                "dropped": dropped,
+                "window_secs": DROP_WINDOW_SECS,
+                "threshold": threshold,
+            });
+            if let Err(e) = publish_channel
+                .publish(topic.clone(), payload.to_string().into_bytes())
+                .await
+            {
+                error!(error=?e, "Error sending drop alert");
+                break;
+            }
+        }
+    }
+    info!("drop_alert_task stopped");
+}
+
 pub(crate) async fn start_event_handlers(
     mut links: ServerLinks,
     metrics: &Arc<MqttServerMetrics>,
@@ -145,9 +212,10 @@ pub(crate) async fn start_event_handlers(
     let _rx_handle = {
         let rx_link = std::mem::replace(&mut links.rx_link, None).expect("No rx_link available");
         let metric_clone = metrics.clone();
+        let clock = links.clock.clone();
         set.spawn(async move {
             let message_forward = links.message_sender;
-            mqtt_message_handler(rx_link, message_forward, &metric_clone).await;
+            mqtt_message_handler(rx_link, message_forward, &metric_clone, clock).await;
         })
     };
 
@@ -167,17 +235,37 @@ pub(crate) async fn start_event_handlers(
     };
 
     let _metrics_handle = {
-        let metrics =
+        let meters =
             std::mem::replace(&mut links.metrics, None).expect("No metrics link available");
+        let metric_clone = metrics.clone();
         set.spawn(async move {
-            meter_handler(metrics).await;
+            meter_handler(meters, &metric_clone).await;
         })
     };
 
     let _heartbeat_handle = if enable_heartbeat {
         let publish_channel = links.publish_sender.clone();
+        let public_prefix = links.public_prefix.clone();
+        let clock = links.clock.clone();
+        Some(set.spawn(async move {
+            heartbeat_task(publish_channel, public_prefix, clock).await;
+        }))
+    } else {
+        None
+    };
+
+    let _drop_alert_handle = if links.drop_alert_threshold > 0 {
+        let alert_channel = links.publish_drop_alerts.then(|| {
+            (
+                links.publish_sender.clone(),
+                format!("{}alerts/messages_dropped", links.public_prefix),
+            )
+        });
+        let metric_clone = metrics.clone();
+        let clock = links.clock.clone();
+        let threshold = links.drop_alert_threshold;
         Some(set.spawn(async move {
-            heartbeat_task(publish_channel).await;
+            drop_alert_task(metric_clone, clock, threshold, alert_channel).await;
         }))
     } else {
         None
@@ -1,7 +1,10 @@
+use dashmap::DashMap;
 use rumqttd::{AdminLink, Broker, ClientStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, AtomicU64};
-use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
 use tokio::sync::broadcast::{Receiver, Sender};
 use tokio_util::sync::CancellationToken;
@@ -15,10 +18,158 @@ use crate::mqtt::messages::{MqttCommand, MqttMessage, MqttSender};
 
 pub static GLOBAL_DB: OnceLock<Arc<DB>> = OnceLock::new();
 
+/// Router meters pushed by the broker every `push_interval` seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouterMeterSnapshot {
+    pub timestamp: u64,
+    pub sequence: usize,
+    pub total_connections: usize,
+    pub total_subscriptions: usize,
+    pub total_publishes: usize,
+    pub failed_publishes: usize,
+}
+
+/// Latest meter of a subscription filter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionMeterSnapshot {
+    pub filter: String,
+    pub timestamp: u64,
+    pub sequence: usize,
+    /// Messages appended to the filter
+    pub count: usize,
+    /// Payload bytes appended to the filter
+    pub total_size: usize,
+}
+
+/// Seconds covered by the windowed count of dropped messages
+pub const DROP_WINDOW_SECS: u64 = 60;
+
 pub struct MqttServerMetrics {
     pub messages_forwarded: AtomicU64,
     pub messages_sent: AtomicU64,
     pub messages_dropped: AtomicU64,
+    /// Drops per second of the last `DROP_WINDOW_SECS`, oldest first
+    recent_drops: Mutex<VecDeque<(u64, u64)>>,
+    /// Last `meter_capacity` router meters, oldest first
+    router_meters: Mutex<VecDeque<RouterMeterSnapshot>>,
+    meter_capacity: usize,
+    subscription_meters: DashMap<String, SubscriptionMeterSnapshot>,
+}
+
+impl MqttServerMetrics {
+    pub fn new(meter_capacity: usize) -> Self {
+        MqttServerMetrics {
+            messages_forwarded: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            messages_dropped: AtomicU64::new(0),
+            recent_drops: Mutex::new(VecDeque::with_capacity(DROP_WINDOW_SECS as usize)),
+            router_meters: Mutex::new(VecDeque::with_capacity(meter_capacity)),
+            meter_capacity,
+            subscription_meters: DashMap::new(),
+        }
+    }
+
+    /// Counts a message that couldn't be forwarded to the processor
+    pub fn record_dropped(&self, now_secs: u64) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+        let mut drops = self.recent_drops.lock().unwrap();
+        match drops.back_mut() {
+            Some((second, count)) if *second == now_secs => *count += 1,
+            _ => drops.push_back((now_secs, 1)),
+        }
+        while drops
+            .front()
+            .is_some_and(|(second, _)| second + DROP_WINDOW_SECS <= now_secs)
+        {
+            drops.pop_front();
+        }
+    }
+
+    /// Messages dropped within the `DROP_WINDOW_SECS` up to `now_secs`
+    pub fn dropped_in_window(&self, now_secs: u64) -> u64 {
+        self.recent_drops
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(second, _)| second + DROP_WINDOW_SECS > now_secs)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Stores a router meter, dropping the oldest one once `meter_capacity` are kept
+    pub fn record_router_meter(&self, snapshot: RouterMeterSnapshot) {
+        if self.meter_capacity == 0 {
+            return;
+        }
+        let mut meters = self.router_meters.lock().unwrap();
+        if meters.len() >= self.meter_capacity {
+            meters.pop_front();
+        }
+        meters.push_back(snapshot);
+    }
+
+    /// Captured router meters, oldest first
+    pub fn router_meters(&self) -> Vec<RouterMeterSnapshot> {
+        self.router_meters.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn meter_capacity(&self) -> usize {
+        self.meter_capacity
+    }
+
+    /// Replaces the meter of the subscription filter
+    pub fn record_subscription_meter(&self, snapshot: SubscriptionMeterSnapshot) {
+        self.subscription_meters
+            .insert(snapshot.filter.clone(), snapshot);
+    }
+
+    /// Latest meter of every subscription filter, sorted by filter
+    pub fn subscription_meters(&self) -> Vec<SubscriptionMeterSnapshot> {
+        let mut meters: Vec<SubscriptionMeterSnapshot> = self
+            .subscription_meters
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+        meters.sort_by(|a, b| a.filter.cmp(&b.filter));
+        meters
+    }
+}
+
+/// Decides when more than `threshold` dropped messages within the window are reported.
+/// While the drops continue the alert is repeated once per window.
+pub struct DropAlert {
+    threshold: u64,
+    last_alert: Option<u64>,
+}
+
+impl DropAlert {
+    /// A `threshold` of 0 never alerts
+    pub fn new(threshold: u64) -> Self {
+        DropAlert {
+            threshold,
+            last_alert: None,
+        }
+    }
+
+    /// Returns the windowed drop count if an alert is due
+    pub fn check(&mut self, metrics: &MqttServerMetrics, now_secs: u64) -> Option<u64> {
+        if self.threshold == 0 {
+            return None;
+        }
+        let dropped = metrics.dropped_in_window(now_secs);
+        if dropped <= self.threshold {
+            self.last_alert = None;
+            return None;
+        }
+        if self
+            .last_alert
+            .is_some_and(|alerted| alerted + DROP_WINDOW_SECS > now_secs)
+        {
+            return None;
+        }
+        self.last_alert = Some(now_secs);
+        Some(dropped)
+    }
 }
 
 pub struct MqttServer {
@@ -52,6 +203,7 @@ impl MqttServer {
 }
 
 pub async fn start_broker(mqtt_config: Option<MqttConfig>, db: Arc<DB>) -> MqttServer {
+    let clock = db.clock().clone();
     // Initialize the global DB for the auth handler
     let _ = GLOBAL_DB.set(db);
 
@@ -149,6 +301,10 @@ pub async fn start_broker(mqtt_config: Option<MqttConfig>, db: Arc<DB>) -> MqttS
         publish_sender: sender.clone(),
         publish_receiver: rx,
         enable_heartbeat: enable_heartbeat,
+        public_prefix: mqtt_config.public_prefix.clone(),
+        drop_alert_threshold: mqtt_config.drop_alert_threshold,
+        publish_drop_alerts: mqtt_config.publish_drop_alerts,
+        clock,
         message_sender: message_sender,
     };
 
@@ -169,11 +325,7 @@ pub async fn start_broker(mqtt_config: Option<MqttConfig>, db: Arc<DB>) -> MqttS
     // sender.subscribe("#".to_string()).await.unwrap();
 
     // Create Metrics
-    let metrics = Arc::new(MqttServerMetrics {
-        messages_forwarded: AtomicU64::new(0),
-        messages_sent: AtomicU64::new(0),
-        messages_dropped: AtomicU64::new(0),
-    });
+    let metrics = Arc::new(MqttServerMetrics::new(mqtt_config.meter_snapshots));
 
     // onshot channel for shutdown signal
     // let (background_sd_s, background_sd_r) = tokio::sync::oneshot::channel::<usize>();
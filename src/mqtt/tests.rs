@@ -1,6 +1,6 @@
 use super::*;
 use crate::db::{DatabaseConfig, DB};
-use crate::models::{AuthConfig, DeviceCredential, Tenant, TenantId};
+use crate::models::{AuthConfig, DeviceCredential, DeviceMetadata, DeviceStatus, Tenant, TenantId};
 use std::sync::Arc;
 use std::time::Duration;
 use tempfile::TempDir;
@@ -82,6 +82,32 @@ async fn test_publish_subscribe() {
     server.shutdown();
 }
 
+#[tokio::test]
+async fn test_heartbeat_uses_public_prefix() {
+    let (db, _temp) = setup_db().await;
+    let mut config = MqttConfig::default();
+    config.bind_v3 = "127.0.0.1:0".to_string();
+    config.bind_v5 = "127.0.0.1:0".to_string();
+    config.public_prefix = "cluster-a/public/".to_string();
+    let mut server = start_broker(Some(config), db).await;
+    let receiver = server.message_receiver();
+
+    for topic in ["cluster-a/public/heartbeat", "public/heartbeat"] {
+        server.mqtt.subscribe(topic.to_string()).await.unwrap();
+    }
+
+    // Heartbeats are sent every 5 seconds
+    let msg = tokio::time::timeout(Duration::from_secs(8), receiver.recv_async())
+        .await
+        .expect("Timeout waiting for heartbeat")
+        .expect("Channel closed");
+    assert_eq!(msg.topic, "cluster-a/public/heartbeat");
+    let payload: serde_json::Value = serde_json::from_slice(&msg.payload).unwrap();
+    assert!(payload["ts"].is_u64());
+
+    server.shutdown();
+}
+
 #[tokio::test]
 async fn test_auth_handler() {
     let (setup_db_inst, _temp) = setup_db().await;
@@ -123,6 +149,12 @@ async fn test_auth_handler() {
     let client_info = result.unwrap().unwrap();
     assert_eq!(client_info.client_id, "device1");
     assert_eq!(client_info.tenant.unwrap(), "test_tenant");
+    // The connection monitor takes the tenant once the client is connected
+    assert_eq!(
+        auth::take_authenticated_tenant("device1"),
+        Some(tenant_id.clone())
+    );
+    assert_eq!(auth::take_authenticated_tenant("device1"), None);
 
     // Test invalid password auth
     let result = auth(
@@ -188,4 +220,35 @@ async fn test_auth_handler() {
     )
     .await;
     assert!(result.unwrap().is_none());
+
+    // Inactive and decommissioned devices are rejected, whatever the credentials
+    let cert_auth = || {
+        auth(
+            "device_cert_1".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "device_cert_1".to_string(),
+            "test_tenant".to_string(),
+            None,
+        )
+    };
+    db.put_device_metadata(&DeviceMetadata::new("device_cert_1", &tenant_id))
+        .await
+        .unwrap();
+    assert!(cert_auth().await.unwrap().is_some());
+    for (status, allowed) in [
+        (DeviceStatus::Inactive, false),
+        (DeviceStatus::Decommissioned, false),
+        (DeviceStatus::Active, true),
+    ] {
+        db.set_device_status(&tenant_id, "device_cert_1", status)
+            .await
+            .unwrap();
+        assert_eq!(
+            cert_auth().await.unwrap().is_some(),
+            allowed,
+            "{:?}",
+            status
+        );
+    }
 }
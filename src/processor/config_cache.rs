@@ -0,0 +1,133 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+use crate::dataconfig::DataConfig;
+use crate::db::{DatabaseError, DB};
+use crate::models::TenantId;
+use crate::processor::ingest::IngestMetrics;
+
+/// Devices cached at most, expired entries are dropped when it is reached
+pub const CONFIG_CACHE_CAPACITY: usize = 10_000;
+
+/// Pending invalidations, the cache is cleared completely if a listener falls behind
+const INVALIDATION_CAPACITY: usize = 64;
+
+/// Sent when a data config of the tenant was stored or deleted
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigInvalidation {
+    pub tenant_id: TenantId,
+}
+
+pub type ConfigInvalidationSender = broadcast::Sender<ConfigInvalidation>;
+
+pub fn config_invalidation_channel() -> ConfigInvalidationSender {
+    broadcast::channel(INVALIDATION_CAPACITY).0
+}
+
+struct CachedConfig {
+    loaded_at: Instant,
+    config: Option<Arc<DataConfig>>,
+}
+
+/// Merged data config per (tenant, device), so extraction doesn't query the DB for every message
+pub struct DataConfigCache {
+    entries: DashMap<(String, String), CachedConfig>,
+    metrics: Arc<IngestMetrics>,
+}
+
+impl Default for DataConfigCache {
+    fn default() -> Self {
+        DataConfigCache::new(Arc::new(IngestMetrics::default()))
+    }
+}
+
+impl DataConfigCache {
+    pub fn new(metrics: Arc<IngestMetrics>) -> Self {
+        DataConfigCache {
+            entries: DashMap::new(),
+            metrics,
+        }
+    }
+
+    /// Returns the config of the device, loaded from the DB if it isn't cached or older than
+    /// `ttl`. A zero `ttl` disables the cache.
+    pub async fn get(
+        &self,
+        db: &DB,
+        tenant_id: &TenantId,
+        device_id: &str,
+        ttl: Duration,
+    ) -> Result<Option<Arc<DataConfig>>, DatabaseError> {
+        let key = (tenant_id.to_string(), device_id.to_string());
+        if !ttl.is_zero() {
+            if let Some(cached) = self.entries.get(&key) {
+                if cached.loaded_at.elapsed() < ttl {
+                    self.metrics
+                        .config_cache_hits
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Ok(cached.config.clone());
+                }
+            }
+        }
+        self.metrics
+            .config_cache_misses
+            .fetch_add(1, Ordering::Relaxed);
+
+        let config = db
+            .get_data_config(tenant_id, Some(device_id))
+            .await?
+            .map(Arc::new);
+        if !ttl.is_zero() {
+            if self.entries.len() >= CONFIG_CACHE_CAPACITY {
+                self.entries
+                    .retain(|_, cached| cached.loaded_at.elapsed() < ttl);
+                if self.entries.len() >= CONFIG_CACHE_CAPACITY {
+                    self.entries.clear();
+                }
+            }
+            self.entries.insert(
+                key,
+                CachedConfig {
+                    loaded_at: Instant::now(),
+                    config: config.clone(),
+                },
+            );
+        }
+        Ok(config)
+    }
+
+    /// Drops the cached configs of all devices of the tenant, a prefix config can apply to any
+    pub fn invalidate_tenant(&self, tenant_id: &TenantId) {
+        let tenant_id = tenant_id.to_string();
+        self.entries.retain(|(tenant, _), _| *tenant != tenant_id);
+    }
+
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    /// Applies invalidations until all senders are dropped
+    pub async fn listen(&self, mut receiver: broadcast::Receiver<ConfigInvalidation>) {
+        loop {
+            match receiver.recv().await {
+                Ok(invalidation) => {
+                    debug!(tenant_id = %invalidation.tenant_id, "Invalidating data configs");
+                    self.invalidate_tenant(&invalidation.tenant_id);
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(
+                        skipped,
+                        "Missed data config invalidations, clearing the cache"
+                    );
+                    self.clear();
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
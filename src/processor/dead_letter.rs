@@ -0,0 +1,111 @@
+use dashmap::DashMap;
+use tracing::warn;
+
+use crate::clock::Clock;
+use crate::models::{DeadLetter, TenantId};
+use crate::processor::ProcessorState;
+
+/// Consecutive failures of a topic before its message is dead lettered
+pub const DEAD_LETTER_THRESHOLD: u32 = 3;
+/// Failures further apart than this start counting again
+pub const DEAD_LETTER_WINDOW_SECS: u64 = 60;
+
+/// Counts consecutive processing failures per topic
+#[derive(Debug, Default)]
+pub struct FailureTracker {
+    /// topic -> (failures, time of the first failure)
+    failures: DashMap<String, (u32, u64)>,
+}
+
+impl FailureTracker {
+    /// Records a failure, returns true once the threshold is reached within the window.
+    /// The count is reset when that happens, so the next failure starts over.
+    pub fn record_failure(&self, topic: &str, now: u64) -> bool {
+        let mut entry = self.failures.entry(topic.to_string()).or_insert((0, now));
+        let (count, first_failure) = entry.value_mut();
+        if now.saturating_sub(*first_failure) > DEAD_LETTER_WINDOW_SECS {
+            *count = 0;
+            *first_failure = now;
+        }
+        *count += 1;
+        if *count >= DEAD_LETTER_THRESHOLD {
+            drop(entry);
+            self.failures.remove(topic);
+            return true;
+        }
+        false
+    }
+
+    pub fn record_success(&self, topic: &str) {
+        self.failures.remove(topic);
+    }
+}
+
+pub(crate) async fn handle_processing_result(
+    state: &ProcessorState,
+    tenant_id: &TenantId,
+    device_id: &str,
+    topic: &str,
+    payload: &[u8],
+    errors: Vec<String>,
+) {
+    if errors.is_empty() {
+        state.failures.record_success(topic);
+        return;
+    }
+
+    let now = state.clock.now_secs();
+    if !state.failures.record_failure(topic, now) {
+        return;
+    }
+    store_dead_letter(
+        state,
+        tenant_id,
+        device_id,
+        topic,
+        payload,
+        errors.join("; "),
+    )
+    .await;
+}
+
+/// Dead letters a message that is rejected without processing, e.g. one published into the
+/// namespace of another tenant. Retrying can't succeed, so the threshold doesn't apply.
+pub(crate) async fn handle_rejected_message(
+    state: &ProcessorState,
+    tenant_id: &TenantId,
+    device_id: &str,
+    topic: &str,
+    payload: &[u8],
+    error: String,
+) {
+    if state.config.read().unwrap().dead_letter_enabled {
+        store_dead_letter(state, tenant_id, device_id, topic, payload, error).await;
+    }
+}
+
+async fn store_dead_letter(
+    state: &ProcessorState,
+    tenant_id: &TenantId,
+    device_id: &str,
+    topic: &str,
+    payload: &[u8],
+    error: String,
+) {
+    let dead_letter = DeadLetter {
+        tenant_id: tenant_id.clone(),
+        device_id: device_id.to_string(),
+        topic: topic.to_string(),
+        payload: String::from_utf8_lossy(payload).into_owned(),
+        error,
+        failed_at: state.clock.now_secs(),
+    };
+    warn!(
+        topic = topic,
+        error = dead_letter.error,
+        "Storing dead letter"
+    );
+    if let Err(e) = state.db.store_dead_letter(&dead_letter).await {
+        warn!(error=?e, "Failed to store dead letter");
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use crate::db::MetricRow;
+use crate::processor::ingest::IngestMetrics;
+use crate::timeseries::MetricValue;
+
+/// Metrics remembered at most, the least recently seen are evicted first
+pub const DEDUP_CAPACITY: usize = 10_000;
+
+type MetricKey = (String, String, String);
+
+#[derive(Debug)]
+struct LastValue {
+    timestamp: u64,
+    value: MetricValue,
+    /// Arrival time of the last value
+    seen_at: u64,
+}
+
+/// Remembers the last value of every (tenant, device, metric) to skip retransmissions
+#[derive(Debug)]
+pub struct MetricDeduplicator {
+    last_values: Mutex<HashMap<MetricKey, LastValue>>,
+    capacity: usize,
+    metrics: Arc<IngestMetrics>,
+}
+
+impl Default for MetricDeduplicator {
+    fn default() -> Self {
+        MetricDeduplicator::new(DEDUP_CAPACITY, Arc::new(IngestMetrics::default()))
+    }
+}
+
+impl MetricDeduplicator {
+    pub fn new(capacity: usize, metrics: Arc<IngestMetrics>) -> Self {
+        MetricDeduplicator {
+            last_values: Mutex::new(HashMap::new()),
+            capacity: capacity.max(1),
+            metrics,
+        }
+    }
+
+    /// True if the same timestamp and value was seen for this metric at most `window_secs`
+    /// ago, counted in `IngestMetrics::deduplicated`. Otherwise the value is remembered.
+    pub fn is_duplicate(&self, row: &MetricRow, now: u64, window_secs: u64) -> bool {
+        let key = (
+            row.tenant_id.to_string(),
+            row.device_id.clone(),
+            row.metric_name.clone(),
+        );
+        let mut last_values = self.last_values.lock().unwrap();
+        if let Some(last) = last_values.get(&key) {
+            let duplicate = last.timestamp == row.timestamp
+                && last.value == row.value
+                && now.saturating_sub(last.seen_at) <= window_secs;
+            if duplicate {
+                self.metrics.deduplicated.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        } else if last_values.len() >= self.capacity {
+            // Expired entries can't match anymore, drop them before evicting live ones
+            last_values.retain(|_, last| now.saturating_sub(last.seen_at) <= window_secs);
+            if last_values.len() >= self.capacity {
+                let oldest = last_values
+                    .iter()
+                    .min_by_key(|(_, last)| last.seen_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    last_values.remove(&oldest);
+                }
+            }
+        }
+        last_values.insert(
+            key,
+            LastValue {
+                timestamp: row.timestamp,
+                value: row.value.clone(),
+                seen_at: now,
+            },
+        );
+        false
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::db::{MetricRow, DB};
+
+/// Counters of the metric ingest path, shown on the API home endpoint
+#[derive(Debug, Default)]
+pub struct IngestMetrics {
+    /// Rows queued in the buffer
+    pub buffered: AtomicU64,
+    /// Rows written by the flusher
+    pub flushed: AtomicU64,
+    /// Rows written directly because the buffer was full
+    pub direct: AtomicU64,
+    /// Retransmitted rows and values equal to the stored one skipped by the deduplication
+    pub deduplicated: AtomicU64,
+    /// Data configs served from the processor cache
+    pub config_cache_hits: AtomicU64,
+    /// Data configs loaded from the database
+    pub config_cache_misses: AtomicU64,
+    /// MQTT messages dropped by the per device message rate limit
+    pub throttled: AtomicU64,
+    /// MQTT messages dropped because their payload exceeds `max_payload_bytes`
+    pub oversized: AtomicU64,
+    /// Streamed metric points skipped because a client fell behind
+    pub stream_dropped: AtomicU64,
+}
+
+/// Bounded queue of metric rows, written in batches by a background flusher
+pub struct IngestBuffer {
+    sender: flume::Sender<MetricRow>,
+    metrics: Arc<IngestMetrics>,
+    cancel_token: CancellationToken,
+    flusher: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl IngestBuffer {
+    /// Starts the flusher, it writes every `flush_interval` or when `batch_size` rows are queued
+    pub fn start(
+        db: Arc<DB>,
+        metrics: Arc<IngestMetrics>,
+        capacity: usize,
+        batch_size: usize,
+        flush_interval: Duration,
+    ) -> Self {
+        let (sender, receiver) = flume::bounded(capacity.max(1));
+        let cancel_token = CancellationToken::new();
+        let flusher = tokio::spawn(run_flusher(
+            receiver,
+            db,
+            metrics.clone(),
+            batch_size.max(1),
+            flush_interval,
+            cancel_token.clone(),
+        ));
+        IngestBuffer {
+            sender,
+            metrics,
+            cancel_token,
+            flusher: Mutex::new(Some(flusher)),
+        }
+    }
+
+    /// Queues a row, hands it back if the buffer is full or shut down
+    pub fn push(&self, row: MetricRow) -> Result<(), MetricRow> {
+        if self.cancel_token.is_cancelled() {
+            return Err(row);
+        }
+        match self.sender.try_send(row) {
+            Ok(()) => {
+                self.metrics.buffered.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(flume::TrySendError::Full(row)) | Err(flume::TrySendError::Disconnected(row)) => {
+                Err(row)
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> &Arc<IngestMetrics> {
+        &self.metrics
+    }
+
+    /// Writes all queued rows and stops the flusher, later rows have to be written directly
+    pub async fn shutdown(&self) {
+        self.cancel_token.cancel();
+        let flusher = self.flusher.lock().unwrap().take();
+        if let Some(flusher) = flusher {
+            let _ = flusher.await;
+        }
+    }
+}
+
+async fn run_flusher(
+    receiver: flume::Receiver<MetricRow>,
+    db: Arc<DB>,
+    metrics: Arc<IngestMetrics>,
+    batch_size: usize,
+    flush_interval: Duration,
+    cancel_token: CancellationToken,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut ticker = tokio::time::interval(flush_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            row = receiver.recv_async() => match row {
+                Ok(row) => {
+                    batch.push(row);
+                    if batch.len() >= batch_size {
+                        flush(&db, &metrics, &batch).await;
+                        batch.clear();
+                    }
+                }
+                Err(_) => break,
+            },
+            _ = ticker.tick() => {
+                flush(&db, &metrics, &batch).await;
+                batch.clear();
+            }
+            _ = cancel_token.cancelled() => break,
+        }
+    }
+
+    batch.extend(receiver.drain());
+    // Later pushes fail and are written directly
+    drop(receiver);
+    debug!(rows = batch.len(), "Flushing ingest buffer on shutdown");
+    for chunk in batch.chunks(batch_size) {
+        flush(&db, &metrics, chunk).await;
+    }
+}
+
+async fn flush(db: &DB, metrics: &IngestMetrics, batch: &[MetricRow]) {
+    if batch.is_empty() {
+        return;
+    }
+    match db.insert_metric_rows(batch).await {
+        Ok(written) => {
+            metrics.flushed.fetch_add(written as u64, Ordering::Relaxed);
+            debug!(rows = written, "Flushed ingest buffer");
+        }
+        Err(e) => {
+            warn!(error=?e, rows = batch.len(), "Failed to flush ingest buffer");
+        }
+    }
+}
@@ -1,26 +1,58 @@
+pub mod config_cache;
+pub mod dead_letter;
+pub mod dedup;
+pub mod ingest;
+pub mod rate_limit;
+pub mod retry;
 pub mod shadow;
+pub mod stream;
 pub mod time;
 pub mod timeseries;
 pub mod topics;
+pub mod webhooks;
 
 pub use shadow::send_delta_to_mqtt;
 
+use crate::clock::Clock;
 use rumqttd::AdminLink;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::broadcast::Receiver;
+use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
 use tracing::{debug, debug_span, warn, Instrument};
 
 use crate::db::DB;
+use crate::models::TenantId;
+use crate::mqtt::auth::take_authenticated_tenant;
 use crate::mqtt::{ClientStatus, MqttError, MqttMessage, MqttSender};
-use crate::server::ConnectionSet;
+use crate::server::{ConnectionInfo, ConnectionSet};
 
+use crate::processor::config_cache::{
+    config_invalidation_channel, ConfigInvalidationSender, DataConfigCache,
+};
+use crate::processor::dead_letter::{
+    handle_processing_result, handle_rejected_message, FailureTracker,
+};
+use crate::processor::dedup::{MetricDeduplicator, DEDUP_CAPACITY};
+use crate::processor::ingest::{IngestBuffer, IngestMetrics};
+use crate::processor::rate_limit::{
+    IngestRateLimit, IngestRateLimiter, ShadowRateLimiter, ThrottleNotice,
+};
 use crate::processor::shadow::handle_shadow_update;
+use crate::processor::stream::{metric_stream_channel, MetricStreamSender};
 use crate::processor::time::handle_time_request;
-use crate::processor::timeseries::handle_metric_extraction;
-use crate::processor::topics::{get_topic_type, TopicType};
+use crate::processor::timeseries::{handle_metric_extraction, MetricSource};
+use crate::processor::topics::{
+    get_topic_type, overlapping_topics, shadow_subscription_filters, subscription_filter,
+    topic_device_id, TopicType,
+};
+
+/// Bytes of an oversized payload kept in its dead letter
+const OVERSIZED_PREVIEW_BYTES: usize = 1024;
 
 #[derive(Error, Debug)]
 pub enum ProcessorError {
@@ -42,6 +74,150 @@ pub enum ProcessorError {
 pub struct ProcessorConfig {
     pub shadow_topic_prefix: String,
     pub telemetry_topics: Vec<String>,
+    /// Topics carrying shadow updates, with the placeholders of `telemetry_topics` and
+    /// `{shadow}`. Without a `{shadow}` placeholder the `+` after the device is the shadow
+    /// name. Unset, the shadow update topics below
+    /// `shadow_topic_prefix`, see `shadow_update_patterns()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow_update_patterns: Option<Vec<String>>,
+    /// Topics carrying time requests, with the placeholders of `telemetry_topics`.
+    /// Unset, the time request topic below `shadow_topic_prefix`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_request_patterns: Option<Vec<String>>,
+    /// Publish the full state to `.../update/accepted` after every MQTT shadow update
+    #[serde(default)]
+    pub publish_accepted: bool,
+    /// Publish an error to `.../update/rejected` when an MQTT shadow update fails
+    #[serde(default)]
+    pub publish_rejected: bool,
+    /// Messages processed at the same time, reading from the broker pauses while all are busy.
+    /// Only read at startup.
+    #[serde(default = "default_max_concurrent_messages")]
+    pub max_concurrent_messages: usize,
+    /// Store messages that failed three times in a row in the `dead_letters` table
+    #[serde(default)]
+    pub dead_letter_enabled: bool,
+    /// Skip metric values with the same timestamp and value as the last one received within
+    /// this many seconds, e.g. retransmissions after a reconnect. `None` disables it.
+    #[serde(default)]
+    pub dedup_window_secs: Option<u64>,
+    /// Payload timestamps older than this are replaced by the server time
+    #[serde(default = "default_max_timestamp_age_secs")]
+    pub max_timestamp_age_secs: u64,
+    /// Deltas larger than this are published in chunks, 0 never splits them
+    #[serde(default)]
+    pub max_delta_bytes: usize,
+    /// Metric rows queued for batched writes, 0 writes every message directly.
+    /// Only read at startup, like the other `ingest_` settings.
+    #[serde(default)]
+    pub ingest_buffer_size: usize,
+    /// Rows written in one transaction
+    #[serde(default = "default_ingest_batch_size")]
+    pub ingest_batch_size: usize,
+    /// Queued rows are written at least this often
+    #[serde(default = "default_ingest_flush_interval_ms")]
+    pub ingest_flush_interval_ms: u64,
+    /// Retry shadow upserts and metric writes failing because the database is busy/locked
+    #[serde(default)]
+    pub retry_db_operations: bool,
+    /// Attempts including the first one
+    #[serde(default = "default_db_retry_max_attempts")]
+    pub db_retry_max_attempts: u32,
+    /// Delay before the first retry, doubled for every further one
+    #[serde(default = "default_db_retry_base_delay_ms")]
+    pub db_retry_base_delay_ms: u64,
+    /// Refuse to start if a telemetry topic overlaps with the shadow topics instead of warning
+    #[serde(default)]
+    pub strict_topic_validation: bool,
+    /// Data configs are cached per device for this long, 0 queries the DB for every message
+    #[serde(default = "default_data_config_cache_ttl_secs")]
+    pub data_config_cache_ttl_secs: u64,
+    /// MQTT shadow updates processed per device and second, further ones are discarded.
+    /// Short bursts of up to this many updates are allowed, 0 disables the limit.
+    #[serde(default = "default_max_shadow_updates_per_second")]
+    pub max_shadow_updates_per_second: u32,
+    /// MQTT messages of any kind processed per device and second, further ones are dropped
+    /// before they are handled. 0 disables the limit, tenants can override it in their quota.
+    #[serde(default)]
+    pub max_messages_per_second: u32,
+    /// Messages a device can send at once after being idle
+    #[serde(default = "default_message_burst")]
+    pub message_burst: u32,
+    /// Publish a `ThrottleNotice` to `.../{device_id}/throttled` when messages of a device
+    /// are dropped, at most once per `THROTTLE_NOTICE_INTERVAL`
+    #[serde(default)]
+    pub publish_throttle_notices: bool,
+    /// Messages with larger payloads are dropped before they are parsed and counted in
+    /// `IngestMetrics::oversized`, 0 allows any size
+    #[serde(default = "default_max_payload_bytes")]
+    pub max_payload_bytes: usize,
+    /// `server_time` of MQTT time responses in milliseconds, seconds if false
+    #[serde(default = "default_time_response_millis")]
+    pub time_response_millis: bool,
+}
+
+impl ProcessorConfig {
+    /// `shadow_update_patterns`, by default `<prefix>+/shadow/update` for the default
+    /// shadow and `<prefix>+/shadow/+/update` for named shadows
+    pub fn shadow_update_patterns(&self) -> Vec<String> {
+        self.shadow_update_patterns.clone().unwrap_or_else(|| {
+            vec![
+                format!("{}+/shadow/update", self.shadow_topic_prefix),
+                format!("{}+/shadow/+/update", self.shadow_topic_prefix),
+            ]
+        })
+    }
+
+    /// `time_request_patterns`, by default `<prefix>+/time/request`
+    pub fn time_request_patterns(&self) -> Vec<String> {
+        self.time_request_patterns
+            .clone()
+            .unwrap_or_else(|| vec![format!("{}+/time/request", self.shadow_topic_prefix)])
+    }
+}
+
+fn default_max_concurrent_messages() -> usize {
+    100
+}
+
+fn default_max_timestamp_age_secs() -> u64 {
+    60 * 60 * 24 * 30
+}
+
+fn default_ingest_batch_size() -> usize {
+    500
+}
+
+fn default_ingest_flush_interval_ms() -> u64 {
+    1000
+}
+
+fn default_db_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_db_retry_base_delay_ms() -> u64 {
+    50
+}
+
+fn default_data_config_cache_ttl_secs() -> u64 {
+    60
+}
+
+fn default_max_shadow_updates_per_second() -> u32 {
+    10
+}
+
+fn default_message_burst() -> u32 {
+    100
+}
+
+fn default_max_payload_bytes() -> usize {
+    256 * 1024
+}
+
+fn default_time_response_millis() -> bool {
+    true
 }
 
 impl Default for ProcessorConfig {
@@ -49,6 +225,29 @@ impl Default for ProcessorConfig {
         ProcessorConfig {
             shadow_topic_prefix: "things/".to_string(),
             telemetry_topics: vec!["things/+/data".to_string()],
+            shadow_update_patterns: None,
+            time_request_patterns: None,
+            publish_accepted: false,
+            publish_rejected: false,
+            max_concurrent_messages: default_max_concurrent_messages(),
+            dead_letter_enabled: false,
+            dedup_window_secs: None,
+            max_timestamp_age_secs: default_max_timestamp_age_secs(),
+            max_delta_bytes: 0,
+            ingest_buffer_size: 0,
+            ingest_batch_size: default_ingest_batch_size(),
+            ingest_flush_interval_ms: default_ingest_flush_interval_ms(),
+            retry_db_operations: false,
+            db_retry_max_attempts: default_db_retry_max_attempts(),
+            db_retry_base_delay_ms: default_db_retry_base_delay_ms(),
+            strict_topic_validation: false,
+            data_config_cache_ttl_secs: default_data_config_cache_ttl_secs(),
+            max_shadow_updates_per_second: default_max_shadow_updates_per_second(),
+            max_messages_per_second: 0,
+            message_burst: default_message_burst(),
+            publish_throttle_notices: false,
+            max_payload_bytes: default_max_payload_bytes(),
+            time_response_millis: default_time_response_millis(),
         }
     }
 }
@@ -56,15 +255,41 @@ impl Default for ProcessorConfig {
 pub struct ProcessorState {
     db: Arc<DB>,
     mqtt_sender: MqttSender,
-    config: Arc<ProcessorConfig>,
+    config: Arc<RwLock<ProcessorConfig>>,
+    failures: Arc<FailureTracker>,
+    /// Set when metric writes are batched
+    ingest: Option<Arc<IngestBuffer>>,
+    dedup: Arc<MetricDeduplicator>,
+    ingest_metrics: Arc<IngestMetrics>,
+    config_cache: Arc<DataConfigCache>,
+    shadow_rate_limiter: Arc<ShadowRateLimiter>,
+    ingest_rate_limiter: Arc<IngestRateLimiter>,
+    metric_stream: MetricStreamSender,
+    clock: Arc<dyn Clock>,
 }
 
 pub struct Processor {
     pub db: Arc<DB>,
     pub mqtt_sender: MqttSender,
+    /// Shared with the workers, writing to it applies the new settings to the next message
+    pub config: Arc<RwLock<ProcessorConfig>>,
+    pub ingest_metrics: Arc<IngestMetrics>,
+    /// Send after storing or deleting a data config, so the cached configs are reloaded
+    pub config_invalidation: ConfigInvalidationSender,
+    pub shadow_rate_limiter: Arc<ShadowRateLimiter>,
+    /// Receives every metric point the processor stores
+    pub metric_stream: MetricStreamSender,
+    ingest: Option<Arc<IngestBuffer>>,
 }
 
 impl Processor {
+    /// Writes the metrics still queued in the ingest buffer
+    pub async fn shutdown(&self) {
+        if let Some(ingest) = &self.ingest {
+            ingest.shutdown().await;
+        }
+    }
+
     pub async fn subscribe_shadow_updates(
         &mut self,
         topic_patterns: Vec<String>,
@@ -75,15 +300,147 @@ impl Processor {
         Ok(())
     }
 }
-async fn handle_message(msg: MqttMessage, state: ProcessorState) {
+/// A client authenticated for one tenant must not publish into another tenant's namespace.
+/// Messages without an authenticated tenant (e.g. internal publishes) are always accepted.
+fn is_tenant_authorized(topic_type: &TopicType, authenticated_tenant: Option<&TenantId>) -> bool {
+    match (topic_type.tenant_id(), authenticated_tenant) {
+        (Some(topic_tenant), Some(authenticated_tenant)) => topic_tenant == authenticated_tenant,
+        _ => true,
+    }
+}
+
+/// Takes a token from the bucket of the device, false if the message has to be dropped.
+/// Throttled devices are told about it if `publish_throttle_notices` is set.
+async fn check_ingest_rate(state: &ProcessorState, tenant_id: &TenantId, device_id: &str) -> bool {
+    // Cached per tenant, tenants without a quota use the processor config
+    let quota = state.db.tenant_quota(tenant_id).await.unwrap_or_default();
+    let (limit, publish_notice, prefix) = {
+        let config = state.config.read().unwrap();
+        (
+            IngestRateLimit::resolve(&config, &quota),
+            config.publish_throttle_notices,
+            config.shadow_topic_prefix.clone(),
+        )
+    };
+    let now = state.clock.now_millis();
+    let throttled = match state
+        .ingest_rate_limiter
+        .check(tenant_id, device_id, limit, now)
+    {
+        Ok(()) => return true,
+        Err(throttled) => throttled,
+    };
+    if publish_notice && throttled.notify {
+        let notice = ThrottleNotice {
+            max_messages_per_second: limit.per_second,
+            dropped: throttled.dropped,
+            retry_after_ms: throttled.retry_after.as_millis() as u64,
+        };
+        let topic = format!(
+            "{}{}/throttled",
+            prefix,
+            topic_device_id(tenant_id, device_id)
+        );
+        if let Ok(json) = serde_json::to_vec(&notice) {
+            if let Err(e) = state.mqtt_sender.publish(topic, json).await {
+                warn!(%tenant_id, device_id, "Failed to publish throttle notice: {}", e);
+            }
+        }
+    }
+    false
+}
+
+/// Counts and logs a message that is too large to be parsed. The dead letter only keeps
+/// the start of the payload.
+async fn reject_oversized_message(
+    state: &ProcessorState,
+    topic_type: &TopicType,
+    msg: &MqttMessage,
+    max_payload_bytes: usize,
+) {
+    state
+        .ingest_metrics
+        .oversized
+        .fetch_add(1, Ordering::Relaxed);
+    warn!(
+        topic = msg.topic,
+        size = msg.payload.len(),
+        max_payload_bytes,
+        "Dropping message exceeding the payload size limit"
+    );
+    if let (Some(tenant_id), Some(device_id)) = (topic_type.tenant_id(), topic_type.device_id()) {
+        let preview = &msg.payload[..msg.payload.len().min(OVERSIZED_PREVIEW_BYTES)];
+        let error = format!(
+            "Payload of {} bytes exceeds the limit of {} bytes",
+            msg.payload.len(),
+            max_payload_bytes
+        );
+        handle_rejected_message(state, tenant_id, device_id, &msg.topic, preview, error).await;
+    }
+}
+
+async fn handle_message(
+    msg: MqttMessage,
+    state: ProcessorState,
+    authenticated_tenant: Option<TenantId>,
+) {
     let topic_type = get_topic_type(&msg, &state);
 
     if matches!(topic_type, TopicType::Other) {
         return;
     }
 
+    if !is_tenant_authorized(&topic_type, authenticated_tenant.as_ref()) {
+        warn!(
+            topic = msg.topic,
+            tenant = ?authenticated_tenant,
+            "Dropping message published into a foreign tenant namespace"
+        );
+        // Dead lettered for the publishing tenant, the other one never sees the message
+        if let (Some(tenant_id), Some(topic_tenant), Some(device_id)) = (
+            &authenticated_tenant,
+            topic_type.tenant_id(),
+            topic_type.device_id(),
+        ) {
+            let error = format!(
+                "Topic of tenant {} published by a client of tenant {}",
+                topic_tenant, tenant_id
+            );
+            handle_rejected_message(
+                &state,
+                tenant_id,
+                device_id,
+                &msg.topic,
+                &msg.payload,
+                error,
+            )
+            .await;
+        }
+        return;
+    }
+
+    // Deltas are published by the processor itself and don't count against the device
+    let from_device = !matches!(topic_type, TopicType::ShadowDelta(..));
+    let max_payload_bytes = state.config.read().unwrap().max_payload_bytes;
+    if from_device && max_payload_bytes > 0 && msg.payload.len() > max_payload_bytes {
+        reject_oversized_message(&state, &topic_type, &msg, max_payload_bytes).await;
+        return;
+    }
+
+    if let (true, Some(tenant_id), Some(device_id)) =
+        (from_device, topic_type.tenant_id(), topic_type.device_id())
+    {
+        if !check_ingest_rate(&state, tenant_id, device_id).await {
+            return;
+        }
+    }
+
     let mut task_set: JoinSet<Result<(), ProcessorError>> = JoinSet::new();
     let payload = msg.payload;
+    let origin = topic_type
+        .tenant_id()
+        .cloned()
+        .zip(topic_type.device_id().map(str::to_string));
 
     match topic_type {
         TopicType::ShadowUpdate(tid, did, sn) => {
@@ -99,14 +456,20 @@ async fn handle_message(msg: MqttMessage, state: ProcessorState) {
                 let payload = payload.clone();
                 let tid = tid.clone();
                 let did = did.clone();
-                async move { handle_metric_extraction(&tid, &did, payload, state).await }
+                async move {
+                    handle_metric_extraction(&tid, &did, payload, MetricSource::ShadowUpdate, state)
+                        .await
+                }
             });
         }
         TopicType::DataUpdate(tid, did) => {
             task_set.spawn({
                 let state = state.clone();
                 let payload = payload.clone();
-                async move { handle_metric_extraction(&tid, &did, payload, state).await }
+                async move {
+                    handle_metric_extraction(&tid, &did, payload, MetricSource::Telemetry, state)
+                        .await
+                }
             });
         }
         TopicType::TimeRequest(tid, did) => {
@@ -117,26 +480,41 @@ async fn handle_message(msg: MqttMessage, state: ProcessorState) {
             });
         }
         _ => {
-            println!("Unknown target {:?}", msg.topic);
+            debug!("Unknown target {:?}", msg.topic);
         }
     }
 
     // Wait for all tasks to complete
+    let mut errors = Vec::new();
     while let Some(res) = task_set.join_next().await {
         match res {
             Ok(Err(e)) => {
                 warn!(error=?e, "Error processing message");
+                errors.push(e.to_string());
             }
             Ok(Ok(_)) => {}
             Err(err) => {
                 warn!(error=?err, "Error processing message");
+                errors.push(err.to_string());
             }
         }
     }
+
+    let dead_letter_enabled = state.config.read().unwrap().dead_letter_enabled;
+    if let (true, Some((tid, did))) = (dead_letter_enabled, origin) {
+        handle_processing_result(&state, &tid, &did, &msg.topic, &payload, errors).await;
+    }
 }
 
 async fn run_stream_worker(mut admin_link: AdminLink, state: ProcessorState) {
+    let max_concurrent_messages = state.config.read().unwrap().max_concurrent_messages.max(1);
+    let permits = Arc::new(Semaphore::new(max_concurrent_messages));
     loop {
+        // Wait for a free slot before reading, the broker buffers messages meanwhile
+        let permit = match permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
         let rs = admin_link.recv().await;
         match rs {
             Ok(Some((publish, client_info))) => {
@@ -146,10 +524,11 @@ async fn run_stream_worker(mut admin_link: AdminLink, state: ProcessorState) {
                         payload: publish.payload.to_vec(),
                     };
 
-                    let _ = client_info.client_id;
+                    let tenant = client_info.tenant.as_deref().map(TenantId::from_str);
                     let state = state.clone();
                     tokio::spawn(async move {
-                        let _ = handle_message(msg, state).await;
+                        let _ = handle_message(msg, state, tenant).await;
+                        drop(permit);
                     });
                 } else {
                     warn!("publish admin topic could not be decoded!");
@@ -170,11 +549,18 @@ async fn run_stream_worker(mut admin_link: AdminLink, state: ProcessorState) {
 async fn connection_monitor(
     mut connection_monitor_rx: Receiver<ClientStatus>,
     clients: Arc<ConnectionSet>,
+    clock: Arc<dyn Clock>,
 ) {
     while let Ok(status) = connection_monitor_rx.recv().await {
         match status {
             ClientStatus::Connected(client_id) => {
-                clients.insert(client_id);
+                // Clients the auth handler didn't see, e.g. without auth, belong to the default tenant
+                let tenant = take_authenticated_tenant(&client_id).unwrap_or(TenantId::Default);
+                let info = ConnectionInfo {
+                    tenant,
+                    connected_at: clock.now_secs(),
+                };
+                clients.insert(client_id, info);
             }
             ClientStatus::Disconnected(client_id) => {
                 clients.remove(&client_id);
@@ -191,19 +577,50 @@ pub async fn start_processor(
     connected_clients: Arc<ConnectionSet>,
     config: ProcessorConfig,
 ) -> Result<(Processor, tokio::task::JoinHandle<()>), ProcessorError> {
+    let topic_patterns = topic_subscriptions(&config)?;
+
+    let ingest_metrics = Arc::new(IngestMetrics::default());
+    let ingest = (config.ingest_buffer_size > 0).then(|| {
+        Arc::new(IngestBuffer::start(
+            db.clone(),
+            ingest_metrics.clone(),
+            config.ingest_buffer_size,
+            config.ingest_batch_size,
+            Duration::from_millis(config.ingest_flush_interval_ms.max(1)),
+        ))
+    });
+
+    let config_cache = Arc::new(DataConfigCache::new(ingest_metrics.clone()));
+    let config_invalidation = config_invalidation_channel();
     let mut processor = Processor {
         db: db,
         mqtt_sender: mqtt_sender,
+        config: Arc::new(RwLock::new(config)),
+        ingest_metrics,
+        config_invalidation,
+        shadow_rate_limiter: Arc::new(ShadowRateLimiter::default()),
+        metric_stream: metric_stream_channel(),
+        ingest,
     };
 
-    let config = Arc::new(config);
-
     //  run stream worker
     let h1 = tokio::spawn({
         let state = ProcessorState {
             db: processor.db.clone(),
             mqtt_sender: processor.mqtt_sender.clone(),
-            config: config.clone(),
+            config: processor.config.clone(),
+            failures: Arc::new(FailureTracker::default()),
+            ingest: processor.ingest.clone(),
+            dedup: Arc::new(MetricDeduplicator::new(
+                DEDUP_CAPACITY,
+                processor.ingest_metrics.clone(),
+            )),
+            ingest_metrics: processor.ingest_metrics.clone(),
+            config_cache: config_cache.clone(),
+            shadow_rate_limiter: processor.shadow_rate_limiter.clone(),
+            ingest_rate_limiter: Arc::new(IngestRateLimiter::new(processor.ingest_metrics.clone())),
+            metric_stream: processor.metric_stream.clone(),
+            clock: processor.db.clock().clone(),
         };
         async move {
             let _ = run_stream_worker(admin_link, state)
@@ -212,10 +629,22 @@ pub async fn start_processor(
         }
     });
 
+    // invalidations end with the processor, it holds a sender
+    tokio::spawn({
+        let receiver = processor.config_invalidation.subscribe();
+        async move {
+            config_cache
+                .listen(receiver)
+                .instrument(debug_span!("DataConfigCache"))
+                .await;
+        }
+    });
+
     // run connection monitor
     let h2 = tokio::spawn({
+        let clock = processor.db.clock().clone();
         async move {
-            let _ = connection_monitor(connection_monitor_rx, connected_clients)
+            let _ = connection_monitor(connection_monitor_rx, connected_clients, clock)
                 .instrument(debug_span!("ConnectionMonitor"))
                 .await;
         }
@@ -225,15 +654,55 @@ pub async fn start_processor(
         let _ = tokio::join!(h1, h2);
     });
 
-    let mut topic_patterns = vec![
-        format!("{}+/shadow/update", config.shadow_topic_prefix),
-        format!("{}+/shadow/+/update", config.shadow_topic_prefix),
-        format!("{}+/time/request", config.shadow_topic_prefix),
-    ];
-    topic_patterns.extend(config.telemetry_topics.clone());
     processor.subscribe_shadow_updates(topic_patterns).await?;
     Ok((processor, combined_handle))
 }
 
+/// Broker filters of the shadow, time and telemetry topics of `config`
+fn topic_subscriptions(config: &ProcessorConfig) -> Result<Vec<String>, ProcessorError> {
+    // Telemetry patterns are matched first, overlapping shadow messages would be taken as telemetry
+    for (telemetry, shadow) in overlapping_topics(config) {
+        let message = format!(
+            "Telemetry topic '{}' overlaps with shadow topic '{}'",
+            telemetry, shadow
+        );
+        if config.strict_topic_validation {
+            return Err(ProcessorError::InvalidTopic(message));
+        }
+        warn!(
+            "{}, matching shadow messages are processed as telemetry",
+            message
+        );
+    }
+
+    let mut topic_patterns = shadow_subscription_filters(config);
+    topic_patterns.extend(
+        config
+            .telemetry_topics
+            .iter()
+            .map(|pattern| subscription_filter(pattern)),
+    );
+    Ok(topic_patterns)
+}
+
+/// Applies a reloaded config to the running processor. Filters of new topics are
+/// subscribed before the config is swapped, so no message on them is missed. The broker
+/// link can't unsubscribe, filters of removed topics stay but their messages no longer
+/// match the config.
+pub async fn apply_config(
+    mqtt_sender: &MqttSender,
+    config: &RwLock<ProcessorConfig>,
+    new_config: ProcessorConfig,
+) -> Result<(), ProcessorError> {
+    let subscribed = topic_subscriptions(&config.read().unwrap()).unwrap_or_default();
+    for pattern in topic_subscriptions(&new_config)? {
+        if !subscribed.contains(&pattern) {
+            mqtt_sender.subscribe(pattern).await?;
+        }
+    }
+    *config.write().unwrap() = new_config;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,301 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::models::{TenantId, TenantQuota};
+use crate::processor::ingest::IngestMetrics;
+use crate::processor::ProcessorConfig;
+
+/// Devices tracked at most, idle devices with a full bucket are dropped first
+pub const RATE_LIMITER_CAPACITY: usize = 10_000;
+
+/// A throttled device is sent a notice at most this often
+pub const THROTTLE_NOTICE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    /// Updates discarded since the device was first seen
+    limited: u64,
+    /// Unix time of the last discarded update
+    last_limited: Option<u64>,
+}
+
+impl TokenBucket {
+    fn full(rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            tokens: rate,
+            last_refill: now,
+            limited: 0,
+            last_limited: None,
+        }
+    }
+
+    /// Tokens available at `now`, at most one second worth of updates
+    fn available(&self, rate: f64, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        (self.tokens + elapsed * rate).min(rate)
+    }
+}
+
+/// Rate limit state of a device, returned by the API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShadowRateLimitStatus {
+    pub device_id: String,
+    pub max_updates_per_second: u32,
+    /// Updates the device can send right now without being limited
+    pub available_updates: u32,
+    /// Updates discarded so far
+    pub rate_limited: u64,
+    /// Unix time of the last discarded update
+    pub last_rate_limited: Option<u64>,
+}
+
+/// Token bucket per device limiting the shadow updates processed from MQTT
+#[derive(Debug, Default)]
+pub struct ShadowRateLimiter {
+    buckets: DashMap<String, TokenBucket>,
+    limited_total: AtomicU64,
+}
+
+fn bucket_key(tenant_id: &TenantId, device_id: &str) -> String {
+    format!("{}/{}", tenant_id, device_id)
+}
+
+impl ShadowRateLimiter {
+    /// Takes a token for a shadow update of the device, false if the update has to be
+    /// discarded. `max_per_second` 0 disables the limit.
+    pub fn check(&self, tenant_id: &TenantId, device_id: &str, max_per_second: u32) -> bool {
+        if max_per_second == 0 {
+            return true;
+        }
+        let rate = max_per_second as f64;
+        let now = Instant::now();
+        let key = bucket_key(tenant_id, device_id);
+        if !self.buckets.contains_key(&key) && self.buckets.len() >= RATE_LIMITER_CAPACITY {
+            // A full bucket behaves like a new one, only its counters are lost
+            self.buckets
+                .retain(|_, bucket| bucket.available(rate, now) < rate);
+            if self.buckets.len() >= RATE_LIMITER_CAPACITY {
+                self.buckets.clear();
+            }
+        }
+
+        let mut bucket = self
+            .buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::full(rate, now));
+        bucket.tokens = bucket.available(rate, now);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        bucket.limited += 1;
+        bucket.last_limited = Some(chrono::Utc::now().timestamp() as u64);
+        self.limited_total.fetch_add(1, Ordering::Relaxed);
+        if bucket.limited == 1 {
+            warn!(
+                %tenant_id,
+                device_id,
+                max_per_second,
+                "Device exceeds the shadow update rate limit, discarding updates"
+            );
+        }
+        false
+    }
+
+    /// Shadow updates discarded across all devices
+    pub fn limited_total(&self) -> u64 {
+        self.limited_total.load(Ordering::Relaxed)
+    }
+
+    pub fn status(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        max_per_second: u32,
+    ) -> ShadowRateLimitStatus {
+        let rate = max_per_second as f64;
+        let (available, rate_limited, last_rate_limited) =
+            match self.buckets.get(&bucket_key(tenant_id, device_id)) {
+                Some(bucket) => (
+                    bucket.available(rate, Instant::now()),
+                    bucket.limited,
+                    bucket.last_limited,
+                ),
+                None => (rate, 0, None),
+            };
+        ShadowRateLimitStatus {
+            device_id: device_id.to_string(),
+            max_updates_per_second: max_per_second,
+            available_updates: available.floor() as u32,
+            rate_limited,
+            last_rate_limited,
+        }
+    }
+}
+
+/// Limit of the MQTT messages of a device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IngestRateLimit {
+    /// Messages per second, 0 disables the limit
+    pub per_second: u32,
+    /// Messages a device can send at once after being idle
+    pub burst: u32,
+}
+
+impl IngestRateLimit {
+    /// The limit of `ProcessorConfig`, with the values set in the quota of the tenant
+    /// taking precedence
+    pub fn resolve(config: &ProcessorConfig, quota: &TenantQuota) -> Self {
+        IngestRateLimit {
+            per_second: quota
+                .max_messages_per_second
+                .unwrap_or(config.max_messages_per_second),
+            burst: quota.message_burst.unwrap_or(config.message_burst),
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        self.per_second as f64
+    }
+
+    /// A bucket holding less than one token would never let a message through
+    fn burst(&self) -> f64 {
+        self.burst.max(1) as f64
+    }
+}
+
+#[derive(Debug)]
+struct IngestBucket {
+    tokens: f64,
+    /// Unix time in milliseconds
+    last_refill: u64,
+    /// Limit of the last check, tenants can change it at any time
+    limit: IngestRateLimit,
+    /// Messages dropped since the device was first seen
+    dropped: u64,
+    /// Unix time in milliseconds of the last throttle notice
+    last_notice: Option<u64>,
+}
+
+impl IngestBucket {
+    /// Tokens available at `now`, at most `burst`
+    fn available(&self, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.last_refill) as f64 / 1000.0;
+        (self.tokens + elapsed * self.limit.rate()).min(self.limit.burst())
+    }
+}
+
+/// A message dropped by the `IngestRateLimiter`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Throttled {
+    /// Messages of the device dropped so far
+    pub dropped: u64,
+    /// Time until the next message of the device is accepted
+    pub retry_after: Duration,
+    /// Set for the first dropped message and then once per `THROTTLE_NOTICE_INTERVAL`
+    pub notify: bool,
+}
+
+/// Payload of the throttle notice published to `.../{device_id}/throttled`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThrottleNotice {
+    pub max_messages_per_second: u32,
+    /// Messages of the device dropped so far
+    pub dropped: u64,
+    pub retry_after_ms: u64,
+}
+
+/// Token bucket per device limiting all MQTT messages handled by the processor
+#[derive(Debug)]
+pub struct IngestRateLimiter {
+    buckets: DashMap<String, IngestBucket>,
+    metrics: Arc<IngestMetrics>,
+}
+
+impl Default for IngestRateLimiter {
+    fn default() -> Self {
+        IngestRateLimiter::new(Arc::new(IngestMetrics::default()))
+    }
+}
+
+impl IngestRateLimiter {
+    pub fn new(metrics: Arc<IngestMetrics>) -> Self {
+        IngestRateLimiter {
+            buckets: DashMap::new(),
+            metrics,
+        }
+    }
+
+    /// Takes a token for a message of the device at `now` (Unix time in milliseconds).
+    /// Dropped messages are counted in `IngestMetrics::throttled`.
+    pub fn check(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        limit: IngestRateLimit,
+        now: u64,
+    ) -> Result<(), Throttled> {
+        if limit.per_second == 0 {
+            return Ok(());
+        }
+        let key = bucket_key(tenant_id, device_id);
+        if !self.buckets.contains_key(&key) && self.buckets.len() >= RATE_LIMITER_CAPACITY {
+            // A full bucket behaves like a new one, only its counters are lost
+            self.buckets
+                .retain(|_, bucket| bucket.available(now) < bucket.limit.burst());
+            if self.buckets.len() >= RATE_LIMITER_CAPACITY {
+                self.buckets.clear();
+            }
+        }
+
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| IngestBucket {
+            tokens: limit.burst(),
+            last_refill: now,
+            limit,
+            dropped: 0,
+            last_notice: None,
+        });
+        bucket.limit = limit;
+        bucket.tokens = bucket.available(now);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        bucket.dropped += 1;
+        self.metrics.throttled.fetch_add(1, Ordering::Relaxed);
+        let notify = bucket.last_notice.map_or(true, |last| {
+            now.saturating_sub(last) >= THROTTLE_NOTICE_INTERVAL.as_millis() as u64
+        });
+        if notify {
+            bucket.last_notice = Some(now);
+            warn!(
+                %tenant_id,
+                device_id,
+                per_second = limit.per_second,
+                dropped = bucket.dropped,
+                "Device exceeds the MQTT message rate limit, dropping messages"
+            );
+        }
+        Err(Throttled {
+            dropped: bucket.dropped,
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate()),
+            notify,
+        })
+    }
+
+    /// Messages dropped across all devices
+    pub fn throttled_total(&self) -> u64 {
+        self.metrics.throttled.load(Ordering::Relaxed)
+    }
+}
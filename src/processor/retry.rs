@@ -0,0 +1,60 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::db::DatabaseError;
+use crate::processor::ProcessorConfig;
+
+/// How often a database operation is attempted and the delay before the first retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// A single attempt unless `retry_db_operations` is set
+    pub fn from_config(config: &ProcessorConfig) -> Self {
+        if config.retry_db_operations {
+            RetryPolicy {
+                max_attempts: config.db_retry_max_attempts.max(1),
+                base_delay: Duration::from_millis(config.db_retry_base_delay_ms),
+            }
+        } else {
+            RetryPolicy {
+                max_attempts: 1,
+                base_delay: Duration::ZERO,
+            }
+        }
+    }
+
+    /// Exponential backoff, doubles the base delay for every retry
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.base_delay.saturating_mul(1 << retry.min(16))
+    }
+}
+
+/// Runs `operation` until it succeeds, fails with an error that isn't `DatabaseError::is_busy`
+/// or all attempts are used up. The last error is returned.
+pub(crate) async fn retry_db<T, F, Fut>(
+    policy: RetryPolicy,
+    mut operation: F,
+) -> Result<T, DatabaseError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DatabaseError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(e) if e.is_busy() && attempt < policy.max_attempts => {
+                let delay = policy.delay(attempt - 1);
+                warn!(attempt, ?delay, "Database busy, retrying: {}", e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
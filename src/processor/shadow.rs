@@ -1,32 +1,116 @@
-use crate::models::{ShadowName, TenantId};
+use crate::clock::Clock;
+use crate::db::DatabaseError;
+use crate::models::{FirmwareReport, ShadowName, TenantId};
 use crate::mqtt::MqttSender;
+use crate::processor::retry::{retry_db, RetryPolicy};
+use crate::processor::topics::topic_device_id;
+use crate::processor::webhooks::ShadowWebhookDispatch;
 use crate::processor::{ProcessorError, ProcessorState};
-use crate::shadow::{Shadow, StateUpdateDocument};
-use tracing::{debug, info};
+use crate::shadow::{Shadow, ShadowError, StateUpdateDocument};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
-pub(crate) fn get_delta_return_topic(
+fn get_return_topic(
     device_id: &str,
     shadow_name: &ShadowName,
     topic_prefix: &str,
+    suffix: &str,
 ) -> String {
     match shadow_name {
-        ShadowName::Default => format!("{}{}/shadow/update/delta", topic_prefix, device_id),
+        ShadowName::Default => format!("{}{}/shadow/{}", topic_prefix, device_id, suffix),
         ShadowName::Custom(name) => {
-            format!("{}{}/shadow/{}/update/delta", topic_prefix, device_id, name)
+            format!("{}{}/shadow/{}/{}", topic_prefix, device_id, name, suffix)
         }
     }
 }
 
+pub(crate) fn get_delta_return_topic(
+    device_id: &str,
+    shadow_name: &ShadowName,
+    topic_prefix: &str,
+) -> String {
+    get_return_topic(device_id, shadow_name, topic_prefix, "update/delta")
+}
+
+pub(crate) fn get_accepted_return_topic(
+    device_id: &str,
+    shadow_name: &ShadowName,
+    topic_prefix: &str,
+) -> String {
+    get_return_topic(device_id, shadow_name, topic_prefix, "update/accepted")
+}
+
+pub async fn send_accepted_to_mqtt(
+    shadow: &Shadow,
+    mqtt_sender: &MqttSender,
+    shadow_topic_prefix: &str,
+) -> Result<(), ProcessorError> {
+    let device_id = topic_device_id(&shadow.tenant_id, &shadow.device_id);
+    let return_topic =
+        get_accepted_return_topic(&device_id, &shadow.shadow_name, shadow_topic_prefix);
+    let accepted_json = shadow.get_accepted_response_json()?;
+    mqtt_sender
+        .publish(return_topic.to_string(), accepted_json.into_bytes())
+        .await?;
+    debug!(topic = return_topic, "Accepted state sent to device");
+    Ok(())
+}
+
+/// Published on `{delta topic}/chunk/count` after the chunks of a segmented delta
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkCount {
+    pub count: usize,
+    /// Size of the reassembled delta
+    pub bytes: usize,
+}
+
+/// Publishes the payload in `max_bytes` sized parts to `{topic}/chunk/{n}`, numbered from 0,
+/// followed by a `ChunkCount` on `{topic}/chunk/count`. Returns the number of chunks.
+async fn publish_chunks(
+    mqtt_sender: &MqttSender,
+    topic: &str,
+    payload: &[u8],
+    max_bytes: usize,
+) -> Result<usize, ProcessorError> {
+    let chunks = payload.chunks(max_bytes.max(1));
+    let count = chunks.len();
+    for (n, chunk) in chunks.enumerate() {
+        mqtt_sender
+            .publish(format!("{}/chunk/{}", topic, n), chunk.to_vec())
+            .await?;
+    }
+    let chunk_count = ChunkCount {
+        count,
+        bytes: payload.len(),
+    };
+    let json = serde_json::to_string(&chunk_count)
+        .map_err(|e| ProcessorError::InvalidJson(e.to_string()))?;
+    mqtt_sender
+        .publish(format!("{}/chunk/count", topic), json.into_bytes())
+        .await?;
+    Ok(count)
+}
+
+/// Sends the delta of the shadow to the device. Deltas larger than `max_delta_bytes`
+/// are split into chunks, 0 always sends a single message.
 pub async fn send_delta_to_mqtt(
     shadow: &Shadow,
     mqtt_sender: &MqttSender,
     shadow_topic_prefix: &str,
+    max_delta_bytes: usize,
 ) -> Result<bool, ProcessorError> {
-    let return_topic =
-        get_delta_return_topic(&shadow.device_id, &shadow.shadow_name, shadow_topic_prefix);
+    let device_id = topic_device_id(&shadow.tenant_id, &shadow.device_id);
+    let return_topic = get_delta_return_topic(&device_id, &shadow.shadow_name, shadow_topic_prefix);
     // Send delta to the device
     let delta_json = shadow.get_delta_response_json()?;
     match delta_json {
+        Some(json) if max_delta_bytes > 0 && json.len() > max_delta_bytes => {
+            let count =
+                publish_chunks(mqtt_sender, &return_topic, json.as_bytes(), max_delta_bytes)
+                    .await?;
+            debug!(topic = return_topic, count, "Delta sent in chunks");
+            Ok(true)
+        }
         Some(json) => {
             mqtt_sender.publish(return_topic.to_string(), json.into_bytes()).await?;
             debug!(topic = return_topic, "Delta sent to device");
@@ -39,30 +123,167 @@ pub(crate) async fn process_update_document(
     update_doc: &StateUpdateDocument,
     state: &ProcessorState,
 ) -> Result<(), ProcessorError> {
-    let shadow = state.db._upsert_shadow(update_doc).await?;
+    let (shadow_topic_prefix, publish_accepted, max_delta_bytes, retry) = {
+        let config = state.config.read().unwrap();
+        (
+            config.shadow_topic_prefix.clone(),
+            config.publish_accepted,
+            config.max_delta_bytes,
+            RetryPolicy::from_config(&config),
+        )
+    };
+    let webhooks = ShadowWebhookDispatch::prepare(&state.db, update_doc).await;
+    let shadow = retry_db(retry, || state.db._upsert_shadow(update_doc)).await?;
+    if let Some(webhooks) = webhooks {
+        webhooks.dispatch(&shadow);
+    }
+    if let Some(report) = FirmwareReport::from_reported(&update_doc.state.reported) {
+        record_firmware_report(update_doc, &report, state).await;
+    }
+    if publish_accepted {
+        send_accepted_to_mqtt(&shadow, &state.mqtt_sender, &shadow_topic_prefix).await?;
+    }
     let delta_sent = send_delta_to_mqtt(
         &shadow,
         &state.mqtt_sender,
-        &state.config.shadow_topic_prefix,
-    ).await?;
+        &shadow_topic_prefix,
+        max_delta_bytes,
+    )
+    .await?;
     info!(
         %update_doc.tenant_id,
-        update_doc.device_id, %update_doc.shadow_name, delta_sent, "Processed shadow update"
+        update_doc.device_id, %update_doc.shadow_name, delta_sent, publish_accepted,
+        "Processed shadow update"
     );
     Ok(())
 }
+
+/// Copies the reported firmware fields into the device metadata. The shadow is stored
+/// already, so failures are only logged.
+async fn record_firmware_report(
+    update_doc: &StateUpdateDocument,
+    report: &FirmwareReport,
+    state: &ProcessorState,
+) {
+    let tenant_id = &update_doc.tenant_id;
+    let device_id = update_doc.device_id.as_str();
+    match state
+        .db
+        .update_device_firmware(tenant_id, device_id, report)
+        .await
+    {
+        Ok(true) => debug!(%tenant_id, device_id, ?report, "Updated device firmware"),
+        Ok(false) => {}
+        // Devices don't need to be registered to keep a shadow
+        Err(DatabaseError::NotFoundError(_)) => {}
+        Err(e) => warn!(%tenant_id, device_id, "Failed to store device firmware: {}", e),
+    }
+}
+
+/// Error envelope published on `.../update/rejected` when an update fails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedResponse {
+    pub code: u16,
+    pub message: String,
+    pub timestamp: u64,
+}
+
+pub(crate) fn get_rejected_return_topic(
+    device_id: &str,
+    shadow_name: &ShadowName,
+    topic_prefix: &str,
+) -> String {
+    get_return_topic(device_id, shadow_name, topic_prefix, "update/rejected")
+}
+
+/// 400 for updates the device has to fix, 413 for shadows that would grow too large,
+/// 500 for failures on the server side
+fn rejection_code(error: &ProcessorError) -> u16 {
+    match error {
+        ProcessorError::InvalidShadowUpdate(_)
+        | ProcessorError::InvalidJson(_)
+        | ProcessorError::ShadowSerializationError(_) => 400,
+        ProcessorError::DatabaseError(DatabaseError::ShadowError(ShadowError::TooLarge {
+            ..
+        })) => 413,
+        _ => 500,
+    }
+}
+
+async fn send_rejected_to_mqtt(
+    tenant_id: &TenantId,
+    device_id: &str,
+    shadow_name: &ShadowName,
+    error: &ProcessorError,
+    mqtt_sender: &MqttSender,
+    shadow_topic_prefix: &str,
+    timestamp: u64,
+) -> Result<(), ProcessorError> {
+    let device_id = topic_device_id(tenant_id, device_id);
+    let return_topic = get_rejected_return_topic(&device_id, shadow_name, shadow_topic_prefix);
+    let rejected = RejectedResponse {
+        code: rejection_code(error),
+        message: error.to_string(),
+        timestamp,
+    };
+    let rejected_json =
+        serde_json::to_string(&rejected).map_err(crate::shadow::ShadowSerializationError::from)?;
+    mqtt_sender
+        .publish(return_topic.to_string(), rejected_json.into_bytes())
+        .await?;
+    debug!(topic = return_topic, "Rejection sent to device");
+    Ok(())
+}
+
 pub(crate) async fn handle_shadow_update(
     tenant_id: &TenantId,
     device_id: &str,
     shadow_name: &ShadowName,
     payload: Vec<u8>,
     state: ProcessorState,
+) -> Result<(), ProcessorError> {
+    let result = apply_shadow_update(tenant_id, device_id, shadow_name, payload, &state).await;
+    if let Err(e) = &result {
+        let (shadow_topic_prefix, publish_rejected) = {
+            let config = state.config.read().unwrap();
+            (config.shadow_topic_prefix.clone(), config.publish_rejected)
+        };
+        if publish_rejected {
+            send_rejected_to_mqtt(
+                tenant_id,
+                device_id,
+                shadow_name,
+                e,
+                &state.mqtt_sender,
+                &shadow_topic_prefix,
+                state.clock.now_secs(),
+            )
+            .await?;
+        }
+    }
+    result
+}
+
+async fn apply_shadow_update(
+    tenant_id: &TenantId,
+    device_id: &str,
+    shadow_name: &ShadowName,
+    payload: Vec<u8>,
+    state: &ProcessorState,
 ) -> Result<(), ProcessorError> {
     if let Ok(json_str) = String::from_utf8(payload) {
         if let Ok(update_doc) =
             StateUpdateDocument::from_nested_json(&json_str, device_id, shadow_name, tenant_id)
         {
-            process_update_document(&update_doc, &state).await?;
+            let max_per_second = state.config.read().unwrap().max_shadow_updates_per_second;
+            if !state
+                .shadow_rate_limiter
+                .check(tenant_id, device_id, max_per_second)
+            {
+                debug!(%tenant_id, device_id, "Discarded rate limited shadow update");
+                return Ok(());
+            }
+            process_update_document(&update_doc, state).await?;
         } else {
             return Err(ProcessorError::InvalidShadowUpdate(
                 "Failed to parse JSON".to_string(),
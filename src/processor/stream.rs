@@ -0,0 +1,44 @@
+use tokio::sync::broadcast;
+
+use crate::db::MetricRow;
+use crate::models::TenantId;
+
+/// Points buffered for every stream client, a client falling further behind skips the oldest
+const METRIC_STREAM_CAPACITY: usize = 1024;
+
+/// A metric point stored by the processor, sent to the clients streaming its metric
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricEvent {
+    pub tenant_id: TenantId,
+    pub device_id: String,
+    pub metric_name: String,
+    pub timestamp: u64,
+    pub value: serde_json::Value,
+}
+
+impl MetricEvent {
+    pub fn matches(&self, tenant_id: &TenantId, device_id: &str, metric_name: &str) -> bool {
+        self.device_id == device_id
+            && self.metric_name == metric_name
+            && &self.tenant_id == tenant_id
+    }
+}
+
+impl From<&MetricRow> for MetricEvent {
+    fn from(row: &MetricRow) -> Self {
+        MetricEvent {
+            tenant_id: row.tenant_id.clone(),
+            device_id: row.device_id.clone(),
+            metric_name: row.metric_name.clone(),
+            timestamp: row.timestamp,
+            value: row.value.clone().into(),
+        }
+    }
+}
+
+pub type MetricStreamSender = broadcast::Sender<MetricEvent>;
+
+/// Every client receives the points of all devices and filters them itself
+pub fn metric_stream_channel() -> MetricStreamSender {
+    broadcast::channel(METRIC_STREAM_CAPACITY).0
+}
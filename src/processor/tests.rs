@@ -1,6 +1,12 @@
 use super::*;
+use crate::clock::{MockClock, SystemClock};
 use crate::db::DB;
+use crate::models::{ShadowName, TenantQuota};
 use crate::mqtt::{config::MqttConfig, start_broker, MqttServer};
+use crate::processor::config_cache::ConfigInvalidation;
+use crate::processor::dead_letter::{DEAD_LETTER_THRESHOLD, DEAD_LETTER_WINDOW_SECS};
+use crate::processor::rate_limit::Throttled;
+use crate::processor::shadow::ChunkCount;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use tempfile::TempDir;
 
@@ -29,6 +35,24 @@ async fn setup_mqtt(db: Arc<DB>) -> MqttServer {
     start_broker(get_unique_test_config(), db).await
 }
 
+/// State with the default settings, tests override fields with `..test_state(db, sender)`
+fn test_state(db: Arc<DB>, mqtt_sender: MqttSender) -> ProcessorState {
+    ProcessorState {
+        db,
+        mqtt_sender,
+        config: Arc::new(RwLock::new(ProcessorConfig::default())),
+        failures: Arc::new(FailureTracker::default()),
+        ingest: None,
+        dedup: Arc::new(MetricDeduplicator::default()),
+        ingest_metrics: Arc::new(IngestMetrics::default()),
+        config_cache: Arc::new(DataConfigCache::default()),
+        shadow_rate_limiter: Arc::new(ShadowRateLimiter::default()),
+        ingest_rate_limiter: Arc::new(IngestRateLimiter::default()),
+        metric_stream: metric_stream_channel(),
+        clock: Arc::new(SystemClock),
+    }
+}
+
 #[tokio::test]
 async fn test_start_processor() {
     let db = setup_db().await;
@@ -52,6 +76,40 @@ async fn test_start_processor() {
     assert!(processor.db.pool.is_some(), "DB should be open");
 }
 
+#[tokio::test]
+async fn test_connection_monitor_records_connections() {
+    let (tx, rx) = tokio::sync::broadcast::channel(10);
+    let clients = Arc::new(ConnectionSet::new());
+    let clock = Arc::new(MockClock::new(1_700_000_000_000));
+    let monitor = tokio::spawn(connection_monitor(rx, clients.clone(), clock.clone()));
+
+    // Clients the auth handler didn't accept belong to the default tenant
+    tx.send(ClientStatus::Connected("sensor_a".to_string()))
+        .unwrap();
+    clock.advance(Duration::from_secs(5));
+    tx.send(ClientStatus::Connected("anonymous_dev".to_string()))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        clients.get("anonymous_dev").map(|c| c.clone()),
+        Some(ConnectionInfo {
+            tenant: TenantId::Default,
+            connected_at: 1_700_000_005,
+        })
+    );
+    assert_eq!(clients.len(), 2);
+
+    tx.send(ClientStatus::Disconnected("anonymous_dev".to_string()))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!clients.contains_key("anonymous_dev"));
+    assert_eq!(clients.get("sensor_a").unwrap().connected_at, 1_700_000_000);
+
+    drop(tx);
+    monitor.await.unwrap();
+}
+
 #[tokio::test]
 async fn test_time_request() {
     let _ = tracing_subscriber::fmt().with_test_writer().try_init();
@@ -104,7 +162,1956 @@ async fn test_time_request() {
     let resp: serde_json::Value = serde_json::from_slice(&msg.payload).unwrap();
     assert_eq!(resp["device_time"], 12345);
     assert!(resp.get("server_time").is_some());
+    assert_eq!(resp["unit"], "ms");
 
     // Crucial: shutdown mqtt broker to prevent background thread from hanging test runner
     mqtt.shutdown();
 }
+
+#[tokio::test]
+async fn test_time_response_units() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let receiver = mqtt.message_receiver();
+    let state = ProcessorState {
+        clock: Arc::new(MockClock::new(1_715_000_000_123)),
+        ..test_state(db.clone(), sender.clone())
+    };
+
+    sender
+        .subscribe("things/unit_dev/time/response".to_string())
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let request = MqttMessage {
+        topic: "things/unit_dev/time/request".to_string(),
+        payload: br#"{"device_time": 1715000000}"#.to_vec(),
+    };
+    let mut responses = Vec::new();
+    for millis in [true, false] {
+        state.config.write().unwrap().time_response_millis = millis;
+        handle_message(request.clone(), state.clone(), None).await;
+        let msg = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv_async())
+            .await
+            .expect("Timeout waiting for time response")
+            .expect("Channel closed");
+        let resp: serde_json::Value = serde_json::from_slice(&msg.payload).unwrap();
+        responses.push(resp);
+    }
+
+    assert_eq!(responses[0]["server_time"], 1_715_000_000_123u64);
+    assert_eq!(responses[0]["unit"], "ms");
+    assert_eq!(responses[1]["server_time"], 1_715_000_000u64);
+    assert_eq!(responses[1]["unit"], "s");
+    // The device time is echoed unchanged
+    assert_eq!(responses[1]["device_time"], 1715000000);
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_foreign_tenant_topic_is_rejected() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let state = test_state(db.clone(), mqtt.mqtt.clone());
+
+    let other_tenant = TenantId::from_str("othertenant");
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&other_tenant, &data_config)
+        .await
+        .unwrap();
+
+    let msg = MqttMessage {
+        topic: "things/othertenant.dev/data".to_string(),
+        payload: br#"{"temp": 21.5}"#.to_vec(),
+    };
+
+    // Authenticated for the default tenant, publishing into othertenant
+    handle_message(msg.clone(), state.clone(), Some(TenantId::Default)).await;
+    let ts = db
+        .get_last_metric(&other_tenant, "dev", "temp", 10)
+        .await
+        .unwrap();
+    assert_eq!(ts.len(), 0, "Foreign tenant message must be dropped");
+
+    // Authenticated for the matching tenant
+    handle_message(msg, state, Some(other_tenant.clone())).await;
+    let ts = db
+        .get_last_metric(&other_tenant, "dev", "temp", 10)
+        .await
+        .unwrap();
+    assert_eq!(ts.len(), 1);
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_foreign_tenant_topic_is_dead_lettered() {
+    use crate::models::ShadowName;
+
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let config = ProcessorConfig {
+        dead_letter_enabled: true,
+        ..ProcessorConfig::default()
+    };
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        ..test_state(db.clone(), mqtt.mqtt.clone())
+    };
+    let acme = TenantId::from_str("acme");
+
+    // A client of acme publishing into the default namespace, rejected on the first attempt
+    let msg = MqttMessage {
+        topic: "things/dev/shadow/update".to_string(),
+        payload: br#"{"state": {"reported": {"temp": 21}}}"#.to_vec(),
+    };
+    handle_message(msg.clone(), state.clone(), Some(acme.clone())).await;
+    let stored = db.list_dead_letters(&acme, Some("dev"), 10).await.unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].topic, "things/dev/shadow/update");
+    assert!(stored[0].error.contains("Topic of tenant default"));
+    // The owner of the namespace doesn't see the message
+    assert!(db
+        .list_dead_letters(&TenantId::Default, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(db
+        ._get_shadow("dev", &ShadowName::Default, &TenantId::Default)
+        .await
+        .is_err());
+
+    // Clients of the default tenant publish without a prefix
+    handle_message(msg, state.clone(), Some(TenantId::Default)).await;
+    db._get_shadow("dev", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap();
+
+    // Matching tenants and messages without an authenticated tenant are not dead lettered
+    let msg = MqttMessage {
+        topic: "things/acme.dev/shadow/update".to_string(),
+        payload: br#"{"state": {"reported": {"temp": 21}}}"#.to_vec(),
+    };
+    handle_message(msg.clone(), state.clone(), Some(acme.clone())).await;
+    handle_message(msg, state, None).await;
+    assert_eq!(
+        db.list_dead_letters(&acme, None, 10).await.unwrap().len(),
+        1
+    );
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_delta_is_published_to_tenant_prefixed_topic() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let receiver = mqtt.message_receiver();
+    let state = test_state(db.clone(), sender.clone());
+
+    sender
+        .subscribe("things/+/shadow/update/delta".to_string())
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let msg = MqttMessage {
+        topic: "things/acme.lamp/shadow/update".to_string(),
+        payload: br#"{"state": {"desired": {"led": "on"}, "reported": {"led": "off"}}}"#.to_vec(),
+    };
+    handle_message(msg, state, Some(TenantId::from_str("acme"))).await;
+
+    let msg = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv_async())
+        .await
+        .expect("Timeout waiting for delta")
+        .expect("Channel closed");
+    assert_eq!(msg.topic, "things/acme.lamp/shadow/update/delta");
+    let delta: serde_json::Value = serde_json::from_slice(&msg.payload).unwrap();
+    assert_eq!(delta["state"]["led"], "on");
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_custom_shadow_update_pattern() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let mut processor_config = ProcessorConfig::default();
+    processor_config.shadow_update_patterns =
+        Some(vec!["devices/{tenant}/{device}/state/{shadow}".to_string()]);
+    processor_config.time_request_patterns = Some(vec!["devices/+/clock".to_string()]);
+    assert_eq!(
+        topics::shadow_subscription_filters(&processor_config),
+        vec!["devices/+/+/state/+", "devices/+/clock"]
+    );
+
+    let (_processor, _handle) = start_processor(
+        db.clone(),
+        sender.clone(),
+        mqtt.admin.take().unwrap(),
+        mqtt.connection_monitor_subscribe(),
+        Arc::new(ConnectionSet::new()),
+        processor_config,
+    )
+    .await
+    .unwrap();
+
+    let payload = br#"{"state": {"reported": {"brightness": 80}}}"#.to_vec();
+    sender
+        .publish(
+            "devices/acme/lamp/state/config".to_string(),
+            payload.clone(),
+        )
+        .await
+        .unwrap();
+    // The default topics are no longer shadow updates
+    sender
+        .publish("things/acme.lamp/shadow/update".to_string(), payload)
+        .await
+        .unwrap();
+
+    let acme = TenantId::from_str("acme");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+    let shadow = loop {
+        match db
+            ._get_shadow("lamp", &ShadowName::from_str("config"), &acme)
+            .await
+        {
+            Ok(shadow) => break shadow,
+            Err(_) if std::time::Instant::now() < deadline => {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await
+            }
+            Err(e) => panic!("Shadow was not updated: {:?}", e),
+        }
+    };
+    assert_eq!(shadow.get_reported_value()["brightness"], 80);
+    assert!(db
+        ._get_shadow("lamp", &ShadowName::Default, &acme)
+        .await
+        .is_err());
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_default_shadow_patterns_follow_prefix() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let mut processor_config = ProcessorConfig::default();
+    processor_config.shadow_topic_prefix = "devices/".to_string();
+    processor_config.telemetry_topics = vec!["devices/+/data".to_string()];
+    assert_eq!(
+        topics::shadow_subscription_filters(&processor_config),
+        vec![
+            "devices/+/shadow/update",
+            "devices/+/shadow/+/update",
+            "devices/+/time/request"
+        ]
+    );
+
+    let (_processor, _handle) = start_processor(
+        db.clone(),
+        sender.clone(),
+        mqtt.admin.take().unwrap(),
+        mqtt.connection_monitor_subscribe(),
+        Arc::new(ConnectionSet::new()),
+        processor_config,
+    )
+    .await
+    .unwrap();
+
+    sender
+        .publish(
+            "devices/acme.lamp/shadow/update".to_string(),
+            br#"{"state": {"reported": {"brightness": 80}}}"#.to_vec(),
+        )
+        .await
+        .unwrap();
+
+    let acme = TenantId::from_str("acme");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+    let shadow = loop {
+        match db._get_shadow("lamp", &ShadowName::Default, &acme).await {
+            Ok(shadow) => break shadow,
+            Err(_) if std::time::Instant::now() < deadline => {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await
+            }
+            Err(e) => panic!("Shadow was not updated: {:?}", e),
+        }
+    };
+    assert_eq!(shadow.get_reported_value()["brightness"], 80);
+
+    mqtt.shutdown();
+}
+
+#[test]
+fn test_webhook_signature() {
+    use crate::processor::webhooks::webhook_signature;
+
+    assert_eq!(
+        webhook_signature("key", b"The quick brown fox jumps over the lazy dog").unwrap(),
+        "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+}
+
+#[test]
+fn test_shadow_webhook_events() {
+    use crate::models::ShadowEvent;
+    use crate::processor::webhooks::shadow_events;
+    use crate::shadow::{Shadow, StateDocument, StateUpdateDocument};
+
+    let update = |reported: serde_json::Value, desired: serde_json::Value| StateUpdateDocument {
+        device_id: "lamp".to_string(),
+        shadow_name: ShadowName::Default,
+        tenant_id: TenantId::Default,
+        state: StateDocument {
+            reported,
+            desired,
+            delta: serde_json::Value::Null,
+        },
+    };
+    let mut shadow = Shadow::new("lamp", &ShadowName::Default, &TenantId::Default);
+    shadow
+        .update(&update(
+            serde_json::json!({"led": "off"}),
+            serde_json::json!({"led": "on"}),
+        ))
+        .unwrap();
+    assert!(shadow.has_delta());
+    assert_eq!(shadow_events(false, &shadow), vec![ShadowEvent::Updated]);
+
+    // The device reports the desired state
+    shadow
+        .update(&update(
+            serde_json::json!({"led": "on"}),
+            serde_json::Value::Null,
+        ))
+        .unwrap();
+    assert!(!shadow.has_delta());
+    assert_eq!(
+        shadow_events(true, &shadow),
+        vec![ShadowEvent::Updated, ShadowEvent::DeltaCleared]
+    );
+    assert_eq!(shadow_events(false, &shadow), vec![ShadowEvent::Updated]);
+}
+
+#[test]
+fn test_match_shadow_pattern() {
+    use crate::processor::topics::match_shadow_pattern;
+
+    assert_eq!(
+        match_shadow_pattern("things/+/shadow/update", "things/acme.lamp/shadow/update"),
+        Some((
+            TenantId::from_str("acme"),
+            "lamp".to_string(),
+            ShadowName::Default
+        ))
+    );
+    // The "+" after the device is the shadow name
+    assert_eq!(
+        match_shadow_pattern(
+            "things/+/shadow/+/update",
+            "things/lamp/shadow/config/update"
+        ),
+        Some((
+            TenantId::Default,
+            "lamp".to_string(),
+            ShadowName::from_str("config")
+        ))
+    );
+    assert_eq!(
+        match_shadow_pattern("{shadow}/{tenant}/{device}", "config/acme/lamp"),
+        Some((
+            TenantId::from_str("acme"),
+            "lamp".to_string(),
+            ShadowName::from_str("config")
+        ))
+    );
+    assert_eq!(
+        match_shadow_pattern(
+            "things/+/shadow/+/update",
+            "things/lamp/shadow/update/delta"
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_match_telemetry_pattern_named_placeholders() {
+    use crate::processor::topics::match_telemetry_pattern;
+
+    assert_eq!(
+        match_telemetry_pattern("{tenant}/{device}/data", "acme/sensor-1/data"),
+        Some((TenantId::from_str("acme"), "sensor-1".to_string()))
+    );
+    // Positions don't have to follow the topic order
+    assert_eq!(
+        match_telemetry_pattern("plant/{device}/{tenant}/data", "plant/pump.7/acme/data"),
+        Some((TenantId::from_str("acme"), "pump.7".to_string()))
+    );
+    // Unknown placeholders match any level
+    assert_eq!(
+        match_telemetry_pattern("{tenant}/{site}/{device}/data", "acme/vienna/sensor-1/data"),
+        Some((TenantId::from_str("acme"), "sensor-1".to_string()))
+    );
+    assert_eq!(
+        match_telemetry_pattern("{tenant}/{device}/data", "acme/sensor-1/shadow"),
+        None
+    );
+    assert_eq!(
+        match_telemetry_pattern("{tenant}/{device}/data", "acme/data"),
+        None
+    );
+}
+
+#[test]
+fn test_match_telemetry_pattern_plus_wildcard() {
+    use crate::processor::topics::match_telemetry_pattern;
+
+    assert_eq!(
+        match_telemetry_pattern("things/+/data", "things/sensor-1/data"),
+        Some((TenantId::Default, "sensor-1".to_string()))
+    );
+    assert_eq!(
+        match_telemetry_pattern("things/+/data", "things/acme.sensor-1/data"),
+        Some((TenantId::from_str("acme"), "sensor-1".to_string()))
+    );
+    assert_eq!(
+        match_telemetry_pattern("{tenant}/+/data", "acme/sensor-1/data"),
+        Some((TenantId::from_str("acme"), "sensor-1".to_string()))
+    );
+    assert_eq!(match_telemetry_pattern("things/data", "things/data"), None);
+}
+
+#[test]
+fn test_subscription_filter() {
+    assert_eq!(
+        subscription_filter("{tenant}/{device}/data"),
+        "+/+/data".to_string()
+    );
+    assert_eq!(subscription_filter("things/+/data"), "things/+/data");
+}
+
+#[tokio::test]
+async fn test_non_finite_telemetry_is_not_stored() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let state = test_state(db.clone(), mqtt.mqtt.clone());
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&TenantId::Default, &data_config)
+        .await
+        .unwrap();
+
+    // NaN isn't valid JSON and out of range numbers fail to parse instead of becoming +/-inf
+    for payload in [
+        r#"{"temp": NaN}"#,
+        r#"{"temp": 1e309}"#,
+        r#"{"temp": -1e309}"#,
+        r#"{"temp": 21.5}"#,
+    ] {
+        let msg = MqttMessage {
+            topic: "things/nf_dev/data".to_string(),
+            payload: payload.as_bytes().to_vec(),
+        };
+        handle_message(msg, state.clone(), None).await;
+    }
+
+    let ts = db
+        .get_last_metric(&TenantId::Default, "nf_dev", "temp", 10)
+        .await
+        .unwrap();
+    assert_eq!(ts.len(), 1);
+    assert_eq!(
+        ts.latest().unwrap().1,
+        &crate::timeseries::MetricValue::Float(21.5)
+    );
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_publish_accepted_shadow_update() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let receiver = mqtt.message_receiver();
+    let mut config = ProcessorConfig::default();
+    config.publish_accepted = true;
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        ..test_state(db.clone(), sender.clone())
+    };
+
+    for topic in [
+        "things/acc_dev/shadow/update/accepted",
+        "things/acc_dev/shadow/config/update/accepted",
+    ] {
+        sender.subscribe(topic.to_string()).await.unwrap();
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let updates = [
+        (
+            "things/acc_dev/shadow/update",
+            r#"{"state": {"reported": {"temp": 21}}}"#,
+        ),
+        (
+            "things/acc_dev/shadow/update",
+            r#"{"state": {"desired": {"temp": 22}}}"#,
+        ),
+        (
+            "things/acc_dev/shadow/config/update",
+            r#"{"state": {"reported": {"mode": "eco"}}}"#,
+        ),
+    ];
+    for (topic, payload) in updates {
+        let msg = MqttMessage {
+            topic: topic.to_string(),
+            payload: payload.as_bytes().to_vec(),
+        };
+        handle_message(msg, state.clone(), None).await;
+    }
+
+    let mut accepted = Vec::new();
+    while accepted.len() < 3 {
+        let msg = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv_async())
+            .await
+            .expect("Timeout waiting for accepted message")
+            .expect("Channel closed");
+        if msg.topic.ends_with("/update/accepted") {
+            let payload: serde_json::Value = serde_json::from_slice(&msg.payload).unwrap();
+            accepted.push((msg.topic, payload));
+        }
+    }
+
+    assert_eq!(accepted[0].0, "things/acc_dev/shadow/update/accepted");
+    assert_eq!(accepted[0].1["state"]["reported"]["temp"], 21);
+    assert_eq!(accepted[0].1["version"], 1);
+    assert_eq!(accepted[1].1["state"]["reported"]["temp"], 21);
+    assert_eq!(accepted[1].1["state"]["desired"]["temp"], 22);
+    assert_eq!(accepted[1].1["version"], 2);
+    assert!(accepted[1].1["state"].get("delta").is_none());
+    assert_eq!(
+        accepted[2].0,
+        "things/acc_dev/shadow/config/update/accepted"
+    );
+    assert_eq!(accepted[2].1["state"]["reported"]["mode"], "eco");
+    assert_eq!(accepted[2].1["version"], 1);
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_large_delta_is_sent_in_chunks() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let receiver = mqtt.message_receiver();
+    let mut config = ProcessorConfig::default();
+    config.max_delta_bytes = 100;
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        ..test_state(db.clone(), sender.clone())
+    };
+
+    for topic in [
+        "things/chunk_dev/shadow/update/delta",
+        "things/chunk_dev/shadow/update/delta/chunk/+",
+    ] {
+        sender.subscribe(topic.to_string()).await.unwrap();
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    // Small deltas are sent as one message
+    let msg = MqttMessage {
+        topic: "things/chunk_dev/shadow/update".to_string(),
+        payload: br#"{"state": {"desired": {"mode": "eco"}}}"#.to_vec(),
+    };
+    handle_message(msg, state.clone(), None).await;
+    let msg = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv_async())
+        .await
+        .expect("Timeout waiting for delta")
+        .expect("Channel closed");
+    assert_eq!(msg.topic, "things/chunk_dev/shadow/update/delta");
+
+    let manifest = "x".repeat(450);
+    let msg = MqttMessage {
+        topic: "things/chunk_dev/shadow/update".to_string(),
+        payload: format!(
+            r#"{{"state": {{"desired": {{"manifest": "{}"}}}}}}"#,
+            manifest
+        )
+        .into_bytes(),
+    };
+    handle_message(msg, state.clone(), None).await;
+
+    let mut chunks = Vec::new();
+    let count = loop {
+        let msg = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv_async())
+            .await
+            .expect("Timeout waiting for chunk")
+            .expect("Channel closed");
+        assert_ne!(msg.topic, "things/chunk_dev/shadow/update/delta");
+        if msg.topic == "things/chunk_dev/shadow/update/delta/chunk/count" {
+            break serde_json::from_slice::<ChunkCount>(&msg.payload).unwrap();
+        }
+        assert!(msg.payload.len() <= 100);
+        chunks.push(msg);
+    };
+
+    assert!(count.count > 1);
+    assert_eq!(chunks.len(), count.count);
+    let mut reassembled = Vec::new();
+    for (n, chunk) in chunks.iter().enumerate() {
+        assert_eq!(
+            chunk.topic,
+            format!("things/chunk_dev/shadow/update/delta/chunk/{}", n)
+        );
+        reassembled.extend_from_slice(&chunk.payload);
+    }
+    assert_eq!(reassembled.len(), count.bytes);
+    let delta: serde_json::Value = serde_json::from_slice(&reassembled).unwrap();
+    assert_eq!(delta["state"]["manifest"], manifest.as_str());
+    assert_eq!(delta["state"]["mode"], "eco");
+
+    mqtt.shutdown();
+}
+
+#[test]
+fn test_return_topics() {
+    use crate::models::ShadowName;
+    use crate::processor::shadow::{get_accepted_return_topic, get_delta_return_topic};
+
+    assert_eq!(
+        get_accepted_return_topic("dev", &ShadowName::Default, "things/"),
+        "things/dev/shadow/update/accepted"
+    );
+    assert_eq!(
+        get_accepted_return_topic("dev", &ShadowName::from_str("config"), "things/"),
+        "things/dev/shadow/config/update/accepted"
+    );
+    assert_eq!(
+        get_delta_return_topic("dev", &ShadowName::from_str("config"), "things/"),
+        "things/dev/shadow/config/update/delta"
+    );
+}
+
+#[test]
+fn test_topic_device_id() {
+    use crate::processor::topics::topic_device_id;
+
+    assert_eq!(topic_device_id(&TenantId::Default, "dev"), "dev");
+    assert_eq!(
+        topic_device_id(&TenantId::from_str("acme"), "dev"),
+        "acme.dev"
+    );
+}
+
+#[tokio::test]
+async fn test_publish_rejected_shadow_update() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let receiver = mqtt.message_receiver();
+    let mut config = ProcessorConfig::default();
+    config.publish_rejected = true;
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        ..test_state(db.clone(), sender.clone())
+    };
+
+    sender
+        .subscribe("things/rej_dev/shadow/update/rejected".to_string())
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let msg = MqttMessage {
+        topic: "things/rej_dev/shadow/update".to_string(),
+        payload: br#"{"state": {"reported": "#.to_vec(),
+    };
+    handle_message(msg, state.clone(), None).await;
+
+    let msg = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv_async())
+        .await
+        .expect("Timeout waiting for rejected message")
+        .expect("Channel closed");
+    assert_eq!(msg.topic, "things/rej_dev/shadow/update/rejected");
+    let rejected: serde_json::Value = serde_json::from_slice(&msg.payload).unwrap();
+    assert_eq!(rejected["code"], 400);
+    assert!(rejected["message"]
+        .as_str()
+        .unwrap()
+        .contains("Failed to parse JSON"));
+    assert!(rejected["timestamp"].as_u64().is_some());
+
+    // Valid updates are not rejected
+    let msg = MqttMessage {
+        topic: "things/rej_dev/shadow/update".to_string(),
+        payload: br#"{"state": {"reported": {"temp": 21}}}"#.to_vec(),
+    };
+    handle_message(msg, state, None).await;
+    let next =
+        tokio::time::timeout(std::time::Duration::from_millis(300), receiver.recv_async()).await;
+    assert!(next.is_err(), "No rejection expected for a valid update");
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_bounded_processing_handles_burst() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let admin = mqtt.admin.take().unwrap();
+    let conn_mon_rx = mqtt.connection_monitor_subscribe();
+    let mut processor_config = ProcessorConfig::default();
+    processor_config.max_concurrent_messages = 4;
+    let (_processor, _handle) = start_processor(
+        db.clone(),
+        sender.clone(),
+        admin,
+        conn_mon_rx,
+        Arc::new(ConnectionSet::new()),
+        processor_config,
+    )
+    .await
+    .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    const MESSAGES: usize = 500;
+    for i in 0..MESSAGES {
+        let payload = format!(r#"{{"state": {{"reported": {{"seq": {}}}}}}}"#, i);
+        sender
+            .publish(
+                format!("things/burst_{}/shadow/update", i),
+                payload.into_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(20);
+    let mut processed = 0;
+    while processed < MESSAGES && std::time::Instant::now() < deadline {
+        match db
+            ._get_shadow(
+                &format!("burst_{}", processed),
+                &crate::models::ShadowName::Default,
+                &TenantId::Default,
+            )
+            .await
+        {
+            Ok(shadow) => {
+                assert_eq!(shadow.get_reported_value()["seq"], processed);
+                processed += 1;
+            }
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(20)).await,
+        }
+    }
+    assert_eq!(processed, MESSAGES, "Not all messages were processed");
+
+    mqtt.shutdown();
+}
+
+#[test]
+fn test_shadow_rate_limiter() {
+    let limiter = ShadowRateLimiter::default();
+    let tenant_id = TenantId::Default;
+    for _ in 0..3 {
+        assert!(limiter.check(&tenant_id, "spammy", 3));
+    }
+    assert!(!limiter.check(&tenant_id, "spammy", 3));
+    assert!(!limiter.check(&tenant_id, "spammy", 3));
+    // Buckets are per device and a disabled limit allows everything
+    assert!(limiter.check(&tenant_id, "quiet", 3));
+    assert!(limiter.check(&tenant_id, "spammy", 0));
+    assert_eq!(limiter.limited_total(), 2);
+
+    let status = limiter.status(&tenant_id, "spammy", 3);
+    assert_eq!(status.rate_limited, 2);
+    assert_eq!(status.available_updates, 0);
+    assert!(status.last_rate_limited.is_some());
+    let status = limiter.status(&tenant_id, "unknown", 3);
+    assert_eq!(status.available_updates, 3);
+    assert_eq!(status.rate_limited, 0);
+
+    // Tokens are refilled over time
+    std::thread::sleep(Duration::from_millis(400));
+    assert!(limiter.check(&tenant_id, "spammy", 3));
+}
+
+#[tokio::test]
+async fn test_rate_limited_shadow_updates_are_discarded() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let config = ProcessorConfig {
+        max_shadow_updates_per_second: 2,
+        ..Default::default()
+    };
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        ..test_state(db.clone(), mqtt.mqtt.clone())
+    };
+
+    for seq in 0..5 {
+        let payload = format!(r#"{{"state": {{"reported": {{"seq": {}}}}}}}"#, seq);
+        handle_shadow_update(
+            &TenantId::Default,
+            "spammy_dev",
+            &crate::models::ShadowName::Default,
+            payload.into_bytes(),
+            state.clone(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let shadow = db
+        ._get_shadow(
+            "spammy_dev",
+            &crate::models::ShadowName::Default,
+            &TenantId::Default,
+        )
+        .await
+        .unwrap();
+    assert_eq!(shadow.get_reported_value()["seq"], 1);
+    assert_eq!(state.shadow_rate_limiter.limited_total(), 3);
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_reported_firmware_is_recorded() {
+    use crate::models::{DeviceMetadata, OtaStatus, ShadowName};
+
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let state = test_state(db.clone(), mqtt.mqtt.clone());
+    db.put_device_metadata(&DeviceMetadata::new("ota_dev", &TenantId::Default))
+        .await
+        .unwrap();
+
+    let update = |payload: &str| {
+        handle_shadow_update(
+            &TenantId::Default,
+            "ota_dev",
+            &ShadowName::Default,
+            payload.as_bytes().to_vec(),
+            state.clone(),
+        )
+    };
+    update(
+        r#"{"state": {"reported": {"firmware_version": "1.0.0",
+            "target_firmware_version": "1.1.0", "ota_status": "downloading"}}}"#,
+    )
+    .await
+    .unwrap();
+    let metadata = db
+        .get_device_metadata(&TenantId::Default, "ota_dev")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(metadata.firmware_version.as_deref(), Some("1.0.0"));
+    assert_eq!(metadata.target_firmware_version.as_deref(), Some("1.1.0"));
+    assert_eq!(metadata.ota_status, Some(OtaStatus::Downloading));
+    assert!(metadata.needs_firmware_update());
+
+    // Fields missing from an update are kept, invalid ones ignored
+    update(r#"{"state": {"reported": {"ota_status": {"failed": "checksum mismatch"}}}}"#)
+        .await
+        .unwrap();
+    update(r#"{"state": {"reported": {"firmware_version": 2, "ota_status": "unknown"}}}"#)
+        .await
+        .unwrap();
+    let metadata = db
+        .get_device_metadata(&TenantId::Default, "ota_dev")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(metadata.firmware_version.as_deref(), Some("1.0.0"));
+    assert_eq!(
+        metadata.ota_status,
+        Some(OtaStatus::Failed("checksum mismatch".to_string()))
+    );
+
+    update(r#"{"state": {"reported": {"firmware_version": "1.1.0", "ota_status": "idle"}}}"#)
+        .await
+        .unwrap();
+    let metadata = db
+        .get_device_metadata(&TenantId::Default, "ota_dev")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!metadata.needs_firmware_update());
+    assert_eq!(metadata.ota_status, Some(OtaStatus::Idle));
+
+    // Shadows of unregistered devices are stored as before
+    handle_shadow_update(
+        &TenantId::Default,
+        "unregistered_dev",
+        &ShadowName::Default,
+        br#"{"state": {"reported": {"firmware_version": "1.0.0"}}}"#.to_vec(),
+        state.clone(),
+    )
+    .await
+    .unwrap();
+
+    mqtt.shutdown();
+}
+
+#[test]
+fn test_ingest_rate_limiter() {
+    let limiter = IngestRateLimiter::default();
+    let tenant_id = TenantId::Default;
+    let limit = IngestRateLimit {
+        per_second: 2,
+        burst: 4,
+    };
+    let now = 1_000_000;
+    for _ in 0..4 {
+        assert!(limiter.check(&tenant_id, "chatty", limit, now).is_ok());
+    }
+    // The burst is used up, the next token arrives after half a second at 2 per second
+    assert_eq!(
+        limiter.check(&tenant_id, "chatty", limit, now),
+        Err(Throttled {
+            dropped: 1,
+            retry_after: Duration::from_millis(500),
+            notify: true,
+        })
+    );
+    // Notices are rate limited themselves
+    let throttled = limiter
+        .check(&tenant_id, "chatty", limit, now + 100)
+        .unwrap_err();
+    assert_eq!(throttled.dropped, 2);
+    assert!(!throttled.notify);
+    // Buckets are per device and a disabled limit allows everything
+    assert!(limiter.check(&tenant_id, "quiet", limit, now).is_ok());
+    let disabled = IngestRateLimit {
+        per_second: 0,
+        burst: 4,
+    };
+    assert!(limiter.check(&tenant_id, "chatty", disabled, now).is_ok());
+
+    // 0.2 tokens left plus one refilled after half a second
+    assert!(limiter
+        .check(&tenant_id, "chatty", limit, now + 600)
+        .is_ok());
+    assert!(limiter
+        .check(&tenant_id, "chatty", limit, now + 600)
+        .is_err());
+
+    // Tokens refill up to the burst only
+    let later = now + 60_000;
+    for _ in 0..4 {
+        assert!(limiter.check(&tenant_id, "chatty", limit, later).is_ok());
+    }
+    let throttled = limiter
+        .check(&tenant_id, "chatty", limit, later)
+        .unwrap_err();
+    assert!(throttled.notify);
+    assert_eq!(throttled.dropped, 4);
+    assert_eq!(limiter.throttled_total(), 4);
+}
+
+#[test]
+fn test_ingest_rate_limit_resolve() {
+    let config = ProcessorConfig {
+        max_messages_per_second: 50,
+        message_burst: 200,
+        ..Default::default()
+    };
+    assert_eq!(
+        IngestRateLimit::resolve(&config, &TenantQuota::default()),
+        IngestRateLimit {
+            per_second: 50,
+            burst: 200,
+        }
+    );
+    let quota = TenantQuota {
+        max_messages_per_second: Some(5),
+        ..Default::default()
+    };
+    assert_eq!(
+        IngestRateLimit::resolve(&config, &quota),
+        IngestRateLimit {
+            per_second: 5,
+            burst: 200,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_throttled_messages_are_dropped() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let receiver = mqtt.message_receiver();
+    let config = ProcessorConfig {
+        max_messages_per_second: 1,
+        message_burst: 2,
+        publish_throttle_notices: true,
+        ..Default::default()
+    };
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        clock: Arc::new(MockClock::new(1_715_000_000_000)),
+        ..test_state(db.clone(), sender.clone())
+    };
+    for topic in ["things/chatty/time/response", "things/chatty/throttled"] {
+        sender.subscribe(topic.to_string()).await.unwrap();
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let request = MqttMessage {
+        topic: "things/chatty/time/request".to_string(),
+        payload: Vec::new(),
+    };
+    let mut received = Vec::new();
+    for _ in 0..5 {
+        handle_message(request.clone(), state.clone(), None).await;
+    }
+    while let Ok(Ok(msg)) =
+        tokio::time::timeout(Duration::from_millis(300), receiver.recv_async()).await
+    {
+        received.push(msg);
+    }
+    let responses = received
+        .iter()
+        .filter(|msg| msg.topic == "things/chatty/time/response")
+        .count();
+    let notices: Vec<ThrottleNotice> = received
+        .iter()
+        .filter(|msg| msg.topic == "things/chatty/throttled")
+        .map(|msg| serde_json::from_slice(&msg.payload).unwrap())
+        .collect();
+    // The burst is answered, the rest is dropped with a single notice
+    assert_eq!(responses, 2);
+    assert_eq!(
+        notices,
+        vec![ThrottleNotice {
+            max_messages_per_second: 1,
+            dropped: 1,
+            retry_after_ms: 1000,
+        }]
+    );
+    assert_eq!(state.ingest_rate_limiter.throttled_total(), 3);
+
+    // The quota of the tenant lifts the limit
+    let quota = TenantQuota {
+        max_messages_per_second: Some(0),
+        ..Default::default()
+    };
+    db.put_tenant(&crate::models::Tenant::new(&TenantId::Default).with_quota(quota))
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        handle_message(request.clone(), state.clone(), None).await;
+    }
+    let mut responses = 0;
+    while let Ok(Ok(msg)) =
+        tokio::time::timeout(Duration::from_millis(300), receiver.recv_async()).await
+    {
+        if msg.topic == "things/chatty/time/response" {
+            responses += 1;
+        }
+    }
+    assert_eq!(responses, 3);
+    assert_eq!(state.ingest_rate_limiter.throttled_total(), 3);
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_dead_letter_after_repeated_failures() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let config = ProcessorConfig {
+        dead_letter_enabled: true,
+        ..ProcessorConfig::default()
+    };
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        ..test_state(db.clone(), mqtt.mqtt.clone())
+    };
+
+    let topic = "things/dlq_device/shadow/update";
+    for attempt in 1..=DEAD_LETTER_THRESHOLD {
+        let msg = MqttMessage {
+            topic: topic.to_string(),
+            payload: b"{not json".to_vec(),
+        };
+        handle_message(msg, state.clone(), None).await;
+
+        let stored = db
+            .list_dead_letters(&TenantId::Default, Some("dlq_device"), 10)
+            .await
+            .unwrap();
+        if attempt < DEAD_LETTER_THRESHOLD {
+            assert!(
+                stored.is_empty(),
+                "dead lettered after {} attempts",
+                attempt
+            );
+        } else {
+            assert_eq!(stored.len(), 1);
+            assert_eq!(stored[0].topic, topic);
+            assert_eq!(stored[0].payload, "{not json");
+            assert!(stored[0].error.contains("Invalid Json"));
+        }
+    }
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_oversized_messages_are_dropped() {
+    use crate::models::ShadowName;
+
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let config = ProcessorConfig {
+        dead_letter_enabled: true,
+        max_payload_bytes: 1500,
+        ..ProcessorConfig::default()
+    };
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        ..test_state(db.clone(), mqtt.mqtt.clone())
+    };
+
+    let topic = "things/big_device/shadow/update";
+    let payload = format!(
+        r#"{{"state": {{"reported": {{"log": "{}"}}}}}}"#,
+        "x".repeat(2000)
+    );
+    let msg = MqttMessage {
+        topic: topic.to_string(),
+        payload: payload.into_bytes(),
+    };
+    handle_message(msg, state.clone(), None).await;
+
+    assert_eq!(state.ingest_metrics.oversized.load(Ordering::Relaxed), 1);
+    assert!(db
+        ._get_shadow("big_device", &ShadowName::Default, &TenantId::Default)
+        .await
+        .is_err());
+    // Dead lettered on the first attempt, with the start of the payload
+    let stored = db
+        .list_dead_letters(&TenantId::Default, Some("big_device"), 10)
+        .await
+        .unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].payload.len(), OVERSIZED_PREVIEW_BYTES);
+    assert!(stored[0].payload.starts_with(r#"{"state": {"reported""#));
+    assert!(stored[0].error.contains("exceeds the limit of 1500 bytes"));
+
+    // Payloads within the limit are processed
+    let msg = MqttMessage {
+        topic: topic.to_string(),
+        payload: br#"{"state": {"reported": {"temp": 21}}}"#.to_vec(),
+    };
+    handle_message(msg, state.clone(), None).await;
+    db._get_shadow("big_device", &ShadowName::Default, &TenantId::Default)
+        .await
+        .unwrap();
+    assert_eq!(state.ingest_metrics.oversized.load(Ordering::Relaxed), 1);
+
+    mqtt.shutdown();
+}
+
+#[test]
+fn test_failure_tracker_window() {
+    let tracker = FailureTracker::default();
+    assert!(!tracker.record_failure("a", 0));
+    assert!(!tracker.record_failure("a", 10));
+    // Outside of the window the count starts over
+    assert!(!tracker.record_failure("a", 10 + DEAD_LETTER_WINDOW_SECS + 1));
+    assert!(!tracker.record_failure("a", 80));
+    assert!(tracker.record_failure("a", 90));
+
+    // A success in between resets the count
+    assert!(!tracker.record_failure("b", 0));
+    assert!(!tracker.record_failure("b", 1));
+    tracker.record_success("b");
+    assert!(!tracker.record_failure("b", 2));
+}
+
+async fn setup_ingest_state(
+    db: Arc<DB>,
+    mqtt: &MqttServer,
+    flush_interval: Duration,
+) -> ProcessorState {
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&TenantId::Default, &data_config)
+        .await
+        .unwrap();
+    let ingest = IngestBuffer::start(
+        db.clone(),
+        Arc::new(IngestMetrics::default()),
+        100,
+        50,
+        flush_interval,
+    );
+    ProcessorState {
+        ingest: Some(Arc::new(ingest)),
+        ..test_state(db, mqtt.mqtt.clone())
+    }
+}
+
+/// Publishes one value to each of `count` devices, values of one device in the
+/// same second would overwrite each other when read back
+async fn publish_temperatures(state: &ProcessorState, device_prefix: &str, count: usize) {
+    for i in 0..count {
+        let msg = MqttMessage {
+            topic: format!("things/{}{}/data", device_prefix, i),
+            payload: format!(r#"{{"temp": {}}}"#, i).into_bytes(),
+        };
+        handle_message(msg, state.clone(), None).await;
+    }
+}
+
+async fn stored_temperatures(db: &DB, device_prefix: &str, count: usize) -> usize {
+    let mut stored = 0;
+    for i in 0..count {
+        stored += db
+            .get_last_metric(
+                &TenantId::Default,
+                &format!("{}{}", device_prefix, i),
+                "temp",
+                10,
+            )
+            .await
+            .unwrap()
+            .len();
+    }
+    stored
+}
+
+#[tokio::test]
+async fn test_ingest_buffer_flushes_after_interval() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let state = setup_ingest_state(db.clone(), &mqtt, Duration::from_millis(50)).await;
+    // Let the first, immediate tick pass
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    publish_temperatures(&state, "buffered_dev", 10).await;
+    let metrics = state.ingest.as_ref().unwrap().metrics().clone();
+    assert_eq!(metrics.buffered.load(Ordering::Relaxed), 10);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(stored_temperatures(&db, "buffered_dev", 10).await, 10);
+    assert_eq!(metrics.flushed.load(Ordering::Relaxed), 10);
+    assert_eq!(metrics.direct.load(Ordering::Relaxed), 0);
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_ingest_buffer_flushes_on_shutdown() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let state = setup_ingest_state(db.clone(), &mqtt, Duration::from_secs(3600)).await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    publish_temperatures(&state, "shutdown_dev", 20).await;
+    let ingest = state.ingest.clone().unwrap();
+    assert_eq!(ingest.metrics().flushed.load(Ordering::Relaxed), 0);
+
+    assert_eq!(stored_temperatures(&db, "shutdown_dev", 20).await, 0);
+
+    ingest.shutdown().await;
+    assert_eq!(stored_temperatures(&db, "shutdown_dev", 20).await, 20);
+
+    // After the shutdown values are written directly
+    publish_temperatures(&state, "shutdown_dev", 1).await;
+    assert_eq!(ingest.metrics().direct.load(Ordering::Relaxed), 1);
+
+    mqtt.shutdown();
+}
+
+fn dedup_row(device_id: &str, timestamp: u64, value: f64) -> crate::db::MetricRow {
+    crate::db::MetricRow {
+        tenant_id: TenantId::Default,
+        device_id: device_id.to_string(),
+        metric_name: "temp".to_string(),
+        timestamp,
+        value: crate::timeseries::MetricValue::Float(value),
+        tags: None,
+    }
+}
+
+#[test]
+fn test_deduplicator_window() {
+    let metrics = Arc::new(IngestMetrics::default());
+    let dedup = MetricDeduplicator::new(DEDUP_CAPACITY, metrics.clone());
+
+    assert!(!dedup.is_duplicate(&dedup_row("dev", 100, 1.0), 100, 10));
+    // Identical retransmission
+    assert!(dedup.is_duplicate(&dedup_row("dev", 100, 1.0), 105, 10));
+    // Different value or timestamp, or another device
+    assert!(!dedup.is_duplicate(&dedup_row("dev", 100, 2.0), 106, 10));
+    assert!(!dedup.is_duplicate(&dedup_row("dev", 101, 2.0), 106, 10));
+    assert!(!dedup.is_duplicate(&dedup_row("other", 101, 2.0), 106, 10));
+    // Outside of the window
+    assert!(!dedup.is_duplicate(&dedup_row("dev", 101, 2.0), 117, 10));
+    assert_eq!(metrics.deduplicated.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_deduplicator_capacity() {
+    let dedup = MetricDeduplicator::new(2, Arc::new(IngestMetrics::default()));
+    assert!(!dedup.is_duplicate(&dedup_row("a", 100, 1.0), 100, 60));
+    assert!(!dedup.is_duplicate(&dedup_row("b", 100, 1.0), 101, 60));
+    // Evicts "a", the least recently seen
+    assert!(!dedup.is_duplicate(&dedup_row("c", 100, 1.0), 102, 60));
+    assert!(dedup.is_duplicate(&dedup_row("b", 100, 1.0), 103, 60));
+    assert!(!dedup.is_duplicate(&dedup_row("a", 100, 1.0), 104, 60));
+}
+
+#[tokio::test]
+async fn test_retransmitted_metrics_are_deduplicated() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let metrics = Arc::new(IngestMetrics::default());
+    let mut config = ProcessorConfig::default();
+    config.dedup_window_secs = Some(10);
+    let state = ProcessorState {
+        config: Arc::new(RwLock::new(config)),
+        dedup: Arc::new(MetricDeduplicator::new(DEDUP_CAPACITY, metrics.clone())),
+        ..test_state(db.clone(), mqtt.mqtt.clone())
+    };
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&TenantId::Default, &data_config)
+        .await
+        .unwrap();
+
+    // Values are stamped with the arrival second, stay clear of the next one
+    let millis = chrono::Utc::now().timestamp_subsec_millis() as u64;
+    if millis > 700 {
+        tokio::time::sleep(Duration::from_millis(1000 - millis + 10)).await;
+    }
+    for payload in [
+        r#"{"temp": 21.5}"#,
+        r#"{"temp": 21.5}"#,
+        r#"{"temp": 22.0}"#,
+        r#"{"temp": 22.0}"#,
+    ] {
+        let msg = MqttMessage {
+            topic: "things/dedup_dev/data".to_string(),
+            payload: payload.as_bytes().to_vec(),
+        };
+        handle_message(msg, state.clone(), None).await;
+    }
+    // Only the identical retransmissions are skipped
+    assert_eq!(metrics.deduplicated.load(Ordering::Relaxed), 2);
+
+    let ts = db
+        .get_last_metric(&TenantId::Default, "dedup_dev", "temp", 10)
+        .await
+        .unwrap();
+    assert_eq!(
+        ts.latest().unwrap().1,
+        &crate::timeseries::MetricValue::Float(22.0)
+    );
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_repeated_values_are_not_stored() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let metrics = Arc::new(IngestMetrics::default());
+    let clock = Arc::new(MockClock::new(1_700_000_000_000));
+    let state = ProcessorState {
+        ingest_metrics: metrics.clone(),
+        clock: clock.clone(),
+        ..test_state(db.clone(), mqtt.mqtt.clone())
+    };
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}],
+            "enable_deduplication": true}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&TenantId::Default, &data_config)
+        .await
+        .unwrap();
+
+    for temp in [5.0, 5.0, 5.0, 6.0, 5.0] {
+        let msg = MqttMessage {
+            topic: "things/constant_dev/data".to_string(),
+            payload: format!(r#"{{"temp": {}}}"#, temp).into_bytes(),
+        };
+        handle_message(msg, state.clone(), None).await;
+        clock.advance(Duration::from_secs(60));
+    }
+    // A value is only skipped if it equals the one stored last
+    assert_eq!(metrics.deduplicated.load(Ordering::Relaxed), 2);
+    let ts = db
+        .get_last_metric(&TenantId::Default, "constant_dev", "temp", 10)
+        .await
+        .unwrap()
+        .to_float_series()
+        .unwrap();
+    let points: Vec<(u64, f64)> = ts.iter().map(|(t, v)| (t, *v)).collect();
+    assert_eq!(
+        points,
+        vec![
+            (1_700_000_000, 5.0),
+            (1_700_000_180, 6.0),
+            (1_700_000_240, 5.0)
+        ]
+    );
+
+    mqtt.shutdown();
+}
+
+#[test]
+fn test_validate_timestamp() {
+    use crate::processor::timeseries::validate_timestamp;
+    let now = 1_700_000_000;
+    let day = 24 * 60 * 60;
+    assert_eq!(
+        validate_timestamp(now - day, now, 30 * day),
+        Some(now - day)
+    );
+    assert_eq!(validate_timestamp(now + 60, now, 30 * day), Some(now + 60));
+    assert_eq!(validate_timestamp(now - 31 * day, now, 30 * day), None);
+    assert_eq!(
+        validate_timestamp(now + crate::db::MAX_FUTURE_SECONDS + 1, now, 30 * day),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_payload_timestamps() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let state = test_state(db.clone(), mqtt.mqtt.clone());
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float", "timestamp_json_pointer": "/ts"}]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&TenantId::Default, &data_config)
+        .await
+        .unwrap();
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    let day = 24 * 60 * 60;
+    let rfc3339 = chrono::DateTime::from_timestamp((now - day) as i64, 0)
+        .unwrap()
+        .to_rfc3339();
+    let cases = [
+        (
+            "ts_valid",
+            format!(r#"{{"temp": 1.0, "ts": {}}}"#, now - 2 * day),
+            Some(now - 2 * day),
+        ),
+        (
+            "ts_rfc3339",
+            format!(r#"{{"temp": 1.0, "ts": "{}"}}"#, rfc3339),
+            Some(now - day),
+        ),
+        (
+            "ts_future",
+            format!(r#"{{"temp": 1.0, "ts": {}}}"#, now + 2 * 365 * day),
+            None,
+        ),
+        (
+            "ts_past",
+            format!(r#"{{"temp": 1.0, "ts": {}}}"#, now - 60 * day),
+            None,
+        ),
+        ("ts_missing", r#"{"temp": 1.0}"#.to_string(), None),
+        (
+            "ts_invalid",
+            r#"{"temp": 1.0, "ts": "yesterday"}"#.to_string(),
+            None,
+        ),
+    ];
+    for (device_id, payload, _) in &cases {
+        let msg = MqttMessage {
+            topic: format!("things/{}/data", device_id),
+            payload: payload.clone().into_bytes(),
+        };
+        handle_message(msg, state.clone(), None).await;
+    }
+
+    for (device_id, _, expected) in cases {
+        let ts = db
+            .get_last_metric(&TenantId::Default, device_id, "temp", 10)
+            .await
+            .unwrap();
+        let (stored, _) = ts.latest().unwrap();
+        match expected {
+            Some(expected) => assert_eq!(*stored, expected, "{}", device_id),
+            // Out of range, missing and invalid timestamps fall back to the server time
+            None => assert!(*stored >= now && *stored <= now + 5, "{}", device_id),
+        }
+    }
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_data_config_cache_invalidation() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let metrics = Arc::new(IngestMetrics::default());
+    let config_cache = Arc::new(DataConfigCache::new(metrics.clone()));
+    let invalidation = config_invalidation_channel();
+    tokio::spawn({
+        let config_cache = config_cache.clone();
+        let receiver = invalidation.subscribe();
+        async move { config_cache.listen(receiver).await }
+    });
+    let state = ProcessorState {
+        ingest_metrics: metrics.clone(),
+        config_cache,
+        ..test_state(db.clone(), mqtt.mqtt.clone())
+    };
+    let store_config = |pointer: &str, name: &str| {
+        let data_config = crate::dataconfig::DataConfig::try_from_json(&format!(
+            r#"{{"metrics": [{{"json_pointer": "{}", "name": "{}", "data_type": "Float"}}]}}"#,
+            pointer, name
+        ))
+        .unwrap();
+        let db = db.clone();
+        async move {
+            db.store_tenant_data_config(&TenantId::Default, &data_config)
+                .await
+                .unwrap()
+        }
+    };
+    let extract = || {
+        handle_metric_extraction(
+            &TenantId::Default,
+            "cache_dev",
+            br#"{"temp": 21.5, "hum": 40.0}"#.to_vec(),
+            MetricSource::Telemetry,
+            state.clone(),
+        )
+    };
+    let stored = |metric: &'static str| {
+        let db = db.clone();
+        async move {
+            db.get_last_metric(&TenantId::Default, "cache_dev", metric, 10)
+                .await
+                .unwrap()
+                .len()
+        }
+    };
+
+    store_config("/temp", "temp").await;
+    extract().await.unwrap();
+    assert_eq!(stored("temp").await, 1);
+    assert_eq!(metrics.config_cache_misses.load(Ordering::Relaxed), 1);
+
+    // The cached config is still used after a change in the DB
+    store_config("/hum", "hum").await;
+    extract().await.unwrap();
+    assert_eq!(stored("hum").await, 0);
+    assert_eq!(metrics.config_cache_hits.load(Ordering::Relaxed), 1);
+
+    invalidation
+        .send(ConfigInvalidation {
+            tenant_id: TenantId::Default,
+        })
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    extract().await.unwrap();
+    assert_eq!(stored("hum").await, 1);
+    assert_eq!(metrics.config_cache_misses.load(Ordering::Relaxed), 2);
+
+    // A zero TTL always reads the DB
+    state.config.write().unwrap().data_config_cache_ttl_secs = 0;
+    extract().await.unwrap();
+    assert_eq!(metrics.config_cache_misses.load(Ordering::Relaxed), 3);
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_alarm_rules_are_checked() {
+    use crate::models::{AlarmCondition, AlarmRule};
+
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let state = test_state(db.clone(), mqtt.mqtt.clone());
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [
+            {"json_pointer": "/temp", "name": "temp", "data_type": "Float"},
+            {"json_pointer": "/pressure", "name": "pressure", "data_type": "Int"}
+        ]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&TenantId::Default, &data_config)
+        .await
+        .unwrap();
+    for (alarm_id, metric_name, condition, threshold) in [
+        ("too_hot", "temp", AlarmCondition::Above, 90.0),
+        ("pressure_lost", "pressure", AlarmCondition::Equal, 0.0),
+    ] {
+        db.store_alarm_rule(&AlarmRule {
+            tenant_id: TenantId::Default,
+            device_id: "boiler".to_string(),
+            metric_name: metric_name.to_string(),
+            condition,
+            threshold,
+            alarm_id: alarm_id.to_string(),
+        })
+        .await
+        .unwrap();
+    }
+
+    for (device_id, payload) in [
+        ("boiler", r#"{"temp": 95.5, "pressure": 0}"#),
+        ("boiler", r#"{"temp": 60.0, "pressure": 2}"#),
+        // Rules only apply to their device
+        ("other", r#"{"temp": 99.0, "pressure": 0}"#),
+    ] {
+        handle_metric_extraction(
+            &TenantId::Default,
+            device_id,
+            payload.as_bytes().to_vec(),
+            MetricSource::Telemetry,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let mut events = db
+        .list_alarm_events(&TenantId::Default, "boiler", 10)
+        .await
+        .unwrap();
+    events.sort_by(|a, b| a.alarm_id.cmp(&b.alarm_id));
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].alarm_id, "pressure_lost");
+    assert_eq!(events[0].value, 0.0);
+    assert_eq!(events[1].alarm_id, "too_hot");
+    assert_eq!(events[1].value, 95.5);
+    assert!(db
+        .list_alarm_events(&TenantId::Default, "other", 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_extraction_errors_are_tracked() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let state = test_state(db.clone(), mqtt.mqtt.clone());
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/sensors/temp", "name": "temp", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&TenantId::Default, &data_config)
+        .await
+        .unwrap();
+
+    for topic in [
+        "things/misconfigured/data",
+        "things/misconfigured/data",
+        // Shadow updates without metrics are not errors
+        "things/misconfigured/shadow/update",
+    ] {
+        let msg = MqttMessage {
+            topic: topic.to_string(),
+            payload: br#"{"temp": 21.5}"#.to_vec(),
+        };
+        handle_message(msg, state.clone(), None).await;
+    }
+
+    let errors = db
+        .list_extraction_errors(&TenantId::Default, "misconfigured")
+        .await
+        .unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].count, 2);
+    assert!(errors[0].error.contains("/sensors/temp"));
+
+    // Matching telemetry doesn't add errors
+    let msg = MqttMessage {
+        topic: "things/configured/data".to_string(),
+        payload: br#"{"sensors": {"temp": 21.5}}"#.to_vec(),
+    };
+    handle_message(msg, state.clone(), None).await;
+    assert!(db
+        .list_extraction_errors(&TenantId::Default, "configured")
+        .await
+        .unwrap()
+        .is_empty());
+
+    mqtt.shutdown();
+}
+
+/// Stands in for the error sqlx returns when SQLite reports `SQLITE_BUSY`
+#[derive(Debug)]
+struct SqliteBusy;
+
+impl std::fmt::Display for SqliteBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("database is locked")
+    }
+}
+
+impl std::error::Error for SqliteBusy {}
+
+impl sqlx::error::DatabaseError for SqliteBusy {
+    fn message(&self) -> &str {
+        "database is locked"
+    }
+
+    fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+        Some("5".into())
+    }
+
+    fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> sqlx::error::ErrorKind {
+        sqlx::error::ErrorKind::Other
+    }
+}
+
+fn busy_error() -> crate::db::DatabaseError {
+    crate::db::DatabaseError::SqlxError(sqlx::Error::Database(Box::new(SqliteBusy)))
+}
+
+#[tokio::test]
+async fn test_retry_db_upsert_after_busy_errors() {
+    let db = setup_db().await;
+    let update_doc = crate::shadow::StateUpdateDocument::from_nested_json(
+        r#"{"state": {"reported": {"temp": 21}}}"#,
+        "busy_device",
+        &crate::models::ShadowName::Default,
+        &TenantId::Default,
+    )
+    .unwrap();
+    let policy = retry::RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+    };
+
+    let attempts = AtomicUsize::new(0);
+    let (attempts_ref, db_ref, update_ref) = (&attempts, &db, &update_doc);
+    let shadow = retry::retry_db(policy, || async move {
+        // The first two attempts hit a locked database
+        if attempts_ref.fetch_add(1, Ordering::SeqCst) < 2 {
+            return Err(busy_error());
+        }
+        db_ref._upsert_shadow(update_ref).await
+    })
+    .await
+    .unwrap();
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert_eq!(shadow.get_reported_value()["temp"], 21);
+
+    let stored = db
+        ._get_shadow(
+            "busy_device",
+            &crate::models::ShadowName::Default,
+            &TenantId::Default,
+        )
+        .await
+        .unwrap();
+    assert_eq!(stored.get_reported_value()["temp"], 21);
+}
+
+#[tokio::test]
+async fn test_retry_db_gives_up() {
+    let policy = retry::RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+    };
+
+    // Busy errors are retried until the attempts are used up
+    let attempts = AtomicUsize::new(0);
+    let attempts_ref = &attempts;
+    let result: Result<(), _> = retry::retry_db(policy, || async move {
+        attempts_ref.fetch_add(1, Ordering::SeqCst);
+        Err(busy_error())
+    })
+    .await;
+    assert!(result.unwrap_err().is_busy());
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+    // Other errors are returned immediately
+    attempts.store(0, Ordering::SeqCst);
+    let result: Result<(), _> = retry::retry_db(policy, || async move {
+        attempts_ref.fetch_add(1, Ordering::SeqCst);
+        Err(crate::db::DatabaseError::NotFoundError(
+            "shadow".to_string(),
+        ))
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    // Disabled retries make a single attempt
+    let disabled = retry::RetryPolicy::from_config(&ProcessorConfig::default());
+    assert_eq!(disabled.max_attempts, 1);
+}
+
+#[test]
+fn test_filters_overlap() {
+    assert!(topics::filters_overlap(
+        "things/+/shadow/update",
+        "things/dev1/shadow/update"
+    ));
+    assert!(topics::filters_overlap(
+        "things/#",
+        "things/+/shadow/update"
+    ));
+    assert!(topics::filters_overlap("+/+/+/+", "things/+/time/request"));
+    assert!(topics::filters_overlap("things/+/#", "things/dev1"));
+    assert!(!topics::filters_overlap(
+        "things/+/data",
+        "things/+/shadow/update"
+    ));
+    assert!(!topics::filters_overlap(
+        "things/+/shadow",
+        "things/+/shadow/update"
+    ));
+}
+
+#[tokio::test]
+async fn test_reload_telemetry_topics() {
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let sender = mqtt.mqtt.clone();
+    let (processor, _handle) = start_processor(
+        db.clone(),
+        sender.clone(),
+        mqtt.admin.take().unwrap(),
+        mqtt.connection_monitor_subscribe(),
+        Arc::new(ConnectionSet::new()),
+        ProcessorConfig::default(),
+    )
+    .await
+    .unwrap();
+    let data_config = crate::dataconfig::DataConfig::try_from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    db.store_tenant_data_config(&TenantId::Default, &data_config)
+        .await
+        .unwrap();
+
+    let mut reloaded = ProcessorConfig::default();
+    reloaded.telemetry_topics = vec!["sensors/+/telemetry".to_string()];
+    apply_config(&processor.mqtt_sender, &processor.config, reloaded)
+        .await
+        .unwrap();
+
+    // Overlapping topics are rejected with strict validation, the running config stays
+    let mut overlapping = ProcessorConfig::default();
+    overlapping.strict_topic_validation = true;
+    overlapping.telemetry_topics = vec!["things/{device}/#".to_string()];
+    assert!(
+        apply_config(&processor.mqtt_sender, &processor.config, overlapping)
+            .await
+            .is_err()
+    );
+    assert_eq!(
+        processor.config.read().unwrap().telemetry_topics,
+        vec!["sensors/+/telemetry"]
+    );
+
+    sender
+        .publish(
+            "sensors/reload_dev/telemetry".to_string(),
+            br#"{"temp": 21.5}"#.to_vec(),
+        )
+        .await
+        .unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    loop {
+        let ts = db
+            .get_last_metric(&TenantId::Default, "reload_dev", "temp", 10)
+            .await
+            .unwrap();
+        if ts.len() == 1 {
+            break;
+        }
+        assert!(
+            std::time::Instant::now() < deadline,
+            "Telemetry on the reloaded topic was not stored"
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    mqtt.shutdown();
+}
+
+#[tokio::test]
+async fn test_overlapping_telemetry_topics() {
+    let mut processor_config = ProcessorConfig::default();
+    assert!(topics::overlapping_topics(&processor_config).is_empty());
+
+    processor_config.telemetry_topics = vec!["things/{device}/#".to_string()];
+    let overlaps = topics::overlapping_topics(&processor_config);
+    assert_eq!(overlaps.len(), 3);
+    assert_eq!(
+        overlaps[0],
+        (
+            "things/{device}/#".to_string(),
+            "things/+/shadow/update".to_string()
+        )
+    );
+
+    // Strict mode refuses to start
+    processor_config.strict_topic_validation = true;
+    let db = setup_db().await;
+    let mut mqtt = setup_mqtt(db.clone()).await;
+    let result = start_processor(
+        db,
+        mqtt.mqtt.clone(),
+        mqtt.admin.take().unwrap(),
+        mqtt.connection_monitor_subscribe(),
+        Arc::new(ConnectionSet::new()),
+        processor_config,
+    )
+    .await;
+    match result {
+        Err(ProcessorError::InvalidTopic(message)) => {
+            assert!(message.contains("things/{device}/#"), "{}", message)
+        }
+        _ => panic!("Expected an InvalidTopic error"),
+    }
+}
@@ -1,4 +1,6 @@
+use crate::clock::Clock;
 use crate::models::TenantId;
+use crate::processor::topics::topic_device_id;
 use crate::processor::{ProcessorError, ProcessorState};
 use serde::{Deserialize, Serialize};
 
@@ -12,10 +14,12 @@ struct TimeResponsePayload {
     pub server_time: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device_time: Option<u64>,
+    /// Unit of `server_time`, `ms` or `s`
+    pub unit: &'static str,
 }
 
 pub(crate) async fn handle_time_request(
-    _tenant_id: &TenantId,
+    tenant_id: &TenantId,
     device_id: &str,
     payload: Vec<u8>,
     state: ProcessorState,
@@ -30,10 +34,15 @@ pub(crate) async fn handle_time_request(
         }
     }
 
-    let server_time = chrono::Utc::now().timestamp_millis() as u64;
+    let (server_time, unit) = if state.config.read().unwrap().time_response_millis {
+        (state.clock.now_millis(), "ms")
+    } else {
+        (state.clock.now_secs(), "s")
+    };
     let resp = TimeResponsePayload {
         server_time,
         device_time: device_time_req,
+        unit,
     };
 
     let response_json =
@@ -41,7 +50,8 @@ pub(crate) async fn handle_time_request(
 
     let return_topic = format!(
         "{}{}/time/response",
-        state.config.shadow_topic_prefix, device_id
+        state.config.read().unwrap().shadow_topic_prefix,
+        topic_device_id(tenant_id, device_id)
     );
 
     state
@@ -1,11 +1,34 @@
-use crate::models::TenantId;
+use crate::clock::Clock;
+use crate::dataconfig::DataConfig;
+use crate::db::{MetricRow, MAX_FUTURE_SECONDS};
+use crate::models::{AlarmEvent, TenantId};
+use crate::processor::retry::{retry_db, RetryPolicy};
+use crate::processor::stream::MetricEvent;
 use crate::processor::{ProcessorError, ProcessorState};
-use tracing::{debug, info};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Payload timestamps may be at most `MAX_FUTURE_SECONDS` ahead of and `max_age_secs` behind `now`
+pub(crate) fn validate_timestamp(timestamp: u64, now: u64, max_age_secs: u64) -> Option<u64> {
+    let in_range = timestamp <= now.saturating_add(MAX_FUTURE_SECONDS)
+        && timestamp >= now.saturating_sub(max_age_secs);
+    in_range.then_some(timestamp)
+}
+
+/// Where the payload of a metric extraction came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MetricSource {
+    Telemetry,
+    /// Shadow updates often carry no metrics, so no extraction errors are tracked for them
+    ShadowUpdate,
+}
 
 pub(crate) async fn handle_metric_extraction(
     tenant_id: &TenantId,
     device_id: &str,
     payload: Vec<u8>,
+    source: MetricSource,
     state: ProcessorState,
 ) -> Result<(), ProcessorError> {
     let maybe_json = serde_json::from_slice::<serde_json::Value>(&payload);
@@ -19,25 +42,108 @@ pub(crate) async fn handle_metric_extraction(
         }
     };
 
-    // get data config from db
-    let maybe_config = state.db.get_data_config(tenant_id, Some(device_id)).await?;
-    let metrics = match maybe_config {
-        Some(data_config) => data_config.extract_metrics_from_json(json),
-        None => return Ok(()),
+    // get data config, cached per device
+    let cache_ttl = Duration::from_secs(state.config.read().unwrap().data_config_cache_ttl_secs);
+    let maybe_config = state
+        .config_cache
+        .get(&state.db, tenant_id, device_id, cache_ttl)
+        .await?;
+    let Some(data_config) = maybe_config else {
+        return Ok(());
     };
+    let metrics = data_config.extract_metrics_from_json(json);
+    if metrics.is_empty() && !data_config.metrics.is_empty() && source == MetricSource::Telemetry {
+        record_extraction_error(tenant_id, device_id, &data_config, &state).await;
+    }
 
     let mut counter = 0;
-    // store metrics
-    // TODO: batch insert for metrics
-    for (metric_name, metric_value) in metrics {
-        let res = state
-            .db
-            .put_metric(tenant_id, device_id, &metric_name, metric_value)
-            .await;
+    // store metrics, queued for a batched write when the ingest buffer is enabled
+    let now = state.clock.now_secs();
+    let (dedup_window_secs, max_timestamp_age_secs, retry) = {
+        let config = state.config.read().unwrap();
+        (
+            config.dedup_window_secs,
+            config.max_timestamp_age_secs,
+            RetryPolicy::from_config(&config),
+        )
+    };
+    // Numeric values checked against the alarm rules once stored
+    let mut alarm_values = Vec::new();
+    for metric in metrics {
+        let timestamp = match metric.timestamp {
+            Some(timestamp) => validate_timestamp(timestamp, now, max_timestamp_age_secs)
+                .unwrap_or_else(|| {
+                    warn!(
+                        device_id,
+                        metric_name = metric.name,
+                        timestamp,
+                        "Payload timestamp out of range, using server time"
+                    );
+                    now
+                }),
+            None => now,
+        };
+        let row = MetricRow {
+            tenant_id: tenant_id.clone(),
+            device_id: device_id.to_string(),
+            metric_name: metric.name,
+            timestamp,
+            value: metric.value,
+            tags: metric.tags,
+        };
+        if let Some(window_secs) = dedup_window_secs {
+            if state.dedup.is_duplicate(&row, now, window_secs) {
+                debug!(metric_name = row.metric_name, "Skipped duplicate metric");
+                continue;
+            }
+        }
+        if data_config.enable_deduplication && is_last_stored_value(&state, &row).await {
+            debug!(
+                metric_name = row.metric_name,
+                "Skipped value equal to the stored one"
+            );
+            state
+                .ingest_metrics
+                .deduplicated
+                .fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        if let Some(value) = row.value.clone().into_float() {
+            alarm_values.push((row.metric_name.clone(), row.timestamp, value));
+        }
+        // Only built while a client streams metrics
+        let event = (state.metric_stream.receiver_count() > 0).then(|| MetricEvent::from(&row));
+        let row = match &state.ingest {
+            Some(ingest) => match ingest.push(row) {
+                Ok(()) => {
+                    counter += 1;
+                    publish_metric_event(&state, event);
+                    continue;
+                }
+                Err(row) => {
+                    // Buffer full or shut down, write directly instead of dropping the value
+                    ingest.metrics().direct.fetch_add(1, Ordering::Relaxed);
+                    row
+                }
+            },
+            None => row,
+        };
+        let res = retry_db(retry, || {
+            state.db.insert_tagged_metric_row(
+                tenant_id,
+                device_id,
+                &row.metric_name,
+                row.timestamp,
+                row.value.clone(),
+                row.tags.as_ref(),
+            )
+        })
+        .await;
         match res {
             Ok(_) => {
                 counter += 1;
-                debug!(metric_name, "Stored metric");
+                debug!(metric_name = row.metric_name, "Stored metric");
+                publish_metric_event(&state, event);
             }
             Err(e) => {
                 return Err(ProcessorError::DatabaseError(e));
@@ -47,5 +153,97 @@ pub(crate) async fn handle_metric_extraction(
 
     info!(%tenant_id, device_id, counter, "Processed metrics");
 
+    if !alarm_values.is_empty() {
+        check_alarm_rules(tenant_id, device_id, &alarm_values, &state).await;
+    }
+
     Ok(())
 }
+
+/// True if `row` has the value last stored for its metric. Values still queued in the
+/// ingest buffer aren't seen, a failed read stores the value.
+async fn is_last_stored_value(state: &ProcessorState, row: &MetricRow) -> bool {
+    let last = state
+        .db
+        .get_last_metric(&row.tenant_id, &row.device_id, &row.metric_name, 1)
+        .await;
+    match last {
+        Ok(last) => last.latest().is_some_and(|(_, value)| *value == row.value),
+        Err(e) => {
+            warn!(error = ?e, metric_name = row.metric_name, "Failed to load the last stored value");
+            false
+        }
+    }
+}
+
+fn publish_metric_event(state: &ProcessorState, event: Option<MetricEvent>) {
+    if let Some(event) = event {
+        // Fails only if the last client disconnected meanwhile
+        let _ = state.metric_stream.send(event);
+    }
+}
+
+/// Stores an alarm event for every value that triggers a rule of the device. The metrics
+/// are stored already, so failures are only logged.
+async fn check_alarm_rules(
+    tenant_id: &TenantId,
+    device_id: &str,
+    values: &[(String, u64, f64)],
+    state: &ProcessorState,
+) {
+    let rules = match state.db.get_alarm_rules(tenant_id, device_id).await {
+        Ok(rules) => rules,
+        Err(e) => {
+            warn!(error=?e, "Failed to load alarm rules");
+            return;
+        }
+    };
+    for rule in rules {
+        for (metric_name, timestamp, value) in values {
+            if *metric_name != rule.metric_name || !rule.is_triggered(*value) {
+                continue;
+            }
+            let event = AlarmEvent {
+                alarm_id: rule.alarm_id.clone(),
+                triggered_at: *timestamp,
+                value: *value,
+            };
+            warn!(%tenant_id, device_id, alarm_id = rule.alarm_id, value, "Alarm triggered");
+            if let Err(e) = state
+                .db
+                .insert_alarm_event(tenant_id, device_id, &event)
+                .await
+            {
+                warn!(error=?e, "Failed to store alarm event");
+            }
+        }
+    }
+}
+
+/// Counts telemetry that matched none of the configured metrics, e.g. a wrong json pointer
+async fn record_extraction_error(
+    tenant_id: &TenantId,
+    device_id: &str,
+    data_config: &DataConfig,
+    state: &ProcessorState,
+) {
+    let pointers: Vec<&str> = data_config
+        .metrics
+        .iter()
+        .map(|metric| metric.json_pointer.as_str())
+        .collect();
+    let error = format!(
+        "No configured metric found in payload ({})",
+        pointers.join(", ")
+    );
+    let now = state.clock.now_secs();
+    match state
+        .db
+        .record_extraction_error(tenant_id, device_id, &error, now)
+        .await
+    {
+        Ok(true) => warn!(%tenant_id, device_id, error, "Metric extraction failed"),
+        Ok(false) => debug!(%tenant_id, device_id, error, "Metric extraction failed"),
+        Err(e) => warn!(error=?e, "Failed to record extraction error"),
+    }
+}
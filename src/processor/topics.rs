@@ -1,6 +1,6 @@
 use crate::models::{ShadowName, TenantId};
 use crate::mqtt::MqttMessage;
-use crate::processor::ProcessorState;
+use crate::processor::{ProcessorConfig, ProcessorState};
 
 type DeviceId = String;
 
@@ -12,64 +12,210 @@ pub enum TopicType {
     TimeRequest(TenantId, DeviceId),
     Other,
 }
+impl TopicType {
+    /// Tenant derived from the topic, `None` for topics without a device
+    pub fn tenant_id(&self) -> Option<&TenantId> {
+        match self {
+            TopicType::ShadowUpdate(tid, _, _)
+            | TopicType::DataUpdate(tid, _)
+            | TopicType::ShadowDelta(tid, _, _)
+            | TopicType::TimeRequest(tid, _) => Some(tid),
+            TopicType::Other => None,
+        }
+    }
+
+    /// Device derived from the topic, `None` for topics without a device
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            TopicType::ShadowUpdate(_, did, _)
+            | TopicType::DataUpdate(_, did)
+            | TopicType::ShadowDelta(_, did, _)
+            | TopicType::TimeRequest(_, did) => Some(did),
+            TopicType::Other => None,
+        }
+    }
+}
+
 fn split_device_id(device_id: &str) -> (TenantId, DeviceId) {
     match device_id.split_once('.') {
         Some((tenant_str, device_id)) => (TenantId::from_str(tenant_str), device_id.to_string()),
         None => (TenantId::Default, device_id.to_string()),
     }
 }
-pub(crate) fn get_topic_type(msg: &MqttMessage, processor_state: &ProcessorState) -> TopicType {
-    // Check if it matches any telemetry topics
-    for pattern in &processor_state.config.telemetry_topics {
-        let pattern_parts: Vec<&str> = pattern.split('/').collect();
-        let topic_parts: Vec<&str> = msg.topic.split('/').collect();
-
-        if pattern_parts.len() == topic_parts.len() {
-            let mut matches = true;
-            let mut extracted_device_id = None;
-            for (p, t) in pattern_parts.iter().zip(topic_parts.iter()) {
-                if *p == "+" {
-                    if extracted_device_id.is_none() {
-                        extracted_device_id = Some(t.to_string());
-                    }
-                } else if p != t {
-                    matches = false;
-                    break;
-                }
+
+/// Inverse of `split_device_id`, the device id used in the topics of the device.
+/// Devices of other tenants than the default one are addressed as `tenant.device`.
+pub(crate) fn topic_device_id(tenant_id: &TenantId, device_id: &str) -> String {
+    match tenant_id {
+        TenantId::Default => device_id.to_string(),
+        _ => format!("{}.{}", tenant_id, device_id),
+    }
+}
+
+/// Converts a telemetry pattern into an MQTT subscription filter,
+/// named placeholders like `{tenant}` become `+`
+pub(crate) fn subscription_filter(pattern: &str) -> String {
+    pattern
+        .split('/')
+        .map(|part| {
+            if part.starts_with('{') && part.ends_with('}') {
+                "+"
+            } else {
+                part
             }
-            if matches {
-                if let Some(device_id_str) = extracted_device_id {
-                    let (tenant, device) = split_device_id(&device_id_str);
-                    return TopicType::DataUpdate(tenant, device);
-                }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// True if a topic exists that matches both MQTT subscription filters
+pub(crate) fn filters_overlap(a: &str, b: &str) -> bool {
+    let mut a_parts = a.split('/');
+    let mut b_parts = b.split('/');
+    loop {
+        match (a_parts.next(), b_parts.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some(a), Some(b)) if a == b || a == "+" || b == "+" => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Shadow update and time request patterns, in the order they are matched
+fn shadow_topic_patterns(config: &ProcessorConfig) -> Vec<String> {
+    let mut patterns = config.shadow_update_patterns();
+    patterns.extend(config.time_request_patterns());
+    patterns
+}
+
+/// Filters the processor subscribes to for shadow updates and time requests
+pub(crate) fn shadow_subscription_filters(config: &ProcessorConfig) -> Vec<String> {
+    shadow_topic_patterns(config)
+        .iter()
+        .map(|pattern| subscription_filter(pattern))
+        .collect()
+}
+
+/// Pairs of telemetry and shadow patterns that match the same topics
+pub(crate) fn overlapping_topics(config: &ProcessorConfig) -> Vec<(String, String)> {
+    let mut overlaps = Vec::new();
+    for telemetry in &config.telemetry_topics {
+        let filter = subscription_filter(telemetry);
+        for shadow in shadow_topic_patterns(config) {
+            if filters_overlap(&filter, &subscription_filter(&shadow)) {
+                overlaps.push((telemetry.clone(), shadow.clone()));
             }
         }
     }
+    overlaps
+}
+
+/// Placeholders of a topic matching a pattern
+struct PatternMatch<'a> {
+    tenant: Option<&'a str>,
+    device: &'a str,
+    shadow: Option<&'a str>,
+}
+
+impl PatternMatch<'_> {
+    fn ids(&self) -> (TenantId, DeviceId) {
+        match self.tenant {
+            Some(tenant) => (TenantId::from_str(tenant), self.device.to_string()),
+            None => split_device_id(self.device),
+        }
+    }
+}
+
+/// Matches a topic level by level. `{tenant}`, `{device}` and `{shadow}` are taken by
+/// position, other `{name}` placeholders match any level. Without a `{device}` placeholder
+/// the first `+` is the device, without `{shadow}` the next `+` is the shadow name.
+fn match_pattern<'a>(pattern: &str, topic: &'a str) -> Option<PatternMatch<'a>> {
+    let pattern_parts: Vec<&str> = pattern.split('/').collect();
+    let topic_parts: Vec<&str> = topic.split('/').collect();
+    if pattern_parts.len() != topic_parts.len() {
+        return None;
+    }
+
+    let mut tenant = None;
+    let mut device = None;
+    let mut shadow = None;
+    let mut wildcards = Vec::new();
+    for (p, t) in pattern_parts.iter().zip(topic_parts.iter()) {
+        match *p {
+            "{tenant}" => tenant = Some(*t),
+            "{device}" => device = Some(*t),
+            "{shadow}" => shadow = Some(*t),
+            "+" => wildcards.push(*t),
+            _ if p.starts_with('{') && p.ends_with('}') => {}
+            _ if p != t => return None,
+            _ => {}
+        }
+    }
+
+    let mut wildcards = wildcards.into_iter();
+    let device = device.or_else(|| wildcards.next())?;
+    let shadow = shadow.or_else(|| wildcards.next());
+    Some(PatternMatch {
+        tenant,
+        device,
+        shadow,
+    })
+}
+
+/// Matches a topic against a telemetry pattern and extracts tenant and device.
+/// `{tenant}` and `{device}` placeholders are taken by position, other `{name}`
+/// placeholders and `+` match any level. Without a `{device}` placeholder the first `+`
+/// is the device id, optionally prefixed with `tenant.`, as is without `{tenant}`.
+pub(crate) fn match_telemetry_pattern(pattern: &str, topic: &str) -> Option<(TenantId, DeviceId)> {
+    match_pattern(pattern, topic).map(|m| m.ids())
+}
 
-    // check if the topic is a shadow update and strip prefix
-    let shadow_topic = match msg
-        .topic
-        .strip_prefix(processor_state.config.shadow_topic_prefix.as_str())
-    {
+/// Matches a topic against a shadow update pattern, like `match_telemetry_pattern`.
+/// The shadow name is taken from a `{shadow}` placeholder or the `+` after the device,
+/// patterns without either update the default shadow.
+pub(crate) fn match_shadow_pattern(
+    pattern: &str,
+    topic: &str,
+) -> Option<(TenantId, DeviceId, ShadowName)> {
+    let m = match_pattern(pattern, topic)?;
+    let (tenant, device) = m.ids();
+    let shadow = m.shadow.map_or(ShadowName::Default, ShadowName::from_str);
+    Some((tenant, device, shadow))
+}
+
+pub(crate) fn get_topic_type(msg: &MqttMessage, processor_state: &ProcessorState) -> TopicType {
+    let config = processor_state.config.read().unwrap();
+
+    // Check if it matches any telemetry topics
+    for pattern in &config.telemetry_topics {
+        if let Some((tenant, device)) = match_telemetry_pattern(pattern, &msg.topic) {
+            return TopicType::DataUpdate(tenant, device);
+        }
+    }
+
+    for pattern in &config.shadow_update_patterns() {
+        if let Some((tenant, device, shadow)) = match_shadow_pattern(pattern, &msg.topic) {
+            return TopicType::ShadowUpdate(tenant, device, shadow);
+        }
+    }
+
+    for pattern in &config.time_request_patterns() {
+        if let Some((tenant, device)) = match_telemetry_pattern(pattern, &msg.topic) {
+            return TopicType::TimeRequest(tenant, device);
+        }
+    }
+
+    // data and delta topics below the shadow prefix
+    let shadow_topic = match msg.topic.strip_prefix(config.shadow_topic_prefix.as_str()) {
         Some(t) => t,
         None => return TopicType::Other,
     };
 
     let parts: Vec<&str> = shadow_topic.split('/').collect();
-    // determine the type of message
     // first part is always the device_id
-    // second part is always shadow or data -> shadow update or data update
-    // return (type, tenant_id, device_id, shadow_name)
 
     match &parts[..] {
-        [device_id, "shadow", "update"] => {
-            let (tenant, device) = split_device_id(device_id);
-            return TopicType::ShadowUpdate(tenant, device, ShadowName::Default);
-        }
-        [device_id, "shadow", shadow_name, "update"] => {
-            let (tenant, device) = split_device_id(device_id);
-            return TopicType::ShadowUpdate(tenant, device, ShadowName::from_str(shadow_name));
-        }
         [device_id, "data"] => {
             let (tenant, device) = split_device_id(device_id);
             return TopicType::DataUpdate(tenant, device);
@@ -82,10 +228,6 @@ pub(crate) fn get_topic_type(msg: &MqttMessage, processor_state: &ProcessorState
             let (tenant, device) = split_device_id(device_id);
             return TopicType::ShadowDelta(tenant, device, ShadowName::from_str(shadow_name));
         }
-        [device_id, "time", "request"] => {
-            let (tenant, device) = split_device_id(device_id);
-            return TopicType::TimeRequest(tenant, device);
-        }
         _ => {
             return TopicType::Other;
         }
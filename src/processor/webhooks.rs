@@ -0,0 +1,191 @@
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::db::DB;
+use crate::models::{ShadowEvent, ShadowWebhook};
+use crate::processor::retry::RetryPolicy;
+use crate::shadow::{Shadow, StateUpdateDocument};
+
+/// Deliveries are attempted three times, 500ms and 1s apart
+const WEBHOOK_RETRY: RetryPolicy = RetryPolicy {
+    max_attempts: 3,
+    base_delay: Duration::from_millis(500),
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const SIGNATURE_HEADER: &str = "X-Forest-Signature";
+pub const EVENT_HEADER: &str = "X-Forest-Event";
+
+static WEBHOOK_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+#[derive(Error, Debug)]
+enum WebhookError {
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Unexpected status: {0}")]
+    Status(reqwest::StatusCode),
+    #[error("Signing failed: {0}")]
+    Signature(#[from] ErrorStack),
+}
+
+fn event_name(event: ShadowEvent) -> &'static str {
+    match event {
+        ShadowEvent::Updated => "updated",
+        ShadowEvent::DeltaCleared => "delta_cleared",
+    }
+}
+
+/// Value of the signature header, `sha256=` and the hex encoded HMAC-SHA256 of the body
+pub fn webhook_signature(secret: &str, body: &[u8]) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(body)?;
+    let hex: String = signer
+        .sign_to_vec()?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok(format!("sha256={}", hex))
+}
+
+/// Events of an update that resulted in `shadow`, `had_delta` is whether the shadow had a
+/// delta before the update
+pub fn shadow_events(had_delta: bool, shadow: &Shadow) -> Vec<ShadowEvent> {
+    let mut events = vec![ShadowEvent::Updated];
+    if had_delta && !shadow.has_delta() {
+        events.push(ShadowEvent::DeltaCleared);
+    }
+    events
+}
+
+/// Webhooks of a device, loaded before its shadow is updated
+pub struct ShadowWebhookDispatch {
+    webhooks: Vec<ShadowWebhook>,
+    had_delta: bool,
+}
+
+impl ShadowWebhookDispatch {
+    /// Loads the webhooks of the device of the update, `None` if it has none.
+    /// Failures are logged, webhooks never fail a shadow update.
+    pub async fn prepare(db: &DB, update: &StateUpdateDocument) -> Option<Self> {
+        let webhooks = match db
+            .get_shadow_webhooks(&update.tenant_id, &update.device_id)
+            .await
+        {
+            Ok(webhooks) if !webhooks.is_empty() => webhooks,
+            Ok(_) => return None,
+            Err(e) => {
+                warn!(
+                    %update.tenant_id,
+                    update.device_id,
+                    "Failed to load shadow webhooks: {}",
+                    e
+                );
+                return None;
+            }
+        };
+        // Only needed to tell whether the update cleared the delta
+        let had_delta = webhooks
+            .iter()
+            .any(|webhook| webhook.events.contains(&ShadowEvent::DeltaCleared))
+            && db
+                ._get_shadow(&update.device_id, &update.shadow_name, &update.tenant_id)
+                .await
+                .is_ok_and(|shadow| shadow.has_delta());
+        Some(ShadowWebhookDispatch {
+            webhooks,
+            had_delta,
+        })
+    }
+
+    /// Posts the updated shadow to the webhooks subscribed to its events, one request per
+    /// webhook and event, in background tasks
+    pub fn dispatch(self, shadow: &Shadow) {
+        let body = match serde_json::to_vec(shadow) {
+            Ok(body) => Arc::new(body),
+            Err(e) => {
+                warn!(
+                    shadow.device_id,
+                    "Failed to serialize shadow for webhooks: {}", e
+                );
+                return;
+            }
+        };
+        for event in shadow_events(self.had_delta, shadow) {
+            for webhook in &self.webhooks {
+                if webhook.events.contains(&event) {
+                    tokio::spawn(deliver(webhook.clone(), event, body.clone()));
+                }
+            }
+        }
+    }
+}
+
+async fn post(
+    webhook: &ShadowWebhook,
+    event: ShadowEvent,
+    body: &[u8],
+) -> Result<(), WebhookError> {
+    let client = WEBHOOK_CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .unwrap_or_default()
+    });
+    let mut request = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, event_name(event))
+        .body(body.to_vec());
+    if let Some(secret) = &webhook.secret {
+        request = request.header(SIGNATURE_HEADER, webhook_signature(secret, body)?);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(WebhookError::Status(response.status()));
+    }
+    Ok(())
+}
+
+/// Posts until the webhook answers with a success status or all attempts are used up
+async fn deliver(webhook: ShadowWebhook, event: ShadowEvent, body: Arc<Vec<u8>>) {
+    let mut attempt = 1;
+    loop {
+        match post(&webhook, event, &body).await {
+            Ok(()) => {
+                debug!(webhook.url, event = event_name(event), "Webhook delivered");
+                return;
+            }
+            Err(e) if attempt < WEBHOOK_RETRY.max_attempts => {
+                let delay = WEBHOOK_RETRY.delay(attempt - 1);
+                debug!(
+                    webhook.url,
+                    attempt,
+                    ?delay,
+                    "Webhook failed, retrying: {}",
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                warn!(
+                    webhook.url,
+                    webhook.webhook_id,
+                    attempts = attempt,
+                    "Webhook delivery failed: {}",
+                    e
+                );
+                return;
+            }
+        }
+    }
+}
@@ -1,23 +1,104 @@
+use futures_util::StreamExt;
 use tokio_util::sync::CancellationToken;
-use tracing::warn;
+use tracing::{error, info, warn};
 
 use crate::api::start_api_server;
-use crate::config::ForestConfig;
+use crate::config::{watch_config, ForestConfig};
 use crate::db::DB;
-use crate::mqtt::start_broker;
-use crate::processor::start_processor;
+use crate::models::TenantId;
+use crate::mqtt::{start_broker, MqttSender};
+use crate::processor::{apply_config, start_processor, ProcessorConfig};
 
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-pub type ConnectionSet = dashmap::DashSet<String>;
+/// A client connected to the MQTT broker
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub tenant: TenantId,
+    /// Unix seconds
+    pub connected_at: u64,
+}
+
+/// Connected MQTT clients by client id
+pub type ConnectionSet = dashmap::DashMap<String, ConnectionInfo>;
+
+/// Applies config file changes while the server is running.
+///
+/// Only the processor section is hot-reloadable, new topics are subscribed before the
+/// processor uses them, see `apply_config`. Everything else (bind addresses, database,
+/// certificates, MQTT limits) requires a restart and is ignored here.
+async fn reload_config(
+    config_path: PathBuf,
+    current_config: Arc<RwLock<ForestConfig>>,
+    mqtt_sender: MqttSender,
+    processor_config: Arc<RwLock<ProcessorConfig>>,
+) {
+    let stream = match watch_config(config_path) {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!(error=?e, "Failed to watch config file, hot reload disabled");
+            return;
+        }
+    };
+    futures_util::pin_mut!(stream);
+
+    while let Some(new_config) = stream.next().await {
+        if let Err(e) = apply_config(
+            &mqtt_sender,
+            &processor_config,
+            new_config.processor.clone(),
+        )
+        .await
+        {
+            error!(error=?e, "Failed to apply the processor config, keeping the running one");
+            continue;
+        }
+        let mut current = current_config.write().unwrap();
+        let mut applied = current.clone();
+        applied.processor = new_config.processor.clone();
+        if serde_json::to_value(&applied).ok() != serde_json::to_value(&new_config).ok() {
+            warn!("Config changed outside of the processor section, restart to apply it");
+        }
+        *current = applied;
+        info!("Reloaded processor config");
+    }
+}
+
+/// Writes a database snapshot every `interval`, the first one after a full interval
+async fn run_backups(db: Arc<DB>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match db.backup().await {
+            Ok(backup) => info!(
+                path = backup.path,
+                size = backup.size_bytes,
+                "Database backup written"
+            ),
+            Err(e) => error!(error = ?e, "Database backup failed"),
+        }
+    }
+}
+
+/// Deletes metric values older than `retention_days` every `interval`, the first time
+/// right after startup
+async fn run_retention(db: Arc<DB>, retention_days: u32, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match db.prune_metrics(retention_days).await {
+            Ok(rows) => info!(rows, retention_days, "Pruned old metric values"),
+            Err(e) => error!(error = ?e, "Pruning old metric values failed"),
+        }
+    }
+}
 
 pub async fn start_server(
     config: &ForestConfig,
+    config_path: Option<&Path>,
 ) -> (CancellationToken, tokio::task::JoinHandle<()>) {
-    let db_path = PathBuf::from(&config.database.path);
-
-    let maybe_db = DB::open_default(db_path.to_str().unwrap()).await;
+    let maybe_db = DB::open(&config.database).await;
     let db = {
         match maybe_db {
             Ok(db) => Arc::new(db),
@@ -48,7 +129,7 @@ pub async fn start_server(
         config.processor.clone(),
     )
     .await;
-    let (_processor, processor_handle) = {
+    let (processor, processor_handle) = {
         match maybe_processor {
             Ok(tuple) => tuple,
             Err(e) => {
@@ -67,10 +148,39 @@ pub async fn start_server(
         mqtt_metrics,
         connected_clients,
         &config,
+        processor.config.clone(),
+        processor.ingest_metrics.clone(),
+        Some(processor.config_invalidation.clone()),
+        Some(processor.metric_stream.clone()),
+        processor.shadow_rate_limiter.clone(),
         Some(controller),
     )
     .await;
 
+    if let Some(config_path) = config_path {
+        let current_config = Arc::new(RwLock::new(config.clone()));
+        tokio::spawn(reload_config(
+            config_path.to_path_buf(),
+            current_config,
+            processor.mqtt_sender.clone(),
+            processor.config.clone(),
+        ));
+    }
+
+    let backup_handle = (config.database.backup_interval_secs > 0).then(|| {
+        tokio::spawn(run_backups(
+            db.clone(),
+            Duration::from_secs(config.database.backup_interval_secs),
+        ))
+    });
+    let retention_handle = config.database.retention_days.map(|retention_days| {
+        tokio::spawn(run_retention(
+            db.clone(),
+            retention_days,
+            Duration::from_secs(config.database.retention_interval_secs),
+        ))
+    });
+
     let server_cancel_token = _broker_cancel_token.clone();
 
     let combined_handle = tokio::spawn(async move {
@@ -85,6 +195,16 @@ pub async fn start_server(
             }
         }
         let _ = tokio::join!(processor_handle, api_handle);
+        if let Some(backup_handle) = backup_handle {
+            backup_handle.abort();
+        }
+        if let Some(retention_handle) = retention_handle {
+            retention_handle.abort();
+        }
+        processor.shutdown().await;
+        if let Err(e) = db.flush().await {
+            warn!(error = ?e, "Failed to write cached shadows on shutdown");
+        }
     });
 
     (server_cancel_token, combined_handle)
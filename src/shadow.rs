@@ -13,6 +13,8 @@ pub enum ShadowError {
     ShadowNameMismatch,
     #[error("TenantId mismatch")]
     TenantIdMismatch,
+    #[error("Shadow of {size} bytes exceeds the limit of {max} bytes")]
+    TooLarge { size: usize, max: usize },
 }
 
 #[derive(Error, Debug)]
@@ -62,6 +64,14 @@ pub struct DeltaResponse {
     pub state: Value,
 }
 
+/// Full state published on `.../update/accepted` after a successful update
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcceptedResponse {
+    pub state: StateDocument,
+    pub version: u64,
+    pub timestamp: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NestedStateDocument {
     pub state: StateDocument,
@@ -149,6 +159,33 @@ impl StateUpdateDocument {
     pub fn set_desired_value(&mut self, value: Value) {
         self.state.desired = value;
     }
+
+    /// Sets the desired field at a JSON pointer (e.g. `/config/sample_rate`) to null, which
+    /// removes it when applied. Returns false if the pointer doesn't start with `/`.
+    pub fn clear_desired_pointer(&mut self, pointer: &str) -> bool {
+        let Some(path) = pointer.strip_prefix('/') else {
+            return false;
+        };
+        let mut value = Value::Null;
+        for key in path.rsplit('/') {
+            let key = key.replace("~1", "/").replace("~0", "~");
+            value = Value::Object(serde_json::Map::from_iter([(key, value)]));
+        }
+        self.state.desired = value;
+        true
+    }
+
+    /// Sets every desired field of the current shadow to null, clearing the whole document
+    pub fn clear_desired(&mut self, current: &Shadow) {
+        let cleared = match current.get_desired_value() {
+            Value::Object(desired) => desired
+                .keys()
+                .map(|key| (key.clone(), Value::Null))
+                .collect(),
+            _ => serde_json::Map::new(),
+        };
+        self.state.desired = Value::Object(cleared);
+    }
 }
 
 impl Shadow {
@@ -187,6 +224,23 @@ impl Shadow {
         Ok(serde_json::to_string_pretty(self)?)
     }
 
+    /// Fails with `TooLarge` if the serialized shadow is larger than `max_bytes`,
+    /// 0 allows any size
+    pub fn check_size(&self, max_bytes: usize) -> Result<(), ShadowError> {
+        if max_bytes == 0 {
+            return Ok(());
+        }
+        // Serializing a shadow of JSON values can't fail
+        let size = serde_json::to_vec(self).map_or(0, |json| json.len());
+        if size > max_bytes {
+            return Err(ShadowError::TooLarge {
+                size,
+                max: max_bytes,
+            });
+        }
+        Ok(())
+    }
+
     pub fn get_reported_value(&self) -> &Value {
         &self.state.reported
     }
@@ -199,6 +253,14 @@ impl Shadow {
         &self.state.delta
     }
 
+    /// True if desired values differ from the reported ones
+    pub fn has_delta(&self) -> bool {
+        self.state
+            .delta
+            .as_object()
+            .is_some_and(|delta| !delta.is_empty())
+    }
+
     pub fn get_delta_response_json(&self) -> Result<Option<String>, ShadowSerializationError> {
         if self.state.delta.is_null() {
             return Ok(None);
@@ -212,6 +274,18 @@ impl Shadow {
         })?))
     }
 
+    pub fn get_accepted_response_json(&self) -> Result<String, ShadowSerializationError> {
+        Ok(serde_json::to_string(&AcceptedResponse {
+            state: StateDocument {
+                reported: self.state.reported.clone(),
+                desired: self.state.desired.clone(),
+                delta: Value::Null,
+            },
+            version: self.version,
+            timestamp: self.last_updated,
+        })?)
+    }
+
     pub fn get_reported_metadata(&self) -> &Value {
         &self.metadata.reported
     }
@@ -224,40 +298,23 @@ impl Shadow {
         self.version
     }
 
+    /// Strong HTTP entity tag for this shadow.
+    /// The version alone is not enough because it restarts at 1 after a delete,
+    /// so the last update timestamp is included as well.
+    pub fn etag(&self) -> String {
+        format!("\"{}-{}\"", self.version, self.last_updated)
+    }
+
     fn calculate_delta(&mut self) {
-        fn diff_recursive(reported: &Value, desired: &Value) -> Option<Value> {
-            match (reported, desired) {
-                (Value::Object(rep), Value::Object(des)) => {
-                    let mut delta_obj = serde_json::Map::new();
-                    for (key, des_val) in des {
-                        match rep.get(key) {
-                            Some(rep_val) => {
-                                if rep_val != des_val {
-                                    if let Some(diff) = diff_recursive(rep_val, des_val) {
-                                        delta_obj.insert(key.clone(), diff);
-                                    }
-                                }
-                            }
-                            None => {
-                                delta_obj.insert(key.clone(), des_val.clone());
-                            }
-                        }
-                    }
-                    if delta_obj.is_empty() {
-                        None
-                    } else {
-                        Some(Value::Object(delta_obj))
-                    }
-                }
-                (reported, desired) if reported != desired => Some(desired.clone()),
-                _ => None,
-            }
-        }
+        self.state.delta =
+            diff_recursive(&self.state.reported, &self.state.desired, false).unwrap_or(Value::Null);
+    }
 
-        self.state.delta = match diff_recursive(&self.state.reported, &self.state.desired) {
-            Some(delta) => delta,
-            None => Value::Null,
-        };
+    /// Reported fields that changed from this shadow to `other`, in the shape of a
+    /// reported update: added and changed fields with their new value, removed ones as
+    /// `null`. `Value::Null` if both report the same.
+    pub fn diff(&self, other: &Shadow) -> Value {
+        diff_recursive(&self.state.reported, &other.state.reported, true).unwrap_or(Value::Null)
     }
 
     pub fn update(&mut self, update: &StateUpdateDocument) -> Result<(), ShadowError> {
@@ -286,6 +343,44 @@ impl Shadow {
     }
 }
 
+/// Fields of `to` that differ from `from`, objects are compared key by key.
+/// With `removals` keys of `from` missing in `to` are included as `null`.
+fn diff_recursive(from: &Value, to: &Value, removals: bool) -> Option<Value> {
+    match (from, to) {
+        (Value::Object(from_obj), Value::Object(to_obj)) => {
+            let mut diff_obj = serde_json::Map::new();
+            for (key, to_val) in to_obj {
+                match from_obj.get(key) {
+                    Some(from_val) => {
+                        if from_val != to_val {
+                            if let Some(diff) = diff_recursive(from_val, to_val, removals) {
+                                diff_obj.insert(key.clone(), diff);
+                            }
+                        }
+                    }
+                    None => {
+                        diff_obj.insert(key.clone(), to_val.clone());
+                    }
+                }
+            }
+            if removals {
+                for key in from_obj.keys() {
+                    if !to_obj.contains_key(key) {
+                        diff_obj.insert(key.clone(), Value::Null);
+                    }
+                }
+            }
+            if diff_obj.is_empty() {
+                None
+            } else {
+                Some(Value::Object(diff_obj))
+            }
+        }
+        (from, to) if from != to => Some(to.clone()),
+        _ => None,
+    }
+}
+
 impl StateDocument {
     pub fn update(&mut self, update: &StateDocument, metadata: &mut MetadataDocument) {
         // Ensure metadata state starts as an object
@@ -356,3 +356,110 @@ fn test_shadow_name_in_struct() {
     let test: TestStruct = serde_json::from_str(r#"{"name":"custom-name"}"#).unwrap();
     assert_eq!(test.name, ShadowName::Custom("custom-name".to_string()));
 }
+
+#[test]
+fn test_clear_desired() {
+    let tenant_id = TenantId::Default;
+    let shadow_name = ShadowName::Default;
+    let mut shadow = Shadow::new("device1", &shadow_name, &tenant_id);
+    let update = StateUpdateDocument::from_nested_json(
+        r#"{"state": {"desired": {"config": {"sample_rate": 10, "mode": "eco"}, "a/b": 1, "led": "on"}}}"#,
+        "device1",
+        &shadow_name,
+        &tenant_id,
+    )
+    .unwrap();
+    shadow.update(&update).unwrap();
+
+    let mut clear = StateUpdateDocument::new("device1", &shadow_name, &tenant_id);
+    assert!(clear.clear_desired_pointer("/config/sample_rate"));
+    assert_eq!(
+        clear.get_desired_value(),
+        &json!({"config": {"sample_rate": null}})
+    );
+    shadow.update(&clear).unwrap();
+    assert_eq!(
+        shadow.get_desired_value(),
+        &json!({"config": {"mode": "eco"}, "a/b": 1, "led": "on"})
+    );
+
+    // "~1" escapes a slash within a key
+    assert!(clear.clear_desired_pointer("/a~1b"));
+    shadow.update(&clear).unwrap();
+    assert_eq!(
+        shadow.get_desired_value(),
+        &json!({"config": {"mode": "eco"}, "led": "on"})
+    );
+
+    assert!(!clear.clear_desired_pointer("config"));
+
+    clear.clear_desired(&shadow);
+    shadow.update(&clear).unwrap();
+    assert_eq!(shadow.get_desired_value(), &json!({}));
+}
+
+#[test]
+fn test_shadow_diff() {
+    let tenant_id = TenantId::Default;
+    let shadow_name = ShadowName::Default;
+    let report = |shadow: &mut Shadow, reported: Value| {
+        let mut update = StateUpdateDocument::new("device1", &shadow_name, &tenant_id);
+        update.set_reported_value(reported);
+        shadow.update(&update).unwrap();
+    };
+
+    let mut before = Shadow::new("device1", &shadow_name, &tenant_id);
+    report(
+        &mut before,
+        json!({
+            "firmware": "1.0.0",
+            "readings": {"temperature": 21.5, "humidity": 45},
+            "tags": ["a", "b"],
+            "led": "on"
+        }),
+    );
+    let mut after = before.clone();
+    report(
+        &mut after,
+        json!({
+            "firmware": "1.1.0",
+            "readings": {"humidity": null, "pressure": 1013},
+            "tags": ["a"],
+            "led": null,
+            "mode": "eco"
+        }),
+    );
+    // Desired state and metadata are not compared
+    let mut update = StateUpdateDocument::new("device1", &shadow_name, &tenant_id);
+    update.set_desired_value(json!({"led": "off"}));
+    after.update(&update).unwrap();
+
+    let diff = before.diff(&after);
+    assert_eq!(
+        diff,
+        json!({
+            "firmware": "1.1.0",
+            "readings": {"humidity": null, "pressure": 1013},
+            "tags": ["a"],
+            "led": null,
+            "mode": "eco"
+        })
+    );
+    // Applied as a reported update the diff turns one shadow into the other
+    let mut patched = before.clone();
+    report(&mut patched, diff);
+    assert_eq!(patched.get_reported_value(), after.get_reported_value());
+
+    // Unchanged keys are left out, equal shadows have no diff
+    assert_eq!(
+        after.diff(&before),
+        json!({
+            "firmware": "1.0.0",
+            "readings": {"humidity": 45, "pressure": null},
+            "tags": ["a", "b"],
+            "led": "on",
+            "mode": null
+        })
+    );
+    assert_eq!(after.diff(&after), Value::Null);
+}
@@ -4,7 +4,8 @@
 //! It provides methods to create a new time series and add data points while keeping the series ordered by timestamp.
 //!
 
-use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thiserror::Error;
@@ -20,6 +21,7 @@ pub enum MetricValue {
     Float(f64),
     Int(i64),
     Location(LatLong),
+    Text(String),
 }
 
 impl std::fmt::Display for MetricValue {
@@ -28,6 +30,7 @@ impl std::fmt::Display for MetricValue {
             MetricValue::Float(val) => write!(f, "{}", val),
             MetricValue::Int(val) => write!(f, "{}", val),
             MetricValue::Location(loc) => write!(f, "({}, {})", loc.latitude, loc.longitude),
+            MetricValue::Text(text) => write!(f, "{}", text),
         }
     }
 }
@@ -37,9 +40,101 @@ pub struct TimeSeriesModel {
     pub device_id: String,
     pub metric: String,
     pub data: Vec<(u64, Value)>,
+    /// `MetricConfig::unit` of the metric, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+}
+
+impl TimeSeriesModel {
+    pub fn with_unit(mut self, unit: Option<String>) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Renders the data as `timestamp,value` CSV, locations and other JSON values are quoted.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("timestamp,value\n");
+        for (timestamp, value) in &self.data {
+            let value = match value {
+                Value::Number(n) => n.to_string(),
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            if value.contains([',', '"', '\n']) {
+                csv.push_str(&format!(
+                    "{},\"{}\"\n",
+                    timestamp,
+                    value.replace('"', "\"\"")
+                ));
+            } else {
+                csv.push_str(&format!("{},{}\n", timestamp, value));
+            }
+        }
+        csv
+    }
+
+    /// Reconstructs the series from the rendered data, the inverse of `to_model`.
+    /// Integral numbers become `Int`, other numbers and `"NaN"`/`"Infinity"` strings `Float`,
+    /// other strings `Text`.
+    pub fn into_metric_series(self) -> Result<MetricTimeSeries, TimeseriesSerializationError> {
+        let mut ts = MetricTimeSeries::new();
+        for (timestamp, value) in self.data {
+            let value = MetricValue::from_json(&value).ok_or_else(|| {
+                TimeseriesSerializationError::InvalidData(format!(
+                    "Unsupported value at {}: {}",
+                    timestamp, value
+                ))
+            })?;
+            ts.add_point(timestamp, value);
+        }
+        Ok(ts)
+    }
 }
 
 impl MetricValue {
+    /// Parses a value as rendered by `TimeSeriesModel`, the inverse of `From<MetricValue> for Value`.
+    /// Strings other than `"NaN"`/`"Infinity"`/`"-Infinity"` become `Text`.
+    pub fn from_json(value: &Value) -> Option<MetricValue> {
+        match value {
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => Some(MetricValue::Int(i)),
+                None => n.as_f64().map(MetricValue::Float),
+            },
+            Value::String(s) => Some(
+                float_from_json(value)
+                    .map(MetricValue::Float)
+                    .unwrap_or_else(|| MetricValue::Text(s.clone())),
+            ),
+            Value::Object(obj) => Some(MetricValue::Location(LatLong::new(
+                float_from_json(obj.get("lat")?)?,
+                float_from_json(obj.get("long")?)?,
+            ))),
+            _ => None,
+        }
+    }
+
+    /// True if all floats of the value are finite
+    pub fn is_finite(&self) -> bool {
+        match self {
+            MetricValue::Float(f) => f.is_finite(),
+            MetricValue::Int(_) => true,
+            MetricValue::Location(loc) => loc.latitude.is_finite() && loc.longitude.is_finite(),
+            MetricValue::Text(_) => true,
+        }
+    }
+
+    /// Applies the policy to non-finite values, `None` if the value must not be stored.
+    /// Locations are never clamped, a clamped coordinate is meaningless.
+    pub fn sanitize(self, policy: NonFinitePolicy) -> Option<MetricValue> {
+        match self {
+            MetricValue::Float(f) if f.is_infinite() && policy == NonFinitePolicy::Clamp => {
+                Some(MetricValue::Float(f.clamp(f64::MIN, f64::MAX)))
+            }
+            value if value.is_finite() => Some(value),
+            _ => None,
+        }
+    }
+
     pub fn as_timeseries(&self, timestamp: u64) -> MetricTimeSeries {
         let mut ts = MetricTimeSeries::new();
         ts.add_point(timestamp, self.clone());
@@ -50,7 +145,7 @@ impl MetricValue {
         match self {
             MetricValue::Float(f) => Some(f),
             MetricValue::Int(i) => Some(i as f64),
-            MetricValue::Location(_) => None,
+            MetricValue::Location(_) | MetricValue::Text(_) => None,
         }
     }
 
@@ -58,7 +153,7 @@ impl MetricValue {
         match self {
             MetricValue::Float(f) => Some(f as i64),
             MetricValue::Int(i) => Some(i),
-            MetricValue::Location(_) => None,
+            MetricValue::Location(_) | MetricValue::Text(_) => None,
         }
     }
 
@@ -70,21 +165,55 @@ impl MetricValue {
     }
 }
 
+/// JSON has no representation for non-finite numbers, they are written as
+/// `"NaN"`, `"Infinity"` and `"-Infinity"` strings instead
+fn float_to_json(f: f64) -> serde_json::Value {
+    match serde_json::Number::from_f64(f) {
+        Some(n) => serde_json::Value::Number(n),
+        None if f.is_nan() => serde_json::Value::String("NaN".to_string()),
+        None if f > 0.0 => serde_json::Value::String("Infinity".to_string()),
+        None => serde_json::Value::String("-Infinity".to_string()),
+    }
+}
+
+fn float_from_json(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => match s.as_str() {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 impl From<MetricValue> for serde_json::Value {
     fn from(value: MetricValue) -> Self {
         match value {
-            MetricValue::Float(f) => serde_json::Value::Number(
-                serde_json::Number::from_f64(f).unwrap_or_else(|| serde_json::Number::from(0)),
-            ),
+            MetricValue::Float(f) => float_to_json(f),
             MetricValue::Int(i) => serde_json::json!(i),
             MetricValue::Location(loc) => serde_json::json!({
-                "lat": loc.latitude,
-                "long": loc.longitude
+                "lat": float_to_json(loc.latitude),
+                "long": float_to_json(loc.longitude)
             }),
+            MetricValue::Text(text) => serde_json::Value::String(text),
         }
     }
 }
 
+/// How non-finite floats (NaN, +/-Infinity) are handled when storing metrics
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NonFinitePolicy {
+    /// Drop the value
+    #[default]
+    Reject,
+    /// Store +/-Infinity as the largest finite float, NaN is still rejected
+    Clamp,
+}
+
 // Add trait for different timeseries types
 pub trait TimeSeriesConversions: Send + Sync {
     fn to_binary(&self) -> Result<Vec<u8>, TimeseriesSerializationError>;
@@ -115,6 +244,43 @@ impl LatLong {
     }
 }
 
+/// Area between two latitudes and two longitudes, the edges belong to it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_long: f64,
+    pub max_lat: f64,
+    pub max_long: f64,
+}
+
+impl BoundingBox {
+    /// Parses `minLat,minLong,maxLat,maxLong`. `None` if a coordinate is missing or out of
+    /// range, or a minimum is larger than its maximum.
+    pub fn parse(text: &str) -> Option<Self> {
+        let coordinates = text
+            .split(',')
+            .map(|c| c.trim().parse::<f64>().ok())
+            .collect::<Option<Vec<f64>>>()?;
+        let [min_lat, min_long, max_lat, max_long] = coordinates[..] else {
+            return None;
+        };
+        let bbox = BoundingBox {
+            min_lat,
+            min_long,
+            max_lat,
+            max_long,
+        };
+        let lat_valid = -90.0 <= min_lat && min_lat <= max_lat && max_lat <= 90.0;
+        let long_valid = -180.0 <= min_long && min_long <= max_long && max_long <= 180.0;
+        (lat_valid && long_valid).then_some(bbox)
+    }
+
+    pub fn contains(&self, location: &LatLong) -> bool {
+        (self.min_lat..=self.max_lat).contains(&location.latitude)
+            && (self.min_long..=self.max_long).contains(&location.longitude)
+    }
+}
+
 pub struct TimeSeriesIter<'a, T> {
     timestamps: std::slice::Iter<'a, u64>,
     values: std::slice::Iter<'a, T>,
@@ -129,9 +295,74 @@ pub struct TimeSeriesRangeIter<'a, T> {
 pub struct TimeSeriesBucketIter<'a, T> {
     series: &'a TimeSeries<T>,
     current_idx: usize,
+    interval: u64,
+}
+
+pub struct TimeSeriesCalendarBucketIter<'a, T> {
+    series: &'a TimeSeries<T>,
+    current_idx: usize,
+    unit: CalendarUnit,
+    tz: Tz,
+}
+
+/// Calendar based bucket size for `TimeSeries::bucket_by_calendar`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalendarUnit {
+    Day,
+    /// ISO week, starting on Monday
+    Week,
+    Month,
+}
+
+impl CalendarUnit {
+    /// Start of the bucket containing `timestamp`, as UTC timestamp
+    fn bucket_start(self, timestamp: u64, tz: &Tz) -> u64 {
+        let date = Utc
+            .timestamp_opt(timestamp as i64, 0)
+            .single()
+            .expect("Invalid timestamp")
+            .with_timezone(tz)
+            .date_naive();
+        let start = match self {
+            CalendarUnit::Day => date,
+            CalendarUnit::Week => {
+                date - chrono::Days::new(date.weekday().num_days_from_monday() as u64)
+            }
+            CalendarUnit::Month => date.with_day(1).unwrap(),
+        };
+        local_midnight(start, tz)
+    }
+
+    /// Start of the bucket following the one starting at `bucket_start`
+    fn next_bucket_start(self, bucket_start: u64, tz: &Tz) -> u64 {
+        let date = Utc
+            .timestamp_opt(bucket_start as i64, 0)
+            .single()
+            .expect("Invalid timestamp")
+            .with_timezone(tz)
+            .date_naive();
+        let next = match self {
+            CalendarUnit::Day => date + chrono::Days::new(1),
+            CalendarUnit::Week => date + chrono::Days::new(7),
+            CalendarUnit::Month => date + chrono::Months::new(1),
+        };
+        local_midnight(next, tz)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// First valid local time of `date`. Midnight may be skipped by a DST change,
+/// then the day starts at the first full hour that exists.
+fn local_midnight(date: NaiveDate, tz: &Tz) -> u64 {
+    (0..24)
+        .find_map(|hour| {
+            tz.from_local_datetime(&date.and_hms_opt(hour, 0, 0)?)
+                .earliest()
+        })
+        .expect("Every day has a valid local time")
+        .timestamp() as u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TimeSeries<T> {
     timestamps: Vec<u64>, // Vector of Unix timestamps in seconds
     values: Vec<T>,       // Vector of values
@@ -162,6 +393,44 @@ impl<T> TimeSeries<T> {
         }
     }
 
+    /// Appends a data point in O(1) if it is not older than the latest one,
+    /// otherwise falls back to `add_point`. An equal timestamp replaces the value.
+    pub fn push_monotonic(&mut self, timestamp: u64, value: T) {
+        match self.timestamps.last() {
+            Some(&last) if timestamp == last => {
+                *self.values.last_mut().unwrap() = value;
+            }
+            Some(&last) if timestamp < last => self.add_point(timestamp, value),
+            _ => {
+                self.timestamps.push(timestamp);
+                self.values.push(value);
+            }
+        }
+    }
+
+    /// Adds data points that are (mostly) in ascending order, see `push_monotonic`
+    pub fn extend_from_sorted<I: IntoIterator<Item = (u64, T)>>(&mut self, points: I) {
+        let points = points.into_iter();
+        let (lower, _) = points.size_hint();
+        self.timestamps.reserve(lower);
+        self.values.reserve(lower);
+        for (timestamp, value) in points {
+            self.push_monotonic(timestamp, value);
+        }
+    }
+
+    /// Creates a TimeSeries from strictly ascending timestamps and their values
+    /// without copying.
+    pub fn from_sorted_vecs(timestamps: Vec<u64>, values: Vec<T>) -> Result<Self, &'static str> {
+        if timestamps.len() != values.len() {
+            return Err("Timestamps and values differ in length");
+        }
+        if timestamps.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("Timestamps are not strictly ascending");
+        }
+        Ok(TimeSeries { timestamps, values })
+    }
+
     /// Retrieves the latest data point, if available.
     pub fn latest(&self) -> Option<(u64, &T)> {
         if let (Some(&timestamp), Some(value)) = (self.timestamps.last(), self.values.last()) {
@@ -270,6 +539,80 @@ impl<T> TimeSeries<T> {
         }
     }
 
+    /// Removes consecutive points with equal values, keeping the first point of each run.
+    pub fn deduplicate(self) -> TimeSeries<T>
+    where
+        T: PartialEq,
+    {
+        self.deduplicate_by(|a, b| a == b)
+    }
+
+    /// Like `deduplicate`, with `f` deciding whether two values are equal.
+    /// Each point is compared with the kept first point of the current run, so a slowly
+    /// drifting value doesn't stay in one run with a tolerance based `f`.
+    pub fn deduplicate_by<F: Fn(&T, &T) -> bool>(self, f: F) -> TimeSeries<T> {
+        let mut deduplicated = TimeSeries::new();
+        for (timestamp, value) in self.timestamps.into_iter().zip(self.values) {
+            let same_run = deduplicated
+                .values
+                .last()
+                .is_some_and(|kept| f(kept, &value));
+            if same_run {
+                continue;
+            }
+            deduplicated.timestamps.push(timestamp);
+            deduplicated.values.push(value);
+        }
+        deduplicated
+    }
+
+    /// Finds the gaps between the first and the last point, see `find_gaps_in`.
+    pub fn find_gaps(&self, expected_interval_secs: u64, tolerance_secs: u64) -> GapReport {
+        match (self.timestamps.first(), self.timestamps.last()) {
+            (Some(&start), Some(&end)) => {
+                self.find_gaps_in(start, end, expected_interval_secs, tolerance_secs)
+            }
+            _ => GapReport::default(),
+        }
+    }
+
+    /// Finds the stretches of the window `start..=end` in which a point was expected every
+    /// `expected_interval_secs` but none arrived for longer than the interval plus
+    /// `tolerance_secs`. A gap runs from the last point before it to the first point after
+    /// it, at the edges of the window from or to the window bound.
+    pub fn find_gaps_in(
+        &self,
+        start: u64,
+        end: u64,
+        expected_interval_secs: u64,
+        tolerance_secs: u64,
+    ) -> GapReport {
+        if end < start {
+            return GapReport::default();
+        }
+        let interval = expected_interval_secs.max(1);
+        let max_distance = interval.saturating_add(tolerance_secs);
+
+        let mut gaps = Vec::new();
+        let mut actual_count = 0;
+        let mut previous = start;
+        for (timestamp, _) in self.range(start, end) {
+            if timestamp - previous > max_distance {
+                gaps.push((previous, timestamp));
+            }
+            previous = timestamp;
+            actual_count += 1;
+        }
+        if end - previous > max_distance {
+            gaps.push((previous, end));
+        }
+        GapReport {
+            gaps,
+            expected_count: (end - start) / interval + 1,
+            actual_count,
+        }
+    }
+
     /// Returns the number of elements in the time series
     pub fn len(&self) -> usize {
         self.timestamps.len()
@@ -285,15 +628,76 @@ impl<T> TimeSeries<T> {
         self.timestamps.first().copied()
     }
 
-    /// Merges another TimeSeries into this one, maintaining timestamp order
+    /// Merges another TimeSeries into this one, maintaining timestamp order.
+    /// Values at identical timestamps are replaced by the other series' value.
     pub fn merge(&mut self, other: &TimeSeries<T>)
     where
         T: Clone,
     {
-        // Extend with other's data
-        for (timestamp, value) in other.timestamps.iter().zip(other.values.iter()) {
-            self.add_point(*timestamp, value.clone());
+        self.merge_with_policy(other, MergePolicy::KeepOther);
+    }
+
+    /// Merges another TimeSeries into this one, resolving values at identical timestamps
+    /// with `policy`. Returns the number of such conflicts.
+    ///
+    /// # Example
+    /// ```
+    /// let mut ts = FloatTimeSeries::new();
+    /// ts.add_point(100, 1.0);
+    /// ts.add_point(200, 2.0);
+    /// let mut backfill = FloatTimeSeries::new();
+    /// backfill.add_point(200, 4.0);
+    /// backfill.add_point(300, 5.0);
+    ///
+    /// // Average duplicates: 100 => 1.0, 200 => 3.0, 300 => 5.0
+    /// let conflicts = ts.merge_with_policy(&backfill, MergePolicy::Combine(|a, b| (a + b) / 2.0));
+    /// assert_eq!(conflicts, 1);
+    /// ```
+    pub fn merge_with_policy(&mut self, other: &TimeSeries<T>, policy: MergePolicy<T>) -> usize
+    where
+        T: Clone,
+    {
+        let len = self.len() + other.len();
+        let mut timestamps = Vec::with_capacity(len);
+        let mut values = Vec::with_capacity(len);
+        let mut conflicts = 0;
+
+        let mut own = std::mem::take(&mut self.timestamps)
+            .into_iter()
+            .zip(std::mem::take(&mut self.values))
+            .peekable();
+        let mut theirs = other.timestamps.iter().zip(&other.values).peekable();
+        loop {
+            let (timestamp, value) = match (own.peek(), theirs.peek()) {
+                (Some((a, _)), Some((b, _))) if a == *b => {
+                    conflicts += 1;
+                    let (timestamp, mine) = own.next().unwrap();
+                    let (_, other_value) = theirs.next().unwrap();
+                    let value = match &policy {
+                        MergePolicy::KeepSelf => mine,
+                        MergePolicy::KeepOther => other_value.clone(),
+                        MergePolicy::Combine(combine) => combine(&mine, other_value),
+                    };
+                    (timestamp, value)
+                }
+                (Some((a, _)), Some((b, _))) if a > *b => {
+                    let (timestamp, value) = theirs.next().unwrap();
+                    (*timestamp, value.clone())
+                }
+                (Some(_), _) => own.next().unwrap(),
+                (None, Some(_)) => {
+                    let (timestamp, value) = theirs.next().unwrap();
+                    (*timestamp, value.clone())
+                }
+                (None, None) => break,
+            };
+            timestamps.push(timestamp);
+            values.push(value);
         }
+
+        self.timestamps = timestamps;
+        self.values = values;
+        conflicts
     }
 
     /// Converts a Unix timestamp into a reverse chronological database key.
@@ -392,10 +796,63 @@ impl<T: Clone> TimeSeries<T> {
     ///     println!("Bucket with {} points", bucket.len());
     /// }
     /// ```
-    pub fn buckets(&self) -> TimeSeriesBucketIter<'_, T> {
+    pub fn buckets(&self) -> impl Iterator<Item = TimeSeries<T>> + '_ {
+        self.bucket_by(3600).map(|(_, bucket)| bucket)
+    }
+
+    /// Returns an iterator of `(bucket_start, bucket)` pairs splitting the time series
+    /// into buckets of `interval_secs`. Bucket starts are aligned to the epoch
+    /// (multiples of `interval_secs`) and empty buckets are not yielded.
+    ///
+    /// # Panics
+    /// Panics if `interval_secs` is 0.
+    ///
+    /// # Example
+    /// ```
+    /// let mut ts = TimeSeries::new();
+    /// ts.add_point(290, 10.0);
+    /// ts.add_point(310, 20.0);
+    ///
+    /// // Yields (0, [290]) and (300, [310])
+    /// for (start, bucket) in ts.bucket_by(300) {
+    ///     println!("Bucket at {} with {} points", start, bucket.len());
+    /// }
+    /// ```
+    pub fn bucket_by(&self, interval_secs: u64) -> TimeSeriesBucketIter<'_, T> {
+        assert!(interval_secs > 0, "bucket interval must be greater than 0");
         TimeSeriesBucketIter {
             series: self,
             current_idx: 0,
+            interval: interval_secs,
+        }
+    }
+
+    /// Returns an iterator of `(bucket_start, bucket)` pairs splitting the time series
+    /// into calendar days, weeks (starting on Monday) or months of the time zone `tz`.
+    /// Bucket starts are the local midnight as UTC timestamp, so a day is 23 or 25 hours
+    /// long on DST transitions. Empty buckets are not yielded.
+    ///
+    /// # Example
+    /// ```
+    /// let mut ts = TimeSeries::new();
+    /// ts.add_point(1711839600, 10.0); // 2024-03-31 00:00 in Berlin
+    /// ts.add_point(1711922399, 20.0); // 2024-03-31 23:59:59 in Berlin, 23 hours later
+    ///
+    /// // Yields a single bucket starting at 1711839600
+    /// for (start, bucket) in ts.bucket_by_calendar(CalendarUnit::Day, chrono_tz::Europe::Berlin) {
+    ///     println!("Day starting at {} with {} points", start, bucket.len());
+    /// }
+    /// ```
+    pub fn bucket_by_calendar(
+        &self,
+        unit: CalendarUnit,
+        tz: Tz,
+    ) -> TimeSeriesCalendarBucketIter<'_, T> {
+        TimeSeriesCalendarBucketIter {
+            series: self,
+            current_idx: 0,
+            unit,
+            tz,
         }
     }
 }
@@ -428,29 +885,52 @@ impl<'a, T> Iterator for TimeSeriesRangeIter<'a, T> {
 }
 
 impl<'a, T: Clone> Iterator for TimeSeriesBucketIter<'a, T> {
-    type Item = TimeSeries<T>;
+    type Item = (u64, TimeSeries<T>);
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.current_idx >= self.series.timestamps.len() {
             return None;
         }
 
-        let mut bucket = TimeSeries::new();
-        let current_hour = self.series.timestamps[self.current_idx] / 3600;
-        let mut idx = self.current_idx;
+        let start_idx = self.current_idx;
+        let current_bucket = self.series.timestamps[start_idx] / self.interval;
+        let mut idx = start_idx;
 
-        // Collect all points in the current hour
-        while idx < self.series.timestamps.len() {
-            let ts = self.series.timestamps[idx];
-            if ts / 3600 != current_hour {
-                break;
-            }
-            bucket.add_point(ts, self.series.values[idx].clone());
+        // Find the end of the current bucket
+        while idx < self.series.timestamps.len()
+            && self.series.timestamps[idx] / self.interval == current_bucket
+        {
             idx += 1;
         }
 
+        // The points are already sorted, so the slices form a valid series
+        let bucket = TimeSeries {
+            timestamps: self.series.timestamps[start_idx..idx].to_vec(),
+            values: self.series.values[start_idx..idx].to_vec(),
+        };
+        self.current_idx = idx;
+        Some((current_bucket * self.interval, bucket))
+    }
+}
+
+impl<'a, T: Clone> Iterator for TimeSeriesCalendarBucketIter<'a, T> {
+    type Item = (u64, TimeSeries<T>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let timestamps = &self.series.timestamps;
+        let start_idx = self.current_idx;
+        let &timestamp = timestamps.get(start_idx)?;
+
+        let bucket_start = self.unit.bucket_start(timestamp, &self.tz);
+        let bucket_end = self.unit.next_bucket_start(bucket_start, &self.tz);
+        let idx = start_idx + timestamps[start_idx..].partition_point(|&t| t < bucket_end);
+
+        let bucket = TimeSeries {
+            timestamps: timestamps[start_idx..idx].to_vec(),
+            values: self.series.values[start_idx..idx].to_vec(),
+        };
         self.current_idx = idx;
-        Some(bucket)
+        Some((bucket_start, bucket))
     }
 }
 
@@ -463,6 +943,16 @@ impl<'a, T> IntoIterator for &'a TimeSeries<T> {
     }
 }
 
+/// How `TimeSeries::merge_with_policy` resolves two values at the same timestamp
+pub enum MergePolicy<T> {
+    /// Keep the value of the series merged into
+    KeepSelf,
+    /// Replace it with the value of the other series
+    KeepOther,
+    /// Combine both values, e.g. average them. Called with `(self, other)`.
+    Combine(fn(&T, &T) -> T),
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum SerializationFormat {
     Binary,
@@ -487,16 +977,36 @@ pub enum TimeseriesSerializationError {
     WrongTypeByte(String),
     #[error("JSON serialization error: {0}")]
     JsonError(#[from] serde_json::Error),
+    #[error("Invalid time series data: {0}")]
+    InvalidData(String),
+}
+
+/// Compact JSON layout of a series, `{"t":[...],"v":[...]}`
+#[derive(Serialize)]
+struct JsonSeriesRef<'a, T> {
+    t: &'a [u64],
+    v: &'a [T],
+}
+
+#[derive(Deserialize)]
+struct JsonSeries<T> {
+    t: Vec<u64>,
+    v: Vec<T>,
 }
 
 impl<T: Serialize> TimeSeries<T> {
+    /// Serializes the series. JSON cannot hold non-finite floats, they are written as `null`
+    /// and fail to deserialize.
     pub fn serialize(
         &self,
         format: SerializationFormat,
     ) -> Result<Vec<u8>, TimeseriesSerializationError> {
         match format {
             SerializationFormat::Binary => Ok(bincode::serialize(self)?),
-            SerializationFormat::Json => Err(TimeseriesSerializationError::UnsupportedFormat),
+            SerializationFormat::Json => Ok(serde_json::to_vec(&JsonSeriesRef {
+                t: &self.timestamps,
+                v: &self.values,
+            })?),
         }
     }
 
@@ -509,7 +1019,11 @@ impl<T: Serialize> TimeSeries<T> {
     {
         match format {
             SerializationFormat::Binary => Ok(bincode::deserialize(bytes)?),
-            SerializationFormat::Json => Err(TimeseriesSerializationError::UnsupportedFormat),
+            SerializationFormat::Json => {
+                let series: JsonSeries<T> = serde_json::from_slice(bytes)?;
+                TimeSeries::from_sorted_vecs(series.t, series.v)
+                    .map_err(|e| TimeseriesSerializationError::InvalidData(e.to_string()))
+            }
         }
     }
 }
@@ -536,6 +1050,7 @@ impl TimeSeriesConversions for IntTimeSeries {
             device_id: device_id.to_string(),
             metric: metric.to_string(),
             data,
+            unit: None,
         }
     }
 
@@ -583,6 +1098,7 @@ impl TimeSeriesConversions for FloatTimeSeries {
             device_id: device_id.to_string(),
             metric: metric.to_string(),
             data,
+            unit: None,
         }
     }
 
@@ -635,6 +1151,7 @@ impl TimeSeriesConversions for LocationTimeSeries {
             device_id: device_id.to_string(),
             metric: metric.to_string(),
             data,
+            unit: None,
         }
     }
 
@@ -700,6 +1217,463 @@ impl MetricTimeSeries {
     }
 }
 
+/// Aggregation applied over the values of a numeric time series
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Mean,
+    Min,
+    Max,
+    Sum,
+    /// Population standard deviation
+    StdDev,
+    /// Percentile between 0 and 100, linearly interpolated between the closest ranks
+    Percentile(f64),
+}
+
+/// Result of an aggregation. NaN values are left out of the calculation and counted in `skipped`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AggregateResult {
+    /// `None` if there are no (non NaN) values to aggregate
+    pub value: Option<f64>,
+    pub skipped: usize,
+}
+
+/// How `resample` handles buckets without (non NaN) values between the first and last point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GapPolicy {
+    /// Leave empty buckets out of the result
+    Skip,
+    /// Emit NaN for empty buckets
+    NullAsNaN,
+    /// Repeat the value of the previous bucket
+    ForwardFill,
+}
+
+/// Completeness of a series against an expected reporting interval, see `TimeSeries::find_gaps_in`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct GapReport {
+    /// `(gap_start, gap_end)` of every gap, oldest first
+    pub gaps: Vec<(u64, u64)>,
+    /// Points expected in the window, both bounds included
+    pub expected_count: u64,
+    /// Points in the window
+    pub actual_count: u64,
+}
+
+/// Value types that can be aggregated as `f64`
+pub trait NumericValue: Copy {
+    fn to_f64(self) -> f64;
+}
+
+impl NumericValue for f64 {
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl NumericValue for i64 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+fn aggregate_values<'a, T: NumericValue + 'a>(
+    values: impl Iterator<Item = &'a T>,
+    aggregation: Aggregation,
+) -> AggregateResult {
+    let mut skipped = 0;
+    let values = values.map(|v| v.to_f64()).filter(|v| {
+        if v.is_nan() {
+            skipped += 1;
+        }
+        !v.is_nan()
+    });
+
+    let value = match aggregation {
+        Aggregation::Percentile(p) => {
+            let mut sorted: Vec<f64> = values.collect();
+            sorted.sort_by(f64::total_cmp);
+            percentile_of_sorted(&sorted, p)
+        }
+        _ => {
+            // Single pass, Welford's algorithm keeps the variance numerically stable
+            let (mut count, mut sum, mut mean, mut m2) = (0usize, 0.0, 0.0, 0.0);
+            let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+            for v in values {
+                count += 1;
+                sum += v;
+                min = min.min(v);
+                max = max.max(v);
+                let delta = v - mean;
+                mean += delta / count as f64;
+                m2 += delta * (v - mean);
+            }
+            (count > 0).then(|| match aggregation {
+                Aggregation::Mean => mean,
+                Aggregation::Min => min,
+                Aggregation::Max => max,
+                Aggregation::Sum => sum,
+                Aggregation::StdDev => (m2 / count as f64).sqrt(),
+                Aggregation::Percentile(_) => unreachable!(),
+            })
+        }
+    };
+    AggregateResult { value, skipped }
+}
+
+fn percentile_of_sorted(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() || !(0.0..=100.0).contains(&p) {
+        return None;
+    }
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    Some(sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64))
+}
+
+impl<T: NumericValue> TimeSeries<T> {
+    /// Aggregates all values of the time series.
+    pub fn aggregate(&self, aggregation: Aggregation) -> AggregateResult {
+        aggregate_values(self.values.iter(), aggregation)
+    }
+
+    /// Aggregates the values between `start_ts` and `end_ts` (both inclusive).
+    pub fn aggregate_range(
+        &self,
+        start_ts: u64,
+        end_ts: u64,
+        aggregation: Aggregation,
+    ) -> AggregateResult {
+        aggregate_values(self.range(start_ts, end_ts).map(|(_, v)| v), aggregation)
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        self.aggregate(Aggregation::Mean).value
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.aggregate(Aggregation::Min).value
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.aggregate(Aggregation::Max).value
+    }
+
+    pub fn sum(&self) -> Option<f64> {
+        self.aggregate(Aggregation::Sum).value
+    }
+
+    pub fn stddev(&self) -> Option<f64> {
+        self.aggregate(Aggregation::StdDev).value
+    }
+
+    /// Returns the `p`th percentile (0 to 100), `None` if `p` is out of range.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        self.aggregate(Aggregation::Percentile(p)).value
+    }
+
+    /// Aggregates the series to one point per non-empty calendar bucket,
+    /// timestamped with the bucket start. See `bucket_by_calendar`.
+    pub fn resample_calendar(
+        &self,
+        unit: CalendarUnit,
+        tz: Tz,
+        aggregation: Aggregation,
+    ) -> FloatTimeSeries {
+        let mut resampled = FloatTimeSeries::new();
+        for (bucket_start, bucket) in self.bucket_by_calendar(unit, tz) {
+            if let Some(value) = bucket.aggregate(aggregation).value {
+                resampled.timestamps.push(bucket_start);
+                resampled.values.push(value);
+            }
+        }
+        resampled
+    }
+
+    /// Resamples the series to one aggregated point per `interval_secs` bucket,
+    /// timestamped with the epoch aligned bucket start. See `bucket_by`.
+    pub fn resample(
+        &self,
+        interval_secs: u64,
+        aggregation: Aggregation,
+        gap_policy: GapPolicy,
+    ) -> FloatTimeSeries {
+        let mut resampled = FloatTimeSeries::new();
+        let mut previous: Option<(u64, f64)> = None;
+        for (bucket_start, bucket) in self.bucket_by(interval_secs) {
+            // Buckets holding only NaN values count as gaps
+            let Some(value) = bucket.aggregate(aggregation).value else {
+                continue;
+            };
+            if let Some((previous_start, previous_value)) = previous {
+                let fill = match gap_policy {
+                    GapPolicy::Skip => None,
+                    GapPolicy::NullAsNaN => Some(f64::NAN),
+                    GapPolicy::ForwardFill => Some(previous_value),
+                };
+                if let Some(fill) = fill {
+                    let mut gap_start = previous_start + interval_secs;
+                    while gap_start < bucket_start {
+                        resampled.timestamps.push(gap_start);
+                        resampled.values.push(fill);
+                        gap_start += interval_secs;
+                    }
+                }
+            }
+            // Buckets are yielded in order, so pushing keeps the series sorted
+            resampled.timestamps.push(bucket_start);
+            resampled.values.push(value);
+            previous = Some((bucket_start, value));
+        }
+        resampled
+    }
+
+    /// Aggregates the values of `[t, t + window_secs)` for every `t` from the first to the
+    /// last timestamp in `step_secs` increments, timestamped with the window start.
+    /// Windows overlap when the step is shorter than the window (sliding windows), a step
+    /// equal to the window gives tumbling windows. Windows without values are left out.
+    pub fn window_aggregate(
+        &self,
+        window_secs: u64,
+        step_secs: u64,
+        aggregation: Aggregation,
+    ) -> FloatTimeSeries {
+        assert!(window_secs > 0, "window must be greater than 0");
+        assert!(step_secs > 0, "window step must be greater than 0");
+        let mut aggregated = FloatTimeSeries::new();
+        let (Some(&first), Some(&last)) = (self.timestamps.first(), self.timestamps.last()) else {
+            return aggregated;
+        };
+        let mut window_start = first;
+        while window_start <= last {
+            let window_end = window_start.saturating_add(window_secs);
+            let from = self.timestamps.partition_point(|&t| t < window_start);
+            let to = self.timestamps.partition_point(|&t| t < window_end);
+            // Windows holding only NaN values are left out as well
+            if let Some(value) = aggregate_values(self.values[from..to].iter(), aggregation).value {
+                aggregated.timestamps.push(window_start);
+                aggregated.values.push(value);
+            }
+            window_start = match window_start.checked_add(step_secs) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        aggregated
+    }
+}
+
+impl LocationTimeSeries {
+    /// Spherical centroid of the locations: the mean of their 3D unit vectors, projected
+    /// back onto the sphere. Unlike the mean of latitude and longitude it stays correct
+    /// across the antimeridian and near the poles. `None` without locations with finite
+    /// coordinates or if they cancel out, e.g. two antipodal points.
+    pub fn centroid(&self) -> Option<LatLong> {
+        let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+        for location in self.values.iter() {
+            if !location.latitude.is_finite() || !location.longitude.is_finite() {
+                continue;
+            }
+            let (lat, long) = (
+                location.latitude.to_radians(),
+                location.longitude.to_radians(),
+            );
+            x += lat.cos() * long.cos();
+            y += lat.cos() * long.sin();
+            z += lat.sin();
+        }
+        let norm = (x * x + y * y + z * z).sqrt();
+        if norm < 1e-9 {
+            return None;
+        }
+        let (x, y, z) = (x / norm, y / norm, z / norm);
+        Some(LatLong::new(
+            z.atan2((x * x + y * y).sqrt()).to_degrees(),
+            y.atan2(x).to_degrees(),
+        ))
+    }
+
+    /// Reduces the series to the centroid of each non-empty calendar bucket,
+    /// timestamped with the bucket start. See `bucket_by_calendar`.
+    pub fn resample_calendar_centroid(&self, unit: CalendarUnit, tz: Tz) -> LocationTimeSeries {
+        let mut resampled = LocationTimeSeries::new();
+        for (bucket_start, bucket) in self.bucket_by_calendar(unit, tz) {
+            if let Some(centroid) = bucket.centroid() {
+                resampled.timestamps.push(bucket_start);
+                resampled.values.push(centroid);
+            }
+        }
+        resampled
+    }
+}
+
+impl FloatTimeSeries {
+    /// Downsamples the series to `target_points` with Largest-Triangle-Three-Buckets.
+    /// The first and last points are always kept, and from each bucket in between the point
+    /// forming the largest triangle with its neighbours is chosen, which keeps the visual
+    /// extremes. Series with at most `target_points` points (or a target below 3) are returned
+    /// unchanged. NaN values should be removed first, they are never selected.
+    pub fn downsample_lttb(&self, target_points: usize) -> FloatTimeSeries {
+        let n = self.len();
+        if target_points >= n || target_points < 3 {
+            return self.clone();
+        }
+
+        let x = |i: usize| self.timestamps[i] as f64;
+        let y = |i: usize| self.values[i];
+        let mut sampled = FloatTimeSeries::new();
+        sampled.timestamps.reserve(target_points);
+        sampled.values.reserve(target_points);
+        sampled.timestamps.push(self.timestamps[0]);
+        sampled.values.push(self.values[0]);
+
+        // The first and last point are fixed, the others are split into equal buckets
+        let bucket_size = (n - 2) as f64 / (target_points - 2) as f64;
+        let mut selected = 0;
+        for bucket in 0..target_points - 2 {
+            let start = (bucket as f64 * bucket_size) as usize + 1;
+            let end = ((bucket + 1) as f64 * bucket_size) as usize + 1;
+
+            // The average of the next bucket is the third corner of the triangle
+            let next_end = (((bucket + 2) as f64 * bucket_size) as usize + 1).min(n);
+            let next_len = (next_end - end) as f64;
+            let avg_x = (end..next_end).map(x).sum::<f64>() / next_len;
+            let avg_y = (end..next_end).map(y).sum::<f64>() / next_len;
+
+            let (ax, ay) = (x(selected), y(selected));
+            let mut max_area = -1.0;
+            let mut max_idx = start;
+            for i in start..end {
+                let area = ((ax - avg_x) * (y(i) - ay) - (ax - x(i)) * (avg_y - ay)).abs();
+                if area > max_area {
+                    max_area = area;
+                    max_idx = i;
+                }
+            }
+            sampled.timestamps.push(self.timestamps[max_idx]);
+            sampled.values.push(self.values[max_idx]);
+            selected = max_idx;
+        }
+
+        sampled.timestamps.push(self.timestamps[n - 1]);
+        sampled.values.push(self.values[n - 1]);
+        sampled
+    }
+
+    /// Rate of change per second between consecutive points, stamped with the later timestamp.
+    pub fn derivative(&self) -> FloatTimeSeries {
+        let mut rates = FloatTimeSeries::new();
+        for i in 1..self.len() {
+            let dt = (self.timestamps[i] - self.timestamps[i - 1]) as f64;
+            let delta = self.values[i] - self.values[i - 1];
+            rates.timestamps.push(self.timestamps[i]);
+            rates.values.push(delta / dt);
+        }
+        rates
+    }
+
+    /// Rate per second of a monotonically increasing counter.
+    /// A drop by more than `reset_threshold` is treated as a counter reset, the counter is
+    /// assumed to have restarted at zero so the new value is the increase since the reset.
+    /// Smaller drops are considered jitter and yield a rate of zero.
+    pub fn counter_rate(&self, reset_threshold: f64) -> FloatTimeSeries {
+        let mut rates = FloatTimeSeries::new();
+        for i in 1..self.len() {
+            let dt = (self.timestamps[i] - self.timestamps[i - 1]) as f64;
+            let (previous, current) = (self.values[i - 1], self.values[i]);
+            let delta = if current >= previous {
+                current - previous
+            } else if previous - current > reset_threshold {
+                current
+            } else {
+                0.0
+            };
+            rates.timestamps.push(self.timestamps[i]);
+            rates.values.push(delta / dt);
+        }
+        rates
+    }
+
+    /// Samples the series at `sample_ts`, carrying the most recent prior value forward.
+    /// Timestamps before the first data point yield no value.
+    pub fn fill_forward(&self, sample_ts: &[u64]) -> FloatTimeSeries {
+        let mut filled = FloatTimeSeries::new();
+        for &ts in sample_ts {
+            let idx = self.timestamps.partition_point(|&t| t <= ts);
+            if idx > 0 {
+                filled.add_point(ts, self.values[idx - 1]);
+            }
+        }
+        filled
+    }
+
+    /// Samples the series at `sample_ts`, linearly interpolating between the surrounding points.
+    /// Timestamps outside of the first and last data point yield no value.
+    pub fn fill_linear(&self, sample_ts: &[u64]) -> FloatTimeSeries {
+        let mut filled = FloatTimeSeries::new();
+        for &ts in sample_ts {
+            let idx = self.timestamps.partition_point(|&t| t <= ts);
+            if idx == 0 {
+                continue;
+            }
+            let (t0, v0) = (self.timestamps[idx - 1], self.values[idx - 1]);
+            if t0 == ts {
+                filled.add_point(ts, v0);
+            } else if idx < self.len() {
+                let (t1, v1) = (self.timestamps[idx], self.values[idx]);
+                let fraction = (ts - t0) as f64 / (t1 - t0) as f64;
+                filled.add_point(ts, v0 + (v1 - v0) * fraction);
+            }
+        }
+        filled
+    }
+}
+
+impl MetricTimeSeries {
+    /// Aggregates all values, series containing locations or text yield no value.
+    pub fn aggregate(&self, aggregation: Aggregation) -> AggregateResult {
+        self.to_float_series()
+            .map(|ts| ts.aggregate(aggregation))
+            .unwrap_or_default()
+    }
+
+    /// Aggregates the values between `start_ts` and `end_ts` (both inclusive).
+    pub fn aggregate_range(
+        &self,
+        start_ts: u64,
+        end_ts: u64,
+        aggregation: Aggregation,
+    ) -> AggregateResult {
+        self.to_float_series()
+            .map(|ts| ts.aggregate_range(start_ts, end_ts, aggregation))
+            .unwrap_or_default()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        self.aggregate(Aggregation::Mean).value
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.aggregate(Aggregation::Min).value
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.aggregate(Aggregation::Max).value
+    }
+
+    pub fn sum(&self) -> Option<f64> {
+        self.aggregate(Aggregation::Sum).value
+    }
+
+    pub fn stddev(&self) -> Option<f64> {
+        self.aggregate(Aggregation::StdDev).value
+    }
+
+    /// Returns the `p`th percentile (0 to 100), `None` if `p` is out of range.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        self.aggregate(Aggregation::Percentile(p)).value
+    }
+}
+
 impl From<&FloatTimeSeries> for MetricTimeSeries {
     fn from(float_ts: &FloatTimeSeries) -> Self {
         let mut metric_ts = MetricTimeSeries::new();
@@ -750,6 +1724,7 @@ impl TimeSeriesConversions for MetricTimeSeries {
             device_id: device_id.to_string(),
             metric: metric.to_string(),
             data,
+            unit: None,
         }
     }
 
@@ -132,6 +132,90 @@ fn test_trim() {
     assert_eq!(ts.len(), 0);
 }
 
+#[test]
+fn test_deduplicate() {
+    let mut ts = FloatTimeSeries::new();
+    for i in 0..100 {
+        ts.add_point(1000 + i, 5.0);
+    }
+    let deduplicated = ts.deduplicate();
+    assert_eq!(deduplicated.timestamps, vec![1000]);
+    assert_eq!(deduplicated.values, vec![5.0]);
+
+    // Only consecutive runs collapse, a value seen again later is kept
+    let mut ts = IntTimeSeries::new();
+    for (timestamp, value) in [(1, 1), (2, 1), (3, 2), (4, 2), (5, 1)] {
+        ts.add_point(timestamp, value);
+    }
+    let deduplicated = ts.deduplicate();
+    assert_eq!(deduplicated.timestamps, vec![1, 3, 5]);
+    assert_eq!(deduplicated.values, vec![1, 2, 1]);
+    assert!(FloatTimeSeries::new().deduplicate().is_empty());
+}
+
+#[test]
+fn test_deduplicate_by() {
+    let mut ts = FloatTimeSeries::new();
+    for (timestamp, value) in [(1, 20.0), (2, 20.05), (3, 20.1), (4, 20.15), (5, 19.0)] {
+        ts.add_point(timestamp, value);
+    }
+    // Compared with the first point of the run, the drift starts a new one
+    let deduplicated = ts.deduplicate_by(|a, b| (a - b).abs() <= 0.12);
+    assert_eq!(deduplicated.timestamps, vec![1, 4, 5]);
+    assert_eq!(deduplicated.values, vec![20.0, 20.15, 19.0]);
+}
+
+#[test]
+fn test_find_gaps() {
+    let mut ts = IntTimeSeries::new();
+    for timestamp in [0, 300, 600, 1500, 1800, 2110] {
+        ts.add_point(timestamp, 1);
+    }
+    // 900 and 1200 are missing, 2110 is late but within the tolerance
+    let report = ts.find_gaps(300, 30);
+    assert_eq!(report.gaps, vec![(600, 1500)]);
+    assert_eq!(report.expected_count, 8);
+    assert_eq!(report.actual_count, 6);
+
+    // Without tolerance the late point is a gap as well
+    assert_eq!(ts.find_gaps(300, 0).gaps, vec![(600, 1500), (1800, 2110)]);
+}
+
+#[test]
+fn test_find_gaps_edges() {
+    let mut ts = IntTimeSeries::new();
+    ts.add_point(1000, 1);
+    ts.add_point(1300, 1);
+
+    // Missing uploads at the start and the end of the window
+    let report = ts.find_gaps_in(0, 2400, 300, 30);
+    assert_eq!(report.gaps, vec![(0, 1000), (1300, 2400)]);
+    assert_eq!(report.expected_count, 9);
+    assert_eq!(report.actual_count, 2);
+    // Points outside the window don't count
+    let report = ts.find_gaps_in(1200, 1500, 300, 30);
+    assert!(report.gaps.is_empty());
+    assert_eq!(report.actual_count, 1);
+
+    // A single point has nothing to miss, an empty window misses everything
+    let mut single = IntTimeSeries::new();
+    single.add_point(500, 1);
+    assert_eq!(
+        single.find_gaps(300, 0),
+        GapReport {
+            gaps: vec![],
+            expected_count: 1,
+            actual_count: 1,
+        }
+    );
+    let empty = IntTimeSeries::new();
+    assert_eq!(empty.find_gaps(300, 0), GapReport::default());
+    let report = empty.find_gaps_in(0, 3000, 300, 0);
+    assert_eq!(report.gaps, vec![(0, 3000)]);
+    assert_eq!(report.expected_count, 11);
+    assert_eq!(report.actual_count, 0);
+}
+
 #[test]
 fn test_serde() {
     let mut ts = FloatTimeSeries::new();
@@ -143,9 +227,107 @@ fn test_serde() {
     assert_eq!(from_binary.len(), ts.len());
 
     // Test JSON format
-    // this should return an UnsupportedFormat error
-    let json = ts.serialize(SerializationFormat::Json);
-    assert!(json.is_err());
+    let json = ts.serialize(SerializationFormat::Json).unwrap();
+    assert_eq!(
+        String::from_utf8(json.clone()).unwrap(),
+        r#"{"t":[1000],"v":[42.0]}"#
+    );
+    let from_json = FloatTimeSeries::deserialize(&json, SerializationFormat::Json).unwrap();
+    assert_eq!(from_json.get_value_for_timestamp(1000), Some(&42.0));
+}
+
+fn assert_json_round_trip<T>(ts: &TimeSeries<T>)
+where
+    T: Serialize + for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug,
+{
+    let json = ts.serialize(SerializationFormat::Json).unwrap();
+    let restored = TimeSeries::<T>::deserialize(&json, SerializationFormat::Json).unwrap();
+    assert_eq!(
+        restored.iter().collect::<Vec<_>>(),
+        ts.iter().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_json_round_trip() {
+    let mut ints = IntTimeSeries::new();
+    let mut floats = FloatTimeSeries::new();
+    let mut locations = LocationTimeSeries::new();
+    let mut metrics = MetricTimeSeries::new();
+    for i in 0..10u64 {
+        ints.add_point(1000 + i, i as i64 - 5);
+        floats.add_point(1000 + i, i as f64 * 0.5);
+        locations.add_point(1000 + i, LatLong::new(48.0 + i as f64, 16.0));
+    }
+    metrics.add_point(1000, MetricValue::Int(1));
+    metrics.add_point(1001, MetricValue::Float(1.5));
+    metrics.add_point(1002, MetricValue::Location(LatLong::new(48.2, 16.4)));
+
+    assert_json_round_trip(&ints);
+    assert_json_round_trip(&floats);
+    assert_json_round_trip(&locations);
+    assert_json_round_trip(&metrics);
+    assert_json_round_trip(&FloatTimeSeries::new());
+
+    let json = metrics.serialize(SerializationFormat::Json).unwrap();
+    assert!(String::from_utf8(json)
+        .unwrap()
+        .starts_with(r#"{"t":[1000,1001,1002],"v":[{"Int":1}"#));
+}
+
+#[test]
+fn test_json_deserialize_invalid() {
+    for json in [
+        r#"{"t":[2,1],"v":[1.0,2.0]}"#,
+        r#"{"t":[1,2],"v":[1.0]}"#,
+        r#"{"t":[1],"v":[null]}"#,
+        r#"{"timestamps":[1],"values":[1.0]}"#,
+    ] {
+        assert!(FloatTimeSeries::deserialize(json.as_bytes(), SerializationFormat::Json).is_err());
+    }
+}
+
+#[test]
+fn test_model_into_metric_series() {
+    let mut metrics = MetricTimeSeries::new();
+    metrics.add_point(1000, MetricValue::Int(7));
+    metrics.add_point(1001, MetricValue::Float(2.5));
+    metrics.add_point(1002, MetricValue::Float(3.0));
+    metrics.add_point(1003, MetricValue::Location(LatLong::new(48.2, 16.4)));
+    metrics.add_point(1004, MetricValue::Float(f64::INFINITY));
+    metrics.add_point(1005, MetricValue::Text("door, open".to_string()));
+
+    let restored = metrics
+        .to_model("dev", "metric")
+        .into_metric_series()
+        .unwrap();
+    assert_eq!(
+        restored.iter().collect::<Vec<_>>(),
+        metrics.iter().collect::<Vec<_>>()
+    );
+
+    let mut floats = FloatTimeSeries::new();
+    floats.add_point(1000, 1.0);
+    let restored = floats
+        .to_model("dev", "metric")
+        .into_metric_series()
+        .unwrap();
+    assert_eq!(
+        restored
+            .to_float_series()
+            .unwrap()
+            .iter()
+            .collect::<Vec<_>>(),
+        floats.iter().collect::<Vec<_>>()
+    );
+
+    let model = TimeSeriesModel {
+        device_id: "dev".to_string(),
+        metric: "metric".to_string(),
+        data: vec![(1000, Value::Bool(true))],
+        unit: None,
+    };
+    assert!(model.into_metric_series().is_err());
 }
 
 #[test]
@@ -282,6 +464,79 @@ fn test_location_timeseries() {
     assert!(series.is_empty());
 }
 
+fn assert_close(location: &LatLong, latitude: f64, longitude: f64) {
+    assert!(
+        (location.latitude - latitude).abs() < 1e-6
+            && (location.longitude - longitude).abs() < 1e-6,
+        "{:?} is not {}, {}",
+        location,
+        latitude,
+        longitude
+    );
+}
+
+#[test]
+fn test_location_centroid() {
+    let mut series = LocationTimeSeries::new();
+    assert_eq!(series.centroid(), None);
+
+    series.add_point(1000, LatLong::new(10.0, 20.0));
+    assert_close(&series.centroid().unwrap(), 10.0, 20.0);
+
+    // On the equator the centroid lies halfway in between
+    series.clear();
+    series.add_point(1000, LatLong::new(0.0, 10.0));
+    series.add_point(2000, LatLong::new(0.0, 30.0));
+    assert_close(&series.centroid().unwrap(), 0.0, 20.0);
+
+    // Straddling the antimeridian the naive mean of the longitudes would be 0
+    series.clear();
+    series.add_point(1000, LatLong::new(0.0, 179.0));
+    series.add_point(2000, LatLong::new(0.0, -179.0));
+    let centroid = series.centroid().unwrap();
+    assert_close(&centroid, 0.0, 180.0_f64.copysign(centroid.longitude));
+
+    series.clear();
+    series.add_point(1000, LatLong::new(-20.0, 170.0));
+    series.add_point(2000, LatLong::new(-20.0, -160.0));
+    let centroid = series.centroid().unwrap();
+    assert!(centroid.latitude < -20.0, "{:?}", centroid);
+    assert!((centroid.longitude - -175.0).abs() < 1e-6, "{:?}", centroid);
+
+    // Around the pole the naive mean would end up at latitude 80 and longitude 45
+    series.clear();
+    for (i, longitude) in [0.0, 90.0, 180.0, -90.0].into_iter().enumerate() {
+        series.add_point(1000 + i as u64, LatLong::new(80.0, longitude));
+    }
+    assert!((series.centroid().unwrap().latitude - 90.0).abs() < 1e-6);
+
+    // Antipodal points have no centroid, non-finite coordinates are left out
+    series.clear();
+    series.add_point(1000, LatLong::new(0.0, 0.0));
+    series.add_point(2000, LatLong::new(0.0, 180.0));
+    assert_eq!(series.centroid(), None);
+    series.add_point(3000, LatLong::new(f64::NAN, 0.0));
+    series.add_point(4000, LatLong::new(45.0, 90.0));
+    assert_close(&series.centroid().unwrap(), 45.0, 90.0);
+}
+
+#[test]
+fn test_location_resample_calendar_centroid() {
+    let mut series = LocationTimeSeries::new();
+    series.add_point(86400 + 100, LatLong::new(0.0, 179.0));
+    series.add_point(86400 + 200, LatLong::new(0.0, -179.0));
+    series.add_point(3 * 86400, LatLong::new(10.0, 20.0));
+
+    let resampled = series.resample_calendar_centroid(CalendarUnit::Day, chrono_tz::UTC);
+    assert_eq!(resampled.timestamps, vec![86400, 3 * 86400]);
+    assert_close(
+        &resampled.values[0],
+        0.0,
+        180.0_f64.copysign(resampled.values[0].longitude),
+    );
+    assert_close(&resampled.values[1], 10.0, 20.0);
+}
+
 #[test]
 fn test_metric_value_conversions() {
     // Test float conversions
@@ -301,6 +556,12 @@ fn test_metric_value_conversions() {
     assert_eq!(loc_val.clone().into_float(), None);
     assert_eq!(loc_val.clone().into_int(), None);
     assert!(loc_val.into_location().is_some());
+
+    // Text is not numeric
+    let text_val = MetricValue::Text("open".to_string());
+    assert_eq!(text_val.clone().into_float(), None);
+    assert_eq!(text_val.clone().into_int(), None);
+    assert_eq!(Value::from(text_val), Value::String("open".to_string()));
 }
 
 #[test]
@@ -323,3 +584,656 @@ fn test_timeseries_conversions() {
         _ => panic!("Wrong value type"),
     }
 }
+
+#[test]
+fn test_aggregations() {
+    let mut ts = FloatTimeSeries::new();
+    for (i, v) in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].iter().enumerate() {
+        ts.add_point(1000 + i as u64, *v);
+    }
+    assert_eq!(ts.mean(), Some(5.0));
+    assert_eq!(ts.min(), Some(2.0));
+    assert_eq!(ts.max(), Some(9.0));
+    assert_eq!(ts.sum(), Some(40.0));
+    assert!((ts.stddev().unwrap() - 2.0).abs() < 1e-12);
+    assert_eq!(ts.percentile(0.0), Some(2.0));
+    assert_eq!(ts.percentile(50.0), Some(4.5));
+    assert_eq!(ts.percentile(100.0), Some(9.0));
+    assert_eq!(ts.percentile(101.0), None);
+    assert_eq!(ts.percentile(f64::NAN), None);
+
+    let mut int_ts = IntTimeSeries::new();
+    int_ts.add_point(1000, 1);
+    int_ts.add_point(2000, 2);
+    assert_eq!(int_ts.mean(), Some(1.5));
+    assert_eq!(int_ts.sum(), Some(3.0));
+    assert_eq!(int_ts.stddev(), Some(0.5));
+    assert_eq!(int_ts.percentile(25.0), Some(1.25));
+}
+
+#[test]
+fn test_aggregations_empty_and_single_point() {
+    let ts = FloatTimeSeries::new();
+    assert_eq!(ts.mean(), None);
+    assert_eq!(ts.min(), None);
+    assert_eq!(ts.max(), None);
+    assert_eq!(ts.sum(), None);
+    assert_eq!(ts.stddev(), None);
+    assert_eq!(ts.percentile(50.0), None);
+    assert_eq!(ts.aggregate(Aggregation::Sum), AggregateResult::default());
+
+    let mut ts = IntTimeSeries::new();
+    ts.add_point(1000, 7);
+    assert_eq!(ts.mean(), Some(7.0));
+    assert_eq!(ts.min(), Some(7.0));
+    assert_eq!(ts.max(), Some(7.0));
+    assert_eq!(ts.sum(), Some(7.0));
+    assert_eq!(ts.stddev(), Some(0.0));
+    assert_eq!(ts.percentile(90.0), Some(7.0));
+}
+
+#[test]
+fn test_aggregations_skip_nan() {
+    let mut ts = FloatTimeSeries::new();
+    ts.add_point(1000, 1.0);
+    ts.add_point(2000, f64::NAN);
+    ts.add_point(3000, 3.0);
+    ts.add_point(4000, f64::NAN);
+
+    assert_eq!(
+        ts.aggregate(Aggregation::Mean),
+        AggregateResult {
+            value: Some(2.0),
+            skipped: 2
+        }
+    );
+    assert_eq!(ts.max(), Some(3.0));
+    assert_eq!(ts.percentile(50.0), Some(2.0));
+
+    // Only NaN values in the window
+    assert_eq!(
+        ts.aggregate_range(2000, 2000, Aggregation::Sum),
+        AggregateResult {
+            value: None,
+            skipped: 1
+        }
+    );
+}
+
+#[test]
+fn test_aggregate_range() {
+    let mut ts = FloatTimeSeries::new();
+    for i in 0..10u64 {
+        ts.add_point(1000 + i * 100, i as f64);
+    }
+    let result = ts.aggregate_range(1200, 1400, Aggregation::Sum);
+    assert_eq!(result.value, Some(9.0));
+    assert_eq!(result.skipped, 0);
+    assert_eq!(
+        ts.aggregate_range(1250, 1350, Aggregation::Max).value,
+        Some(3.0)
+    );
+    assert_eq!(
+        ts.aggregate_range(5000, 6000, Aggregation::Mean).value,
+        None
+    );
+}
+
+#[test]
+fn test_metric_series_aggregations() {
+    let mut ts = MetricTimeSeries::new();
+    ts.add_point(1000, MetricValue::Int(1));
+    ts.add_point(2000, MetricValue::Float(2.0));
+    ts.add_point(3000, MetricValue::Int(6));
+    assert_eq!(ts.mean(), Some(3.0));
+    assert_eq!(ts.min(), Some(1.0));
+    assert_eq!(ts.max(), Some(6.0));
+    assert_eq!(ts.sum(), Some(9.0));
+    assert_eq!(ts.percentile(50.0), Some(2.0));
+    assert_eq!(
+        ts.aggregate_range(2000, 3000, Aggregation::Mean).value,
+        Some(4.0)
+    );
+    assert!(ts.stddev().unwrap() > 0.0);
+
+    // Locations can't be aggregated
+    ts.add_point(4000, MetricValue::Location(LatLong::new(1.0, 2.0)));
+    assert_eq!(ts.mean(), None);
+    assert_eq!(ts.aggregate(Aggregation::Sum), AggregateResult::default());
+}
+
+#[test]
+fn test_bucket_by_interval() {
+    let mut ts = IntTimeSeries::new();
+    assert_eq!(ts.bucket_by(60).count(), 0);
+
+    // Irregular spacing, bucket 120..180 stays empty
+    for (timestamp, value) in [(5, 1), (59, 2), (60, 3), (190, 4), (239, 5), (601, 6)] {
+        ts.add_point(timestamp, value);
+    }
+
+    let buckets: Vec<(u64, IntTimeSeries)> = ts.bucket_by(60).collect();
+    let starts: Vec<u64> = buckets.iter().map(|(start, _)| *start).collect();
+    assert_eq!(starts, vec![0, 60, 180, 600]);
+    assert_eq!(buckets[0].1.values, vec![1, 2]);
+    assert_eq!(buckets[1].1.values, vec![3]);
+    assert_eq!(buckets[2].1.timestamps, vec![190, 239]);
+    assert_eq!(buckets[3].1.values, vec![6]);
+
+    // A single bucket spanning everything
+    let buckets: Vec<(u64, IntTimeSeries)> = ts.bucket_by(1000).collect();
+    assert_eq!(buckets.len(), 1);
+    assert_eq!(buckets[0].0, 0);
+    assert_eq!(buckets[0].1.len(), 6);
+}
+
+fn irregular_float_series() -> FloatTimeSeries {
+    let mut ts = FloatTimeSeries::new();
+    // Buckets of 100s: [1.0, 3.0] at 100, nothing at 200 and 300, [4.0] at 400, NaN only at 500, [8.0] at 600
+    ts.add_point(110, 1.0);
+    ts.add_point(190, 3.0);
+    ts.add_point(401, 4.0);
+    ts.add_point(550, f64::NAN);
+    ts.add_point(699, 8.0);
+    ts
+}
+
+#[test]
+fn test_resample_skip() {
+    let resampled = irregular_float_series().resample(100, Aggregation::Mean, GapPolicy::Skip);
+    assert_eq!(resampled.timestamps, vec![100, 400, 600]);
+    assert_eq!(resampled.values, vec![2.0, 4.0, 8.0]);
+
+    let resampled = irregular_float_series().resample(1000, Aggregation::Sum, GapPolicy::Skip);
+    assert_eq!(resampled.timestamps, vec![0]);
+    assert_eq!(resampled.values, vec![16.0]);
+
+    let empty = FloatTimeSeries::new().resample(100, Aggregation::Mean, GapPolicy::ForwardFill);
+    assert!(empty.is_empty());
+}
+
+#[test]
+fn test_resample_null_as_nan() {
+    let resampled = irregular_float_series().resample(100, Aggregation::Max, GapPolicy::NullAsNaN);
+    assert_eq!(resampled.timestamps, vec![100, 200, 300, 400, 500, 600]);
+    assert_eq!(resampled.values[0], 3.0);
+    assert!(resampled.values[1].is_nan());
+    assert!(resampled.values[2].is_nan());
+    assert_eq!(resampled.values[3], 4.0);
+    assert!(resampled.values[4].is_nan());
+    assert_eq!(resampled.values[5], 8.0);
+}
+
+#[test]
+fn test_resample_forward_fill() {
+    let resampled =
+        irregular_float_series().resample(100, Aggregation::Min, GapPolicy::ForwardFill);
+    assert_eq!(resampled.timestamps, vec![100, 200, 300, 400, 500, 600]);
+    assert_eq!(resampled.values, vec![1.0, 1.0, 1.0, 4.0, 4.0, 8.0]);
+
+    let mut int_ts = IntTimeSeries::new();
+    int_ts.add_point(0, 10);
+    int_ts.add_point(180, 20);
+    let resampled = int_ts.resample(60, Aggregation::Sum, GapPolicy::ForwardFill);
+    assert_eq!(resampled.timestamps, vec![0, 60, 120, 180]);
+    assert_eq!(resampled.values, vec![10.0, 10.0, 10.0, 20.0]);
+}
+
+#[test]
+fn test_window_aggregate() {
+    let mut ts = FloatTimeSeries::new();
+    for i in 0..10u64 {
+        ts.add_point(i, i as f64);
+    }
+    // Sliding windows of 2s every second overlap by one point
+    let sliding = ts.window_aggregate(2, 1, Aggregation::Mean);
+    assert_eq!(sliding.timestamps, (0..10).collect::<Vec<u64>>());
+    assert_eq!(
+        sliding.values,
+        vec![0.5, 1.5, 2.5, 3.5, 4.5, 5.5, 6.5, 7.5, 8.5, 9.0]
+    );
+
+    // A step equal to the window gives tumbling windows
+    let tumbling = ts.window_aggregate(4, 4, Aggregation::Sum);
+    assert_eq!(tumbling.timestamps, vec![0, 4, 8]);
+    assert_eq!(tumbling.values, vec![6.0, 22.0, 17.0]);
+
+    // Windows without points are left out
+    let mut sparse = IntTimeSeries::new();
+    sparse.add_point(100, 1);
+    sparse.add_point(101, 3);
+    sparse.add_point(110, 5);
+    let windows = sparse.window_aggregate(3, 2, Aggregation::Max);
+    assert_eq!(windows.timestamps, vec![100, 108, 110]);
+    assert_eq!(windows.values, vec![3.0, 5.0, 5.0]);
+
+    assert!(FloatTimeSeries::new()
+        .window_aggregate(2, 1, Aggregation::Mean)
+        .is_empty());
+}
+
+#[test]
+fn test_downsample_lttb() {
+    let mut ts = FloatTimeSeries::new();
+    for i in 0..100u64 {
+        ts.add_point(i, (i % 10) as f64);
+    }
+    // Spikes the downsampled series must keep
+    ts.add_point(37, 100.0);
+    ts.add_point(71, -100.0);
+
+    let sampled = ts.downsample_lttb(20);
+    assert_eq!(sampled.len(), 20);
+    assert_eq!(sampled.first_timestamp(), Some(0));
+    assert_eq!(sampled.latest(), Some((99, &9.0)));
+    assert_eq!(sampled.get_value_for_timestamp(37), Some(&100.0));
+    assert_eq!(sampled.get_value_for_timestamp(71), Some(&-100.0));
+    assert!(sampled.timestamps.windows(2).all(|w| w[0] < w[1]));
+
+    // Nothing to reduce
+    assert_eq!(ts.downsample_lttb(100).len(), 100);
+    assert_eq!(ts.downsample_lttb(500).len(), 100);
+    assert_eq!(ts.downsample_lttb(2).len(), 100);
+    assert!(FloatTimeSeries::new().downsample_lttb(10).is_empty());
+}
+
+#[test]
+fn test_downsample_lttb_output_length() {
+    let mut ts = FloatTimeSeries::new();
+    for i in 0..1000u64 {
+        ts.add_point(i * 10, (i as f64 / 20.0).sin());
+    }
+    for target in [3, 10, 99, 500, 999] {
+        let sampled = ts.downsample_lttb(target);
+        assert_eq!(sampled.len(), target);
+        assert_eq!(sampled.first_timestamp(), Some(0));
+        assert_eq!(sampled.latest().unwrap().0, 9990);
+    }
+    // The peaks of the sine wave survive a strong reduction
+    let sampled = ts.downsample_lttb(100);
+    assert!(sampled.max().unwrap() > 0.95);
+    assert!(sampled.min().unwrap() < -0.95);
+}
+
+fn sparse_series() -> FloatTimeSeries {
+    let mut ts = FloatTimeSeries::new();
+    ts.add_point(100, 1.0);
+    ts.add_point(200, 3.0);
+    ts.add_point(400, 7.0);
+    ts
+}
+
+#[test]
+fn test_fill_forward() {
+    let filled = sparse_series().fill_forward(&[50, 100, 150, 200, 300, 500]);
+
+    let points: Vec<(u64, f64)> = filled.iter().map(|(t, v)| (t, *v)).collect();
+    assert_eq!(
+        points,
+        vec![(100, 1.0), (150, 1.0), (200, 3.0), (300, 3.0), (500, 7.0)]
+    );
+    assert!(FloatTimeSeries::new().fill_forward(&[100]).is_empty());
+}
+
+#[test]
+fn test_fill_linear() {
+    let filled = sparse_series().fill_linear(&[50, 100, 150, 200, 300, 400, 500]);
+
+    let points: Vec<(u64, f64)> = filled.iter().map(|(t, v)| (t, *v)).collect();
+    assert_eq!(
+        points,
+        vec![(100, 1.0), (150, 2.0), (200, 3.0), (300, 5.0), (400, 7.0)]
+    );
+}
+
+fn synthetic_counter() -> FloatTimeSeries {
+    let mut ts = FloatTimeSeries::new();
+    ts.add_point(0, 100.0);
+    ts.add_point(10, 150.0);
+    ts.add_point(30, 250.0);
+    // Reset, the meter restarted and counted 20 since
+    ts.add_point(40, 20.0);
+    ts.add_point(50, 70.0);
+    ts
+}
+
+#[test]
+fn test_derivative() {
+    let rates = synthetic_counter().derivative();
+
+    let points: Vec<(u64, f64)> = rates.iter().map(|(t, v)| (t, *v)).collect();
+    assert_eq!(points, vec![(10, 5.0), (30, 5.0), (40, -23.0), (50, 5.0)]);
+    assert!(FloatTimeSeries::new().derivative().is_empty());
+}
+
+#[test]
+fn test_counter_rate_handles_reset() {
+    let rates = synthetic_counter().counter_rate(0.0);
+
+    let points: Vec<(u64, f64)> = rates.iter().map(|(t, v)| (t, *v)).collect();
+    assert_eq!(points, vec![(10, 5.0), (30, 5.0), (40, 2.0), (50, 5.0)]);
+
+    // Small drops below the threshold are jitter, not resets
+    let mut jittery = FloatTimeSeries::new();
+    jittery.add_point(0, 100.0);
+    jittery.add_point(10, 99.5);
+    jittery.add_point(20, 110.0);
+    let rates = jittery.counter_rate(1.0);
+    assert_eq!(rates.get_value_for_timestamp(10), Some(&0.0));
+    assert_eq!(rates.get_value_for_timestamp(20), Some(&1.05));
+}
+
+#[test]
+fn test_push_monotonic_bulk_load() {
+    let n = 100_000;
+    let mut ts = FloatTimeSeries::new();
+    for i in 0..n {
+        ts.push_monotonic(1710511200 + i, i as f64);
+    }
+
+    assert_eq!(ts.len(), n as usize);
+    assert_eq!(ts.first_timestamp(), Some(1710511200));
+    assert_eq!(ts.latest(), Some((1710511200 + n - 1, &((n - 1) as f64))));
+    assert_eq!(ts.get_value_for_timestamp(1710511200 + 5000), Some(&5000.0));
+
+    let mut extended = FloatTimeSeries::new();
+    extended.extend_from_sorted(ts.iter().map(|(t, v)| (t, *v)));
+    assert_eq!(extended.timestamps, ts.timestamps);
+    assert_eq!(extended.values, ts.values);
+}
+
+#[test]
+fn test_push_monotonic_out_of_order_and_duplicates() {
+    let mut ts = FloatTimeSeries::new();
+    ts.push_monotonic(10, 1.0);
+    ts.push_monotonic(30, 3.0);
+    ts.push_monotonic(20, 2.0);
+    ts.push_monotonic(30, 4.0);
+
+    let points: Vec<(u64, f64)> = ts.iter().map(|(t, v)| (t, *v)).collect();
+    assert_eq!(points, vec![(10, 1.0), (20, 2.0), (30, 4.0)]);
+}
+
+#[test]
+fn test_push_monotonic_keeps_ordering_invariants() {
+    // Deterministic xorshift, a mix of ascending runs, duplicates and late points
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..50 {
+        let mut ts = IntTimeSeries::new();
+        let mut reference = std::collections::BTreeMap::new();
+        let mut clock = 0u64;
+        for _ in 0..500 {
+            let r = next();
+            let timestamp = match r % 4 {
+                0 => clock.saturating_sub(r % 50),
+                _ => {
+                    clock += r % 3;
+                    clock
+                }
+            };
+            let value = (r >> 8) as i64;
+            ts.push_monotonic(timestamp, value);
+            reference.insert(timestamp, value);
+        }
+
+        assert!(ts.timestamps.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(ts.timestamps.len(), ts.values.len());
+        let expected: Vec<(u64, i64)> = reference.into_iter().collect();
+        let actual: Vec<(u64, i64)> = ts.iter().map(|(t, v)| (t, *v)).collect();
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn test_from_sorted_vecs() {
+    let ts = FloatTimeSeries::from_sorted_vecs(vec![1, 2, 3], vec![1.0, 2.0, 3.0]).unwrap();
+    assert_eq!(ts.len(), 3);
+    assert_eq!(ts.latest(), Some((3, &3.0)));
+
+    assert!(FloatTimeSeries::from_sorted_vecs(vec![1, 3, 2], vec![1.0, 2.0, 3.0]).is_err());
+    assert!(FloatTimeSeries::from_sorted_vecs(vec![1, 1], vec![1.0, 2.0]).is_err());
+    assert!(FloatTimeSeries::from_sorted_vecs(vec![1, 2], vec![1.0]).is_err());
+}
+
+#[test]
+fn test_non_finite_metric_values_to_json() {
+    assert_eq!(
+        serde_json::Value::from(MetricValue::Float(f64::NAN)),
+        serde_json::json!("NaN")
+    );
+    assert_eq!(
+        serde_json::Value::from(MetricValue::Float(f64::INFINITY)),
+        serde_json::json!("Infinity")
+    );
+    assert_eq!(
+        serde_json::Value::from(MetricValue::Float(f64::NEG_INFINITY)),
+        serde_json::json!("-Infinity")
+    );
+    assert_eq!(
+        serde_json::Value::from(MetricValue::Float(1.5)),
+        serde_json::json!(1.5)
+    );
+}
+
+#[test]
+fn test_sanitize_non_finite() {
+    for policy in [NonFinitePolicy::Reject, NonFinitePolicy::Clamp] {
+        assert_eq!(MetricValue::Float(f64::NAN).sanitize(policy), None);
+        assert_eq!(
+            MetricValue::Location(LatLong::new(f64::INFINITY, 16.4)).sanitize(policy),
+            None
+        );
+        assert_eq!(
+            MetricValue::Float(2.0).sanitize(policy),
+            Some(MetricValue::Float(2.0))
+        );
+    }
+    assert_eq!(
+        MetricValue::Float(f64::INFINITY).sanitize(NonFinitePolicy::Reject),
+        None
+    );
+    assert_eq!(
+        MetricValue::Float(f64::INFINITY).sanitize(NonFinitePolicy::Clamp),
+        Some(MetricValue::Float(f64::MAX))
+    );
+    assert_eq!(
+        MetricValue::Float(f64::NEG_INFINITY).sanitize(NonFinitePolicy::Clamp),
+        Some(MetricValue::Float(f64::MIN))
+    );
+}
+
+fn merge_fixture() -> (FloatTimeSeries, FloatTimeSeries) {
+    let mut ts = FloatTimeSeries::new();
+    for (t, v) in [(100, 1.0), (200, 2.0), (300, 3.0)] {
+        ts.add_point(t, v);
+    }
+    let mut other = FloatTimeSeries::new();
+    for (t, v) in [(50, 0.5), (200, 4.0), (300, 5.0), (400, 6.0)] {
+        other.add_point(t, v);
+    }
+    (ts, other)
+}
+
+fn points(ts: &FloatTimeSeries) -> Vec<(u64, f64)> {
+    ts.iter().map(|(t, v)| (t, *v)).collect()
+}
+
+#[test]
+fn test_merge_policy_keep_self() {
+    let (mut ts, other) = merge_fixture();
+    assert_eq!(ts.merge_with_policy(&other, MergePolicy::KeepSelf), 2);
+    assert_eq!(
+        points(&ts),
+        vec![(50, 0.5), (100, 1.0), (200, 2.0), (300, 3.0), (400, 6.0)]
+    );
+}
+
+#[test]
+fn test_merge_policy_keep_other() {
+    let (mut ts, other) = merge_fixture();
+    assert_eq!(ts.merge_with_policy(&other, MergePolicy::KeepOther), 2);
+    let expected = vec![(50, 0.5), (100, 1.0), (200, 4.0), (300, 5.0), (400, 6.0)];
+    assert_eq!(points(&ts), expected);
+
+    // merge keeps its behavior of overwriting duplicates
+    let (mut ts, other) = merge_fixture();
+    ts.merge(&other);
+    assert_eq!(points(&ts), expected);
+}
+
+#[test]
+fn test_merge_policy_combine() {
+    let (mut ts, other) = merge_fixture();
+    let conflicts = ts.merge_with_policy(&other, MergePolicy::Combine(|a, b| (a + b) / 2.0));
+    assert_eq!(conflicts, 2);
+    assert_eq!(
+        points(&ts),
+        vec![(50, 0.5), (100, 1.0), (200, 3.0), (300, 4.0), (400, 6.0)]
+    );
+
+    let mut ints = IntTimeSeries::new();
+    ints.add_point(1, 10);
+    let mut other = IntTimeSeries::new();
+    other.add_point(1, 5);
+    assert_eq!(
+        ints.merge_with_policy(&other, MergePolicy::Combine(|a, b| a + b)),
+        1
+    );
+    assert_eq!(ints.get_value_for_timestamp(1), Some(&15));
+}
+
+#[test]
+fn test_merge_policy_without_conflicts() {
+    let (mut ts, _) = merge_fixture();
+    assert_eq!(
+        ts.merge_with_policy(&FloatTimeSeries::new(), MergePolicy::KeepSelf),
+        0
+    );
+    assert_eq!(points(&ts), vec![(100, 1.0), (200, 2.0), (300, 3.0)]);
+
+    let mut empty = FloatTimeSeries::new();
+    assert_eq!(empty.merge_with_policy(&ts, MergePolicy::KeepSelf), 0);
+    assert_eq!(points(&empty), points(&ts));
+}
+
+fn hourly_series(start: u64, hours: u64) -> FloatTimeSeries {
+    let mut ts = FloatTimeSeries::new();
+    for i in 0..hours {
+        ts.add_point(start + i * 3600, 1.0);
+    }
+    ts
+}
+
+#[test]
+fn test_bucket_by_calendar_dst_spring_forward() {
+    // 2024-03-31 in Berlin has 23 hours, 2024-03-30 and 2024-04-01 have 24
+    let day_before = 1711753200; // 2024-03-30 00:00 CET
+    let dst_day = 1711839600; // 2024-03-31 00:00 CET
+    let day_after = 1711922400; // 2024-04-01 00:00 CEST
+    let ts = hourly_series(day_before, 24 + 23 + 24);
+
+    let buckets: Vec<_> = ts
+        .bucket_by_calendar(CalendarUnit::Day, chrono_tz::Europe::Berlin)
+        .collect();
+    assert_eq!(buckets.len(), 3);
+    assert_eq!(buckets[0].0, day_before);
+    assert_eq!(buckets[0].1.len(), 24);
+    assert_eq!(buckets[1].0, dst_day);
+    assert_eq!(buckets[1].1.len(), 23);
+    assert_eq!(buckets[2].0, day_after);
+    assert_eq!(buckets[2].1.len(), 24);
+}
+
+#[test]
+fn test_bucket_by_calendar_dst_fall_back() {
+    // 2024-10-27 in Berlin has 25 hours
+    let dst_day = 1729980000; // 2024-10-27 00:00 CEST
+    let day_after = 1730070000; // 2024-10-28 00:00 CET
+    let ts = hourly_series(dst_day, 25 + 1);
+
+    let sums = ts.resample_calendar(
+        CalendarUnit::Day,
+        chrono_tz::Europe::Berlin,
+        Aggregation::Sum,
+    );
+    assert_eq!(
+        sums.iter().map(|(t, v)| (t, *v)).collect::<Vec<_>>(),
+        vec![(dst_day, 25.0), (day_after, 1.0)]
+    );
+
+    // The same points in UTC days
+    let utc: Vec<_> = ts
+        .bucket_by_calendar(CalendarUnit::Day, chrono_tz::UTC)
+        .map(|(start, bucket)| (start, bucket.len()))
+        .collect();
+    assert_eq!(utc, vec![(1729900800, 2), (1729987200, 24)]);
+}
+
+#[test]
+fn test_bucket_by_calendar_week_and_month() {
+    let berlin = chrono_tz::Europe::Berlin;
+    let mut ts = FloatTimeSeries::new();
+    ts.add_point(1711321200, 1.0); // Mon 2024-03-25 00:00 CET
+    ts.add_point(1711839600, 2.0); // Sun 2024-03-31 00:00 CET
+    ts.add_point(1711922400, 3.0); // Mon 2024-04-01 00:00 CEST
+
+    let weeks: Vec<_> = ts
+        .bucket_by_calendar(CalendarUnit::Week, berlin)
+        .map(|(start, bucket)| (start, bucket.len()))
+        .collect();
+    assert_eq!(weeks, vec![(1711321200, 2), (1711922400, 1)]);
+
+    let months: Vec<_> = ts
+        .bucket_by_calendar(CalendarUnit::Month, berlin)
+        .map(|(start, bucket)| (start, bucket.len()))
+        .collect();
+    // 2024-03-01 00:00 CET and 2024-04-01 00:00 CEST
+    assert_eq!(months, vec![(1709247600, 2), (1711922400, 1)]);
+
+    assert_eq!(
+        FloatTimeSeries::new()
+            .bucket_by_calendar(CalendarUnit::Day, berlin)
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn test_bounding_box() {
+    let bbox = BoundingBox::parse("48.0, 16.0, 48.5, 16.5").unwrap();
+    assert_eq!(
+        bbox,
+        BoundingBox {
+            min_lat: 48.0,
+            min_long: 16.0,
+            max_lat: 48.5,
+            max_long: 16.5,
+        }
+    );
+    assert!(bbox.contains(&LatLong::new(48.2, 16.4)));
+    // The edges belong to the box
+    assert!(bbox.contains(&LatLong::new(48.0, 16.5)));
+    assert!(bbox.contains(&LatLong::new(48.5, 16.0)));
+    assert!(!bbox.contains(&LatLong::new(48.6, 16.4)));
+    assert!(!bbox.contains(&LatLong::new(48.2, 15.9)));
+
+    for invalid in [
+        "",
+        "48.0,16.0,48.5",
+        "48.0,16.0,48.5,16.5,1.0",
+        "48.0,16.0,north,16.5",
+        "48.5,16.0,48.0,16.5",
+        "48.0,16.5,48.5,16.0",
+        "-91.0,16.0,48.5,16.5",
+        "48.0,16.0,48.5,181.0",
+        "NaN,16.0,48.5,16.5",
+    ] {
+        assert!(BoundingBox::parse(invalid).is_none(), "{}", invalid);
+    }
+}
@@ -1,35 +1,33 @@
-use forest::config::ForestConfig;
-use forest::models::{AuthConfig, Tenant, TenantId};
-use forest::server::start_server;
+mod common;
+
+use common::{spawn_test_server, test_config};
+use flate2::read::GzDecoder;
+use forest::api::start_api_server;
+use forest::dataconfig::DataConfig;
+use forest::db::DB;
+use forest::models::{AlarmEvent, AuthConfig, DeviceMetadata, OtaStatus, Tenant, TenantId};
+use forest::mqtt::{
+    start_broker, DropAlert, MqttServerMetrics, RouterMeterSnapshot, SubscriptionMeterSnapshot,
+};
+use forest::processor::ingest::IngestMetrics;
+use forest::processor::rate_limit::ShadowRateLimiter;
+use forest::server::{ConnectionInfo, ConnectionSet};
+use forest::timeseries::{LatLong, MetricValue};
 use reqwest::Client;
 use serde_json::json;
-use std::fs;
+use std::io::Read;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::time::sleep;
-use uuid::Uuid;
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn test_full_user_management_flow() {
-    let db_id = Uuid::new_v4().simple();
-
-    // 1. Setup custom config
-    let mut config = ForestConfig::default();
-    config.bind_api = "127.0.0.1:9191".to_string();
-    config.mqtt.bind_v3 = "127.0.0.1:9192".to_string();
-    config.mqtt.bind_v5 = "127.0.0.1:9193".to_string();
-    config.cert_dir = format!("/tmp/forest_certs_{}", db_id);
-    fs::create_dir_all(&config.cert_dir).unwrap();
-    config.database.path = format!("sqlite:file:memdb_{}?mode=memory&cache=shared", db_id);
-
-    // 2. Start server
-    let (cancel_token, handle) = start_server(&config).await;
-
-    // Wait a brief moment for server to come up
-    sleep(Duration::from_millis(500)).await;
+    // 1. Start server
+    let server = spawn_test_server(|_| {}).await;
 
-    // 3. Test API Flow using reqwest
+    // 2. Test API Flow using reqwest
     let client = Client::new();
-    let api_url = "http://127.0.0.1:9191";
+    let api_url = &server.url;
 
     // Create Tenant
     let mut auth_config = AuthConfig::default();
@@ -94,9 +92,2325 @@ async fn test_full_user_management_flow() {
     assert_eq!(passwords.len(), 1);
     assert_eq!(passwords[0], "device_user");
 
-    // 4. Teardown
-    cancel_token.cancel();
+    // 3. Teardown
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_shadow_etag_conditional_requests() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let shadow_url = format!("{}/default/things/etag_device/shadow", server.url);
+
+    // Create the shadow
+    let res = client
+        .post(&shadow_url)
+        .json(&json!({"state": {"reported": {"temp": 21}}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    // Fetch it and remember the ETag
+    let res = client.get(&shadow_url).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let etag = res
+        .headers()
+        .get("etag")
+        .expect("ETag header missing")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // Conditional GET with the same ETag returns 304 without a body
+    let res = client
+        .get(&shadow_url)
+        .header("If-None-Match", &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 304);
+    assert!(res.bytes().await.unwrap().is_empty());
+
+    // Update the shadow with the current ETag
+    let res = client
+        .post(&shadow_url)
+        .header("If-Match", &etag)
+        .json(&json!({"state": {"desired": {"temp": 22}}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    // The old ETag is stale now, so a conditional update fails
+    let res = client
+        .post(&shadow_url)
+        .header("If-Match", &etag)
+        .json(&json!({"state": {"desired": {"temp": 23}}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 412);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_create_device_is_idempotent() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let device_url = format!("{}/default/devices/idempotent_device", server.url);
+
+    let first: serde_json::Value = client
+        .post(&device_url)
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(first["certificate"].is_string());
+
+    // A retry returns the same device and certificate
+    let second: serde_json::Value = client
+        .post(&device_url)
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(first["certificate"], second["certificate"]);
+
+    // Shares the in-memory database with the running server
+    let db = DB::open_default(&server.config.database.path)
+        .await
+        .unwrap();
+    let mut metadata = db
+        .get_device_metadata(&TenantId::Default, "idempotent_device")
+        .await
+        .unwrap()
+        .unwrap();
+    metadata.created_at = 1700000000;
+    metadata.firmware_version = Some("1.0.0".to_string());
+    metadata.target_firmware_version = Some("1.1.0".to_string());
+    metadata.ota_status = Some(OtaStatus::Downloading);
+    db.put_device_metadata(&metadata).await.unwrap();
+
+    // Forcing regenerates the certificate and keeps everything else
+    let forced: serde_json::Value = client
+        .post(&format!("{}?force=true", device_url))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_ne!(first["certificate"], forced["certificate"]);
+    assert_ne!(first["key"], forced["key"]);
+    assert_eq!(forced["created_at"], 1700000000);
+    assert_eq!(forced["firmware_version"], "1.0.0");
+    assert_eq!(forced["target_firmware_version"], "1.1.0");
+    assert_eq!(forced["ota_status"], "downloading");
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_timeseries_response_is_gzip_compressed() {
+    let server = spawn_test_server(|_| {}).await;
+
+    // Shares the in-memory database with the running server
+    let db = DB::open_default(&server.config.database.path)
+        .await
+        .unwrap();
+    for i in 0..2000 {
+        db.insert_metric_row(
+            &TenantId::Default,
+            "gzip_device",
+            "temp",
+            1710511200 + i,
+            MetricValue::Float(i as f64 / 10.0),
+        )
+        .await
+        .unwrap();
+    }
+
+    let client = Client::new();
+    let res = client
+        .get(format!(
+            "{}/default/data/gzip_device/temp?start=0&end=2000000000",
+            server.url
+        ))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers().get("content-encoding").unwrap(), "gzip");
+
+    let compressed = res.bytes().await.unwrap();
+    let mut decoder = GzDecoder::new(&compressed[..]);
+    let mut body = String::new();
+    decoder.read_to_string(&mut body).unwrap();
+    assert!(compressed.len() < body.len());
+
+    let model: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(model["device_id"], "gzip_device");
+    assert_eq!(model["data"].as_array().unwrap().len(), 2000);
+    assert_eq!(model["data"][0], json!([1710511200, 0.0]));
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_openapi_spec_lists_routes() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let res = client
+        .get(format!("{}/openapi.json", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+
+    let spec: serde_json::Value = res.json().await.unwrap();
+    assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+    for path in [
+        "/{tenant_id}/things/{device_id}/shadow",
+        "/{tenant_id}/things/{device_id}/shadow/desired",
+        "/{tenant_id}/data/{device_id}/{metric}",
+        "/{tenant_id}/dataconfig/device/{device_prefix}",
+        "/{tenant_id}/devices/{device_id}",
+        "/tenants",
+    ] {
+        assert!(spec["paths"].get(path).is_some(), "missing path {}", path);
+    }
+    for schema in [
+        "Shadow",
+        "TimeSeriesModel",
+        "DataConfig",
+        "DeviceMetadata",
+        "Tenant",
+    ] {
+        assert!(
+            spec["components"]["schemas"].get(schema).is_some(),
+            "missing schema {}",
+            schema
+        );
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_cors_preflight() {
+    let server = spawn_test_server(|config| {
+        config.cors_allowed_origins = vec!["https://dashboard.example.com".to_string()];
+    })
+    .await;
+
+    let client = Client::new();
+    let url = format!("{}/default/things/cors_device/shadow", server.url);
+    let res = client
+        .request(reqwest::Method::OPTIONS, &url)
+        .header("Origin", "https://dashboard.example.com")
+        .header("Access-Control-Request-Method", "POST")
+        .header("Access-Control-Request-Headers", "content-type")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(
+        res.headers().get("access-control-allow-origin").unwrap(),
+        "https://dashboard.example.com"
+    );
+    let methods = res
+        .headers()
+        .get("access-control-allow-methods")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(methods.contains("POST") && methods.contains("DELETE"));
+
+    // Origins that are not configured get no CORS headers
+    let res = client
+        .request(reqwest::Method::OPTIONS, &url)
+        .header("Origin", "https://evil.example.com")
+        .header("Access-Control-Request-Method", "POST")
+        .send()
+        .await
+        .unwrap();
+    assert!(res.headers().get("access-control-allow-origin").is_none());
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_admin_token_auth() {
+    let server = spawn_test_server(|config| {
+        config.admin_api_token = Some("s3cret".to_string());
+    })
+    .await;
+
+    let client = Client::new();
+    let url = format!("{}/default/devices", server.url);
+
+    // Missing token
+    let res = client.get(&url).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 401);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Unauthorized"));
+
+    // Wrong token
+    let res = client.get(&url).bearer_auth("wrong").send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 401);
+
+    // Valid token
+    let res = client.get(&url).bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    // Health stays open for probes
+    let res = client
+        .get(format!("{}/health", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_api_is_open_without_admin_token() {
+    let server = spawn_test_server(|_| {}).await;
+    assert!(server.config.admin_api_token.is_none());
+
+    let client = Client::new();
+    let res = client
+        .get(format!("{}/default/devices", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    // A token sent to an open API is ignored
+    let res = client
+        .get(format!("{}/default/devices", server.url))
+        .bearer_auth("anything")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_timeseries_downsampling() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let db = DB::open_default(&server.config.database.path)
+        .await
+        .unwrap();
+    let start = 1710511200;
+    for i in 0..1000 {
+        let value = match i {
+            250 => 500.0,
+            777 => -500.0,
+            _ => (i % 7) as f64,
+        };
+        db.insert_metric_row(
+            &TenantId::Default,
+            "lttb_device",
+            "temp",
+            start + i,
+            MetricValue::Float(value),
+        )
+        .await
+        .unwrap();
+    }
+    db.insert_metric_row(
+        &TenantId::Default,
+        "lttb_device",
+        "position",
+        start,
+        MetricValue::Location(LatLong::new(48.2, 16.4)),
+    )
+    .await
+    .unwrap();
+
+    let client = Client::new();
+    let res = client
+        .get(format!(
+            "{}/default/data/lttb_device/temp?start=0&end=2000000000&points=50",
+            server.url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let model: serde_json::Value = res.json().await.unwrap();
+    let data = model["data"].as_array().unwrap();
+    assert_eq!(data.len(), 50);
+    assert_eq!(data[0][0], start);
+    assert_eq!(data[49][0], start + 999);
+    assert!(data.contains(&json!([start + 250, 500.0])));
+    assert!(data.contains(&json!([start + 777, -500.0])));
+
+    // Locations can't be downsampled
+    let res = client
+        .get(format!(
+            "{}/default/data/lttb_device/position?start=0&end=2000000000&points=50",
+            server.url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+
+    // but averaged per bucket, on the sphere across the antimeridian
+    db.insert_metric_row(
+        &TenantId::Default,
+        "lttb_device",
+        "route",
+        start,
+        MetricValue::Location(LatLong::new(0.0, 179.0)),
+    )
+    .await
+    .unwrap();
+    db.insert_metric_row(
+        &TenantId::Default,
+        "lttb_device",
+        "route",
+        start + 60,
+        MetricValue::Location(LatLong::new(0.0, -179.0)),
+    )
+    .await
+    .unwrap();
+    let res = client
+        .get(format!(
+            "{}/default/data/lttb_device/route?start=0&end=2000000000&bucket=1d&agg=mean",
+            server.url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let model: serde_json::Value = res.json().await.unwrap();
+    let data = model["data"].as_array().unwrap();
+    assert_eq!(data.len(), 1);
+    assert_eq!(data[0][0], start - start % 86400);
+    assert!(data[0][1]["lat"].as_f64().unwrap().abs() < 1e-6);
+    assert!((data[0][1]["long"].as_f64().unwrap().abs() - 180.0).abs() < 1e-6);
+    let res = client
+        .get(format!(
+            "{}/default/data/lttb_device/route?start=0&end=2000000000&bucket=1d&agg=max",
+            server.url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_timeseries_counter_rate() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let db = DB::open_default(&server.config.database.path)
+        .await
+        .unwrap();
+    let start = 1710511200;
+    // Counter resets after the third reading
+    for (i, value) in [100.0, 160.0, 220.0, 30.0, 90.0].into_iter().enumerate() {
+        db.insert_metric_row(
+            &TenantId::Default,
+            "meter",
+            "energy",
+            start + i as u64 * 60,
+            MetricValue::Float(value),
+        )
+        .await
+        .unwrap();
+    }
+
+    let client = Client::new();
+    let res = client
+        .get(format!(
+            "{}/default/data/meter/energy?start=0&end=2000000000&agg=rate",
+            server.url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let model: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(
+        model["data"],
+        json!([
+            [start + 60, 1.0],
+            [start + 120, 1.0],
+            [start + 180, 0.5],
+            [start + 240, 1.0]
+        ])
+    );
+
+    let res = client
+        .get(format!(
+            "{}/default/data/meter/energy?start=0&end=2000000000&agg=median",
+            server.url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 400);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_timeseries_calendar_buckets() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let db = DB::open_default(&server.config.database.path)
+        .await
+        .unwrap();
+    // Hourly readings over 2024-03-31 in Berlin, a 23 hour day, and the first hour after it
+    let dst_day = 1711839600;
+    for i in 0..24 {
+        db.insert_metric_row(
+            &TenantId::Default,
+            "meter",
+            "power",
+            dst_day + i * 3600,
+            MetricValue::Float(2.0),
+        )
+        .await
+        .unwrap();
+    }
+
+    let client = Client::new();
+    let url = format!(
+        "{}/default/data/meter/power?start=0&end=2000000000",
+        server.url
+    );
+    let res = client
+        .get(format!("{}&bucket=1d&tz=Europe/Berlin&agg=sum", url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let model: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(
+        model["data"],
+        json!([[dst_day, 46.0], [dst_day + 23 * 3600, 2.0]])
+    );
+
+    // UTC days by default, mean aggregation
+    let res = client
+        .get(format!("{}&bucket=1d", url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let model: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(model["data"], json!([[1711756800, 2.0], [1711843200, 2.0]]));
+
+    for query in ["&bucket=1d&tz=Mars/Olympus", "&bucket=2h", "&agg=sum"] {
+        let res = client
+            .get(format!("{}{}", url, query))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 422, "{}", query);
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_patch_desired_shadow() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let shadow_url = format!("{}/default/things/patch_device/shadow", server.url);
+
+    let res = client
+        .patch(format!("{}/reported", shadow_url))
+        .json(&json!({"temp": 21, "mode": "eco"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    let res = client
+        .patch(format!("{}/desired", shadow_url))
+        .json(&json!({"temp": 23}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let shadow: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(shadow["state"]["desired"], json!({"temp": 23}));
+    assert_eq!(
+        shadow["state"]["reported"],
+        json!({"temp": 21, "mode": "eco"})
+    );
+    assert_eq!(shadow["state"]["delta"], json!({"temp": 23}));
+
+    // Named shadows are addressed like in the full update
+    let res = client
+        .patch(format!("{}/desired?name=config", shadow_url))
+        .json(&json!({"interval": 60}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .get(format!("{}?name=config", shadow_url))
+        .send()
+        .await
+        .unwrap();
+    let shadow: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(shadow["state"]["desired"], json!({"interval": 60}));
+    assert!(shadow["state"]["reported"].is_null());
+
+    let res = client
+        .patch(format!("{}/desired", shadow_url))
+        .json(&json!([1, 2]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_delete_desired_shadow_fields() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let shadow_url = format!("{}/default/things/clear_device/shadow", server.url);
+
+    // Nothing to clear yet
+    let res = client
+        .delete(format!("{}/desired", shadow_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    let res = client
+        .post(&shadow_url)
+        .json(&json!({"state": {
+            "reported": {"config": {"sample_rate": 5}},
+            "desired": {"config": {"sample_rate": 10, "mode": "eco"}, "led": "on"}
+        }}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    let res = client
+        .delete(format!(
+            "{}/desired?pointer=/config/sample_rate",
+            shadow_url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let shadow: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(
+        shadow["state"]["desired"],
+        json!({"config": {"mode": "eco"}, "led": "on"})
+    );
+    assert_eq!(
+        shadow["state"]["reported"],
+        json!({"config": {"sample_rate": 5}})
+    );
+    // The removed field no longer shows up in the delta
+    assert_eq!(
+        shadow["state"]["delta"],
+        json!({"config": {"mode": "eco"}, "led": "on"})
+    );
+
+    let res = client
+        .delete(format!("{}/desired?pointer=config", shadow_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+
+    // Without a pointer the whole desired document is cleared
+    let res = client
+        .delete(format!("{}/desired", shadow_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let shadow: serde_json::Value = res.json().await.unwrap();
+    assert!(shadow["state"]["desired"]
+        .as_object()
+        .is_none_or(|desired| desired.is_empty()));
+    assert!(shadow["state"]["delta"]
+        .as_object()
+        .is_none_or(|delta| delta.is_empty()));
+    assert_eq!(
+        shadow["state"]["reported"],
+        json!({"config": {"sample_rate": 5}})
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_publish_to_device() {
+    let config = test_config();
+
+    // Broker and API without the processor, so the test can subscribe on the broker link
+    let db = Arc::new(DB::open_default(&config.database.path).await.unwrap());
+    let mut mqtt = start_broker(Some(config.mqtt.clone()), db.clone()).await;
+    let receiver = mqtt.message_receiver();
+    mqtt.mqtt
+        .subscribe("things/cmd_device/cmd".to_string())
+        .await
+        .unwrap();
+    let (api_cancel_token, api_handle) = start_api_server(
+        &config.bind_api,
+        db,
+        Some(mqtt.mqtt.clone()),
+        mqtt.metrics.clone(),
+        Arc::new(ConnectionSet::new()),
+        &config,
+        Arc::new(RwLock::new(config.processor.clone())),
+        Arc::new(IngestMetrics::default()),
+        None,
+        None,
+        Arc::new(ShadowRateLimiter::default()),
+        None,
+    )
+    .await;
+    sleep(Duration::from_millis(500)).await;
+
+    let client = Client::new();
+    let publish_url = format!(
+        "http://{}/default/things/cmd_device/publish",
+        config.bind_api
+    );
+
+    let res = client
+        .post(&publish_url)
+        .json(&json!({"topic_suffix": "cmd", "payload": {"action": "reboot"}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["topic"], "things/cmd_device/cmd");
+
+    let msg = tokio::time::timeout(Duration::from_secs(2), receiver.recv_async())
+        .await
+        .expect("Timeout waiting for published message")
+        .unwrap();
+    assert_eq!(msg.topic, "things/cmd_device/cmd");
+    let payload: serde_json::Value = serde_json::from_slice(&msg.payload).unwrap();
+    assert_eq!(payload, json!({"action": "reboot"}));
+
+    // Raw bytes are sent base64 encoded
+    let res = client
+        .post(&publish_url)
+        .json(&json!({"topic_suffix": "cmd", "payload": "AAEC", "encoding": "base64"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let msg = tokio::time::timeout(Duration::from_secs(2), receiver.recv_async())
+        .await
+        .expect("Timeout waiting for published message")
+        .unwrap();
+    assert_eq!(msg.payload, vec![0u8, 1, 2]);
+
+    for body in [
+        json!({"topic_suffix": "cmd/#", "payload": {}}),
+        json!({"topic_suffix": "+/cmd", "payload": {}}),
+        json!({"topic_suffix": "", "payload": {}}),
+        json!({"topic_suffix": "cmd", "payload": {}, "qos": 1}),
+        json!({"topic_suffix": "cmd", "payload": "%%", "encoding": "base64"}),
+    ] {
+        let res = client.post(&publish_url).json(&body).send().await.unwrap();
+        assert_eq!(res.status().as_u16(), 422, "{}", body);
+    }
+
+    api_cancel_token.cancel();
+    let _ = tokio::time::timeout(Duration::from_secs(2), api_handle).await;
+    mqtt.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_dataconfig_scaling_round_trip() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let metric = json!({
+        "json_pointer": "/adc",
+        "name": "level",
+        "data_type": "Int",
+        "scale": 0.5,
+        "offset": -10.0
+    });
+    let res = client
+        .put(format!("{}/default/dataconfig", server.url))
+        .json(&json!({"metrics": [metric.clone()]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    let res = client
+        .get(format!("{}/default/dataconfig", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let stored: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(stored["metrics"], json!([metric]));
+
+    let res = client
+        .post(format!("{}/default/data/scaled_device", server.url))
+        .json(&json!({"adc": 1023}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .get(format!(
+            "{}/default/data/scaled_device/level?start=0&end=2000000000",
+            server.url
+        ))
+        .send()
+        .await
+        .unwrap();
+    let model: serde_json::Value = res.json().await.unwrap();
+    let data = model["data"].as_array().unwrap();
+    assert_eq!(data.len(), 1);
+    assert_eq!(data[0][1], 502);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_group_desired_shadow() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+
+    let group = json!({"group_id": "lights", "device_ids": ["lamp_2", "lamp_1"]});
+    let res = client
+        .post(format!("{}/default/groups", api_url))
+        .json(&group)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .post(format!("{}/default/groups", api_url))
+        .json(&group)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 409);
+
+    let res = client
+        .patch(format!("{}/default/things/lamp_1/shadow/reported", api_url))
+        .json(&json!({"brightness": 20}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    let res = client
+        .post(format!("{}/default/groups/lights/shadow/desired", api_url))
+        .json(&json!({"brightness": 80}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let shadows: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(shadows.as_array().unwrap().len(), 2);
+    assert_eq!(shadows[0]["device_id"], "lamp_1");
+    assert_eq!(shadows[0]["state"]["delta"], json!({"brightness": 80}));
+    assert_eq!(shadows[1]["state"]["desired"], json!({"brightness": 80}));
+
+    let res = client
+        .get(format!("{}/default/things/lamp_2/shadow", api_url))
+        .send()
+        .await
+        .unwrap();
+    let shadow: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(shadow["state"]["desired"], json!({"brightness": 80}));
+
+    let res = client
+        .post(format!("{}/default/groups/lights/shadow/desired", api_url))
+        .json(&json!([80]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+    let res = client
+        .post(format!("{}/default/groups/missing/shadow/desired", api_url))
+        .json(&json!({"brightness": 80}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+    let res = client
+        .get(format!("{}/other/groups/lights/devices", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_timeseries_bbox_query() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+    let data_config = json!({"metrics": [
+        {"json_pointer": "/pos", "name": "gps", "data_type": "LocationTuple", "timestamp_json_pointer": "/ts"},
+        {"json_pointer": "/speed", "name": "speed", "data_type": "Float", "timestamp_json_pointer": "/ts"}
+    ]});
+    let res = client
+        .put(format!("{}/default/dataconfig", api_url))
+        .json(&data_config)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    let now = chrono::Utc::now().timestamp();
+    for (offset, pos) in [(30, [48.2, 16.4]), (20, [48.5, 16.5]), (10, [52.5, 13.4])] {
+        let res = client
+            .post(format!("{}/default/data/tracker", api_url))
+            .json(&json!({"pos": pos, "speed": 12.5, "ts": now - offset}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+    }
+
+    let range = format!("start={}&end={}", now - 60, now);
+    let res = client
+        .get(format!(
+            "{}/default/data/tracker/gps?{}&bbox=48.0,16.0,48.5,16.5",
+            api_url, range
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let model: serde_json::Value = res.json().await.unwrap();
+    let data = model["data"].as_array().unwrap();
+    // The point on the max corner is included, Berlin is not
+    assert_eq!(data.len(), 2);
+    assert_eq!(data[1][0], now - 20);
+
+    for (metric, bbox) in [
+        ("speed", "48.0,16.0,48.5,16.5"),
+        ("gps", "48.5,16.0,48.0,16.5"),
+    ] {
+        let res = client
+            .get(format!(
+                "{}/default/data/tracker/{}?{}&bbox={}",
+                api_url, metric, range, bbox
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 422, "{} {}", metric, bbox);
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_device_tags() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+    for device_id in ["sensor_1", "sensor_2"] {
+        let res = client
+            .post(format!("{}/default/devices/{}", api_url, device_id))
+            .json(&json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+    }
+
+    let res = client
+        .put(format!("{}/default/devices/sensor_2/tags", api_url))
+        .json(&json!({"location": "warehouse-3", "model": "th-20"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .get(format!("{}/default/devices/sensor_2/tags", api_url))
+        .send()
+        .await
+        .unwrap();
+    let tags: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(tags, json!({"location": "warehouse-3", "model": "th-20"}));
+
+    // Forced re-provisioning keeps the tags
+    let res = client
+        .post(format!("{}/default/devices/sensor_2?force=true", api_url))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    let metadata: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(metadata["tags"]["location"], "warehouse-3");
+
+    let res = client
+        .get(format!(
+            "{}/default/devices?tag=location:warehouse-3",
+            api_url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let device_ids: Vec<String> = res.json().await.unwrap();
+    assert_eq!(device_ids, vec!["sensor_2"]);
+
+    let res = client
+        .put(format!("{}/default/devices/missing/tags", api_url))
+        .json(&json!({"location": "office"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+    let res = client
+        .put(format!("{}/default/devices/sensor_1/tags", api_url))
+        .json(&json!({"bad key": "x"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+    let res = client
+        .get(format!("{}/default/devices?tag=location", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_device_lifecycle() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+    for device_id in ["meter_1", "meter_2", "meter_3"] {
+        let res = client
+            .post(format!("{}/default/devices/{}", api_url, device_id))
+            .json(&json!({}))
+            .send()
+            .await
+            .unwrap();
+        let metadata: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(metadata["status"], "registered");
+    }
+
+    let res = client
+        .post(format!("{}/default/devices/meter_1/activate", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let metadata: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(metadata["status"], "active");
+    let res = client
+        .post(format!("{}/default/devices/meter_2/deactivate", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .post(format!(
+            "{}/default/devices/meter_3/deactivate?decommission=true",
+            api_url
+        ))
+        .send()
+        .await
+        .unwrap();
+    let metadata: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(metadata["status"], "decommissioned");
+
+    for (status, expected) in [
+        ("active", vec!["meter_1"]),
+        ("inactive", vec!["meter_2"]),
+        ("decommissioned", vec!["meter_3"]),
+    ] {
+        let res = client
+            .get(format!("{}/default/devices?status={}", api_url, status))
+            .send()
+            .await
+            .unwrap();
+        let device_ids: Vec<String> = res.json().await.unwrap();
+        assert_eq!(device_ids, expected);
+    }
+
+    // Decommissioning is final
+    let res = client
+        .post(format!("{}/default/devices/meter_3/activate", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 409);
+    let res = client
+        .post(format!("{}/default/devices/missing/activate", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_device_labels() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+    for device_id in ["meter_1", "meter_2", "meter_3"] {
+        client
+            .post(format!("{}/default/devices/{}", api_url, device_id))
+            .json(&json!({}))
+            .send()
+            .await
+            .unwrap();
+    }
+    for (device_id, site) in [
+        ("meter_1", "berlin"),
+        ("meter_2", "berlin"),
+        ("meter_3", "paris"),
+    ] {
+        let res = client
+            .patch(format!("{}/default/devices/{}/labels", api_url, device_id))
+            .json(&json!({"site": site, "floor": "2"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+    }
+
+    // Null removes a label, other labels are kept
+    let res = client
+        .patch(format!("{}/default/devices/meter_2/labels", api_url))
+        .json(&json!({"floor": null}))
+        .send()
+        .await
+        .unwrap();
+    let labels: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(labels, json!({"site": "berlin"}));
+    let res = client
+        .get(format!("{}/default/devices/meter_2/labels", api_url))
+        .send()
+        .await
+        .unwrap();
+    let labels: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(labels, json!({"site": "berlin"}));
+
+    let res = client
+        .get(format!("{}/default/devices?label=site=berlin", api_url))
+        .send()
+        .await
+        .unwrap();
+    let device_ids: Vec<String> = res.json().await.unwrap();
+    assert_eq!(device_ids, vec!["meter_1", "meter_2"]);
+    let res = client
+        .get(format!("{}/default/devices?label=floor=2", api_url))
+        .send()
+        .await
+        .unwrap();
+    let device_ids: Vec<String> = res.json().await.unwrap();
+    assert_eq!(device_ids, vec!["meter_1", "meter_3"]);
+    let res = client
+        .get(format!("{}/default/devices?label=site", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+
+    // A label config applies to the devices with the label
+    let res = client
+        .put(format!("{}/default/dataconfig/label/site=berlin", api_url))
+        .json(&json!({"metrics": [{"json_pointer": "/power", "name": "power", "data_type": "Float"}]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .get(format!("{}/default/dataconfig/device/meter_1", api_url))
+        .send()
+        .await
+        .unwrap();
+    let config: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(config["metrics"][0]["name"], "power");
+    let res = client
+        .get(format!("{}/default/dataconfig/device/meter_3", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    let res = client
+        .patch(format!("{}/default/devices/meter_1/labels", api_url))
+        .json(&json!({"bad key": "x"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+    let res = client
+        .patch(format!("{}/default/devices/missing/labels", api_url))
+        .json(&json!({"site": "berlin"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_http_telemetry_rate_limit() {
+    let server = spawn_test_server(|config| {
+        config.http_telemetry_rate_per_second = 1;
+        config.http_telemetry_burst = 3;
+    })
+    .await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+    client
+        .put(format!("{}/default/dataconfig", api_url))
+        .json(
+            &json!({"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}]}),
+        )
+        .send()
+        .await
+        .unwrap();
+
+    // The burst is accepted, the request after it is rejected
+    for i in 0..3 {
+        let res = client
+            .post(format!("{}/default/data/meter_1", api_url))
+            .json(&json!({"temp": 20.0 + i as f64}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+    }
+    let res = client
+        .post(format!("{}/default/data/meter_1", api_url))
+        .json(&json!({"temp": 23.0}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 429);
+    assert_eq!(res.headers()["retry-after"], "1");
+
+    // Other devices are not affected
+    let res = client
+        .post(format!("{}/default/data/meter_2", api_url))
+        .json(&json!({"temp": 20.0}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    // A retry after the announced time is accepted again
+    sleep(Duration::from_millis(1100)).await;
+    let res = client
+        .post(format!("{}/default/data/meter_1", api_url))
+        .json(&json!({"temp": 23.0}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_tenant_quota() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+
+    // Quotas can only be set on existing tenants
+    let res = client
+        .put(format!("{}/tenants/limited/quota", api_url))
+        .json(&json!({"max_devices": 1}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    let tenant = Tenant::new(&TenantId::from_str("limited"));
+    let res = client
+        .post(format!("{}/tenants", api_url))
+        .json(&tenant)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .put(format!("{}/tenants/limited/quota", api_url))
+        .json(&json!({"max_devices": 1, "max_timeseries_rows": 2}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["quota"]["max_devices"], 1);
+    assert_eq!(body["quota"]["max_timeseries_rows"], 2);
+    assert!(body["quota"]["max_shadows"].is_null());
+
+    // The second device exceeds the quota, recreating the first one doesn't
+    let res = client
+        .post(format!("{}/limited/devices/dev_1", api_url))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .post(format!("{}/limited/devices/dev_2", api_url))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 429);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Quota exceeded"));
+    let res = client
+        .post(format!("{}/limited/devices/dev_1", api_url))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    // Telemetry beyond the row quota is rejected
+    client
+        .put(format!("{}/limited/dataconfig", api_url))
+        .json(
+            &json!({"metrics": [{"json_pointer": "/temp", "name": "temp", "data_type": "Float"}]}),
+        )
+        .send()
+        .await
+        .unwrap();
+    for status in [200, 200, 429] {
+        let res = client
+            .post(format!("{}/limited/data/dev_1", api_url))
+            .json(&json!({"temp": 20.0}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), status);
+    }
+
+    // Usage counts what the tenant stored
+    let res = client
+        .get(format!("{}/tenants/limited/usage", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let usage: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(usage["device_count"], 1);
+    assert_eq!(usage["metric_row_count"], 2);
+    assert_eq!(usage["shadow_count"], 0);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_alarms() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+
+    // The tenant of the path wins over the one in the body
+    let res = client
+        .post(format!("{}/acme/alarms", api_url))
+        .json(&json!({
+            "tenant_id": "other",
+            "alarm_id": "too_hot",
+            "device_id": "boiler",
+            "metric_name": "temp",
+            "condition": "above",
+            "threshold": 90.0
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let rule: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(rule["tenant_id"], "acme");
+    assert_eq!(rule["condition"], "above");
+
+    for body in [
+        json!({"alarm_id": "", "device_id": "boiler", "metric_name": "temp", "condition": "above", "threshold": 1.0}),
+        json!({"alarm_id": "a", "device_id": "boiler", "metric_name": "temp", "condition": "between", "threshold": 1.0}),
+    ] {
+        let res = client
+            .post(format!("{}/acme/alarms", api_url))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 422);
+    }
+
+    let db = DB::open_default(&server.config.database.path)
+        .await
+        .unwrap();
+    let acme = TenantId::from_str("acme");
+    for triggered_at in [1000, 1001] {
+        let event = AlarmEvent {
+            alarm_id: "too_hot".to_string(),
+            triggered_at,
+            value: 95.0,
+        };
+        db.insert_alarm_event(&acme, "boiler", &event)
+            .await
+            .unwrap();
+    }
+
+    let res = client
+        .get(format!("{}/acme/devices/boiler/alarms?limit=1", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let events: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(
+        events,
+        json!([{"alarm_id": "too_hot", "triggered_at": 1001, "value": 95.0}])
+    );
+    let res = client
+        .get(format!("{}/default/devices/boiler/alarms", api_url))
+        .send()
+        .await
+        .unwrap();
+    let events: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(events, json!([]));
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_mqtt_admin_endpoints() {
+    let config = test_config();
+
+    // Meters are captured by hand, the broker only pushes them every 10 seconds
+    let metrics = Arc::new(MqttServerMetrics::new(2));
+    for sequence in 1..=3 {
+        metrics.record_router_meter(RouterMeterSnapshot {
+            timestamp: 1000 * sequence as u64,
+            sequence,
+            total_connections: sequence,
+            total_subscriptions: 4,
+            total_publishes: 10 * sequence,
+            failed_publishes: 0,
+        });
+    }
+    metrics.record_subscription_meter(SubscriptionMeterSnapshot {
+        filter: "things/#".to_string(),
+        timestamp: 3000,
+        sequence: 3,
+        count: 7,
+        total_size: 512,
+    });
+    let connected_clients = Arc::new(ConnectionSet::new());
+    connected_clients.insert(
+        "sensor_1".to_string(),
+        ConnectionInfo {
+            tenant: TenantId::Default,
+            connected_at: 1000,
+        },
+    );
+
+    let db = Arc::new(DB::open_default(&config.database.path).await.unwrap());
+    let (api_cancel_token, api_handle) = start_api_server(
+        &config.bind_api,
+        db,
+        None,
+        metrics,
+        connected_clients,
+        &config,
+        Arc::new(RwLock::new(config.processor.clone())),
+        Arc::new(IngestMetrics::default()),
+        None,
+        None,
+        Arc::new(ShadowRateLimiter::default()),
+        None,
+    )
+    .await;
+    sleep(Duration::from_millis(500)).await;
+
+    let client = Client::new();
+    let api_url = format!("http://{}", config.bind_api);
+
+    // Only the last two meters are kept, oldest first
+    let res = client
+        .get(format!("{}/admin/mqtt/meters", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let meters: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(meters["capacity"], 2);
+    let sequences: Vec<u64> = meters["meters"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["sequence"].as_u64().unwrap())
+        .collect();
+    assert_eq!(sequences, vec![2, 3]);
+    assert_eq!(meters["meters"][1]["total_publishes"], 30);
+
+    let res = client
+        .get(format!("{}/admin/mqtt/subscriptions", api_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let subscriptions: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(subscriptions["filters"][0]["filter"], "things/#");
+    assert_eq!(subscriptions["filters"][0]["count"], 7);
+    assert_eq!(subscriptions["connections"], json!(["sensor_1"]));
+    assert_eq!(subscriptions["total_connections"], 3);
+    assert_eq!(subscriptions["total_subscriptions"], 4);
+
+    api_cancel_token.cancel();
+    let _ = tokio::time::timeout(Duration::from_secs(2), api_handle).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_connected_clients_per_tenant() {
+    let config = test_config();
+
+    // Filled by hand, the connection monitor fills it from the broker
+    let connected_clients = Arc::new(ConnectionSet::new());
+    for (client_id, tenant, connected_at) in [
+        ("sensor_2", "default", 1200),
+        ("sensor_1", "default", 1100),
+        ("sensor_1_acme", "acme", 1300),
+    ] {
+        connected_clients.insert(
+            client_id.to_string(),
+            ConnectionInfo {
+                tenant: TenantId::from_str(tenant),
+                connected_at,
+            },
+        );
+    }
+
+    let db = Arc::new(DB::open_default(&config.database.path).await.unwrap());
+    db.put_device_metadata(&DeviceMetadata::new("sensor_1", &TenantId::Default))
+        .await
+        .unwrap();
+    db.put_device_metadata(&DeviceMetadata::new(
+        "sensor_2",
+        &TenantId::from_str("acme"),
+    ))
+    .await
+    .unwrap();
+    let (api_cancel_token, api_handle) = start_api_server(
+        &config.bind_api,
+        db,
+        None,
+        Arc::new(MqttServerMetrics::new(1)),
+        connected_clients,
+        &config,
+        Arc::new(RwLock::new(config.processor.clone())),
+        Arc::new(IngestMetrics::default()),
+        None,
+        None,
+        Arc::new(ShadowRateLimiter::default()),
+        None,
+    )
+    .await;
+    sleep(Duration::from_millis(500)).await;
+
+    let client = Client::new();
+    let api_url = format!("http://{}", config.bind_api);
+
+    // Only clients of the path tenant, sorted by client id
+    let connected: serde_json::Value = client
+        .get(format!("{}/default/connected", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        connected,
+        json!([
+            {"client_id": "sensor_1", "connected_at": 1100},
+            {"client_id": "sensor_2", "connected_at": 1200}
+        ])
+    );
+    let connected: serde_json::Value = client
+        .get(format!("{}/acme/connected", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        connected,
+        json!([{"client_id": "sensor_1_acme", "connected_at": 1300}])
+    );
+
+    // The home page counts every tenant
+    let home: serde_json::Value = client
+        .get(&api_url)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(home["connected_devices"], 3);
+
+    // A client id connected for another tenant doesn't count for the device
+    let info: serde_json::Value = client
+        .get(format!("{}/default/devices/sensor_1", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(info["connected"], true);
+    let info: serde_json::Value = client
+        .get(format!("{}/acme/devices/sensor_2", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(info["connected"], false);
+
+    api_cancel_token.cancel();
+    let _ = tokio::time::timeout(Duration::from_secs(2), api_handle).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_timeseries_gaps() {
+    let config = test_config();
+
+    // Uploads every 5 minutes, the ones at 1200 and 1500 are missing
+    let db = Arc::new(DB::open_default(&config.database.path).await.unwrap());
+    let tenant_id = TenantId::from_str("acme");
+    for timestamp in [300, 600, 900, 1800, 2100] {
+        db.insert_metric_row(
+            &tenant_id,
+            "meter_1",
+            "power",
+            timestamp,
+            MetricValue::Float(1.5),
+        )
+        .await
+        .unwrap();
+    }
+    let (api_cancel_token, api_handle) = start_api_server(
+        &config.bind_api,
+        db,
+        None,
+        Arc::new(MqttServerMetrics::new(1)),
+        Arc::new(ConnectionSet::new()),
+        &config,
+        Arc::new(RwLock::new(config.processor.clone())),
+        Arc::new(IngestMetrics::default()),
+        None,
+        None,
+        Arc::new(ShadowRateLimiter::default()),
+        None,
+    )
+    .await;
+    sleep(Duration::from_millis(500)).await;
+
+    let client = Client::new();
+    let api_url = format!("http://{}", config.bind_api);
+
+    // The window ends 10 minutes after the last upload, that's a gap too
+    let res = client
+        .get(format!(
+            "{}/acme/data/meter_1/power/gaps?start=300&end=2700&interval=300",
+            api_url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let report: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(
+        report,
+        json!({
+            "gaps": [[900, 1800], [2100, 2700]],
+            "expected_count": 9,
+            "actual_count": 5
+        })
+    );
+
+    // Another tenant has no data, the whole window is a gap
+    let report: serde_json::Value = client
+        .get(format!(
+            "{}/default/data/meter_1/power/gaps?start=300&end=2700&interval=300",
+            api_url
+        ))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["gaps"], json!([[300, 2700]]));
+    assert_eq!(report["actual_count"], 0);
+
+    let res = client
+        .get(format!(
+            "{}/acme/data/meter_1/power/gaps?start=300&end=2700&interval=0",
+            api_url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+
+    api_cancel_token.cancel();
+    let _ = tokio::time::timeout(Duration::from_secs(2), api_handle).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_metric_catalog() {
+    let config = test_config();
+
+    let db = Arc::new(DB::open_default(&config.database.path).await.unwrap());
+    let tenant_id = TenantId::Default;
+    let tenant_config: DataConfig = serde_json::from_value(json!({"metrics": [
+        {"json_pointer": "/temp", "name": "temp", "data_type": "Float",
+         "unit": "°C", "display_name": "Temperature"},
+        {"json_pointer": "/channels/*", "name": "ch", "data_type": "Float", "unit": "V"}
+    ]}))
+    .unwrap();
+    db.store_tenant_data_config(&tenant_id, &tenant_config)
+        .await
+        .unwrap();
+    // Devices starting with us_ report Fahrenheit
+    let device_config: DataConfig = serde_json::from_value(json!({"metrics": [
+        {"json_pointer": "/temp_f", "name": "temp", "data_type": "Float", "unit": "°F"}
+    ]}))
+    .unwrap();
+    db.store_device_data_config(&tenant_id, "us_", &device_config)
+        .await
+        .unwrap();
+    for device_id in ["us_1", "eu_1"] {
+        for metric in ["temp", "ch_0", "legacy"] {
+            db.insert_metric_row(&tenant_id, device_id, metric, 1000, MetricValue::Float(1.5))
+                .await
+                .unwrap();
+        }
+    }
+    let (api_cancel_token, api_handle) = start_api_server(
+        &config.bind_api,
+        db,
+        None,
+        Arc::new(MqttServerMetrics::new(1)),
+        Arc::new(ConnectionSet::new()),
+        &config,
+        Arc::new(RwLock::new(config.processor.clone())),
+        Arc::new(IngestMetrics::default()),
+        None,
+        None,
+        Arc::new(ShadowRateLimiter::default()),
+        None,
+    )
+    .await;
+    sleep(Duration::from_millis(500)).await;
+
+    let client = Client::new();
+    let api_url = format!("http://{}", config.bind_api);
+
+    let catalog: serde_json::Value = client
+        .get(format!("{}/default/data/us_1/catalog", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        catalog,
+        json!([
+            {"name": "ch_0", "unit": "V", "display_name": null, "data_type": "Float"},
+            {"name": "legacy", "unit": null, "display_name": null, "data_type": null},
+            {"name": "temp", "unit": "°F", "display_name": "Temperature", "data_type": "Float"}
+        ])
+    );
+    let catalog: serde_json::Value = client
+        .get(format!("{}/default/data/eu_1/catalog", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(catalog[2]["unit"], "°C");
+
+    // Timeseries carry the unit of their metric
+    let model: serde_json::Value = client
+        .get(format!("{}/default/data/us_1/temp/last", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(model["unit"], "°F");
+    let model: serde_json::Value = client
+        .get(format!("{}/default/data/us_1/legacy/last", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(model.get("unit").is_none());
+
+    // A device without data has an empty catalog
+    let catalog: serde_json::Value = client
+        .get(format!("{}/default/data/us_2/catalog", api_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(catalog, json!([]));
+
+    api_cancel_token.cancel();
+    let _ = tokio::time::timeout(Duration::from_secs(2), api_handle).await;
+}
+
+#[test]
+fn test_message_drop_alert() {
+    let metrics = MqttServerMetrics::new(0);
+    let mut alert = DropAlert::new(5);
+
+    // Drops up to the threshold don't alert
+    for _ in 0..5 {
+        metrics.record_dropped(1000);
+    }
+    assert_eq!(alert.check(&metrics, 1000), None);
+
+    // One more within the minute does, repeated at most once per window
+    metrics.record_dropped(1030);
+    assert_eq!(alert.check(&metrics, 1030), Some(6));
+    metrics.record_dropped(1040);
+    assert_eq!(alert.check(&metrics, 1040), None);
+    assert_eq!(alert.check(&metrics, 1055), None);
+
+    // Drops older than the window no longer count
+    assert_eq!(metrics.dropped_in_window(1060), 2);
+    assert_eq!(alert.check(&metrics, 1060), None);
+    for _ in 0..10 {
+        metrics.record_dropped(1070);
+    }
+    assert_eq!(alert.check(&metrics, 1070), Some(12));
+    assert_eq!(
+        metrics
+            .messages_dropped
+            .load(std::sync::atomic::Ordering::Relaxed),
+        17
+    );
+
+    // A threshold of 0 disables the alert
+    assert_eq!(DropAlert::new(0).check(&metrics, 1070), None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_shadow_webhooks() {
+    use axum::{body::Bytes, http::HeaderMap, routing::post, Router};
+    use forest::processor::webhooks::webhook_signature;
+
+    // Receiver of the webhooks, fails the first request to exercise the retry
+    let (tx, rx) = flume::unbounded::<(HeaderMap, Bytes)>();
+    let failed_once = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let receiver = Router::new().route(
+        "/hook",
+        post(move |headers: HeaderMap, body: Bytes| {
+            let tx = tx.clone();
+            let failed_once = failed_once.clone();
+            async move {
+                if !failed_once.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    return axum::http::StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                tx.send((headers, body)).unwrap();
+                axum::http::StatusCode::OK
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook_url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+    let webhooks_url = format!("{}/default/devices/lamp/webhooks", api_url);
+
+    for body in [
+        json!({"url": "ftp://example.com", "events": ["updated"]}),
+        json!({"url": &hook_url, "events": []}),
+    ] {
+        let res = client.post(&webhooks_url).json(&body).send().await.unwrap();
+        assert_eq!(res.status().as_u16(), 422);
+    }
+
+    // The secret is never returned
+    let res = client
+        .post(&webhooks_url)
+        .json(&json!({
+            "url": &hook_url,
+            "secret": "s3cret",
+            "events": ["delta_cleared"]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let webhook: serde_json::Value = res.json().await.unwrap();
+    assert!(webhook.get("secret").is_none());
+    let webhook_id = webhook["webhook_id"].as_str().unwrap().to_string();
+    let webhook_url = format!("{}/{}", webhooks_url, webhook_id);
+
+    let res = client.get(&webhooks_url).send().await.unwrap();
+    let webhooks: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(webhooks.as_array().unwrap().len(), 1);
+    assert_eq!(webhooks[0]["events"], json!(["delta_cleared"]));
+
+    let shadow_url = format!("{}/default/things/lamp/shadow", api_url);
+    client
+        .post(&shadow_url)
+        .json(&json!({"state": {"desired": {"led": "on"}, "reported": {"led": "off"}}}))
+        .send()
+        .await
+        .unwrap();
+    // Only the update clearing the delta is posted, after one failed attempt
+    client
+        .post(&shadow_url)
+        .json(&json!({"state": {"reported": {"led": "on"}}}))
+        .send()
+        .await
+        .unwrap();
+    let (headers, body) = tokio::time::timeout(Duration::from_secs(5), rx.recv_async())
+        .await
+        .expect("Timeout waiting for the webhook")
+        .unwrap();
+    assert_eq!(headers["x-forest-event"], "delta_cleared");
+    assert_eq!(
+        headers["x-forest-signature"],
+        webhook_signature("s3cret", &body).unwrap().as_str()
+    );
+    let shadow: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(shadow["device_id"], "lamp");
+    assert_eq!(shadow["state"]["reported"]["led"], "on");
+    assert!(rx.is_empty());
+
+    let res = client
+        .put(&webhook_url)
+        .json(&json!({"url": "https://example.com/hook", "events": ["updated"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let webhook: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(webhook["webhook_id"], webhook_id.as_str());
+    assert_eq!(webhook["url"], "https://example.com/hook");
+
+    let res = client.delete(&webhook_url).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client.get(&webhook_url).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+    let res = client.delete(&webhook_url).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_provisioning_token() {
+    let server = spawn_test_server(|config| {
+        config.admin_api_token = Some("s3cret".to_string());
+    })
+    .await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+
+    let res = client
+        .post(format!(
+            "{}/default/devices/sensor_1/provisioning-token?ttl_seconds=0",
+            api_url
+        ))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 422);
+    for ttl_seconds in [2_592_001, u64::MAX] {
+        let res = client
+            .post(format!(
+                "{}/default/devices/sensor_1/provisioning-token?ttl_seconds={}",
+                api_url, ttl_seconds
+            ))
+            .bearer_auth("s3cret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 422);
+    }
+
+    let res = client
+        .post(format!(
+            "{}/default/devices/sensor_1/provisioning-token?ttl_seconds=600",
+            api_url
+        ))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["device_id"], "sensor_1");
+    let token = body["token"].as_str().unwrap().to_string();
+    assert_eq!(token.len(), 64);
+
+    // The device needs no admin token, only the provisioning token
+    let res = client
+        .post(format!("{}/provision", api_url))
+        .query(&[("token", &token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["device_id"], "sensor_1");
+    assert_eq!(body["tenant_id"], "default");
+    assert!(body["certificate"]["cert"]
+        .as_str()
+        .unwrap()
+        .starts_with("-----BEGIN CERTIFICATE-----"));
+    assert!(body["certificate"]["key"]
+        .as_str()
+        .unwrap()
+        .contains("PRIVATE KEY"));
+
+    // A token can only be used once
+    let res = client
+        .post(format!("{}/provision", api_url))
+        .query(&[("token", &token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 401);
+    let res = client
+        .post(format!("{}/provision", api_url))
+        .query(&[("token", "unknown")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 401);
+
+    // The certificate is stored like for devices created through the API
+    let res = client
+        .get(format!("{}/default/devices/sensor_1", api_url))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let metadata: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(metadata["certificate"], body["certificate"]["cert"]);
+
+    // Inactive devices get no certificate, the token stays usable
+    let res = client
+        .post(format!("{}/default/devices/sensor_1/deactivate", api_url))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .post(format!(
+            "{}/default/devices/sensor_1/provisioning-token?ttl_seconds=600",
+            api_url
+        ))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    let token: serde_json::Value = res.json().await.unwrap();
+    let token = token["token"].as_str().unwrap().to_string();
+    let res = client
+        .post(format!("{}/provision", api_url))
+        .query(&[("token", &token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 409);
+    let res = client
+        .post(format!("{}/default/devices/sensor_1/activate", api_url))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .post(format!("{}/provision", api_url))
+        .query(&[("token", &token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    // New devices count against the device quota
+    let res = client
+        .post(format!("{}/tenants", api_url))
+        .bearer_auth("s3cret")
+        .json(&Tenant::new(&TenantId::from_str("limited")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .put(format!("{}/tenants/limited/quota", api_url))
+        .bearer_auth("s3cret")
+        .json(&json!({"max_devices": 0}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .post(format!(
+            "{}/limited/devices/sensor_2/provisioning-token?ttl_seconds=600",
+            api_url
+        ))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    let token: serde_json::Value = res.json().await.unwrap();
+    let token = token["token"].as_str().unwrap().to_string();
+    let res = client
+        .post(format!("{}/provision", api_url))
+        .query(&[("token", &token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 429);
+    let res = client
+        .put(format!("{}/tenants/limited/quota", api_url))
+        .bearer_auth("s3cret")
+        .json(&json!({"max_devices": 1}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client
+        .post(format!("{}/provision", api_url))
+        .query(&[("token", &token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_metric_stream() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+    let res = client
+        .put(format!("{}/default/dataconfig", api_url))
+        .json(&json!({"metrics": [
+            {"json_pointer": "/temp", "name": "temp", "data_type": "Float"},
+            {"json_pointer": "/humidity", "name": "humidity", "data_type": "Float"}
+        ]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    let stream_url = format!(
+        "ws://{}/default/data/stream_device/temp/stream",
+        server.config.bind_api
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(stream_url).await.unwrap();
+
+    // Telemetry published on the MQTT topic of the device reaches the processor
+    let publish = |device_id: &str, payload: serde_json::Value| {
+        client
+            .post(format!("{}/default/things/{}/publish", api_url, device_id))
+            .json(&json!({"topic_suffix": "data", "payload": payload}))
+            .send()
+    };
+    // Other devices and metrics are filtered out
+    publish("other_device", json!({"temp": 30.0}))
+        .await
+        .unwrap();
+    publish("stream_device", json!({"temp": 21.5, "humidity": 40.0}))
+        .await
+        .unwrap();
+
+    let message = tokio::time::timeout(Duration::from_secs(2), socket.next())
+        .await
+        .expect("Timeout waiting for streamed metric")
+        .unwrap()
+        .unwrap();
+    let point: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+    assert!(point[0].as_u64().unwrap() > 0);
+    assert_eq!(point[1], 21.5);
+    // Nothing else is pushed
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), socket.next())
+            .await
+            .is_err()
+    );
+
+    socket.send(Message::Close(None)).await.unwrap();
+    let res = client.get(api_url).send().await.unwrap();
+    let home: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(home["metric_stream_dropped"], 0);
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_read_only_mode() {
+    let server = spawn_test_server(|config| {
+        config.api_read_only = true;
+    })
+    .await;
+
+    let client = Client::new();
+    let api_url = &server.url;
+    let shadow_url = format!("{}/default/things/ro_device/shadow", api_url);
+    let update = json!({"state": {"reported": {"temp": 21}}});
+
+    // Writes of every kind are rejected
+    let writes = [
+        client.post(&shadow_url).json(&update),
+        client.delete(&shadow_url),
+        client
+            .post(format!("{}/default/data/ro_device", api_url))
+            .json(&json!({"temp": 21})),
+        client
+            .put(format!("{}/default/dataconfig", api_url))
+            .json(&json!({"metrics": []})),
+        client.post(format!("{}/default/devices/ro_device", api_url)),
+        client
+            .post(format!("{}/tenants", api_url))
+            .json(&json!({"tenant_id": "ro_tenant"})),
+    ];
+    for request in writes {
+        let res = request.send().await.unwrap();
+        assert_eq!(res.status().as_u16(), 503);
+        assert_eq!(res.headers()["retry-after"], "60");
+    }
+
+    // Reads keep working
+    let res = client.get(&shadow_url).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+    let res = client
+        .get(format!("{}/admin/readonly", api_url))
+        .send()
+        .await
+        .unwrap();
+    let mode: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(mode["enabled"], true);
+
+    let res = client
+        .post(format!("{}/admin/readonly", api_url))
+        .json(&json!({"enabled": false}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client.post(&shadow_url).json(&update).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+
+    let res = client
+        .post(format!("{}/admin/readonly", api_url))
+        .json(&json!({"enabled": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let res = client.post(&shadow_url).json(&update).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 503);
+    let res = client.get(&shadow_url).send().await.unwrap();
+    assert_eq!(res.status().as_u16(), 200);
 
-    // Wait for shutdown (handle typically finishes within a few ms of cancellation)
-    let _ = tokio::time::timeout(Duration::from_secs(2), handle).await;
+    server.stop().await;
 }
@@ -0,0 +1,442 @@
+mod common;
+
+use common::spawn_test_server;
+use forest::api::client::{ClientError, ForestClient};
+use forest::dataconfig::{DataConfig, DataType, MetricConfig};
+use forest::models::{
+    AlarmCondition, AlarmRule, AuthConfig, DeviceStatus, LabelSelector, ShadowEvent, ShadowWebhook,
+    Tenant, TenantId, TenantQuota,
+};
+use forest::shadow::NestedStateDocument;
+use openssl::pkcs12::Pkcs12;
+use serde_json::json;
+use std::collections::HashMap;
+
+fn temp_config() -> DataConfig {
+    DataConfig {
+        metrics: vec![MetricConfig {
+            json_pointer: "/temp".to_string(),
+            name: "temp".to_string(),
+            data_type: DataType::Float,
+            ..Default::default()
+        }],
+        transformations: Vec::new(),
+        payload_schema: None,
+        enable_deduplication: false,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_client_covers_all_routes() {
+    let server = spawn_test_server(|_| {}).await;
+
+    let client = ForestClient::new(&server.url, None);
+
+    // Server
+    assert_eq!(client.health().await.unwrap(), "OK");
+    assert_eq!(client.home().await.unwrap().connected_devices, 0);
+    assert_eq!(client.time(Some(42)).await.unwrap().device_time, Some(42));
+    assert!(client.openapi().await.unwrap()["paths"].is_object());
+    assert_eq!(client.mqtt_meters().await.unwrap().capacity, 60);
+    assert!(client
+        .mqtt_subscriptions()
+        .await
+        .unwrap()
+        .connections
+        .is_empty());
+
+    // Read-only mode
+    assert!(!client.read_only().await.unwrap());
+    client.set_read_only(true).await.unwrap();
+    assert!(client.read_only().await.unwrap());
+    let update = NestedStateDocument::from_json(r#"{"state": {"desired": {}}}"#).unwrap();
+    let err = client
+        .update_shadow("default", "client_dev", None, &update)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Server { status: 503, .. }));
+    client.set_read_only(false).await.unwrap();
+
+    // Shadows
+    let update =
+        NestedStateDocument::from_json(r#"{"state": {"desired": {"led": "on"}}}"#).unwrap();
+    let shadow = client
+        .update_shadow("default", "client_dev", None, &update)
+        .await
+        .unwrap();
+    assert_eq!(shadow.get_version(), 1);
+    let shadow = client
+        .get_shadow("default", "client_dev", None)
+        .await
+        .unwrap();
+    assert_eq!(shadow.get_desired_value()["led"], "on");
+    client
+        .update_shadow("default", "client_dev", Some("config"), &update)
+        .await
+        .unwrap();
+    client
+        .delete_shadow("default", "client_dev", Some("config"))
+        .await
+        .unwrap();
+    let err = client
+        .get_shadow("default", "client_dev", Some("config"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Client { status: 404, .. }));
+
+    // Data configs
+    client
+        .store_tenant_data_config("default", &temp_config())
+        .await
+        .unwrap();
+    let tenant_config = client.get_tenant_data_config("default").await.unwrap();
+    assert_eq!(tenant_config.metrics.len(), 1);
+    client
+        .store_device_data_config("default", "client_", &temp_config())
+        .await
+        .unwrap();
+    let device_config = client
+        .get_device_data_config("default", "client_dev")
+        .await
+        .unwrap();
+    assert_eq!(device_config.metrics[0].name, "temp");
+    assert_eq!(client.list_data_configs("default").await.unwrap().len(), 2);
+
+    // Timeseries
+    client
+        .post_telemetry("default", "client_dev", &json!({"temp": 21.5}))
+        .await
+        .unwrap();
+    let last = client
+        .get_last_timeseries("default", "client_dev", "temp", Some(5))
+        .await
+        .unwrap();
+    assert_eq!(last.data.len(), 1);
+    assert_eq!(last.data[0].1, json!(21.5));
+    let range = client
+        .get_timeseries("default", "client_dev", "temp", 0, u32::MAX as u64, None)
+        .await
+        .unwrap();
+    assert_eq!(range.data.len(), 1);
+    let by_prefix = client
+        .get_timeseries_by_prefix("default", "client_", "temp", 0, u32::MAX as u64)
+        .await
+        .unwrap();
+    assert_eq!(by_prefix["client_dev"].data.len(), 1);
+
+    client
+        .delete_device_data_config("default", "client_")
+        .await
+        .unwrap();
+    client.delete_tenant_data_config("default").await.unwrap();
+    assert!(client
+        .list_data_configs("default")
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Groups
+    let group = client
+        .create_group("default", "client_group", &["client_dev", "other_dev"])
+        .await
+        .unwrap();
+    assert_eq!(group.device_ids, vec!["client_dev", "other_dev"]);
+    client
+        .add_device_to_group("default", "client_group", "third_dev")
+        .await
+        .unwrap();
+    let devices = client
+        .remove_device_from_group("default", "client_group", "other_dev")
+        .await
+        .unwrap();
+    assert_eq!(devices, vec!["client_dev", "third_dev"]);
+    assert_eq!(
+        client
+            .list_group_devices("default", "client_group")
+            .await
+            .unwrap(),
+        devices
+    );
+    let shadows = client
+        .update_group_desired("default", "client_group", None, &json!({"led": "off"}))
+        .await
+        .unwrap();
+    assert_eq!(shadows.len(), 2);
+    assert_eq!(shadows[0].get_desired_value()["led"], "off");
+
+    // Alarms
+    let rule = AlarmRule {
+        tenant_id: TenantId::Default,
+        device_id: "client_dev".to_string(),
+        metric_name: "temp".to_string(),
+        condition: AlarmCondition::Below,
+        threshold: 5.0,
+        alarm_id: "client_frost".to_string(),
+    };
+    assert_eq!(
+        client.create_alarm_rule("default", &rule).await.unwrap(),
+        rule
+    );
+    assert!(client
+        .list_device_alarms("default", "client_dev", Some(10))
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Webhooks
+    let mut webhook = ShadowWebhook {
+        webhook_id: String::new(),
+        url: "http://127.0.0.1:1/hook".to_string(),
+        secret: Some("s3cret".to_string()),
+        events: vec![ShadowEvent::Updated],
+    };
+    let created = client
+        .create_shadow_webhook("default", "client_dev", &webhook)
+        .await
+        .unwrap();
+    assert!(!created.webhook_id.is_empty());
+    assert_eq!(created.secret, None);
+    webhook.events = vec![ShadowEvent::DeltaCleared];
+    client
+        .update_shadow_webhook("default", "client_dev", &created.webhook_id, &webhook)
+        .await
+        .unwrap();
+    let webhooks = client
+        .list_shadow_webhooks("default", "client_dev")
+        .await
+        .unwrap();
+    assert_eq!(webhooks.len(), 1);
+    assert_eq!(webhooks[0].events, vec![ShadowEvent::DeltaCleared]);
+    client
+        .delete_shadow_webhook("default", "client_dev", &created.webhook_id)
+        .await
+        .unwrap();
+    assert!(client
+        .list_shadow_webhooks("default", "client_dev")
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Tenants
+    let mut auth_config = AuthConfig::default();
+    auth_config.allow_passwords = true;
+    let tenant = Tenant::new(&TenantId::from_str("client-tenant")).with_auth_config(auth_config);
+    client.create_tenant(&tenant).await.unwrap();
+    let fetched = client.get_tenant("client-tenant").await.unwrap();
+    assert!(fetched.auth_config.allow_passwords);
+    let quota = TenantQuota {
+        max_devices: Some(10),
+        ..Default::default()
+    };
+    let limited = client
+        .set_tenant_quota("client-tenant", &quota)
+        .await
+        .unwrap();
+    assert_eq!(limited.quota, quota);
+    assert!(limited.auth_config.allow_passwords);
+    let usage = client.tenant_usage("client-tenant").await.unwrap();
+    assert_eq!(usage.device_count, 0);
+    assert_eq!(usage.metric_row_count, 0);
+
+    // Devices
+    let created = client
+        .create_device("default", "client_dev", false)
+        .await
+        .unwrap();
+    assert!(created.certificate.is_some());
+    assert_eq!(
+        client.list_devices("default").await.unwrap(),
+        vec!["client_dev".to_string()]
+    );
+    let info = client
+        .get_device_info("default", "client_dev")
+        .await
+        .unwrap();
+    assert!(!info.connected);
+    assert!(info.last_shadow_update.is_some());
+    let metadata = client
+        .get_device_metadata("default", "client_dev")
+        .await
+        .unwrap();
+    assert_eq!(metadata.certificate, created.certificate);
+    assert!(client.list_connected("default").await.unwrap().is_empty());
+
+    assert!(client
+        .list_stale_devices("default", 24)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Lifecycle
+    let deactivated = client
+        .deactivate_device("default", "client_dev", false)
+        .await
+        .unwrap();
+    assert_eq!(deactivated.status, DeviceStatus::Inactive);
+    client
+        .activate_device("default", "client_dev")
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .list_devices_by_status("default", DeviceStatus::Active)
+            .await
+            .unwrap(),
+        vec!["client_dev".to_string()]
+    );
+
+    // Firmware
+    let summary = client.firmware_summary("default").await.unwrap();
+    assert_eq!(summary.device_count, 1);
+    assert_eq!(summary.unknown_version, 1);
+    assert!(client
+        .list_devices_needing_update("default")
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Tags
+    let tags = HashMap::from([("location".to_string(), "lab".to_string())]);
+    client
+        .set_device_tags("default", "client_dev", &tags)
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .get_device_tags("default", "client_dev")
+            .await
+            .unwrap(),
+        tags
+    );
+    assert_eq!(
+        client
+            .list_devices_by_tag("default", "location", "lab")
+            .await
+            .unwrap(),
+        vec!["client_dev".to_string()]
+    );
+
+    // Labels
+    let site = LabelSelector::parse("site=lab").unwrap();
+    let changes = HashMap::from([("site".to_string(), Some("lab".to_string()))]);
+    let labels = client
+        .update_device_labels("default", "client_dev", &changes)
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .get_device_labels("default", "client_dev")
+            .await
+            .unwrap(),
+        labels
+    );
+    assert_eq!(
+        client
+            .list_devices_by_label("default", &site)
+            .await
+            .unwrap(),
+        vec!["client_dev".to_string()]
+    );
+    client
+        .store_label_data_config("default", &site, &temp_config())
+        .await
+        .unwrap();
+    let label_config = client
+        .get_label_data_config("default", &site)
+        .await
+        .unwrap();
+    assert_eq!(label_config.metrics[0].name, "temp");
+    client
+        .delete_label_data_config("default", &site)
+        .await
+        .unwrap();
+
+    // Credentials
+    client
+        .add_device_password("client-tenant", "client_dev", "user", "secret")
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .list_device_passwords("client-tenant", "client_dev")
+            .await
+            .unwrap(),
+        vec!["user".to_string()]
+    );
+
+    // Audit log
+    let page = client.audit_log("client-tenant", None, None).await.unwrap();
+    let entry = page.entries.last().unwrap();
+    assert_eq!(entry.action, "add_password");
+    assert_eq!(entry.resource_id, "client_dev");
+    assert_eq!(entry.actor, "127.0.0.1");
+    assert_eq!(entry.payload, json!({"username": "user"}));
+    let first = client.audit_log("default", None, Some(1)).await.unwrap();
+    assert_eq!(first.entries.len(), 1);
+    assert_eq!(first.entries[0].action, "update");
+    assert_eq!(first.entries[0].resource_type, "shadow");
+    let next = client
+        .audit_log("default", first.next_after, Some(1))
+        .await
+        .unwrap();
+    assert!(next.entries[0].id > first.entries[0].id);
+
+    let export = client
+        .export_device("default", "client_dev", true)
+        .await
+        .unwrap();
+    assert_eq!(export.device_id, "client_dev");
+    assert!(export.metadata.is_some());
+    assert!(!export.shadows.is_empty());
+    assert!(export.metrics.is_some());
+
+    client.delete_device("default", "client_dev").await.unwrap();
+    let err = client
+        .get_device_metadata("default", "client_dev")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Client { status: 404, .. }));
+
+    // Certificates
+    client.generate_server_ca().await.unwrap();
+    let server_ca = client.get_server_ca().await.unwrap();
+    assert!(server_ca.contains("BEGIN CERTIFICATE"));
+    client.generate_tenant_ca("client-tenant").await.unwrap();
+    let tenant_ca = client.get_tenant_ca("client-tenant").await.unwrap();
+    assert!(tenant_ca.contains("BEGIN CERTIFICATE"));
+    let cert = client
+        .generate_client_cert("client-tenant", "client_dev")
+        .await
+        .unwrap();
+    assert!(cert.cert.contains("BEGIN CERTIFICATE"));
+    let p12 = client
+        .export_client_pkcs12("client-tenant", "client_dev", "p12-secret")
+        .await
+        .unwrap();
+    let parsed = Pkcs12::from_der(&p12)
+        .unwrap()
+        .parse2("p12-secret")
+        .unwrap();
+    assert_eq!(parsed.cert.unwrap().to_pem().unwrap(), cert.cert.as_bytes());
+    let err = client
+        .export_client_pkcs12("client-tenant", "unknown_dev", "p12-secret")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Client { status: 404, .. }));
+    let provisioning = client
+        .create_provisioning_token("client-tenant", "client_dev", 60)
+        .await
+        .unwrap();
+    let provisioned = client.provision(&provisioning.token).await.unwrap();
+    assert_eq!(provisioned.device_id, "client_dev");
+    assert!(provisioned.certificate.cert.contains("BEGIN CERTIFICATE"));
+    let err = client.provision(&provisioning.token).await.unwrap_err();
+    assert!(matches!(err, ClientError::Client { status: 401, .. }));
+    // Replace the generated tenant CA with an uploaded one
+    client
+        .upload_tenant_ca("client-tenant", &server_ca)
+        .await
+        .unwrap();
+
+    server.stop().await;
+}
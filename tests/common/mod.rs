@@ -0,0 +1,67 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use forest::config::ForestConfig;
+use forest::server::start_server;
+use std::fs;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Picks `N` distinct free local addresses by binding port 0
+pub fn free_local_addrs<const N: usize>() -> [String; N] {
+    // All listeners are held until every port is picked, so none is handed out twice
+    let listeners: [std::net::TcpListener; N] =
+        std::array::from_fn(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap());
+    listeners.map(|listener| listener.local_addr().unwrap().to_string())
+}
+
+/// Full server started by `spawn_test_server`
+pub struct TestServer {
+    /// Base URL of the HTTP API
+    pub url: String,
+    pub config: ForestConfig,
+    cancel_token: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn stop(self) {
+        self.cancel_token.cancel();
+        let _ = tokio::time::timeout(Duration::from_secs(2), self.handle).await;
+    }
+}
+
+/// Config on free local ports with its own in-memory database and certificate directory
+pub fn test_config() -> ForestConfig {
+    let db_id = Uuid::new_v4().simple();
+
+    let mut config = ForestConfig::default();
+    let [bind_api, bind_v3, bind_v5] = free_local_addrs();
+    config.bind_api = bind_api;
+    config.mqtt.bind_v3 = bind_v3;
+    config.mqtt.bind_v5 = bind_v5;
+    config.cert_dir = format!("/tmp/forest_certs_{}", db_id);
+    fs::create_dir_all(&config.cert_dir).unwrap();
+    config.database.path = format!("sqlite:file:memdb_{}?mode=memory&cache=shared", db_id);
+    config
+}
+
+/// Starts a server, `configure` adjusts the test config before the start
+pub async fn spawn_test_server(configure: impl FnOnce(&mut ForestConfig)) -> TestServer {
+    let mut config = test_config();
+    configure(&mut config);
+
+    let (cancel_token, handle) = start_server(&config, None).await;
+    // Wait a brief moment for server to come up
+    sleep(Duration::from_millis(500)).await;
+
+    TestServer {
+        url: format!("http://{}", config.bind_api),
+        config,
+        cancel_token,
+        handle,
+    }
+}